@@ -0,0 +1,376 @@
+use serde::{de, Deserialize, Deserializer};
+use std::error::Error;
+use std::fmt;
+use std::ops::{Add, Neg, Sub};
+use std::str::FromStr;
+
+use crate::core::{FIXED_DECIMAL_PLACES, FIXED_DECIMAL_SCALING};
+
+/// Fixed point monetary amount, stored as an integer number of 1/10,000ths of a unit.
+///
+/// Amounts are parsed directly from decimal strings so that no floating point rounding is
+/// involved at any point. Parsed amounts are never negative, but results of arithmetic may be
+/// (e.g. the held funds of a disputed withdrawal).
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Default, Hash)]
+pub struct Amount(i128);
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum AmountParseError {
+    Empty,
+    InvalidDigit,
+    Negative,
+    ExcessPrecision,
+    Overflow,
+}
+
+impl fmt::Display for AmountParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmountParseError::Empty => write!(f, "amount is empty"),
+            AmountParseError::InvalidDigit => write!(f, "amount is not a decimal number"),
+            AmountParseError::Negative => write!(f, "amount cannot be negative"),
+            AmountParseError::ExcessPrecision => write!(
+                f,
+                "amount has more than {} decimal places",
+                FIXED_DECIMAL_PLACES
+            ),
+            AmountParseError::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl Error for AmountParseError {}
+
+/// The result of arithmetic on amounts doesn't fit in an amount
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct AmountOverflow;
+
+impl fmt::Display for AmountOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "amount is out of range")
+    }
+}
+
+impl Error for AmountOverflow {}
+
+/// Options for formatting an amount as a decimal string
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct AmountFormat {
+    /// Number of digits after the decimal point, values are rounded half away from zero if this is
+    /// less than the stored precision
+    pub fraction_digits: u32,
+    /// Remove trailing zeros (and the decimal point if no digits are left) from the fraction
+    pub trim_trailing_zeros: bool,
+}
+
+impl Default for AmountFormat {
+    fn default() -> AmountFormat {
+        AmountFormat {
+            fraction_digits: FIXED_DECIMAL_PLACES,
+            trim_trailing_zeros: false,
+        }
+    }
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    /// Largest amount parsed from input, just under 10^20 units. Balances sum many amounts, so
+    /// this keeps them far from the limit of the representation, but arithmetic on balances is
+    /// still checked.
+    pub const MAX: Amount = Amount(10i128.pow(20 + FIXED_DECIMAL_PLACES) - 1);
+
+    /// Build an amount from its fixed point representation (1/10,000ths of a unit)
+    pub const fn from_fixed(value: i128) -> Amount {
+        Amount(value)
+    }
+
+    /// The fixed point representation of the amount (1/10,000ths of a unit)
+    pub const fn to_fixed(self) -> i128 {
+        self.0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Format the amount as an exact decimal string, without passing through floating point
+    pub fn format(&self, format: &AmountFormat) -> String {
+        let places = FIXED_DECIMAL_PLACES;
+        let mut magnitude = self.0.unsigned_abs();
+        let mut digits = places;
+
+        // Round away the stored digits that aren't displayed
+        if format.fraction_digits < places {
+            let divisor = 10u128.pow(places - format.fraction_digits);
+            magnitude = (magnitude + divisor / 2) / divisor;
+            digits = format.fraction_digits;
+        }
+
+        let scaling = 10u128.pow(digits);
+        let whole = magnitude / scaling;
+        let mut fraction = match digits {
+            0 => String::new(),
+            _ => format!("{:0width$}", magnitude % scaling, width = digits as usize),
+        };
+        // Pad out to the requested digits beyond the stored precision
+        for _ in digits..format.fraction_digits {
+            fraction.push('0');
+        }
+        if format.trim_trailing_zeros {
+            fraction.truncate(fraction.trim_end_matches('0').len());
+        }
+
+        // Don't show a sign on a negative amount that has been rounded to zero
+        let is_zero = whole == 0 && fraction.bytes().all(|b| b == b'0');
+        let sign = if self.is_negative() && !is_zero {
+            "-"
+        } else {
+            ""
+        };
+
+        if fraction.is_empty() {
+            format!("{}{}", sign, whole)
+        } else {
+            format!("{}{}.{}", sign, whole, fraction)
+        }
+    }
+}
+
+impl FromStr for Amount {
+    type Err = AmountParseError;
+
+    fn from_str(value: &str) -> Result<Amount, AmountParseError> {
+        if value.is_empty() {
+            return Err(AmountParseError::Empty);
+        }
+        if value.starts_with('-') {
+            return Err(AmountParseError::Negative);
+        }
+
+        let (whole, fraction) = match value.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (value, ""),
+        };
+        let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+            return Err(AmountParseError::InvalidDigit);
+        }
+        if value.ends_with('.') {
+            return Err(AmountParseError::InvalidDigit);
+        }
+        if fraction.len() > FIXED_DECIMAL_PLACES as usize {
+            return Err(AmountParseError::ExcessPrecision);
+        }
+
+        let whole = whole
+            .parse::<i128>()
+            .map_err(|_| AmountParseError::Overflow)?;
+        // Right pad the fraction so that e.g. "5" is read as 5000 1/10,000ths
+        let fraction = format!(
+            "{:0<width$}",
+            fraction,
+            width = FIXED_DECIMAL_PLACES as usize
+        )
+        .parse::<i128>()
+        .map_err(|_| AmountParseError::InvalidDigit)?;
+
+        whole
+            .checked_mul(FIXED_DECIMAL_SCALING as i128)
+            .and_then(|scaled| scaled.checked_add(fraction))
+            .map(Amount)
+            .filter(|&amount| amount <= Amount::MAX)
+            .ok_or(AmountParseError::Overflow)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format(&AmountFormat::default()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, other: Amount) -> Amount {
+        Amount(self.0 + other.0)
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, other: Amount) -> Amount {
+        Amount(self.0 - other.0)
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_decimal_strings_exactly() {
+        assert_eq!("1".parse(), Ok(Amount::from_fixed(10000)));
+        assert_eq!("1.5".parse(), Ok(Amount::from_fixed(15000)));
+        assert_eq!("0.0001".parse(), Ok(Amount::from_fixed(1)));
+        assert_eq!("32.1234".parse(), Ok(Amount::from_fixed(321234)));
+        assert_eq!("007.10".parse(), Ok(Amount::from_fixed(71000)));
+    }
+
+    #[test]
+    fn it_rejects_invalid_amounts() {
+        assert_eq!("".parse::<Amount>(), Err(AmountParseError::Empty));
+        assert_eq!("-1.0".parse::<Amount>(), Err(AmountParseError::Negative));
+        assert_eq!(
+            "0.00001".parse::<Amount>(),
+            Err(AmountParseError::ExcessPrecision)
+        );
+        assert_eq!("1e3".parse::<Amount>(), Err(AmountParseError::InvalidDigit));
+        assert_eq!(".5".parse::<Amount>(), Err(AmountParseError::InvalidDigit));
+        assert_eq!("5.".parse::<Amount>(), Err(AmountParseError::InvalidDigit));
+        assert_eq!("+5".parse::<Amount>(), Err(AmountParseError::InvalidDigit));
+        assert_eq!(
+            "1.2.3".parse::<Amount>(),
+            Err(AmountParseError::InvalidDigit)
+        );
+        assert_eq!(
+            "99999999999999999999999999999999999999".parse::<Amount>(),
+            Err(AmountParseError::Overflow)
+        );
+        assert_eq!(
+            "99999999999999999999.9999".parse::<Amount>(),
+            Ok(Amount::MAX)
+        );
+        assert_eq!(
+            "100000000000000000000".parse::<Amount>(),
+            Err(AmountParseError::Overflow)
+        );
+    }
+
+    #[test]
+    fn it_checks_arithmetic_for_overflow() {
+        let max = Amount::from_fixed(i128::MAX);
+        assert_eq!(max.checked_add(Amount::from_fixed(1)), None);
+        assert_eq!(
+            max.checked_sub(Amount::from_fixed(1)),
+            Some(Amount::from_fixed(i128::MAX - 1))
+        );
+        assert_eq!((-max).checked_sub(Amount::from_fixed(2)), None);
+    }
+
+    #[test]
+    fn it_sums_small_amounts_without_rounding_error() {
+        let tick: Amount = "0.0001".parse().unwrap();
+        let mut sum = Amount::ZERO;
+        for _ in 0..10000 {
+            sum = sum + tick;
+        }
+        assert_eq!(sum, "1".parse().unwrap());
+    }
+
+    #[test]
+    fn it_displays_four_decimal_places() {
+        assert_eq!(Amount::from_fixed(15000).to_string(), "1.5000");
+        assert_eq!(Amount::from_fixed(1).to_string(), "0.0001");
+        assert_eq!(Amount::from_fixed(-30000).to_string(), "-3.0000");
+        assert_eq!(
+            Amount::from_fixed(i128::MIN).to_string(),
+            "-17014118346046923173168730371588410.5728"
+        );
+    }
+
+    #[test]
+    fn it_formats_with_fewer_fraction_digits() {
+        let format = AmountFormat {
+            fraction_digits: 2,
+            trim_trailing_zeros: false,
+        };
+        assert_eq!(Amount::from_fixed(15000).format(&format), "1.50");
+        assert_eq!(Amount::from_fixed(12349).format(&format), "1.23");
+        assert_eq!(Amount::from_fixed(12350).format(&format), "1.24");
+        assert_eq!(Amount::from_fixed(-12350).format(&format), "-1.24");
+        assert_eq!(Amount::from_fixed(99999).format(&format), "10.00");
+        // A negative amount that rounds to zero has no sign
+        assert_eq!(Amount::from_fixed(-1).format(&format), "0.00");
+
+        let format = AmountFormat {
+            fraction_digits: 0,
+            trim_trailing_zeros: false,
+        };
+        assert_eq!(Amount::from_fixed(15000).format(&format), "2");
+        assert_eq!(Amount::from_fixed(14999).format(&format), "1");
+    }
+
+    #[test]
+    fn it_formats_with_more_fraction_digits() {
+        let format = AmountFormat {
+            fraction_digits: 6,
+            trim_trailing_zeros: false,
+        };
+        assert_eq!(Amount::from_fixed(15001).format(&format), "1.500100");
+        assert_eq!(Amount::from_fixed(-1).format(&format), "-0.000100");
+    }
+
+    #[test]
+    fn it_formats_with_trailing_zeros_trimmed() {
+        let format = AmountFormat {
+            fraction_digits: 4,
+            trim_trailing_zeros: true,
+        };
+        assert_eq!(Amount::from_fixed(15000).format(&format), "1.5");
+        assert_eq!(Amount::from_fixed(20000).format(&format), "2");
+        assert_eq!(Amount::from_fixed(1).format(&format), "0.0001");
+        assert_eq!(Amount::ZERO.format(&format), "0");
+        assert_eq!(Amount::from_fixed(-30000).format(&format), "-3");
+    }
+
+    #[test]
+    fn it_formats_extreme_values_exactly() {
+        let format = AmountFormat {
+            fraction_digits: 4,
+            trim_trailing_zeros: true,
+        };
+        assert_eq!(
+            Amount::from_fixed(i128::MAX).format(&format),
+            "17014118346046923173168730371588410.5727"
+        );
+        assert_eq!(
+            Amount::from_fixed(i128::MIN).format(&format),
+            "-17014118346046923173168730371588410.5728"
+        );
+
+        let format = AmountFormat {
+            fraction_digits: 0,
+            trim_trailing_zeros: false,
+        };
+        assert_eq!(
+            Amount::from_fixed(i128::MIN).format(&format),
+            "-17014118346046923173168730371588411"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use serde::{de, Deserialize, Deserializer};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::core::amount::{Amount, AmountFormat};
+use crate::core::FIXED_DECIMAL_PLACES;
+
+/// Codes of the supported currencies with the number of digits of their minor unit, as in ISO
+/// 4217, sorted by code. Amounts are stored with `FIXED_DECIMAL_PLACES`, so only currencies with
+/// at most that many minor unit digits can be supported.
+const CURRENCIES: &[(&str, u32)] = &[
+    ("AUD", 2),
+    ("BHD", 3),
+    ("BRL", 2),
+    ("CAD", 2),
+    ("CHF", 2),
+    ("CLF", 4),
+    ("CNY", 2),
+    ("CZK", 2),
+    ("DKK", 2),
+    ("EUR", 2),
+    ("GBP", 2),
+    ("HKD", 2),
+    ("HUF", 2),
+    ("INR", 2),
+    ("IQD", 3),
+    ("ISK", 0),
+    ("JOD", 3),
+    ("JPY", 0),
+    ("KRW", 0),
+    ("KWD", 3),
+    ("LYD", 3),
+    ("MXN", 2),
+    ("NOK", 2),
+    ("NZD", 2),
+    ("OMR", 3),
+    ("PLN", 2),
+    ("SEK", 2),
+    ("SGD", 2),
+    ("TND", 3),
+    ("USD", 2),
+    ("UYW", 4),
+    ("ZAR", 2),
+];
+
+/// Currency of an account and the transactions on it, identified by its ISO 4217 code.
+///
+/// Input without a currency is in the unnamed default currency, which has the full precision of
+/// amounts. Every other currency is limited to the digits of its minor unit (e.g. cents for USD).
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Default, Hash)]
+pub struct Currency(Option<[u8; 3]>);
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct UnknownCurrency(pub String);
+
+impl fmt::Display for UnknownCurrency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown currency code '{}'", self.0)
+    }
+}
+
+impl Error for UnknownCurrency {}
+
+impl Currency {
+    pub const DEFAULT: Currency = Currency(None);
+
+    /// The ISO 4217 code, empty for the default currency
+    pub fn code(&self) -> &str {
+        match &self.0 {
+            // Only built from the ASCII codes of CURRENCIES
+            Some(code) => std::str::from_utf8(code).unwrap(),
+            None => "",
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// Number of digits after the decimal point of amounts in the currency
+    pub fn minor_units(&self) -> u32 {
+        match self.0 {
+            Some(_) => CURRENCIES
+                .binary_search_by_key(&self.code(), |&(code, _)| code)
+                .map(|index| CURRENCIES[index].1)
+                .expect("Currency codes are only built from the supported currencies"),
+            None => FIXED_DECIMAL_PLACES,
+        }
+    }
+
+    /// Whether the amount is a whole number of the currency's minor unit
+    pub fn accepts(&self, amount: Amount) -> bool {
+        let divisor = 10i128.pow(FIXED_DECIMAL_PLACES - self.minor_units());
+        amount.to_fixed() % divisor == 0
+    }
+
+    /// The format for amounts in the currency, which show no more digits than its minor unit.
+    /// Amounts in the default currency are formatted as requested.
+    pub fn amount_format(&self, format: &AmountFormat) -> AmountFormat {
+        match self.0 {
+            Some(_) => AmountFormat {
+                fraction_digits: format.fraction_digits.min(self.minor_units()),
+                ..*format
+            },
+            None => *format,
+        }
+    }
+}
+
+impl FromStr for Currency {
+    type Err = UnknownCurrency;
+
+    fn from_str(value: &str) -> Result<Currency, UnknownCurrency> {
+        if value.is_empty() {
+            return Ok(Currency::DEFAULT);
+        }
+        match CURRENCIES.binary_search_by_key(&value, |&(code, _)| code) {
+            Ok(_) => {
+                let mut code = [0; 3];
+                code.copy_from_slice(value.as_bytes());
+                Ok(Currency(Some(code)))
+            }
+            Err(_) => Err(UnknownCurrency(value.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Currency, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn currency(code: &str) -> Currency {
+        code.parse().unwrap()
+    }
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn it_parses_supported_codes() {
+        assert_eq!(currency("USD").code(), "USD");
+        assert_eq!(currency("USD").to_string(), "USD");
+        assert_eq!(currency(""), Currency::DEFAULT);
+        assert_eq!(
+            "usd".parse::<Currency>(),
+            Err(UnknownCurrency("usd".to_string()))
+        );
+        assert!("XYZ".parse::<Currency>().is_err());
+        assert!("US".parse::<Currency>().is_err());
+    }
+
+    #[test]
+    fn it_sorts_codes_for_lookup() {
+        assert!(CURRENCIES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(CURRENCIES
+            .iter()
+            .all(|&(_, minor_units)| minor_units <= FIXED_DECIMAL_PLACES));
+    }
+
+    #[test]
+    fn it_limits_amounts_to_minor_units() {
+        assert_eq!(currency("JPY").minor_units(), 0);
+        assert_eq!(currency("KWD").minor_units(), 3);
+        assert_eq!(Currency::DEFAULT.minor_units(), FIXED_DECIMAL_PLACES);
+
+        assert!(currency("USD").accepts(amount("1.23")));
+        assert!(!currency("USD").accepts(amount("1.234")));
+        assert!(currency("JPY").accepts(amount("100")));
+        assert!(!currency("JPY").accepts(amount("100.5")));
+        assert!(Currency::DEFAULT.accepts(amount("1.2345")));
+    }
+
+    #[test]
+    fn it_formats_amounts_to_minor_units() {
+        let format = AmountFormat::default();
+        assert_eq!(
+            amount("1.5").format(&currency("USD").amount_format(&format)),
+            "1.50"
+        );
+        assert_eq!(
+            amount("100").format(&currency("JPY").amount_format(&format)),
+            "100"
+        );
+        assert_eq!(
+            amount("1.5").format(&Currency::DEFAULT.amount_format(&format)),
+            "1.5000"
+        );
+
+        let format = AmountFormat {
+            fraction_digits: 1,
+            trim_trailing_zeros: true,
+        };
+        assert_eq!(
+            amount("1.5").format(&currency("USD").amount_format(&format)),
+            "1.5"
+        );
+    }
+}
@@ -1,38 +1,91 @@
-use crate::core::FIXED_DECIMAL_SCALING;
+use crate::core::amount::{Amount, AmountOverflow};
+use crate::core::currency::Currency;
+use crate::core::ledger::{LedgerAccount, LedgerEntry};
+
+/// Accounts are held per client and currency
+pub type AccountKey = (u16, Currency);
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Account {
     pub client_id: u16,
-    pub available: i128,
-    pub held: i128,
-    pub total: i128,
+    pub currency: Currency,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
     pub locked: bool,
+    /// How far below zero withdrawals may take the available balance
+    pub credit_limit: Amount,
 }
 
 impl Account {
-    pub fn new(client_id: u16, available: f64, held: f64, total: f64, locked: bool) -> Account {
+    /// Build an account in the default currency
+    pub fn new(
+        client_id: u16,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+        locked: bool,
+    ) -> Account {
         Account {
             client_id,
-            available: Self::to_fixed(available),
-            held: Self::to_fixed(held),
-            total: Self::to_fixed(total),
+            currency: Currency::DEFAULT,
+            available,
+            held,
+            total,
             locked,
+            credit_limit: Amount::ZERO,
         }
     }
 
-    pub fn to_fixed(value: f64) -> i128 {
-        (value * FIXED_DECIMAL_SCALING as f64).round() as i128
+    pub fn with_currency(self, currency: Currency) -> Account {
+        Account { currency, ..self }
+    }
+
+    pub fn with_credit_limit(self, credit_limit: Amount) -> Account {
+        Account {
+            credit_limit,
+            ..self
+        }
     }
 
-    pub fn from_fixed(value: i128) -> f64 {
-        (value as f64 / FIXED_DECIMAL_SCALING as f64) as f64
+    pub fn key(&self) -> AccountKey {
+        (self.client_id, self.currency)
     }
 
-    pub fn available(&self) -> i128 {
+    pub fn available(&self) -> Amount {
         self.available
     }
 
-    pub fn available_f64(&self) -> f64 {
-        Self::from_fixed(self.available)
+    /// The most that can be withdrawn, the available balance plus the credit limit. Negative if a
+    /// chargeback took the account beyond its limit.
+    pub fn headroom(&self) -> Amount {
+        self.available + self.credit_limit
+    }
+
+    /// Post the entries of a ledger entry to the client's available and held accounts. The total
+    /// is derived from them rather than adjusted on its own. The account is left unchanged if a
+    /// balance would overflow.
+    pub fn post(&mut self, entry: &LedgerEntry) -> Result<(), AmountOverflow> {
+        let (mut available, mut held) = (Some(self.available), Some(self.held));
+        for posting in entry.postings() {
+            match posting.account {
+                LedgerAccount::ClientAvailable(client_id, currency)
+                    if (client_id, currency) == self.key() =>
+                {
+                    available = available.and_then(|a| a.checked_add(posting.amount))
+                }
+                LedgerAccount::ClientHeld(client_id, currency)
+                    if (client_id, currency) == self.key() =>
+                {
+                    held = held.and_then(|h| h.checked_add(posting.amount))
+                }
+                _ => {}
+            }
+        }
+        let (available, held) = available.zip(held).ok_or(AmountOverflow)?;
+        self.total = available.checked_add(held).ok_or(AmountOverflow)?;
+        self.available = available;
+        self.held = held;
+        Ok(())
     }
 }
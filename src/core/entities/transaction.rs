@@ -1,51 +1,119 @@
-use crate::core::FIXED_DECIMAL_SCALING;
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
+use crate::core::exchange::Conversion;
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Transaction {
     pub tx_id: u32,
     pub tx_type: u8,
     pub client_id: u16,
-    pub amount: i128,
+    pub amount: Amount,
+    pub currency: Currency,
     pub state: u8,
+    /// How the amount was converted, for a transaction made in another currency
+    pub conversion: Option<Conversion>,
+    /// Transaction a fee transaction was charged for
+    pub fee_for: Option<u32>,
+    /// Part of the amount of an authorization captured so far
+    pub captured: Amount,
+    /// Client a transfer paid the amount to
+    pub destination: Option<u16>,
+    /// Part of the amount of a deposit refunded so far
+    pub refunded: Amount,
 }
 
 pub enum TransactionType {
     Deposit = 0,
     Withdrawal = 1,
+    /// Funds reserved for a later capture
+    Authorization = 2,
+    /// Funds moved from the client to another client
+    Transfer = 3,
+    /// Fee charged to the client for another of their transactions
+    Fee = 4,
 }
 
+/// States of a transaction. Deposits and withdrawals move between `Normal`, `Disputed` and
+/// `Reversed`, authorizations start `Authorized` and end `Captured`, `Voided` or `Expired`.
 pub enum TransactionState {
     Normal = 0,
     Disputed = 1,
     Reversed = 2,
+    /// The funds are held, and can still be captured
+    Authorized = 3,
+    /// The whole amount was captured
+    Captured = 4,
+    /// The uncaptured funds were released by a void
+    Voided = 5,
+    /// The uncaptured funds were released as the authorization went stale
+    Expired = 6,
 }
 
 impl Transaction {
-    pub fn new(tx_id: u32, tx_type: u8, client_id: u16, amount: f64, state: u8) -> Transaction {
-        if tx_type != TransactionType::Deposit as u8 && tx_type != TransactionType::Withdrawal as u8
-        {
+    /// Build a transaction in the default currency
+    pub fn new(tx_id: u32, tx_type: u8, client_id: u16, amount: Amount, state: u8) -> Transaction {
+        if tx_type > TransactionType::Fee as u8 {
             panic!("Invalid TransactionType");
         }
-        if state != TransactionState::Normal as u8
-            && state != TransactionState::Disputed as u8
-            && state != TransactionState::Reversed as u8
-        {
+        if state > TransactionState::Expired as u8 {
             panic!("Invalid TransactionState");
         }
         Transaction {
             tx_id,
             tx_type,
             client_id,
-            amount: Self::to_fixed(amount),
+            amount,
+            currency: Currency::DEFAULT,
             state,
+            conversion: None,
+            fee_for: None,
+            captured: Amount::ZERO,
+            destination: None,
+            refunded: Amount::ZERO,
         }
     }
 
-    pub fn to_fixed(value: f64) -> i128 {
-        (value * FIXED_DECIMAL_SCALING as f64).round() as i128
+    pub fn with_currency(self, currency: Currency) -> Transaction {
+        Transaction { currency, ..self }
     }
 
-    pub fn from_fixed(value: i128) -> f64 {
-        (value as f64 / FIXED_DECIMAL_SCALING as f64).round() as f64
+    pub fn with_conversion(self, conversion: Option<Conversion>) -> Transaction {
+        Transaction { conversion, ..self }
+    }
+
+    pub fn with_fee_for(self, fee_for: Option<u32>) -> Transaction {
+        Transaction { fee_for, ..self }
+    }
+
+    pub fn with_captured(self, captured: Amount) -> Transaction {
+        Transaction { captured, ..self }
+    }
+
+    pub fn with_destination(self, destination: Option<u16>) -> Transaction {
+        Transaction {
+            destination,
+            ..self
+        }
+    }
+
+    pub fn with_refunded(self, refunded: Amount) -> Transaction {
+        Transaction { refunded, ..self }
+    }
+
+    /// Id of the transaction the fee for a transaction is posted as. Fee transactions are numbered
+    /// down from the largest id, so the fee for `tx_id` is posted as `u32::MAX - tx_id`, and the
+    /// fee transaction for a fee transaction's id is the transaction it was charged for.
+    pub fn fee_tx_id(tx_id: u32) -> u32 {
+        u32::MAX - tx_id
+    }
+
+    /// Part of the amount of an authorization still held for capture
+    pub fn uncaptured(&self) -> Amount {
+        self.amount - self.captured
+    }
+
+    /// Part of the amount of a deposit not refunded yet, which is all a dispute can hold
+    pub fn unrefunded(&self) -> Amount {
+        self.amount - self.refunded
     }
 }
@@ -0,0 +1,909 @@
+use std::collections::BTreeMap;
+
+use crate::core::amount::{Amount, AmountOverflow};
+use crate::core::currency::Currency;
+use crate::core::entities::account::{Account, AccountKey};
+use crate::core::entities::transaction::{Transaction, TransactionState, TransactionType};
+use crate::core::exchange::Conversion;
+use crate::core::ledger::{Ledger, LedgerAccount, LedgerEntry};
+
+/// A change made to the state of an account and the transaction it concerns.
+///
+/// The payment service records an event for every change it makes, and the effect of each event
+/// on the account and transaction is defined by `apply()` alone. Replaying the events recorded
+/// by a service therefore rebuilds exactly the state it holds. A transfer moves funds between
+/// the accounts of two clients, so it and its dispute, resolve and chargeback record an event for
+/// each of them.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Event {
+    /// A deposit was credited to the account
+    DepositApplied {
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+        conversion: Option<Conversion>,
+    },
+    /// A withdrawal was debited from the account
+    WithdrawalApplied {
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+        conversion: Option<Conversion>,
+    },
+    /// The amount of a transaction was held by a dispute
+    FundsHeld { client_id: u16, tx_id: u32 },
+    /// The amount held by a dispute was released by a resolve
+    FundsReleased { client_id: u16, tx_id: u32 },
+    /// A disputed transaction was reversed
+    ChargedBack { client_id: u16, tx_id: u32 },
+    /// The account was locked
+    AccountLocked { client_id: u16, currency: Currency },
+    /// A fee transaction was charged to the account for a deposit or withdrawal. The fee
+    /// transaction has an id of its own (see `Transaction::fee_tx_id()`).
+    FeeCharged {
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+    },
+    /// A fee transaction was refunded when the transaction it was charged for was charged back
+    FeeRefunded { client_id: u16, tx_id: u32 },
+    /// The credit limit of the account was set
+    CreditLimitSet {
+        client_id: u16,
+        currency: Currency,
+        limit: Amount,
+    },
+    /// A chargeback left the account's total below zero by `amount`, which is recorded to flag it
+    /// rather than to change the account
+    Overdrawn {
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+    },
+    /// Funds were held by an authorization
+    FundsAuthorized {
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+        conversion: Option<Conversion>,
+    },
+    /// Part of the funds held by an authorization were paid out
+    FundsCaptured {
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+    },
+    /// The funds an authorization still held were released by a void
+    AuthorizationVoided { client_id: u16, tx_id: u32 },
+    /// The funds an authorization still held were released as it went stale
+    AuthorizationExpired { client_id: u16, tx_id: u32 },
+    /// A transfer to another client was debited from the account
+    TransferSent {
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+        conversion: Option<Conversion>,
+    },
+    /// A transfer from another client was credited to the account
+    TransferReceived { client_id: u16, tx_id: u32 },
+    /// Part of a deposit was refunded to the payer
+    DepositRefunded {
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+    },
+}
+
+impl Event {
+    /// Stable, machine-readable name of the event variant
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::DepositApplied { .. } => "DepositApplied",
+            Event::WithdrawalApplied { .. } => "WithdrawalApplied",
+            Event::FundsHeld { .. } => "FundsHeld",
+            Event::FundsReleased { .. } => "FundsReleased",
+            Event::ChargedBack { .. } => "ChargedBack",
+            Event::AccountLocked { .. } => "AccountLocked",
+            Event::FeeCharged { .. } => "FeeCharged",
+            Event::FeeRefunded { .. } => "FeeRefunded",
+            Event::CreditLimitSet { .. } => "CreditLimitSet",
+            Event::Overdrawn { .. } => "Overdrawn",
+            Event::FundsAuthorized { .. } => "FundsAuthorized",
+            Event::FundsCaptured { .. } => "FundsCaptured",
+            Event::AuthorizationVoided { .. } => "AuthorizationVoided",
+            Event::AuthorizationExpired { .. } => "AuthorizationExpired",
+            Event::TransferSent { .. } => "TransferSent",
+            Event::TransferReceived { .. } => "TransferReceived",
+            Event::DepositRefunded { .. } => "DepositRefunded",
+        }
+    }
+
+    pub fn client_id(&self) -> u16 {
+        match self {
+            Event::DepositApplied { client_id, .. }
+            | Event::WithdrawalApplied { client_id, .. }
+            | Event::FundsHeld { client_id, .. }
+            | Event::FundsReleased { client_id, .. }
+            | Event::ChargedBack { client_id, .. }
+            | Event::AccountLocked { client_id, .. }
+            | Event::FeeCharged { client_id, .. }
+            | Event::FeeRefunded { client_id, .. }
+            | Event::CreditLimitSet { client_id, .. }
+            | Event::Overdrawn { client_id, .. }
+            | Event::FundsAuthorized { client_id, .. }
+            | Event::FundsCaptured { client_id, .. }
+            | Event::AuthorizationVoided { client_id, .. }
+            | Event::AuthorizationExpired { client_id, .. }
+            | Event::TransferSent { client_id, .. }
+            | Event::TransferReceived { client_id, .. }
+            | Event::DepositRefunded { client_id, .. } => *client_id,
+        }
+    }
+
+    pub fn tx_id(&self) -> Option<u32> {
+        match self {
+            Event::DepositApplied { tx_id, .. }
+            | Event::WithdrawalApplied { tx_id, .. }
+            | Event::FundsHeld { tx_id, .. }
+            | Event::FundsReleased { tx_id, .. }
+            | Event::ChargedBack { tx_id, .. }
+            | Event::FeeCharged { tx_id, .. }
+            | Event::FeeRefunded { tx_id, .. }
+            | Event::Overdrawn { tx_id, .. }
+            | Event::FundsAuthorized { tx_id, .. }
+            | Event::FundsCaptured { tx_id, .. }
+            | Event::AuthorizationVoided { tx_id, .. }
+            | Event::AuthorizationExpired { tx_id, .. }
+            | Event::TransferSent { tx_id, .. }
+            | Event::TransferReceived { tx_id, .. }
+            | Event::DepositRefunded { tx_id, .. } => Some(*tx_id),
+            Event::AccountLocked { .. } | Event::CreditLimitSet { .. } => None,
+        }
+    }
+
+    /// Currency of the account the event concerns. Events on an existing transaction are in the
+    /// currency of the transaction, so they don't record it themselves.
+    pub fn currency(&self) -> Option<Currency> {
+        match self {
+            Event::DepositApplied { currency, .. }
+            | Event::WithdrawalApplied { currency, .. }
+            | Event::AccountLocked { currency, .. }
+            | Event::CreditLimitSet { currency, .. }
+            | Event::FeeCharged { currency, .. }
+            | Event::FundsAuthorized { currency, .. }
+            | Event::TransferSent { currency, .. } => Some(*currency),
+            Event::FundsHeld { .. }
+            | Event::FundsReleased { .. }
+            | Event::ChargedBack { .. }
+            | Event::FeeRefunded { .. }
+            | Event::Overdrawn { .. }
+            | Event::FundsCaptured { .. }
+            | Event::AuthorizationVoided { .. }
+            | Event::AuthorizationExpired { .. }
+            | Event::TransferReceived { .. }
+            | Event::DepositRefunded { .. } => None,
+        }
+    }
+
+    /// Amount of the transaction created by the event, of the fee charged, the funds captured or
+    /// the part of a deposit refunded by it, of the credit limit it set or of the shortfall it
+    /// flagged
+    pub fn amount(&self) -> Option<Amount> {
+        match self {
+            Event::DepositApplied { amount, .. }
+            | Event::WithdrawalApplied { amount, .. }
+            | Event::FundsAuthorized { amount, .. }
+            | Event::FundsCaptured { amount, .. }
+            | Event::DepositRefunded { amount, .. }
+            | Event::TransferSent { amount, .. }
+            | Event::FeeCharged { amount, .. }
+            | Event::CreditLimitSet { limit: amount, .. }
+            | Event::Overdrawn { amount, .. } => Some(*amount),
+            _ => None,
+        }
+    }
+
+    /// How the amount of the transaction created by the event was converted from the currency it
+    /// was made in
+    pub fn conversion(&self) -> Option<Conversion> {
+        match self {
+            Event::DepositApplied { conversion, .. }
+            | Event::WithdrawalApplied { conversion, .. }
+            | Event::FundsAuthorized { conversion, .. }
+            | Event::TransferSent { conversion, .. } => *conversion,
+            _ => None,
+        }
+    }
+
+    /// Apply the event to the account and transaction it concerns, returning the ledger entry it
+    /// posted to the account.
+    ///
+    /// Deposits, withdrawals, authorizations, sent transfers and fees create their transaction,
+    /// every other event that concerns a transaction expects it to exist. Events are only ever
+    /// recorded once the payment service has validated the change, so this applies them
+    /// unconditionally, but for a balance that would overflow, which leaves the account and
+    /// transaction unchanged.
+    pub fn apply(
+        &self,
+        account: &mut Account,
+        transaction: &mut Option<Transaction>,
+    ) -> Result<Option<LedgerEntry>, AmountOverflow> {
+        let entry = self.ledger_entry(transaction.as_ref());
+        if let Some(entry) = &entry {
+            account.post(entry)?;
+        }
+        match *self {
+            Event::DepositApplied {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+                conversion,
+            } => {
+                *transaction = Some(
+                    Transaction::new(
+                        tx_id,
+                        TransactionType::Deposit as u8,
+                        client_id,
+                        amount,
+                        TransactionState::Normal as u8,
+                    )
+                    .with_currency(currency)
+                    .with_conversion(conversion),
+                )
+            }
+            Event::WithdrawalApplied {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+                conversion,
+            } => {
+                *transaction = Some(
+                    Transaction::new(
+                        tx_id,
+                        TransactionType::Withdrawal as u8,
+                        client_id,
+                        amount,
+                        TransactionState::Normal as u8,
+                    )
+                    .with_currency(currency)
+                    .with_conversion(conversion),
+                )
+            }
+            Event::FundsHeld { .. } => {
+                self.transaction_mut(transaction).state = TransactionState::Disputed as u8
+            }
+            Event::FundsReleased { .. } => {
+                self.transaction_mut(transaction).state = TransactionState::Normal as u8
+            }
+            Event::ChargedBack { .. } => {
+                self.transaction_mut(transaction).state = TransactionState::Reversed as u8
+            }
+            Event::AccountLocked { .. } => account.locked = true,
+            Event::FeeCharged {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+            } => {
+                *transaction = Some(
+                    Transaction::new(
+                        tx_id,
+                        TransactionType::Fee as u8,
+                        client_id,
+                        amount,
+                        TransactionState::Normal as u8,
+                    )
+                    .with_currency(currency)
+                    .with_fee_for(Some(Transaction::fee_tx_id(tx_id))),
+                )
+            }
+            Event::FeeRefunded { .. } => {
+                self.transaction_mut(transaction).state = TransactionState::Reversed as u8
+            }
+            Event::CreditLimitSet { limit, .. } => account.credit_limit = limit,
+            Event::Overdrawn { .. } => {}
+            Event::FundsAuthorized {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+                conversion,
+            } => {
+                *transaction = Some(
+                    Transaction::new(
+                        tx_id,
+                        TransactionType::Authorization as u8,
+                        client_id,
+                        amount,
+                        TransactionState::Authorized as u8,
+                    )
+                    .with_currency(currency)
+                    .with_conversion(conversion),
+                )
+            }
+            Event::FundsCaptured { amount, .. } => {
+                // never more than the amount of the authorization, so this can't overflow
+                let tx = self.transaction_mut(transaction);
+                tx.captured = tx.captured + amount;
+                if tx.uncaptured() == Amount::ZERO {
+                    tx.state = TransactionState::Captured as u8
+                }
+            }
+            Event::AuthorizationVoided { .. } => {
+                self.transaction_mut(transaction).state = TransactionState::Voided as u8
+            }
+            Event::AuthorizationExpired { .. } => {
+                self.transaction_mut(transaction).state = TransactionState::Expired as u8
+            }
+            Event::TransferSent {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+                conversion,
+            } => {
+                *transaction = Some(
+                    Transaction::new(
+                        tx_id,
+                        TransactionType::Transfer as u8,
+                        client_id,
+                        amount,
+                        TransactionState::Normal as u8,
+                    )
+                    .with_currency(currency)
+                    .with_conversion(conversion),
+                )
+            }
+            Event::TransferReceived { client_id, .. } => {
+                self.transaction_mut(transaction).destination = Some(client_id)
+            }
+            Event::DepositRefunded { amount, .. } => {
+                // never more than the amount of the deposit, so this can't overflow
+                let tx = self.transaction_mut(transaction);
+                tx.refunded = tx.refunded + amount;
+            }
+        }
+        Ok(entry)
+    }
+
+    /// The movement of funds made by the event, given the transaction it concerns before the
+    /// event is applied. Locking an account, setting its credit limit or flagging it as overdrawn
+    /// moves no funds.
+    pub fn ledger_entry(&self, transaction: Option<&Transaction>) -> Option<LedgerEntry> {
+        let (client_id, currency) = self.account_key(transaction);
+        let available = LedgerAccount::ClientAvailable(client_id, currency);
+        let held = LedgerAccount::ClientHeld(client_id, currency);
+        let external_funding = LedgerAccount::ExternalFunding(currency);
+        let chargeback_loss = LedgerAccount::ChargebackLoss(currency);
+        let house_revenue = LedgerAccount::HouseRevenue(currency);
+        let transfer_clearing = LedgerAccount::TransferClearing(currency);
+        let (from, to, amount) = match *self {
+            Event::DepositApplied { amount, .. } => (external_funding, available, amount),
+            Event::WithdrawalApplied { amount, .. } => (available, external_funding, amount),
+            Event::FundsHeld { .. } => {
+                let tx = self.transaction(transaction);
+                match Transaction::transaction_type_decode(tx.tx_type) {
+                    // deposited funds are held until the dispute is settled, less what was
+                    // already refunded
+                    TransactionType::Deposit => (available, held, tx.unrefunded()),
+                    // withdrawn funds have already left the account, the claim on them is held
+                    TransactionType::Withdrawal => (chargeback_loss, held, tx.amount),
+                    TransactionType::Authorization | TransactionType::Fee => self.not_disputable(),
+                    // the sender's claim is held, and the funds the recipient got with it
+                    TransactionType::Transfer if client_id == tx.client_id => {
+                        (transfer_clearing, held, tx.amount)
+                    }
+                    TransactionType::Transfer => (available, held, tx.amount),
+                }
+            }
+            Event::FundsReleased { .. } => {
+                let tx = self.transaction(transaction);
+                match Transaction::transaction_type_decode(tx.tx_type) {
+                    // deposited funds are released back to the client
+                    TransactionType::Deposit => (held, available, tx.unrefunded()),
+                    // the withdrawal stands, so the claim on the withdrawn funds is dropped
+                    TransactionType::Withdrawal => (held, chargeback_loss, tx.amount),
+                    TransactionType::Authorization | TransactionType::Fee => self.not_disputable(),
+                    // the transfer stands, so the sender's claim is dropped
+                    TransactionType::Transfer if client_id == tx.client_id => {
+                        (held, transfer_clearing, tx.amount)
+                    }
+                    TransactionType::Transfer => (held, available, tx.amount),
+                }
+            }
+            Event::ChargedBack { .. } => {
+                let tx = self.transaction(transaction);
+                match Transaction::transaction_type_decode(tx.tx_type) {
+                    // deposited funds are returned to the payer
+                    TransactionType::Deposit => (held, external_funding, tx.unrefunded()),
+                    // withdrawn funds are credited back to the client
+                    TransactionType::Withdrawal => (held, available, tx.amount),
+                    TransactionType::Authorization | TransactionType::Fee => self.not_disputable(),
+                    // the funds are taken back from the recipient and credited to the sender
+                    TransactionType::Transfer if client_id == tx.client_id => {
+                        (held, available, tx.amount)
+                    }
+                    TransactionType::Transfer => (held, transfer_clearing, tx.amount),
+                }
+            }
+            Event::FeeCharged { amount, .. } => (available, house_revenue, amount),
+            // authorized funds are held until they are captured or released
+            Event::FundsAuthorized { amount, .. } => (available, held, amount),
+            Event::FundsCaptured { amount, .. } => (held, external_funding, amount),
+            // refunded funds are returned to the payer
+            Event::DepositRefunded { amount, .. } => (available, external_funding, amount),
+            // transferred funds pass through clearing between the sender and the recipient
+            Event::TransferSent { amount, .. } => (available, transfer_clearing, amount),
+            Event::TransferReceived { .. } => (
+                transfer_clearing,
+                available,
+                self.transaction(transaction).amount,
+            ),
+            Event::AuthorizationVoided { .. } | Event::AuthorizationExpired { .. } => {
+                (held, available, self.transaction(transaction).uncaptured())
+            }
+            Event::FeeRefunded { .. } => (
+                house_revenue,
+                available,
+                self.transaction(transaction).amount,
+            ),
+            Event::AccountLocked { .. }
+            | Event::CreditLimitSet { .. }
+            | Event::Overdrawn { .. } => return None,
+        };
+        Some(LedgerEntry::transfer(from, to, amount))
+    }
+
+    /// Key of the account the event concerns, given the transaction it concerns before the event
+    /// is applied
+    pub fn account_key(&self, transaction: Option<&Transaction>) -> AccountKey {
+        let currency = match self.currency() {
+            Some(currency) => currency,
+            None => self.transaction(transaction).currency,
+        };
+        (self.client_id(), currency)
+    }
+
+    fn transaction<'a>(&self, transaction: Option<&'a Transaction>) -> &'a Transaction {
+        match transaction {
+            Some(tx) => tx,
+            None => panic!("{} event for a transaction that doesn't exist", self.kind()),
+        }
+    }
+
+    fn not_disputable(&self) -> ! {
+        panic!(
+            "{} event for an authorization or fee, which can't be disputed",
+            self.kind()
+        )
+    }
+
+    fn transaction_mut<'a>(&self, transaction: &'a mut Option<Transaction>) -> &'a mut Transaction {
+        match transaction {
+            Some(tx) => tx,
+            None => panic!("{} event for a transaction that doesn't exist", self.kind()),
+        }
+    }
+}
+
+/// Where an event was recorded in the history of the service
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Position {
+    /// Line of the input being applied when the event was recorded
+    pub line: u64,
+    /// Time of the input line being applied, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// An event as stored in the event log
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct RecordedEvent {
+    pub event: Event,
+    pub position: Position,
+}
+
+/// A point in the history of the service that its state can be queried at
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum PointInTime {
+    /// After the input line was applied
+    AfterLine(u64),
+    /// At the end of the second since the Unix epoch, by the time of the input lines
+    At(u64),
+}
+
+impl PointInTime {
+    /// Whether an event recorded at `position` happened by this point
+    pub fn includes(&self, position: &Position) -> bool {
+        match *self {
+            PointInTime::AfterLine(line) => position.line <= line,
+            PointInTime::At(timestamp) => position.timestamp <= timestamp,
+        }
+    }
+}
+
+/// Accounts and transactions rebuilt by replaying events, along with the ledger the events
+/// posted to
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct ReplayedState {
+    pub accounts: BTreeMap<AccountKey, Account>,
+    pub transactions: BTreeMap<u32, Transaction>,
+    pub ledger: Ledger,
+}
+
+impl ReplayedState {
+    /// Apply an event, creating the account it concerns if needed
+    pub fn apply(&mut self, event: &Event) {
+        let mut transaction = event
+            .tx_id()
+            .and_then(|tx_id| self.transactions.remove(&tx_id));
+
+        let (client_id, currency) = event.account_key(transaction.as_ref());
+        let account = self
+            .accounts
+            .entry((client_id, currency))
+            .or_insert_with(|| Account::build_default_account(client_id, currency));
+        // the service applied the event to the same account before recording it
+        let entry = event
+            .apply(account, &mut transaction)
+            .expect("Recorded event overflowed an account");
+        if let Some(entry) = entry {
+            self.ledger.post(&entry);
+        }
+        if let Some(transaction) = transaction {
+            self.transactions.insert(transaction.tx_id, transaction);
+        }
+    }
+}
+
+/// Rebuild the state of accounts and transactions from events, in the order they were recorded
+pub fn replay<'a>(events: impl IntoIterator<Item = &'a Event>) -> ReplayedState {
+    let mut state = ReplayedState::default();
+    for event in events {
+        state.apply(event);
+    }
+    state
+}
+
+/// Rebuild the state as it was at a point in the history of the recorded events. Events are
+/// replayed up to the first one recorded after the point, so that the state is one the service
+/// actually held.
+pub fn replay_until<'a>(
+    events: impl IntoIterator<Item = &'a RecordedEvent>,
+    point: PointInTime,
+) -> ReplayedState {
+    replay(
+        events
+            .into_iter()
+            .take_while(|recorded| point.includes(&recorded.position))
+            .map(|recorded| &recorded.event),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn it_replays_deposits_and_withdrawals() {
+        let state = replay(&[
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: 1,
+                amount: amount("5.0"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::WithdrawalApplied {
+                client_id: 1,
+                tx_id: 2,
+                amount: amount("1.5"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::DepositApplied {
+                client_id: 2,
+                tx_id: 3,
+                amount: amount("1.0"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+        ]);
+
+        assert_eq!(
+            state.accounts.values().collect::<Vec<_>>(),
+            vec![
+                &Account::new(1, amount("3.5"), Amount::ZERO, amount("3.5"), false),
+                &Account::new(2, amount("1.0"), Amount::ZERO, amount("1.0"), false),
+            ]
+        );
+        assert_eq!(
+            state.transactions[&2],
+            Transaction::new(2, 1, 1, amount("1.5"), 0)
+        );
+    }
+
+    #[test]
+    fn it_replays_a_disputed_deposit_through_to_chargeback() {
+        let mut state = replay(&[
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: 1,
+                amount: amount("5.0"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::FundsHeld {
+                client_id: 1,
+                tx_id: 1,
+            },
+        ]);
+        assert_eq!(
+            state.accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, Amount::ZERO, amount("5.0"), amount("5.0"), false)
+        );
+        assert_eq!(
+            state.transactions[&1].state,
+            TransactionState::Disputed as u8
+        );
+
+        state.apply(&Event::ChargedBack {
+            client_id: 1,
+            tx_id: 1,
+        });
+        state.apply(&Event::AccountLocked {
+            client_id: 1,
+            currency: Currency::DEFAULT,
+        });
+        assert_eq!(
+            state.accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, Amount::ZERO, Amount::ZERO, Amount::ZERO, true)
+        );
+        assert_eq!(
+            state.transactions[&1].state,
+            TransactionState::Reversed as u8
+        );
+        // The deposit was returned to the payer
+        assert_eq!(
+            state
+                .ledger
+                .balance(LedgerAccount::ExternalFunding(Currency::DEFAULT)),
+            Amount::ZERO
+        );
+    }
+
+    #[test]
+    fn it_holds_only_the_unrefunded_part_of_a_disputed_deposit() {
+        let mut state = replay(&[
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: 1,
+                amount: amount("5.0"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::DepositRefunded {
+                client_id: 1,
+                tx_id: 1,
+                amount: amount("2.0"),
+            },
+            Event::FundsHeld {
+                client_id: 1,
+                tx_id: 1,
+            },
+        ]);
+        assert_eq!(
+            state.accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, Amount::ZERO, amount("3.0"), amount("3.0"), false)
+        );
+        assert_eq!(state.transactions[&1].refunded, amount("2.0"));
+
+        state.apply(&Event::ChargedBack {
+            client_id: 1,
+            tx_id: 1,
+        });
+        assert_eq!(
+            state.accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, Amount::ZERO, Amount::ZERO, Amount::ZERO, false)
+        );
+        // The refund and the chargeback together returned the whole deposit to the payer
+        assert_eq!(
+            state
+                .ledger
+                .balance(LedgerAccount::ExternalFunding(Currency::DEFAULT)),
+            Amount::ZERO
+        );
+    }
+
+    #[test]
+    fn it_replays_a_disputed_withdrawal_through_to_resolve_or_chargeback() {
+        let state = replay(&[
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: 1,
+                amount: amount("5.0"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::WithdrawalApplied {
+                client_id: 1,
+                tx_id: 2,
+                amount: amount("2.0"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::FundsHeld {
+                client_id: 1,
+                tx_id: 2,
+            },
+        ]);
+        assert_eq!(
+            state.accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, amount("3.0"), amount("2.0"), amount("5.0"), false)
+        );
+
+        let mut resolved = state.clone();
+        resolved.apply(&Event::FundsReleased {
+            client_id: 1,
+            tx_id: 2,
+        });
+        assert_eq!(
+            resolved.accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, amount("3.0"), Amount::ZERO, amount("3.0"), false)
+        );
+        assert_eq!(
+            resolved.transactions[&2].state,
+            TransactionState::Normal as u8
+        );
+
+        let mut charged_back = state.clone();
+        charged_back.apply(&Event::ChargedBack {
+            client_id: 1,
+            tx_id: 2,
+        });
+        assert_eq!(
+            charged_back.accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, amount("5.0"), Amount::ZERO, amount("5.0"), false)
+        );
+        assert_eq!(
+            charged_back.ledger.trial_balance(),
+            vec![
+                (
+                    LedgerAccount::ClientAvailable(1, Currency::DEFAULT),
+                    amount("5.0")
+                ),
+                (
+                    LedgerAccount::ClientHeld(1, Currency::DEFAULT),
+                    Amount::ZERO
+                ),
+                (
+                    LedgerAccount::ExternalFunding(Currency::DEFAULT),
+                    -amount("3.0")
+                ),
+                (
+                    LedgerAccount::ChargebackLoss(Currency::DEFAULT),
+                    -amount("2.0")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_replays_events_on_the_account_in_the_transactions_currency() {
+        let usd: Currency = "USD".parse().unwrap();
+        let state = replay(&[
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: 1,
+                amount: amount("5.0"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: 2,
+                amount: amount("2.5"),
+                currency: usd,
+                conversion: None,
+            },
+            Event::FundsHeld {
+                client_id: 1,
+                tx_id: 2,
+            },
+        ]);
+
+        assert_eq!(
+            state.accounts.values().collect::<Vec<_>>(),
+            vec![
+                &Account::new(1, amount("5.0"), Amount::ZERO, amount("5.0"), false),
+                &Account::new(1, Amount::ZERO, amount("2.5"), amount("2.5"), false)
+                    .with_currency(usd),
+            ]
+        );
+        assert_eq!(state.transactions[&2].currency, usd);
+        assert_eq!(
+            state.ledger.balance(LedgerAccount::ExternalFunding(usd)),
+            -amount("2.5")
+        );
+    }
+
+    #[test]
+    fn it_replays_until_a_point_in_time() {
+        let recorded = |event, line, timestamp| RecordedEvent {
+            event,
+            position: Position { line, timestamp },
+        };
+        let events = [
+            recorded(
+                Event::DepositApplied {
+                    client_id: 1,
+                    tx_id: 1,
+                    amount: amount("5.0"),
+                    currency: Currency::DEFAULT,
+                    conversion: None,
+                },
+                2,
+                100,
+            ),
+            recorded(
+                Event::FundsHeld {
+                    client_id: 1,
+                    tx_id: 1,
+                },
+                3,
+                160,
+            ),
+            recorded(
+                Event::ChargedBack {
+                    client_id: 1,
+                    tx_id: 1,
+                },
+                5,
+                200,
+            ),
+            recorded(
+                Event::AccountLocked {
+                    client_id: 1,
+                    currency: Currency::DEFAULT,
+                },
+                5,
+                200,
+            ),
+        ];
+
+        assert!(replay_until(&events, PointInTime::AfterLine(1))
+            .accounts
+            .is_empty());
+        assert_eq!(
+            replay_until(&events, PointInTime::AfterLine(4)).accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, Amount::ZERO, amount("5.0"), amount("5.0"), false)
+        );
+        assert_eq!(
+            replay_until(&events, PointInTime::At(159)).accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, amount("5.0"), Amount::ZERO, amount("5.0"), false)
+        );
+        assert_eq!(
+            replay_until(&events, PointInTime::At(200)).accounts[&(1, Currency::DEFAULT)],
+            Account::new(1, Amount::ZERO, Amount::ZERO, Amount::ZERO, true)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn it_panics_on_an_event_for_a_missing_transaction() {
+        replay(&[Event::FundsHeld {
+            client_id: 1,
+            tx_id: 1,
+        }]);
+    }
+}
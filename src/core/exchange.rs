@@ -0,0 +1,262 @@
+use serde::{de, Deserialize, Deserializer};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
+use crate::core::FIXED_DECIMAL_PLACES;
+
+/// Number of decimal places of an exchange rate
+const RATE_DECIMAL_PLACES: u32 = 8;
+
+/// Fixed point exchange rate, the number of units of one currency paid for a unit of another,
+/// stored as an integer number of 1/100,000,000ths. Rates are always positive.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct Rate(i128);
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum RateParseError {
+    InvalidDigit,
+    NotPositive,
+    ExcessPrecision,
+    Overflow,
+}
+
+impl fmt::Display for RateParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RateParseError::InvalidDigit => write!(f, "rate is not a decimal number"),
+            RateParseError::NotPositive => write!(f, "rate must be greater than zero"),
+            RateParseError::ExcessPrecision => write!(
+                f,
+                "rate has more than {} decimal places",
+                RATE_DECIMAL_PLACES
+            ),
+            RateParseError::Overflow => write!(f, "rate is too large"),
+        }
+    }
+}
+
+impl Error for RateParseError {}
+
+/// How a converted amount is rounded to the minor unit of the currency it is converted into
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum RoundingMode {
+    /// To the nearest unit, halves away from zero (as amounts are formatted)
+    #[default]
+    HalfUp,
+    /// To the nearest unit, halves to the even unit
+    HalfEven,
+    /// Towards zero
+    Down,
+    /// Away from zero
+    Up,
+}
+
+impl RoundingMode {
+    /// Divide `value` by a positive `divisor`, rounding the quotient
+    pub fn divide(&self, value: i128, divisor: i128) -> i128 {
+        let quotient = value / divisor;
+        let remainder = (value % divisor).abs();
+        let away = match self {
+            RoundingMode::HalfUp => remainder * 2 >= divisor,
+            RoundingMode::HalfEven => {
+                remainder * 2 > divisor || (remainder * 2 == divisor && quotient % 2 != 0)
+            }
+            RoundingMode::Down => false,
+            RoundingMode::Up => remainder != 0,
+        };
+        match (away, value < 0) {
+            (false, _) => quotient,
+            (true, false) => quotient + 1,
+            (true, true) => quotient - 1,
+        }
+    }
+}
+
+impl FromStr for RoundingMode {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<RoundingMode, &'static str> {
+        match value {
+            "half-up" => Ok(RoundingMode::HalfUp),
+            "half-even" => Ok(RoundingMode::HalfEven),
+            "down" => Ok(RoundingMode::Down),
+            "up" => Ok(RoundingMode::Up),
+            _ => Err("Unknown rounding mode"),
+        }
+    }
+}
+
+impl Rate {
+    /// Build a rate from its fixed point representation (1/100,000,000ths)
+    pub const fn from_fixed(value: i128) -> Rate {
+        Rate(value)
+    }
+
+    /// The fixed point representation of the rate (1/100,000,000ths)
+    pub const fn to_fixed(self) -> i128 {
+        self.0
+    }
+
+    /// Convert an amount at this rate into `currency`, rounded to its minor unit. Returns `None`
+    /// if the converted amount is out of range.
+    pub fn convert(
+        &self,
+        amount: Amount,
+        currency: Currency,
+        rounding: RoundingMode,
+    ) -> Option<Amount> {
+        // The product has the decimal places of both the amount and the rate
+        let product = amount.to_fixed().checked_mul(self.0)?;
+        let dropped = FIXED_DECIMAL_PLACES - currency.minor_units();
+        let minor_units = rounding.divide(product, 10i128.pow(RATE_DECIMAL_PLACES + dropped));
+        minor_units
+            .checked_mul(10i128.pow(dropped))
+            .map(Amount::from_fixed)
+    }
+}
+
+impl FromStr for Rate {
+    type Err = RateParseError;
+
+    fn from_str(value: &str) -> Result<Rate, RateParseError> {
+        let (whole, fraction) = match value.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (value, ""),
+        };
+        let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) || value.ends_with('.') {
+            return Err(RateParseError::InvalidDigit);
+        }
+        if fraction.len() > RATE_DECIMAL_PLACES as usize {
+            return Err(RateParseError::ExcessPrecision);
+        }
+
+        // Right pad the fraction so that e.g. "5" is read as 50,000,000 1/100,000,000ths
+        let fraction = format!(
+            "{:0<width$}",
+            fraction,
+            width = RATE_DECIMAL_PLACES as usize
+        );
+        let rate = format!("{}{}", whole, fraction)
+            .parse::<i128>()
+            .map_err(|_| RateParseError::Overflow)?;
+        match rate {
+            0 => Err(RateParseError::NotPositive),
+            rate => Ok(Rate(rate)),
+        }
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scaling = 10i128.pow(RATE_DECIMAL_PLACES);
+        let fraction = format!(
+            "{:0width$}",
+            self.0 % scaling,
+            width = RATE_DECIMAL_PLACES as usize
+        );
+        let fraction = fraction.trim_end_matches('0');
+        match fraction.is_empty() {
+            true => write!(f, "{}", self.0 / scaling),
+            false => write!(f, "{}.{}", self.0 / scaling, fraction),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Rate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Rate, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+/// How the amount of a transaction made in another currency was converted into the currency of
+/// its account
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Conversion {
+    /// Amount of the transaction in the currency it was made in
+    pub amount: Amount,
+    /// Currency the transaction was made in
+    pub currency: Currency,
+    /// Units of the account's currency per unit of the transaction's currency
+    pub rate: Rate,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn rate(value: &str) -> Rate {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn it_parses_rates() {
+        assert_eq!(rate("1.5"), Rate::from_fixed(150_000_000));
+        assert_eq!(rate("0.00000001"), Rate::from_fixed(1));
+        assert_eq!(rate("110").to_string(), "110");
+        assert_eq!(rate("0.0067").to_string(), "0.0067");
+        assert_eq!("0".parse::<Rate>(), Err(RateParseError::NotPositive));
+        assert_eq!("-1".parse::<Rate>(), Err(RateParseError::InvalidDigit));
+        assert_eq!("1.".parse::<Rate>(), Err(RateParseError::InvalidDigit));
+        assert_eq!(
+            "0.000000001".parse::<Rate>(),
+            Err(RateParseError::ExcessPrecision)
+        );
+    }
+
+    #[test]
+    fn it_converts_to_the_minor_unit_of_the_currency() {
+        let usd = "USD".parse().unwrap();
+        assert_eq!(
+            rate("0.0067").convert(amount("1000"), usd, RoundingMode::HalfUp),
+            Some(amount("6.7"))
+        );
+        assert_eq!(
+            rate("1.08").convert(amount("10.25"), usd, RoundingMode::HalfUp),
+            Some(amount("11.07"))
+        );
+        assert_eq!(
+            rate("1.08").convert(amount("10.25"), Currency::DEFAULT, RoundingMode::HalfUp),
+            Some(amount("11.07"))
+        );
+        assert_eq!(
+            rate("1.5").convert(Amount::from_fixed(i128::MAX), usd, RoundingMode::HalfUp),
+            None
+        );
+    }
+
+    #[test]
+    fn it_rounds_converted_amounts() {
+        let usd = "USD".parse().unwrap();
+        let convert = |value, rounding| rate("0.5").convert(amount(value), usd, rounding);
+
+        // 0.025 and 0.035 are halfway between cents
+        assert_eq!(convert("0.05", RoundingMode::HalfUp), Some(amount("0.03")));
+        assert_eq!(
+            convert("0.05", RoundingMode::HalfEven),
+            Some(amount("0.02"))
+        );
+        assert_eq!(
+            convert("0.07", RoundingMode::HalfEven),
+            Some(amount("0.04"))
+        );
+        assert_eq!(convert("0.05", RoundingMode::Down), Some(amount("0.02")));
+        assert_eq!(convert("0.05", RoundingMode::Up), Some(amount("0.03")));
+        assert_eq!(convert("0.04", RoundingMode::Up), Some(amount("0.02")));
+
+        assert_eq!(RoundingMode::HalfUp.divide(-25, 10), -3);
+        assert_eq!(RoundingMode::HalfEven.divide(-25, 10), -2);
+        assert_eq!(RoundingMode::Down.divide(-29, 10), -2);
+        assert_eq!(RoundingMode::Up.divide(-21, 10), -3);
+        assert_eq!("half-even".parse(), Ok(RoundingMode::HalfEven));
+        assert!("nearest".parse::<RoundingMode>().is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
+
+/// An account of the double-entry ledger.
+///
+/// Each client has an available and a held account per currency, the balances of which are the
+/// `Account::available` and `Account::held` of the client's account in that currency. The system
+/// accounts are the other side of the funds entering and leaving client accounts, and are also
+/// kept per currency so that the balances of each currency sum to zero on their own.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub enum LedgerAccount {
+    ClientAvailable(u16, Currency),
+    ClientHeld(u16, Currency),
+    /// Funds deposited from and withdrawn to outside the system, including deposits returned to
+    /// the payer by a chargeback
+    ExternalFunding(Currency),
+    /// Funds credited back to clients whose withdrawals are disputed and charged back
+    ChargebackLoss(Currency),
+    /// Fees charged to clients, less the fees refunded by chargebacks
+    HouseRevenue(Currency),
+    /// Funds between the two legs of a transfer, which only has a balance while a transfer is
+    /// disputed
+    TransferClearing(Currency),
+}
+
+impl LedgerAccount {
+    pub fn currency(&self) -> Currency {
+        match *self {
+            LedgerAccount::ClientAvailable(_, currency)
+            | LedgerAccount::ClientHeld(_, currency)
+            | LedgerAccount::ExternalFunding(currency)
+            | LedgerAccount::ChargebackLoss(currency)
+            | LedgerAccount::HouseRevenue(currency)
+            | LedgerAccount::TransferClearing(currency) => currency,
+        }
+    }
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerAccount::ClientAvailable(client_id, _) => {
+                write!(f, "client {} available", client_id)?
+            }
+            LedgerAccount::ClientHeld(client_id, _) => write!(f, "client {} held", client_id)?,
+            LedgerAccount::ExternalFunding(_) => write!(f, "external funding")?,
+            LedgerAccount::ChargebackLoss(_) => write!(f, "chargeback loss")?,
+            LedgerAccount::HouseRevenue(_) => write!(f, "house revenue")?,
+            LedgerAccount::TransferClearing(_) => write!(f, "transfer clearing")?,
+        }
+        // Accounts in the default currency are named as they were before currencies
+        match self.currency() {
+            currency if currency.is_default() => Ok(()),
+            currency => write!(f, " {}", currency),
+        }
+    }
+}
+
+/// An amount added to the balance of a ledger account
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Posting {
+    pub account: LedgerAccount,
+    pub amount: Amount,
+}
+
+/// A set of postings that sum to zero, so that funds are only ever moved between accounts
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct LedgerEntry {
+    postings: Vec<Posting>,
+}
+
+impl LedgerEntry {
+    /// Move an amount from one account to another
+    pub fn transfer(from: LedgerAccount, to: LedgerAccount, amount: Amount) -> LedgerEntry {
+        LedgerEntry {
+            postings: vec![
+                Posting {
+                    account: from,
+                    amount: -amount,
+                },
+                Posting {
+                    account: to,
+                    amount,
+                },
+            ],
+        }
+    }
+
+    pub fn postings(&self) -> &[Posting] {
+        &self.postings
+    }
+}
+
+/// Balances of the ledger accounts that have had postings
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Ledger {
+    balances: BTreeMap<LedgerAccount, Amount>,
+}
+
+impl Ledger {
+    pub fn post(&mut self, entry: &LedgerEntry) {
+        for posting in entry.postings() {
+            let balance = self.balances.entry(posting.account).or_default();
+            *balance = *balance + posting.amount;
+        }
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> Amount {
+        self.balances.get(&account).copied().unwrap_or_default()
+    }
+
+    /// Balance of every account, ordered by account. The balances in each currency always sum to
+    /// zero.
+    pub fn trial_balance(&self) -> Vec<(LedgerAccount, Amount)> {
+        self.balances
+            .iter()
+            .map(|(account, balance)| (*account, *balance))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    const DEFAULT: Currency = Currency::DEFAULT;
+
+    #[test]
+    fn it_posts_balanced_transfers() {
+        let mut ledger = Ledger::default();
+        ledger.post(&LedgerEntry::transfer(
+            LedgerAccount::ExternalFunding(DEFAULT),
+            LedgerAccount::ClientAvailable(1, DEFAULT),
+            amount("5.0"),
+        ));
+        ledger.post(&LedgerEntry::transfer(
+            LedgerAccount::ClientAvailable(1, DEFAULT),
+            LedgerAccount::ClientHeld(1, DEFAULT),
+            amount("2.0"),
+        ));
+
+        assert_eq!(
+            ledger.trial_balance(),
+            vec![
+                (LedgerAccount::ClientAvailable(1, DEFAULT), amount("3.0")),
+                (LedgerAccount::ClientHeld(1, DEFAULT), amount("2.0")),
+                (LedgerAccount::ExternalFunding(DEFAULT), -amount("5.0")),
+            ]
+        );
+        assert_eq!(
+            ledger.balance(LedgerAccount::ChargebackLoss(DEFAULT)),
+            Amount::ZERO
+        );
+    }
+
+    #[test]
+    fn it_names_accounts() {
+        assert_eq!(
+            LedgerAccount::ClientHeld(7, DEFAULT).to_string(),
+            "client 7 held".to_string()
+        );
+        assert_eq!(
+            LedgerAccount::ChargebackLoss(DEFAULT).to_string(),
+            "chargeback loss".to_string()
+        );
+        assert_eq!(
+            LedgerAccount::ExternalFunding("EUR".parse().unwrap()).to_string(),
+            "external funding EUR".to_string()
+        );
+        assert_eq!(
+            LedgerAccount::HouseRevenue(DEFAULT).to_string(),
+            "house revenue".to_string()
+        );
+    }
+}
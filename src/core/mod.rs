@@ -1,5 +1,11 @@
 
+pub mod amount;
+pub mod currency;
 pub mod entities;
+pub mod event;
+pub mod exchange;
+pub mod ledger;
 
 // Entities share fixed point decimal accuracy for direct arithmetic operations
+pub(crate) const FIXED_DECIMAL_PLACES: u32 = 4;
 const FIXED_DECIMAL_SCALING: i32 = 10000;
@@ -1,13 +1,23 @@
 use anyhow::Result;
-use csv::{Reader, ReaderBuilder, Trim};
+use csv::{Reader, ReaderBuilder, StringRecord, Trim, Writer, WriterBuilder};
 use repositories::{
-    account::in_memory::AccountRepositoryInMemory,
-    transaction::in_memory::TransactionRepositoryInMemory,
+    account::{
+        file::AccountRepositoryFile, in_memory::AccountRepositoryInMemory, AccountRepositoryTrait,
+    },
+    event::{file::EventRepositoryFile, in_memory::EventRepositoryInMemory, EventRepositoryTrait},
+    journal::{self, Journal, JournalContents},
+    transaction::{
+        file::TransactionRepositoryFile, in_memory::TransactionRepositoryInMemory,
+        TransactionRepositoryTrait,
+    },
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
 use std::iter::Iterator;
+use std::path::Path;
 #[macro_use]
 extern crate anyhow;
 
@@ -15,88 +25,1026 @@ pub mod core;
 pub mod repositories;
 
 pub mod services;
+use crate::core::amount::{Amount, AmountFormat};
+use crate::core::currency::Currency;
 use crate::core::entities::account::Account;
-use crate::services::payment::{PaymentService, PaymentServiceTrait};
+use crate::core::event::{self, PointInTime, Position};
+use crate::core::exchange::RoundingMode;
+use crate::services::exchange::Exchange;
+use crate::services::fees::FeeSchedule;
+use crate::services::invariants::{self, InvariantPolicy};
+use crate::services::payment::{
+    PaymentService, PaymentServiceError, PaymentServicePolicy, PaymentServiceTrait,
+};
+use crate::services::profiles::ClientProfiles;
+use crate::services::snapshot::Snapshot;
+
+/// How input lines that cannot be applied are handled.
+///
+/// The policy applies uniformly to lines that fail to parse, fail validation or are rejected by
+/// the payment service.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ErrorPolicy {
+    /// Abort the run on the first line that cannot be applied
+    Strict,
+    /// Skip lines that cannot be applied and report the number skipped
+    Lenient,
+    /// Skip lines that cannot be applied and write the raw rows with the reason to a CSV file
+    Quarantine(String),
+}
+
+/// Where account and transaction state is kept.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Backend {
+    /// In memory, discarded at the end of the run
+    Memory,
+    /// Append-only logs in the state directory
+    File,
+    /// A SQLite database in the state directory (requires the `sqlite` feature)
+    Sqlite,
+}
+
+/// What a run of the engine does.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Command {
+    /// Apply the transactions in the input file and write the account summary
+    Process,
+    /// Recover the state directory from its journal and check the repositories match it
+    Recover,
+    /// Rebuild the state of the state directory from its event log and write the account summary
+    Replay,
+    /// Write the account summary as it was at a point in the history of the input file, or of the
+    /// state directory when there is one, for one client or all of them
+    Query {
+        point: PointInTime,
+        client_id: Option<u16>,
+    },
+    /// Write the balance of every ledger account posted to in the history of the input file, or of
+    /// the state directory when there is one
+    TrialBalance,
+    /// Check the invariants of the accounts left by the input file, or held in the state directory
+    /// when there is one, and report any violation
+    Verify,
+}
 
 pub struct Config {
+    pub command: Command,
     pub filename: String,
+    pub outcomes: Option<String>,
+    pub error_policy: ErrorPolicy,
+    pub amount_format: AmountFormat,
+    pub service_policy: PaymentServicePolicy,
+    pub invariant_policy: InvariantPolicy,
+    pub state_dir: Option<String>,
+    pub backend: Backend,
+    pub snapshot: Option<String>,
+    pub restore: Option<String>,
+    pub rates: Option<String>,
+    pub base_currency: Currency,
+    pub rounding: RoundingMode,
+    pub fees: Option<String>,
+    pub profiles: Option<String>,
+}
+
+impl Default for Config {
+    /// Reads from stdin with the default options
+    fn default() -> Config {
+        Config {
+            command: Command::Process,
+            filename: "-".to_string(),
+            outcomes: None,
+            error_policy: ErrorPolicy::Lenient,
+            amount_format: AmountFormat::default(),
+            service_policy: PaymentServicePolicy::default(),
+            invariant_policy: InvariantPolicy::default(),
+            state_dir: None,
+            backend: Backend::Memory,
+            snapshot: None,
+            restore: None,
+            rates: None,
+            base_currency: Currency::DEFAULT,
+            rounding: RoundingMode::default(),
+            fees: None,
+            profiles: None,
+        }
+    }
 }
 
 impl Config {
-    pub fn new(mut args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
-        args.next();
+    pub fn new(args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
+        let mut args = args.skip(1).peekable();
+
+        let command = match args.peek().map(String::as_str) {
+            Some("recover") => {
+                args.next();
+                Command::Recover
+            }
+            Some("replay") => {
+                args.next();
+                Command::Replay
+            }
+            Some("query") => {
+                args.next();
+                // The point and client are filled in from their options
+                Command::Query {
+                    point: PointInTime::AfterLine(0),
+                    client_id: None,
+                }
+            }
+            Some("trial-balance") => {
+                args.next();
+                Command::TrialBalance
+            }
+            Some("verify") => {
+                args.next();
+                Command::Verify
+            }
+            _ => Command::Process,
+        };
+
+        let mut filename = None;
+        let mut outcomes = None;
+        let mut error_policy = None;
+        let mut quarantine = None;
+        let mut amount_format = AmountFormat::default();
+        let mut service_policy = PaymentServicePolicy::default();
+        let mut invariant_policy = InvariantPolicy::default();
+        let mut state_dir = None;
+        let mut backend = None;
+        let mut snapshot = None;
+        let mut restore = None;
+        let mut rates = None;
+        let mut base_currency = None;
+        let mut rounding = None;
+        let mut fees = None;
+        let mut profiles = None;
+        let mut point = None;
+        let mut client_id = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--outcomes" => match args.next() {
+                    Some(path) => outcomes = Some(path),
+                    None => return Err("Didn't get a file name for --outcomes"),
+                },
+                "--error-policy" => match args.next() {
+                    Some(policy) => error_policy = Some(policy),
+                    None => return Err("Didn't get a policy for --error-policy"),
+                },
+                "--quarantine" => match args.next() {
+                    Some(path) => quarantine = Some(path),
+                    None => return Err("Didn't get a file name for --quarantine"),
+                },
+                "--fraction-digits" => match args.next().map(|digits| digits.parse()) {
+                    Some(Ok(digits)) => amount_format.fraction_digits = digits,
+                    _ => return Err("Didn't get a number of digits for --fraction-digits"),
+                },
+                "--trim-zeros" => amount_format.trim_trailing_zeros = true,
+                "--no-withdrawal-disputes" => service_policy.withdrawals_disputable = false,
+                "--auth-expiry-lines" => match args.next().map(|lines| lines.parse()) {
+                    Some(Ok(lines)) => service_policy.authorization_expiry_lines = Some(lines),
+                    _ => return Err("Didn't get a number of lines for --auth-expiry-lines"),
+                },
+                "--auth-expiry-seconds" => match args.next().map(|seconds| seconds.parse()) {
+                    Some(Ok(seconds)) => {
+                        service_policy.authorization_expiry_seconds = Some(seconds)
+                    }
+                    _ => return Err("Didn't get a number of seconds for --auth-expiry-seconds"),
+                },
+                "--allow-negative-balances" => invariant_policy.negative_balances_allowed = true,
+                "--state-dir" => match args.next() {
+                    Some(path) => state_dir = Some(path),
+                    None => return Err("Didn't get a directory for --state-dir"),
+                },
+                "--snapshot" => match args.next() {
+                    Some(path) => snapshot = Some(path),
+                    None => return Err("Didn't get a file name for --snapshot"),
+                },
+                "--restore" => match args.next() {
+                    Some(path) => restore = Some(path),
+                    None => return Err("Didn't get a file name for --restore"),
+                },
+                "--rates" => match args.next() {
+                    Some(path) => rates = Some(path),
+                    None => return Err("Didn't get a file name for --rates"),
+                },
+                "--base-currency" => match args.next().map(|code| code.parse()) {
+                    Some(Ok(currency)) => base_currency = Some(currency),
+                    Some(Err(_)) => return Err("Unknown currency for --base-currency"),
+                    None => return Err("Didn't get a currency for --base-currency"),
+                },
+                "--rounding" => match args.next().map(|mode| mode.parse()) {
+                    Some(Ok(mode)) => rounding = Some(mode),
+                    Some(Err(error)) => return Err(error),
+                    None => return Err("Didn't get a rounding mode for --rounding"),
+                },
+                "--fees" => match args.next() {
+                    Some(path) => fees = Some(path),
+                    None => return Err("Didn't get a file name for --fees"),
+                },
+                "--profiles" => match args.next() {
+                    Some(path) => profiles = Some(path),
+                    None => return Err("Didn't get a file name for --profiles"),
+                },
+                "--line" => match args.next().map(|line| line.parse()) {
+                    Some(Ok(line)) if point.is_none() => point = Some(PointInTime::AfterLine(line)),
+                    Some(Ok(_)) => return Err("Only one of --line and --at can be given"),
+                    _ => return Err("Didn't get a line number for --line"),
+                },
+                "--at" => match args.next().map(|timestamp| timestamp.parse()) {
+                    Some(Ok(timestamp)) if point.is_none() => {
+                        point = Some(PointInTime::At(timestamp))
+                    }
+                    Some(Ok(_)) => return Err("Only one of --line and --at can be given"),
+                    _ => return Err("Didn't get a Unix timestamp for --at"),
+                },
+                "--client" => match args.next().map(|client| client.parse()) {
+                    Some(Ok(client)) => client_id = Some(client),
+                    _ => return Err("Didn't get a client id for --client"),
+                },
+                "--backend" => match args.next().as_deref() {
+                    Some("memory") => backend = Some(Backend::Memory),
+                    Some("file") => backend = Some(Backend::File),
+                    Some("sqlite") => backend = Some(Backend::Sqlite),
+                    Some(_) => return Err("Unknown backend"),
+                    None => return Err("Didn't get a backend for --backend"),
+                },
+                _ if filename.is_none() => filename = Some(arg),
+                _ => return Err("Unexpected argument"),
+            }
+        }
+
+        let command = match (command, point) {
+            (Command::Query { .. }, Some(point)) => Command::Query { point, client_id },
+            (Command::Query { .. }, None) => return Err("query requires --line or --at"),
+            (command, None) if client_id.is_none() => command,
+            _ => return Err("--line, --at and --client can only be used with query"),
+        };
+
+        if invariant_policy != InvariantPolicy::default() && command != Command::Verify {
+            return Err("--allow-negative-balances can only be used with verify");
+        }
+
+        // Commands inspecting state read that of the state directory if there is one, otherwise
+        // they apply their input file in memory
+        let inspects = matches!(
+            command,
+            Command::Query { .. } | Command::TrialBalance | Command::Verify
+        );
+        let filename = match (command, filename) {
+            (_, Some(_)) if inspects && state_dir.is_some() => {
+                return Err(
+                    "query, trial-balance and verify read either an input file or --state-dir",
+                )
+            }
+            (_, None) if inspects && state_dir.is_some() => "-".to_string(),
+            (
+                Command::Process | Command::Query { .. } | Command::TrialBalance | Command::Verify,
+                Some(arg),
+            ) => arg,
+            (
+                Command::Process | Command::Query { .. } | Command::TrialBalance | Command::Verify,
+                None,
+            ) => return Err("Didn't get a file name"),
+            (Command::Recover | Command::Replay, None) => "-".to_string(),
+            (Command::Recover | Command::Replay, Some(_)) => return Err("Unexpected argument"),
+        };
+
+        // A quarantine file on its own is enough to select the quarantine policy
+        let error_policy = match (error_policy.as_deref(), quarantine) {
+            (None | Some("lenient"), None) => ErrorPolicy::Lenient,
+            (None | Some("quarantine"), Some(path)) => ErrorPolicy::Quarantine(path),
+            (Some("strict"), None) => ErrorPolicy::Strict,
+            (Some("quarantine"), None) => return Err("Quarantine policy requires --quarantine"),
+            (Some("strict" | "lenient"), Some(_)) => {
+                return Err("--quarantine can only be used with the quarantine policy")
+            }
+            (Some(_), _) => return Err("Unknown error policy"),
+        };
+
+        // A state directory on its own selects the file backend
+        let backend = match (backend, &state_dir) {
+            (None, None) => Backend::Memory,
+            (None, Some(_)) => Backend::File,
+            (Some(Backend::Memory), Some(_)) => {
+                return Err("--state-dir can't be used with the memory backend")
+            }
+            (Some(Backend::File | Backend::Sqlite), None) => {
+                return Err("The file and sqlite backends require --state-dir")
+            }
+            (Some(backend), _) => backend,
+        };
+        if matches!(command, Command::Recover | Command::Replay) && backend == Backend::Memory {
+            return Err("recover and replay require --state-dir");
+        }
+        // Inspecting commands read the history of an input or a state directory, not of a snapshot
+        if inspects && (restore.is_some() || snapshot.is_some()) {
+            return Err(
+                "--restore and --snapshot can't be used with query, trial-balance or verify",
+            );
+        }
+        // Line numbers restart with every input file, so they don't identify a point in the
+        // history of a state directory
+        if matches!(
+            command,
+            Command::Query {
+                point: PointInTime::AfterLine(_),
+                ..
+            }
+        ) && state_dir.is_some()
+        {
+            return Err("--line can't be used with --state-dir, use --at instead");
+        }
+        // Restored state would bypass the journal of a state directory
+        if restore.is_some() && state_dir.is_some() {
+            return Err("--restore can't be used with --state-dir");
+        }
+        if rates.is_none() && (base_currency.is_some() || rounding.is_some()) {
+            return Err("--base-currency and --rounding can only be used with --rates");
+        }
+
+        Ok(Config {
+            command,
+            filename,
+            outcomes,
+            error_policy,
+            amount_format,
+            service_policy,
+            invariant_policy,
+            state_dir,
+            backend,
+            snapshot,
+            restore,
+            rates,
+            base_currency: base_currency.unwrap_or_default(),
+            rounding: rounding.unwrap_or_default(),
+            fees,
+            profiles,
+        })
+    }
+}
 
-        let filename = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file name"),
+/// The repositories the payment service is built on
+struct Repositories {
+    transactions: Box<dyn TransactionRepositoryTrait>,
+    accounts: Box<dyn AccountRepositoryTrait>,
+    events: Box<dyn EventRepositoryTrait>,
+}
+
+/// Build the repositories selected by the config, loading any existing state
+fn build_repositories(config: &Config) -> Result<Repositories> {
+    let state_dir = || -> Result<&Path> {
+        let dir = match &config.state_dir {
+            Some(dir) => Path::new(dir),
+            None => {
+                return Err(anyhow!(
+                    "The {:?} backend requires a state directory",
+                    config.backend
+                ))
+            }
         };
+        fs::create_dir_all(dir)?;
+        Ok(dir)
+    };
 
-        Ok(Config { filename })
+    match config.backend {
+        Backend::Memory => Ok(Repositories {
+            transactions: Box::new(TransactionRepositoryInMemory::new()),
+            accounts: Box::new(AccountRepositoryInMemory::new()),
+            events: Box::new(EventRepositoryInMemory::new()),
+        }),
+        Backend::File => {
+            let dir = state_dir()?;
+            Ok(Repositories {
+                transactions: Box::new(TransactionRepositoryFile::open(dir)?),
+                accounts: Box::new(AccountRepositoryFile::open(dir)?),
+                events: Box::new(EventRepositoryFile::open(dir)?),
+            })
+        }
+        Backend::Sqlite => build_sqlite_repositories(state_dir()?),
     }
 }
 
-pub fn run(config: Config) -> Result<()> {
-    let transaction_reader = transaction_line_iter(&config.filename)?;
+#[cfg(feature = "sqlite")]
+fn build_sqlite_repositories(dir: &Path) -> Result<Repositories> {
+    use repositories::account::sqlite::AccountRepositorySqlite;
+    use repositories::event::sqlite::EventRepositorySqlite;
+    use repositories::sqlite::{open_database, DATABASE_FILENAME};
+    use repositories::transaction::sqlite::TransactionRepositorySqlite;
 
+    let connection = open_database(&dir.join(DATABASE_FILENAME))?;
+    Ok(Repositories {
+        transactions: Box::new(TransactionRepositorySqlite::new(connection.clone())),
+        accounts: Box::new(AccountRepositorySqlite::new(connection.clone())),
+        events: Box::new(EventRepositorySqlite::new(connection)),
+    })
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn build_sqlite_repositories(_dir: &Path) -> Result<Repositories> {
+    Err(anyhow!(
+        "The sqlite backend requires building with the sqlite feature"
+    ))
+}
+
+/// Set up the payment service to convert amounts with the rates file and charge the fees of the
+/// fee schedule named by the config, if there are any
+fn with_rates_and_fees(
+    config: &Config,
+    mut payment_service: PaymentService,
+) -> Result<PaymentService> {
+    if let Some(path) = &config.rates {
+        let mut exchange = Exchange::new(config.base_currency, config.rounding);
+        exchange.read_rates(BufReader::new(File::open(path)?))?;
+        payment_service = payment_service.with_exchange(exchange);
+    }
+    if let Some(path) = &config.fees {
+        let fees = FeeSchedule::read(BufReader::new(File::open(path)?))?;
+        payment_service = payment_service.with_fees(fees);
+    }
+    Ok(payment_service)
+}
+
+/// Set the credit limits of the client profiles named by the config, if there are any. Limits are
+/// set before the first input line, and setting a limit an account already has changes nothing.
+fn apply_profiles(config: &Config, payment_service: &mut dyn PaymentServiceTrait) -> Result<()> {
+    let path = match &config.profiles {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let profiles = ClientProfiles::read(BufReader::new(File::open(path)?))?;
+    let timestamp = start_time(payment_service);
+    payment_service.set_position(Position { line: 0, timestamp });
+    for ((client_id, currency), limit) in profiles.credit_limits() {
+        payment_service.set_credit_limit(client_id, currency, limit)?;
+    }
+    Ok(())
+}
+
+/// Open the journal of a persistent backend, rolling back any incomplete entry and redoing the
+/// last committed one in case the process died before it reached the repositories. The journal is
+/// checkpointed once the entry is redone, and its contents are returned for `recover` to check.
+fn open_journal(
+    config: &Config,
+    repositories: &mut Repositories,
+) -> Result<Option<(Journal, JournalContents)>> {
+    let dir = match (&config.state_dir, config.backend) {
+        (Some(dir), Backend::File | Backend::Sqlite) => Path::new(dir),
+        _ => return Ok(None),
+    };
+
+    let (mut journal, contents) = Journal::open(dir)?;
+    if let Some(entry) = contents.committed.last() {
+        journal::redo(
+            entry,
+            repositories.transactions.as_mut(),
+            repositories.accounts.as_mut(),
+            repositories.events.as_mut(),
+        )?;
+    }
+    journal.checkpoint()?;
+    Ok(Some((journal, contents)))
+}
+
+pub fn run(config: Config) -> Result<()> {
     // Instantiate here to inject the service into the application functions, specifically process_transaction()
-    let transaction_repository = Box::new(TransactionRepositoryInMemory::new());
-    let account_repository = Box::new(AccountRepositoryInMemory::new());
-    let payment_service: Box<dyn PaymentServiceTrait> = Box::new(PaymentService::new(
-        transaction_repository,
-        account_repository,
-    ));
+    let mut repositories = build_repositories(&config)?;
+    let journal = open_journal(&config, &mut repositories)?;
+
+    match (config.command, journal) {
+        (Command::Process, journal) => run_process(&config, repositories, journal),
+        (Command::Recover, Some((_, contents))) => {
+            recover(contents, repositories, io::stdout().lock())
+        }
+        (Command::Replay, Some(_)) => replay(&config, repositories, io::stdout().lock()),
+        (Command::Query { point, client_id }, _) => {
+            let mut payment_service = inspected_service(&config, repositories)?;
+            query(
+                &mut payment_service,
+                point,
+                client_id,
+                io::stdout().lock(),
+                &config.amount_format,
+            )
+        }
+        (Command::Verify, _) => {
+            let mut payment_service = inspected_service(&config, repositories)?;
+            verify(
+                &mut payment_service,
+                &config.invariant_policy,
+                io::stdout().lock(),
+            )
+        }
+        (Command::TrialBalance, _) => {
+            let mut payment_service = inspected_service(&config, repositories)?;
+            trial_balance(
+                &mut payment_service,
+                io::stdout().lock(),
+                &config.amount_format,
+            )
+        }
+        (Command::Recover | Command::Replay, None) => Err(anyhow!(
+            "The {:?} command requires a state directory",
+            config.command
+        )),
+    }
+}
+
+fn run_process(
+    config: &Config,
+    mut repositories: Repositories,
+    journal: Option<(Journal, JournalContents)>,
+) -> Result<()> {
+    if let Some(path) = &config.restore {
+        let snapshot = Snapshot::read(BufReader::new(File::open(path)?))?;
+        snapshot.restore(
+            repositories.transactions.as_mut(),
+            repositories.accounts.as_mut(),
+            repositories.events.as_mut(),
+        );
+    }
+
+    let Repositories {
+        transactions,
+        accounts,
+        events,
+    } = repositories;
+    let payment_service = match journal {
+        Some((journal, _)) => PaymentService::with_journal(
+            transactions,
+            accounts,
+            events,
+            config.service_policy.clone(),
+            journal,
+        ),
+        None => PaymentService::with_event_store(
+            transactions,
+            accounts,
+            events,
+            config.service_policy.clone(),
+        ),
+    };
+    let mut payment_service = with_rates_and_fees(config, payment_service)?;
+    apply_profiles(config, &mut payment_service)?;
+
+    process_file(config, io::stdout().lock(), &mut payment_service)?;
+
+    if let Some(path) = &config.snapshot {
+        Snapshot::capture(&mut payment_service).write(BufWriter::new(File::create(path)?))?;
+    }
+    Ok(())
+}
+
+/// Build a payment service holding the state to inspect: that of the state directory if there is
+/// one, otherwise that of the input file, which is applied in memory
+fn inspected_service(config: &Config, repositories: Repositories) -> Result<PaymentService> {
+    let mut payment_service = PaymentService::with_event_store(
+        repositories.transactions,
+        repositories.accounts,
+        repositories.events,
+        config.service_policy.clone(),
+    );
+    if config.state_dir.is_none() {
+        payment_service = with_rates_and_fees(config, payment_service)?;
+        apply_profiles(config, &mut payment_service)?;
+        process_file(config, io::sink(), &mut payment_service)?;
+    }
+    Ok(payment_service)
+}
+
+/// Apply the transactions in the input file named by the config
+fn process_file<W: io::Write>(
+    config: &Config,
+    output: W,
+    payment_service: &mut dyn PaymentServiceTrait,
+) -> Result<()> {
+    if config.filename == "-" {
+        process(io::stdin().lock(), output, config, payment_service)
+    } else {
+        process(
+            File::open(&config.filename)?,
+            output,
+            config,
+            payment_service,
+        )
+    }
+}
+
+/// Write the account summary as it was at a point in the history of the payment service, for one
+/// client or all of them
+pub fn query<W: io::Write>(
+    payment_service: &mut dyn PaymentServiceTrait,
+    point: PointInTime,
+    client_id: Option<u16>,
+    mut output: W,
+    amount_format: &AmountFormat,
+) -> Result<()> {
+    // A client has an account per currency
+    let accounts = payment_service.get_accounts_at(point);
+    let accounts: Vec<&Account> = accounts
+        .iter()
+        .filter(|account| client_id.is_none_or(|client_id| account.client_id == client_id))
+        .collect();
+    write_accounts(&mut output, &accounts, amount_format)
+}
+
+/// Check the invariants of the payment service's accounts and write a report of the violations,
+/// failing if there are any
+pub fn verify<W: io::Write>(
+    payment_service: &mut dyn PaymentServiceTrait,
+    policy: &InvariantPolicy,
+    mut output: W,
+) -> Result<()> {
+    let violations = invariants::check(payment_service, policy);
+    writeln!(
+        output,
+        "Checked {} accounts and {} transactions",
+        payment_service.get_accounts().len(),
+        payment_service.get_transactions().len()
+    )?;
+    for violation in &violations {
+        writeln!(output, "Violation: {}", violation)?;
+    }
+    if !violations.is_empty() {
+        return Err(anyhow!(
+            "State violates invariants ({} violations)",
+            violations.len()
+        ));
+    }
+
+    writeln!(output, "All invariants hold")?;
+    Ok(())
+}
+
+/// Write the balance of every ledger account the payment service has posted to, followed by
+/// their sum in each currency
+pub fn trial_balance<W: io::Write>(
+    payment_service: &mut dyn PaymentServiceTrait,
+    mut output: W,
+    amount_format: &AmountFormat,
+) -> Result<()> {
+    let balances = payment_service.get_trial_balance();
+    let mut sums: BTreeMap<Currency, Amount> = BTreeMap::new();
+    for (account, balance) in &balances {
+        let sum = sums.entry(account.currency()).or_default();
+        *sum = *sum + *balance;
+    }
+
+    writeln!(output, "account, balance")?;
+    for (account, balance) in &balances {
+        let format = account.currency().amount_format(amount_format);
+        writeln!(output, "{}, {}", account, balance.format(&format))?;
+    }
+    // The default currency is totalled even when nothing was posted, as before currencies
+    if sums.is_empty() {
+        sums.insert(Currency::DEFAULT, Amount::ZERO);
+    }
+    for (currency, sum) in &sums {
+        let format = currency.amount_format(amount_format);
+        match currency.is_default() {
+            true => writeln!(output, "total, {}", sum.format(&format))?,
+            false => writeln!(output, "total {}, {}", currency, sum.format(&format))?,
+        }
+    }
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Rebuild the state of the accounts and transactions from the event log, write the replayed
+/// account summary and check that it matches the stored state
+fn replay<W: io::Write>(
+    config: &Config,
+    mut repositories: Repositories,
+    mut output: W,
+) -> Result<()> {
+    let events = repositories.events.find_all();
+    let state = event::replay(events.into_iter().map(|recorded| &recorded.event));
+    let accounts: Vec<&Account> = state.accounts.values().collect();
+    write_accounts(&mut output, &accounts, &config.amount_format)?;
+
+    let stored_accounts = repositories.accounts.find_all();
+    let stored_transactions = repositories.transactions.find_all();
+    if !state.accounts.values().eq(stored_accounts)
+        || !state.transactions.values().eq(stored_transactions)
+    {
+        return Err(anyhow!("The replayed events don't match the stored state"));
+    }
+    Ok(())
+}
+
+/// Report on the recovered journal and check that the repositories hold the state it records
+fn recover<W: io::Write>(
+    contents: JournalContents,
+    mut repositories: Repositories,
+    mut output: W,
+) -> Result<()> {
+    writeln!(
+        output,
+        "Journal has {} committed entries, rolled back {} incomplete entries",
+        contents.committed.len(),
+        contents.rolled_back.len()
+    )?;
+
+    let mismatches = journal::verify(
+        &contents.committed,
+        repositories.transactions.as_mut(),
+        repositories.accounts.as_mut(),
+    );
+    for mismatch in &mismatches {
+        writeln!(output, "Mismatch: {}", mismatch)?;
+    }
+    if !mismatches.is_empty() {
+        return Err(anyhow!(
+            "State doesn't match the journal ({} mismatches)",
+            mismatches.len()
+        ));
+    }
+
+    writeln!(output, "State matches the journal")?;
+    Ok(())
+}
+
+/// Apply the transactions read as CSV from `input` to the payment service, and write the
+/// resulting account summary to `output`.
+///
+/// The input file name, service policy, rates, fees, client profiles and state directory in
+/// `config` are ignored (the payment service is provided by the caller), all other options apply
+/// as they do to `run()`.
+pub fn process<R: io::Read, W: io::Write>(
+    input: R,
+    output: W,
+    config: &Config,
+    payment_service: &mut dyn PaymentServiceTrait,
+) -> Result<()> {
+    let outcome_writer = match &config.outcomes {
+        Some(path) => Some(outcome_writer(path)?),
+        None => None,
+    };
+    let quarantine_writer = match &config.error_policy {
+        ErrorPolicy::Quarantine(path) => Some(quarantine_writer(path)?),
+        _ => None,
+    };
+
+    process_lines(
+        transaction_line_iter(input),
+        output,
+        payment_service,
+        config,
+        outcome_writer,
+        quarantine_writer,
+    )
+}
+
+fn transaction_line_iter<R: io::Read>(input: R) -> Reader<R> {
+    // Flexible so that rows with missing or extra fields are reported as parse errors for the row
+    ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(input)
+}
+
+fn outcome_writer(filename: &str) -> Result<Writer<File>> {
+    // Header is written up front so that an empty input still produces a well formed file
+    let mut writer = WriterBuilder::new()
+        .has_headers(false)
+        .from_path(filename)?;
+    writer.write_record(["line", "tx", "client", "type", "outcome", "error"])?;
+
+    Ok(writer)
+}
+
+fn quarantine_writer(filename: &str) -> Result<Writer<File>> {
+    // Rows are written as they were read, which may not match the header for malformed input
+    let writer = WriterBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(filename)?;
+
+    Ok(writer)
+}
+
+fn process_lines<R: io::Read, W: io::Write>(
+    mut reader: Reader<R>,
+    mut output: W,
+    payment_service: &mut dyn PaymentServiceTrait,
+    config: &Config,
+    mut outcome_writer: Option<Writer<File>>,
+    mut quarantine_writer: Option<Writer<File>>,
+) -> Result<()> {
+    let headers = reader.headers()?.clone();
+    if let Some(writer) = quarantine_writer.as_mut() {
+        let mut header = StringRecord::from(vec!["line", "error", "detail"]);
+        header.extend(headers.iter());
+        writer.write_record(&header)?;
+    }
+
+    let mut line_count = 0;
+    let mut skipped_count = 0;
+    let mut timestamp = start_time(payment_service);
+    for record_result in reader.records() {
+        let (line, record, (transaction, result)) = match record_result {
+            Ok(record) => {
+                let processed = process_record(&record, &headers, &mut timestamp, payment_service);
+                let line = record.position().map_or(0, |p| p.line());
+                (line, Some(record), processed)
+            }
+            Err(error) if error.is_io_error() => return Err(error.into()),
+            Err(error) => {
+                let line = error.position().map_or(0, |p| p.line());
+                (line, None, (None, Err(LineError::Parse(error.to_string()))))
+            }
+        };
+        line_count += 1;
+
+        if let Some(writer) = outcome_writer.as_mut() {
+            writer.serialize(LineOutcome::new(line, transaction.as_ref(), &result))?;
+        }
+
+        let error = match result {
+            Ok(()) => {
+                if let Some(transaction) = &transaction {
+                    warn_if_overdrawn(line, transaction, payment_service);
+                }
+                continue;
+            }
+            Err(error) => error,
+        };
+
+        if config.error_policy == ErrorPolicy::Strict {
+            return Err(anyhow!("Error processing input line {}: {}", line, error));
+        }
+
+        eprintln!("Error processing input line {}: {}", line, error);
+        skipped_count += 1;
+
+        if let Some(writer) = quarantine_writer.as_mut() {
+            let mut row = StringRecord::from(vec![
+                line.to_string(),
+                error.kind().to_string(),
+                error.to_string(),
+            ]);
+            if let Some(record) = &record {
+                row.extend(record.iter());
+            }
+            writer.write_record(&row)?;
+        }
+    }
+
+    if let Some(writer) = outcome_writer.as_mut() {
+        writer.flush()?;
+    }
+    if let Some(writer) = quarantine_writer.as_mut() {
+        writer.flush()?;
+    }
+    if skipped_count > 0 {
+        eprintln!("Skipped {} of {} input lines", skipped_count, line_count);
+    }
 
-    process_lines(transaction_reader, payment_service)
+    write_accounts(
+        &mut output,
+        &payment_service.get_accounts(),
+        &config.amount_format,
+    )
 }
 
-fn transaction_line_iter(filename: &str) -> Result<Reader<File>> {
-    let file = File::open(filename)?;
-    let reader = ReaderBuilder::new().trim(Trim::All).from_reader(file);
+/// Warn about a chargeback that was applied even though it left an account's total below zero,
+/// which the payment service flags with an `Overdrawn` event. Reversing a transfer can overdraw
+/// the account of its destination.
+fn warn_if_overdrawn(
+    line: u64,
+    transaction: &TransactionLine,
+    payment_service: &mut dyn PaymentServiceTrait,
+) {
+    if !matches!(transaction.tx_type, TransactionType::Chargeback) {
+        return;
+    }
+    let (currency, destination) = match payment_service.get_transaction(transaction.tx_id) {
+        Some(tx) => (tx.currency, tx.destination),
+        None => return,
+    };
+    for client_id in std::iter::once(transaction.client_id).chain(destination) {
+        if let Some(account) = payment_service.get_account(client_id, currency) {
+            if account.total.is_negative() {
+                eprintln!(
+                    "Warning: chargeback on input line {} left client {} with a total of {}",
+                    line, account.client_id, account.total
+                );
+            }
+        }
+    }
+}
 
-    Ok(reader)
+/// Time of the input lines before the first with a timestamp: that of the last recorded event,
+/// so that the time of a state directory never goes back from one run to the next, or 0
+fn start_time(payment_service: &mut dyn PaymentServiceTrait) -> u64 {
+    payment_service
+        .get_last_event()
+        .map_or(0, |recorded| recorded.position.timestamp)
 }
 
-fn process_lines(
-    mut reader: Reader<File>,
-    mut payment_service: Box<dyn PaymentServiceTrait>,
+/// Write the account summary, a row per client and currency. The currency column is only written
+/// when there are accounts in a currency other than the default, and the credit limit and the
+/// headroom left under it only when an account has credit, so that the summary of input without
+/// either is unchanged.
+fn write_accounts<W: io::Write>(
+    output: &mut W,
+    accounts: &[&Account],
+    amount_format: &AmountFormat,
 ) -> Result<()> {
-    let line_offset = 2; // Offset due to bing zero indexed and header line is skipped
-    for (i, line_result) in reader.deserialize::<TransactionLine>().enumerate() {
-        let transaction = line_result.unwrap();
-
-        transaction.validate().map_err(|error| {
-            anyhow!("Error processing input line {}: {}", i + line_offset, error)
-        })?;
-
-        let _result = process_transaction(&transaction, &mut payment_service);
-
-        // Handle error based on client requirements.
-        // match _result {
-        //     Err(error) => {
-        //         eprintln!("Error processing input line {}: {}", i + line_offset, error);
-        //     }
-        //     Ok(_) => (),
-        // }
-    }
-
-    println!("client, available, held, total, locked");
-    for account in payment_service.get_accounts() {
-        println!(
-            "{}, {:0.4}, {:0.4}, {:0.4}, {}",
-            account.client_id,
-            Account::from_fixed(account.available),
-            Account::from_fixed(account.held),
-            Account::from_fixed(account.total),
+    let has_currencies = accounts
+        .iter()
+        .any(|account| !account.currency.is_default());
+    let has_credit = accounts
+        .iter()
+        .any(|account| account.credit_limit != Amount::ZERO);
+    write!(output, "client, ")?;
+    if has_currencies {
+        write!(output, "currency, ")?;
+    }
+    write!(output, "available, held, total, locked")?;
+    match has_credit {
+        true => writeln!(output, ", limit, headroom")?,
+        false => writeln!(output)?,
+    }
+    for account in accounts {
+        let format = account.currency.amount_format(amount_format);
+        write!(output, "{}, ", account.client_id)?;
+        if has_currencies {
+            write!(output, "{}, ", account.currency)?;
+        }
+        write!(
+            output,
+            "{}, {}, {}, {}",
+            account.available.format(&format),
+            account.held.format(&format),
+            account.total.format(&format),
             account.locked
-        );
+        )?;
+        match has_credit {
+            true => writeln!(
+                output,
+                ", {}, {}",
+                account.credit_limit.format(&format),
+                account.headroom().format(&format)
+            )?,
+            false => writeln!(output)?,
+        }
     }
+    output.flush()?;
+
     Ok(())
 }
 
+/// Apply an input record at the time given by its timestamp, or `timestamp` (the time of the line
+/// before) if it has none
+fn process_record(
+    record: &StringRecord,
+    headers: &StringRecord,
+    timestamp: &mut u64,
+    payment_service: &mut dyn PaymentServiceTrait,
+) -> (Option<TransactionLine>, Result<(), LineError>) {
+    let transaction: TransactionLine = match record.deserialize(Some(headers)) {
+        Ok(transaction) => transaction,
+        Err(error) => return (None, Err(LineError::Parse(error.to_string()))),
+    };
+
+    let referenced = payment_service
+        .get_transaction(transaction.tx_id)
+        .map(|tx| tx.currency);
+    let base = payment_service.get_base_currency();
+    if let Err(error) = transaction.validate(referenced, base) {
+        return (
+            Some(transaction),
+            Err(LineError::Invalid(error.to_string())),
+        );
+    }
+
+    match transaction.timestamp {
+        Some(at) if at < *timestamp => {
+            let error = format!(
+                "TransactionLine timestamp {} is before the time of the line before, {}",
+                at, timestamp
+            );
+            return (Some(transaction), Err(LineError::Invalid(error)));
+        }
+        Some(at) => *timestamp = at,
+        None => {}
+    }
+    payment_service.set_position(Position {
+        line: record.position().map_or(0, |p| p.line()),
+        timestamp: *timestamp,
+    });
+    payment_service.expire_authorizations();
+
+    let result = process_transaction(&transaction, payment_service).map_err(LineError::Rejected);
+    (Some(transaction), result)
+}
+
 fn process_transaction(
     transaction: &TransactionLine,
-    transaction_service: &mut Box<dyn PaymentServiceTrait>,
-) -> Result<()> {
+    transaction_service: &mut dyn PaymentServiceTrait,
+) -> Result<(), PaymentServiceError> {
     // println!("transaction: {:?}", transaction.format());
 
     match &transaction.tx_type {
@@ -104,11 +1052,13 @@ fn process_transaction(
             transaction.client_id,
             transaction.tx_id,
             transaction.amount.unwrap(),
+            transaction.currency,
         ),
         TransactionType::Withdrawal => transaction_service.withdrawal(
             transaction.client_id,
             transaction.tx_id,
             transaction.amount.unwrap(),
+            transaction.currency,
         ),
         TransactionType::Dispute => {
             transaction_service.dispute(transaction.client_id, transaction.tx_id)
@@ -119,10 +1069,39 @@ fn process_transaction(
         TransactionType::Chargeback => {
             transaction_service.chargeback(transaction.client_id, transaction.tx_id)
         }
+        TransactionType::Limit => transaction_service.set_credit_limit(
+            transaction.client_id,
+            transaction.currency,
+            transaction.amount.unwrap(),
+        ),
+        TransactionType::Authorize => transaction_service.authorize(
+            transaction.client_id,
+            transaction.tx_id,
+            transaction.amount.unwrap(),
+            transaction.currency,
+        ),
+        TransactionType::Capture => transaction_service.capture(
+            transaction.client_id,
+            transaction.tx_id,
+            transaction.amount,
+        ),
+        TransactionType::Void => transaction_service.void(transaction.client_id, transaction.tx_id),
+        TransactionType::Transfer => transaction_service.transfer(
+            transaction.client_id,
+            transaction.tx_id,
+            transaction.amount.unwrap(),
+            transaction.currency,
+            transaction.destination.unwrap(),
+        ),
+        TransactionType::Refund => transaction_service.refund(
+            transaction.client_id,
+            transaction.tx_id,
+            transaction.amount.unwrap(),
+        ),
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 enum TransactionType {
     #[serde(rename = "deposit")]
     Deposit,
@@ -134,6 +1113,24 @@ enum TransactionType {
     Resolve,
     #[serde(rename = "chargeback")]
     Chargeback,
+    /// Set the credit limit of the client's account to the amount, an admin operation whose tx id
+    /// only identifies the line
+    #[serde(rename = "limit")]
+    Limit,
+    #[serde(rename = "authorize")]
+    Authorize,
+    /// Capture the amount of the authorization with the tx id, or all it still holds without an
+    /// amount
+    #[serde(rename = "capture")]
+    Capture,
+    #[serde(rename = "void")]
+    Void,
+    /// Move the amount from the client's account to that of the client in the destination column
+    #[serde(rename = "transfer")]
+    Transfer,
+    /// Refund the amount of the deposit with the tx id to the payer
+    #[serde(rename = "refund")]
+    Refund,
 }
 
 impl fmt::Display for TransactionType {
@@ -144,6 +1141,12 @@ impl fmt::Display for TransactionType {
             TransactionType::Dispute => write!(f, "Dispute"),
             TransactionType::Resolve => write!(f, "Resolve"),
             TransactionType::Chargeback => write!(f, "Chargeback"),
+            TransactionType::Limit => write!(f, "Limit"),
+            TransactionType::Authorize => write!(f, "Authorize"),
+            TransactionType::Capture => write!(f, "Capture"),
+            TransactionType::Void => write!(f, "Void"),
+            TransactionType::Transfer => write!(f, "Transfer"),
+            TransactionType::Refund => write!(f, "Refund"),
         }
     }
 }
@@ -157,10 +1160,20 @@ pub struct TransactionLine {
     #[serde(rename = "tx")]
     tx_id: u32,
     #[serde(rename = "amount")]
-    amount: Option<f64>,
+    amount: Option<Amount>,
+    /// Input without a currency column, or with an empty one, is in the default currency
+    #[serde(rename = "currency", default)]
+    currency: Currency,
+    /// Client a transfer is paid to, input without a destination column has no transfers
+    #[serde(rename = "destination", default)]
+    destination: Option<u16>,
+    /// Seconds since the Unix epoch, a line without a timestamp happens at the time of the line
+    /// before
+    #[serde(rename = "timestamp", default)]
+    timestamp: Option<u64>,
 }
 
-impl<'a> TransactionLine {
+impl TransactionLine {
     // fn format(&self) -> String {
     //     format!(
     //         "TransactionLine {{ type: {}, client: {:4?}, tx: {:8?}, amount: {:?} }}",
@@ -168,27 +1181,523 @@ impl<'a> TransactionLine {
     //     )
     // }
 
-    fn validate(&self) -> Result<()> {
+    /// Check the fields of the line, given the currency of the transaction it refers to if that
+    /// exists, and the base currency amounts are converted into if there is one
+    fn validate(&self, referenced: Option<Currency>, base: Option<Currency>) -> Result<()> {
+        // Only transfers have a destination, which is another client
+        match (self.tx_type, self.destination) {
+            (TransactionType::Transfer, None) => {
+                return Err(anyhow!(
+                    "TransactionLine type '{}' must have a destination field",
+                    self.tx_type
+                ))
+            }
+            (TransactionType::Transfer, Some(destination)) if destination == self.client_id => {
+                return Err(anyhow!(
+                    "TransactionLine type '{}' must have another client as its destination",
+                    self.tx_type
+                ))
+            }
+            (TransactionType::Transfer, Some(_)) | (_, None) => {}
+            (_, Some(_)) => {
+                return Err(anyhow!(
+                    "TransactionLine type '{}' cannot have a destination field",
+                    self.tx_type
+                ))
+            }
+        }
         match self.tx_type {
-            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+            // These act in the currency of the transaction they refer to
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Void => match (self.amount, self.currency.is_default()) {
+                (None, true) => Ok(()),
+                (None, false) => Err(anyhow!(
+                    "TransactionLine type '{}' cannot have a currency field",
+                    self.tx_type
+                )),
+                _other => Err(anyhow!(
+                    "TransactionLine type '{}' cannot have an amounts field",
+                    self.tx_type
+                )),
+            },
+            // A capture is in the currency of its authorization, with or without an amount
+            TransactionType::Capture => match self.currency.is_default() {
+                true => self.validate_precision(referenced),
+                false => Err(anyhow!(
+                    "TransactionLine type '{}' cannot have a currency field",
+                    self.tx_type
+                )),
+            },
+            // A refund is in the currency of its deposit, and always has an amount
+            TransactionType::Refund => match (self.amount, self.currency.is_default()) {
+                (Some(_), true) => self.validate_precision(referenced),
+                (Some(_), false) => Err(anyhow!(
+                    "TransactionLine type '{}' cannot have a currency field",
+                    self.tx_type
+                )),
+                (None, _) => Err(anyhow!(
+                    "TransactionLine type '{}' must have an amounts field",
+                    self.tx_type
+                )),
+            },
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Limit
+            | TransactionType::Authorize
+            | TransactionType::Transfer => {
+                // amounts without a currency are booked in the base currency, but for limits,
+                // which are set on the account in the currency of the line as it is
+                let converted = !matches!(self.tx_type, TransactionType::Limit);
+                let currency = match base {
+                    Some(base) if converted && self.currency.is_default() => base,
+                    _ => self.currency,
+                };
                 match self.amount {
-                    None => Ok(()),
-                    _other => Err(anyhow!(
-                        "TransactionLine type '{}' cannot have an amounts field",
+                    None => Err(anyhow!(
+                        "TransactionLine type '{}' must have an amounts field",
                         self.tx_type
                     )),
+                    Some(amount) if !currency.accepts(amount) => Err(anyhow!(
+                        "TransactionLine amount has more than {} decimal places for {}",
+                        currency.minor_units(),
+                        currency
+                    )),
+                    _other => Ok(()),
                 }
             }
-            TransactionType::Deposit | TransactionType::Withdrawal => match self.amount {
-                None => Err(anyhow!(
-                    "TransactionLine type '{}' must have an amounts field",
-                    self.tx_type
-                )),
-                _other => Ok(()),
-            },
+        }
+    }
+
+    /// Check that the amount of a line acting in the currency of another transaction has no more
+    /// decimal places than that currency's minor unit
+    fn validate_precision(&self, referenced: Option<Currency>) -> Result<()> {
+        match (self.amount, referenced) {
+            (Some(amount), Some(currency)) if !currency.accepts(amount) => Err(anyhow!(
+                "TransactionLine amount has more than {} decimal places for {}",
+                currency.minor_units(),
+                currency
+            )),
+            _other => Ok(()),
+        }
+    }
+}
+
+/// Reason an input line could not be applied
+#[derive(Debug)]
+enum LineError {
+    Parse(String),
+    Invalid(String),
+    Rejected(PaymentServiceError),
+}
+
+impl LineError {
+    fn kind(&self) -> &'static str {
+        match self {
+            LineError::Parse(_) => "ParseError",
+            LineError::Invalid(_) => "ValidationError",
+            LineError::Rejected(error) => error.kind(),
+        }
+    }
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LineError::Parse(message) | LineError::Invalid(message) => write!(f, "{}", message),
+            LineError::Rejected(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+enum Outcome {
+    Applied,
+    Rejected,
+}
+
+/// Record of how a single input line was handled, written to the outcomes file
+#[derive(Debug, Serialize)]
+struct LineOutcome {
+    line: u64,
+    tx: Option<u32>,
+    client: Option<u16>,
+    #[serde(rename = "type")]
+    tx_type: Option<TransactionType>,
+    outcome: Outcome,
+    error: Option<&'static str>,
+}
+
+impl LineOutcome {
+    fn new(
+        line: u64,
+        transaction: Option<&TransactionLine>,
+        result: &Result<(), LineError>,
+    ) -> LineOutcome {
+        let (outcome, error) = match result {
+            Ok(()) => (Outcome::Applied, None),
+            Err(error) => (Outcome::Rejected, Some(error.kind())),
+        };
+        LineOutcome {
+            line,
+            tx: transaction.map(|t| t.tx_id),
+            client: transaction.map(|t| t.client_id),
+            tx_type: transaction.map(|t| t.tx_type),
+            outcome,
+            error,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> impl Iterator<Item = String> {
+        let mut args = vec!["payments".to_string()];
+        args.extend(values.iter().map(|value| value.to_string()));
+        args.into_iter()
+    }
+
+    #[test]
+    fn config_parses_filename() {
+        let config = Config::new(args(&["input.csv"])).unwrap();
+        assert_eq!(config.filename, "input.csv");
+        assert_eq!(config.outcomes, None);
+        assert_eq!(config.error_policy, ErrorPolicy::Lenient);
+        assert_eq!(config.amount_format, AmountFormat::default());
+        assert_eq!(config.service_policy, PaymentServicePolicy::default());
+        assert_eq!(config.state_dir, None);
+    }
+
+    #[test]
+    fn config_parses_outcomes_option() {
+        let config = Config::new(args(&["input.csv", "--outcomes", "out.csv"])).unwrap();
+        assert_eq!(config.filename, "input.csv");
+        assert_eq!(config.outcomes, Some("out.csv".to_string()));
+
+        let config = Config::new(args(&["--outcomes", "out.csv", "input.csv"])).unwrap();
+        assert_eq!(config.filename, "input.csv");
+        assert_eq!(config.outcomes, Some("out.csv".to_string()));
+    }
+
+    #[test]
+    fn config_parses_error_policy() {
+        let config = Config::new(args(&["input.csv", "--error-policy", "strict"])).unwrap();
+        assert_eq!(config.error_policy, ErrorPolicy::Strict);
+
+        let config = Config::new(args(&["input.csv", "--error-policy", "lenient"])).unwrap();
+        assert_eq!(config.error_policy, ErrorPolicy::Lenient);
+
+        let config = Config::new(args(&["input.csv", "--quarantine", "bad.csv"])).unwrap();
+        assert_eq!(
+            config.error_policy,
+            ErrorPolicy::Quarantine("bad.csv".to_string())
+        );
+
+        let config = Config::new(args(&[
+            "input.csv",
+            "--error-policy",
+            "quarantine",
+            "--quarantine",
+            "bad.csv",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.error_policy,
+            ErrorPolicy::Quarantine("bad.csv".to_string())
+        );
+    }
+
+    #[test]
+    fn config_rejects_inconsistent_error_policy() {
+        assert!(Config::new(args(&["input.csv", "--error-policy", "quarantine"])).is_err());
+        assert!(Config::new(args(&["input.csv", "--error-policy", "ignore"])).is_err());
+        assert!(Config::new(args(&[
+            "input.csv",
+            "--error-policy",
+            "strict",
+            "--quarantine",
+            "bad.csv"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn config_parses_amount_format() {
+        let config = Config::new(args(&[
+            "input.csv",
+            "--fraction-digits",
+            "2",
+            "--trim-zeros",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.amount_format,
+            AmountFormat {
+                fraction_digits: 2,
+                trim_trailing_zeros: true
+            }
+        );
+
+        assert!(Config::new(args(&["input.csv", "--fraction-digits", "two"])).is_err());
+    }
+
+    #[test]
+    fn config_parses_service_policy() {
+        let config = Config::new(args(&["input.csv", "--no-withdrawal-disputes"])).unwrap();
+        assert!(!config.service_policy.withdrawals_disputable);
+
+        let config = Config::new(args(&[
+            "input.csv",
+            "--auth-expiry-lines",
+            "100",
+            "--auth-expiry-seconds",
+            "3600",
+        ]))
+        .unwrap();
+        assert_eq!(config.service_policy.authorization_expiry_lines, Some(100));
+        assert_eq!(
+            config.service_policy.authorization_expiry_seconds,
+            Some(3600)
+        );
+
+        assert!(Config::new(args(&["input.csv", "--auth-expiry-lines", "-1"])).is_err());
+        assert!(Config::new(args(&["input.csv", "--auth-expiry-seconds"])).is_err());
+    }
+
+    #[test]
+    fn config_parses_state_dir() {
+        let config = Config::new(args(&["input.csv", "--state-dir", "state"])).unwrap();
+        assert_eq!(config.state_dir, Some("state".to_string()));
+
+        assert!(Config::new(args(&["input.csv", "--state-dir"])).is_err());
+    }
+
+    #[test]
+    fn config_parses_recover_command() {
+        let config = Config::new(args(&["input.csv"])).unwrap();
+        assert_eq!(config.command, Command::Process);
+        let config = Config::new(args(&["recover", "--state-dir", "state"])).unwrap();
+        assert_eq!(config.command, Command::Recover);
+        assert_eq!(config.backend, Backend::File);
+
+        let config = Config::new(args(&["replay", "--state-dir", "state"])).unwrap();
+        assert_eq!(config.command, Command::Replay);
+
+        assert!(Config::new(args(&["recover"])).is_err());
+        assert!(Config::new(args(&["replay"])).is_err());
+        assert!(Config::new(args(&["recover", "input.csv", "--state-dir", "state"])).is_err());
+    }
+
+    #[test]
+    fn config_parses_verify_command() {
+        let config = Config::new(args(&["verify", "--state-dir", "state"])).unwrap();
+        assert_eq!(config.command, Command::Verify);
+        assert_eq!(config.invariant_policy, InvariantPolicy::default());
+
+        let config =
+            Config::new(args(&["verify", "input.csv", "--allow-negative-balances"])).unwrap();
+        assert_eq!(config.filename, "input.csv");
+        assert!(config.invariant_policy.negative_balances_allowed);
+
+        assert!(Config::new(args(&["verify"])).is_err());
+        assert!(Config::new(args(&["verify", "input.csv", "--state-dir", "state"])).is_err());
+        assert!(Config::new(args(&["input.csv", "--allow-negative-balances"])).is_err());
+    }
+
+    #[test]
+    fn config_parses_trial_balance_command() {
+        let config = Config::new(args(&["trial-balance", "input.csv"])).unwrap();
+        assert_eq!(config.command, Command::TrialBalance);
+        let config = Config::new(args(&["trial-balance", "--state-dir", "state"])).unwrap();
+        assert_eq!(config.command, Command::TrialBalance);
+
+        assert!(Config::new(args(&["trial-balance"])).is_err());
+        assert!(Config::new(args(&["trial-balance", "input.csv", "--line", "1"])).is_err());
+        assert!(Config::new(args(&["trial-balance", "input.csv", "--snapshot", "s"])).is_err());
+    }
+
+    #[test]
+    fn config_parses_query_command() {
+        let config = Config::new(args(&[
+            "query",
+            "input.csv",
+            "--line",
+            "120",
+            "--client",
+            "7",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.command,
+            Command::Query {
+                point: PointInTime::AfterLine(120),
+                client_id: Some(7),
+            }
+        );
+        assert_eq!(config.filename, "input.csv");
+
+        let config = Config::new(args(&[
+            "query",
+            "--state-dir",
+            "state",
+            "--at",
+            "1700000000",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.command,
+            Command::Query {
+                point: PointInTime::At(1_700_000_000),
+                client_id: None,
+            }
+        );
+
+        assert!(Config::new(args(&["query", "input.csv"])).is_err());
+        assert!(Config::new(args(&["query", "--line", "1"])).is_err());
+        assert!(Config::new(args(&["query", "input.csv", "--line", "1", "--at", "1"])).is_err());
+        assert!(Config::new(args(&["query", "input.csv", "--line", "x"])).is_err());
+        assert!(Config::new(args(&[
+            "query",
+            "input.csv",
+            "--line",
+            "1",
+            "--state-dir",
+            "state"
+        ]))
+        .is_err());
+        assert!(Config::new(args(&[
+            "query",
+            "input.csv",
+            "--line",
+            "1",
+            "--restore",
+            "s"
+        ]))
+        .is_err());
+        assert!(Config::new(args(&["query", "--line", "1", "--state-dir", "state"])).is_err());
+        assert!(Config::new(args(&["query", "--at", "1", "--state-dir", "state"])).is_ok());
+        assert!(Config::new(args(&["input.csv", "--line", "1"])).is_err());
+        assert!(Config::new(args(&["input.csv", "--client", "1"])).is_err());
+    }
+
+    #[test]
+    fn config_parses_snapshot_options() {
+        let config = Config::new(args(&[
+            "input.csv",
+            "--restore",
+            "before.snapshot",
+            "--snapshot",
+            "after.snapshot",
+        ]))
+        .unwrap();
+        assert_eq!(config.restore, Some("before.snapshot".to_string()));
+        assert_eq!(config.snapshot, Some("after.snapshot".to_string()));
+
+        assert!(Config::new(args(&["input.csv", "--snapshot"])).is_err());
+        assert!(Config::new(args(&[
+            "input.csv",
+            "--restore",
+            "before.snapshot",
+            "--state-dir",
+            "state",
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn config_parses_rates_options() {
+        let config = Config::new(args(&["input.csv", "--rates", "rates.csv"])).unwrap();
+        assert_eq!(config.rates, Some("rates.csv".to_string()));
+        assert_eq!(config.base_currency, Currency::DEFAULT);
+        assert_eq!(config.rounding, RoundingMode::HalfUp);
+
+        let config = Config::new(args(&[
+            "input.csv",
+            "--rates",
+            "rates.csv",
+            "--base-currency",
+            "USD",
+            "--rounding",
+            "half-even",
+        ]))
+        .unwrap();
+        assert_eq!(config.base_currency, "USD".parse().unwrap());
+        assert_eq!(config.rounding, RoundingMode::HalfEven);
+        assert_eq!(config.fees, None);
+
+        assert!(Config::new(args(&["input.csv", "--base-currency", "USD"])).is_err());
+        assert!(Config::new(args(&["input.csv", "--rounding", "down"])).is_err());
+        assert!(Config::new(args(&[
+            "input.csv",
+            "--rates",
+            "rates.csv",
+            "--base-currency",
+            "XYZ"
+        ]))
+        .is_err());
+        assert!(Config::new(args(&[
+            "input.csv",
+            "--rates",
+            "rates.csv",
+            "--rounding",
+            "nearest"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn config_parses_fees_option() {
+        let config = Config::new(args(&["input.csv", "--fees", "fees.csv"])).unwrap();
+        assert_eq!(config.fees, Some("fees.csv".to_string()));
+
+        assert!(Config::new(args(&["input.csv", "--fees"])).is_err());
+    }
+
+    #[test]
+    fn config_parses_profiles_option() {
+        let config = Config::new(args(&["input.csv", "--profiles", "profiles.csv"])).unwrap();
+        assert_eq!(config.profiles, Some("profiles.csv".to_string()));
+
+        assert!(Config::new(args(&["input.csv", "--profiles"])).is_err());
+    }
+
+    #[test]
+    fn config_parses_backend() {
+        let config = Config::new(args(&["input.csv"])).unwrap();
+        assert_eq!(config.backend, Backend::Memory);
+        let config = Config::new(args(&["input.csv", "--state-dir", "state"])).unwrap();
+        assert_eq!(config.backend, Backend::File);
+        let config = Config::new(args(&[
+            "input.csv",
+            "--backend",
+            "sqlite",
+            "--state-dir",
+            "state",
+        ]))
+        .unwrap();
+        assert_eq!(config.backend, Backend::Sqlite);
+
+        assert!(Config::new(args(&["input.csv", "--backend", "sqlite"])).is_err());
+        assert!(Config::new(args(&["input.csv", "--backend", "file"])).is_err());
+        assert!(Config::new(args(&[
+            "input.csv",
+            "--backend",
+            "memory",
+            "--state-dir",
+            "s"
+        ]))
+        .is_err());
+        assert!(Config::new(args(&["input.csv", "--backend", "postgres"])).is_err());
+        assert!(Config::new(args(&["input.csv", "--backend"])).is_err());
+    }
+
+    #[test]
+    fn config_rejects_missing_arguments() {
+        assert!(Config::new(args(&[])).is_err());
+        assert!(Config::new(args(&["input.csv", "--outcomes"])).is_err());
+        assert!(Config::new(args(&["input.csv", "other.csv"])).is_err());
+    }
+}
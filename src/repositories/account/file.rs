@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use super::{AccountKey, AccountRepositoryTrait};
+use crate::core::entities::account::Account;
+use crate::repositories::log::RecordLog;
+use crate::repositories::record::{account_from_record, account_to_record, LAYOUT_VERSION};
+use crate::repositories::unit_of_work::UndoLog;
+
+/// Account repository persisted to an append-only log file in a state directory.
+///
+/// The latest version of every account is indexed in memory, and the index is rebuilt from the
+/// log when the repository is opened so that state survives process restarts.
+pub struct AccountRepositoryFile {
+    log: RecordLog,
+    store: BTreeMap<AccountKey, Account>,
+    undo: UndoLog<AccountKey, Account>,
+    /// Records written during a unit of work, appended to the log when it is committed
+    pending: Vec<Account>,
+}
+
+impl AccountRepositoryFile {
+    pub const FILENAME: &'static str = "accounts.log";
+
+    pub fn open(state_dir: &Path) -> io::Result<AccountRepositoryFile> {
+        let (log, records) =
+            RecordLog::open_versioned(&state_dir.join(Self::FILENAME), LAYOUT_VERSION)?;
+
+        let mut store = BTreeMap::new();
+        for record in records {
+            let account = account_from_record(&record)?;
+            store.insert(account.key(), account);
+        }
+
+        Ok(AccountRepositoryFile {
+            log,
+            store,
+            undo: UndoLog::default(),
+            pending: Vec::new(),
+        })
+    }
+
+    fn append(&mut self, account: &Account) {
+        // Failing to persist is a fundamental system failure
+        self.log
+            .append(&account_to_record(account))
+            .expect("Failed to write to the account log");
+    }
+
+    fn sync(&mut self) {
+        self.log.sync().expect("Failed to sync the account log");
+    }
+}
+
+impl AccountRepositoryTrait for AccountRepositoryFile {
+    fn update(&mut self, key: AccountKey, account: Account) {
+        if self.undo.is_active() {
+            self.pending.push(account.clone());
+        } else {
+            self.append(&account);
+            self.sync();
+        }
+        let previous = self.store.insert(key, account);
+        self.undo.record(key, previous);
+    }
+
+    fn find(&mut self, key: AccountKey) -> Option<&Account> {
+        self.store.get(&key)
+    }
+
+    fn find_or_create(&mut self, key: AccountKey) -> Option<&Account> {
+        if !self.store.contains_key(&key) {
+            let (client_id, currency) = key;
+            self.update(key, Account::build_default_account(client_id, currency));
+        }
+        self.store.get(&key)
+    }
+
+    fn find_all(&mut self) -> Vec<&Account> {
+        self.store.values().collect()
+    }
+
+    fn begin(&mut self) {
+        self.undo.begin();
+    }
+
+    fn commit(&mut self) {
+        for account in std::mem::take(&mut self.pending) {
+            self.append(&account);
+        }
+        self.sync();
+        self.undo.commit();
+    }
+
+    fn rollback(&mut self) {
+        self.pending.clear();
+        self.undo.rollback(&mut self.store);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::currency::Currency;
+    use crate::repositories::log::test_dir;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn it_reloads_accounts_after_reopening() {
+        let dir = test_dir("account-file-reload");
+
+        let a = Account::new(42, amount("1.23"), amount("0.0"), amount("1.23"), false);
+        let b = Account::new(21, amount("0.23"), amount("0.1"), amount("0.33"), true);
+        let a_update = Account::new(42, amount("2.23"), amount("0.0"), amount("2.23"), false);
+
+        let mut ar = AccountRepositoryFile::open(&dir).unwrap();
+        ar.update(a.key(), a.clone());
+        ar.update(b.key(), b.clone());
+        ar.update(a_update.key(), a_update.clone());
+        drop(ar);
+
+        let mut ar = AccountRepositoryFile::open(&dir).unwrap();
+        assert_eq!(ar.find((42, Currency::DEFAULT)).unwrap(), &a_update);
+        assert_eq!(ar.find((21, Currency::DEFAULT)).unwrap(), &b);
+        assert_eq!(ar.find((68, Currency::DEFAULT)), None);
+        assert_eq!(ar.find_all(), vec![&b, &a_update]);
+    }
+
+    #[test]
+    fn it_persists_created_accounts() {
+        let dir = test_dir("account-file-create");
+
+        let mut ar = AccountRepositoryFile::open(&dir).unwrap();
+        let expected = Account::build_default_account(42, Currency::DEFAULT);
+        assert_eq!(
+            ar.find_or_create((42, Currency::DEFAULT)).unwrap(),
+            &expected
+        );
+        drop(ar);
+
+        let mut ar = AccountRepositoryFile::open(&dir).unwrap();
+        assert_eq!(ar.find((42, Currency::DEFAULT)).unwrap(), &expected);
+    }
+
+    #[test]
+    fn it_only_persists_committed_changes() {
+        let dir = test_dir("account-file-unit-of-work");
+
+        let mut ar = AccountRepositoryFile::open(&dir).unwrap();
+        let committed = Account::new(42, amount("1.0"), amount("0.0"), amount("1.0"), false);
+        let discarded = Account::new(42, amount("2.0"), amount("0.0"), amount("2.0"), false);
+
+        ar.begin();
+        ar.update((42, Currency::DEFAULT), committed.clone());
+        ar.commit();
+        ar.begin();
+        ar.update((42, Currency::DEFAULT), discarded);
+        ar.find_or_create((7, Currency::DEFAULT));
+        ar.rollback();
+        assert_eq!(ar.find_all(), vec![&committed]);
+        drop(ar);
+
+        let mut ar = AccountRepositoryFile::open(&dir).unwrap();
+        assert_eq!(ar.find_all(), vec![&committed]);
+    }
+}
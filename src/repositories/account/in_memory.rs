@@ -1,21 +1,32 @@
 use std::collections::BTreeMap;
 
-use super::AccountRepositoryTrait;
+use super::{AccountKey, AccountRepositoryTrait};
+use crate::core::amount::Amount;
 use crate::core::entities::account::Account;
+use crate::repositories::unit_of_work::UndoLog;
 
 pub struct AccountRepositoryInMemory {
-    pub store: Box<BTreeMap<u16, Account>>,
+    pub store: Box<BTreeMap<AccountKey, Account>>,
+    undo: UndoLog<AccountKey, Account>,
+}
+
+impl Default for AccountRepositoryInMemory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AccountRepositoryInMemory {
     pub fn new() -> AccountRepositoryInMemory {
         AccountRepositoryInMemory {
             store: Box::new(BTreeMap::new()),
+            undo: UndoLog::default(),
         }
     }
 
-    pub fn default(key: u16) -> Account {
-        Account::new(key, 0.0, 0.0, 0.0, false)
+    pub fn default((client_id, currency): AccountKey) -> Account {
+        Account::new(client_id, Amount::ZERO, Amount::ZERO, Amount::ZERO, false)
+            .with_currency(currency)
     }
 
     pub fn print(&self) {
@@ -26,19 +37,23 @@ impl AccountRepositoryInMemory {
 }
 
 impl AccountRepositoryTrait for AccountRepositoryInMemory {
-    fn update(&mut self, client_id: u16, account: Account) {
-        self.store.insert(client_id, account);
+    fn update(&mut self, key: AccountKey, account: Account) {
+        let previous = self.store.insert(key, account);
+        self.undo.record(key, previous);
     }
 
-    fn find(&mut self, client_id: u16) -> Option<&Account> {
-        self.store.get(&client_id)
+    fn find(&mut self, key: AccountKey) -> Option<&Account> {
+        self.store.get(&key)
     }
 
-    fn find_or_create(&mut self, client_id: u16) -> Option<&Account> {
+    fn find_or_create(&mut self, key: AccountKey) -> Option<&Account> {
+        if !self.store.contains_key(&key) {
+            self.undo.record(key, None);
+        }
         Some(
             self.store
-                .entry(client_id)
-                .or_insert(AccountRepositoryInMemory::default(client_id)),
+                .entry(key)
+                .or_insert(AccountRepositoryInMemory::default(key)),
         )
     }
 
@@ -49,32 +64,49 @@ impl AccountRepositoryTrait for AccountRepositoryInMemory {
         }
         elements
     }
+
+    fn begin(&mut self) {
+        self.undo.begin();
+    }
+
+    fn commit(&mut self) {
+        self.undo.commit();
+    }
+
+    fn rollback(&mut self) {
+        self.undo.rollback(&mut self.store);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::currency::Currency;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
 
     #[test]
     fn it_can_insert_and_find() {
         let mut ar = AccountRepositoryInMemory::new();
 
-        let a = Account::new(42, 1.23, 0.0, 1.23, false);
-        let b = Account::new(21, 0.23, 0.0, 0.23, false);
+        let a = Account::new(42, amount("1.23"), amount("0.0"), amount("1.23"), false);
+        let b = Account::new(21, amount("0.23"), amount("0.0"), amount("0.23"), false);
 
-        ar.update(a.client_id, a.clone());
-        ar.update(b.client_id, b.clone());
+        ar.update(a.key(), a.clone());
+        ar.update(b.key(), b.clone());
 
         // it finds an inserted key
-        let res = ar.find(a.client_id).unwrap();
+        let res = ar.find(a.key()).unwrap();
         assert_eq!(res, &a);
 
         // it finds an inserted key
-        let res = ar.find(b.client_id).unwrap();
+        let res = ar.find(b.key()).unwrap();
         assert_eq!(res, &b);
 
         // it fails to find an invalid key
-        let res = ar.find(68);
+        let res = ar.find((68, Currency::DEFAULT));
         assert_eq!(res, None);
     }
 
@@ -82,13 +114,13 @@ mod tests {
     fn it_can_insert_and_find_all_sorted() {
         let mut ar = AccountRepositoryInMemory::new();
 
-        let a = Account::new(42, 1.23, 0.0, 1.23, false);
-        let b = Account::new(420, 10.23, 0.0, 10.23, true);
-        let c = Account::new(1, 1.11, 1.22, 1.33, true);
+        let a = Account::new(42, amount("1.23"), amount("0.0"), amount("1.23"), false);
+        let b = Account::new(420, amount("10.23"), amount("0.0"), amount("10.23"), true);
+        let c = Account::new(1, amount("1.11"), amount("1.22"), amount("1.33"), true);
 
-        ar.update(a.client_id, a.clone());
-        ar.update(b.client_id, b.clone());
-        ar.update(c.client_id, c.clone());
+        ar.update(a.key(), a.clone());
+        ar.update(b.key(), b.clone());
+        ar.update(c.key(), c.clone());
 
         let res = ar.find_all();
         println!("res: {:?}", res);
@@ -102,9 +134,9 @@ mod tests {
     fn it_can_create_a_new_account() {
         let mut ar = AccountRepositoryInMemory::new();
 
-        let expected = Account::new(42, 0.0, 0.0, 0.0, false);
+        let expected = Account::new(42, amount("0.0"), amount("0.0"), amount("0.0"), false);
 
-        let account = ar.find_or_create(42).unwrap();
+        let account = ar.find_or_create((42, Currency::DEFAULT)).unwrap();
 
         assert_eq!(account, &expected);
     }
@@ -113,24 +145,44 @@ mod tests {
     fn it_can_update_an_existing_account() {
         let mut ar = AccountRepositoryInMemory::new();
 
-        let initial = Account::new(42, 42.42, 0.0, 42.42, false);
+        let initial = Account::new(42, amount("42.42"), amount("0.0"), amount("42.42"), false);
 
-        let update = Account::new(42, 20.23, 3.0, 23.23, false);
+        let update = Account::new(42, amount("20.23"), amount("3.0"), amount("23.23"), false);
 
         let expected = update.clone();
 
-        ar.update(42, initial);
-        ar.update(42, update);
+        ar.update((42, Currency::DEFAULT), initial);
+        ar.update((42, Currency::DEFAULT), update);
 
         let client_id = 42;
-        let account = match ar.find(client_id) {
+        let account = match ar.find((client_id, Currency::DEFAULT)) {
             Some(a) => a,
             None => {
-                ar.update(client_id, AccountRepositoryInMemory::default(client_id));
-                ar.find(client_id).unwrap()
+                ar.update(
+                    (client_id, Currency::DEFAULT),
+                    AccountRepositoryInMemory::default((client_id, Currency::DEFAULT)),
+                );
+                ar.find((client_id, Currency::DEFAULT)).unwrap()
             }
         };
 
         assert_eq!(account, &expected);
     }
+
+    #[test]
+    fn it_keeps_an_account_per_currency() {
+        let mut ar = AccountRepositoryInMemory::new();
+        let usd: Currency = "USD".parse().unwrap();
+
+        let a = Account::new(42, amount("1.23"), amount("0.0"), amount("1.23"), false);
+        let b =
+            Account::new(42, amount("5.0"), amount("0.0"), amount("5.0"), true).with_currency(usd);
+
+        ar.update(b.key(), b.clone());
+        ar.update(a.key(), a.clone());
+
+        assert_eq!(ar.find((42, usd)).unwrap(), &b);
+        assert_eq!(ar.find((42, Currency::DEFAULT)).unwrap(), &a);
+        assert_eq!(ar.find_all(), vec![&a, &b]);
+    }
 }
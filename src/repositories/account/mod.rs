@@ -1,17 +1,32 @@
+pub mod file;
 pub mod in_memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 // Use the Account structure from core in repository layer as it is identical
-pub use crate::core::entities::account::Account;
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
+pub use crate::core::entities::account::{Account, AccountKey};
 
 impl Account {
-    pub fn build_default_account(client_id: u16) -> Account {
-        Account::new(client_id, 0.0, 0.0, 0.0, false)
+    pub fn build_default_account(client_id: u16, currency: Currency) -> Account {
+        Account::new(client_id, Amount::ZERO, Amount::ZERO, Amount::ZERO, false)
+            .with_currency(currency)
     }
 }
 
+/// Accounts are stored per client and currency, see `AccountKey`
 pub trait AccountRepositoryTrait {
-    fn update(&mut self, client_id: u16, account: Account);
-    fn find(&mut self, client_id: u16) -> Option<&Account>;
-    fn find_or_create(&mut self, client_id: u16) -> Option<&Account>;
+    fn update(&mut self, key: AccountKey, account: Account);
+    fn find(&mut self, key: AccountKey) -> Option<&Account>;
+    fn find_or_create(&mut self, key: AccountKey) -> Option<&Account>;
+    /// Every account, ordered by client and then currency
     fn find_all(&mut self) -> Vec<&Account>;
+
+    /// Start a unit of work. Changes made until `commit()` or `rollback()` is called are applied
+    /// or discarded together (see `repositories::unit_of_work::UnitOfWork`)
+    fn begin(&mut self);
+    fn commit(&mut self);
+    /// Discard the changes made since `begin()`
+    fn rollback(&mut self);
 }
@@ -0,0 +1,162 @@
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use std::rc::Rc;
+
+use super::{AccountKey, AccountRepositoryTrait};
+use crate::core::entities::account::Account;
+use crate::repositories::record::{account_from_record, account_to_record};
+use crate::repositories::sqlite::{self, row_to_record};
+
+const COLUMNS: &str = "client_id, available, held, total, locked, currency, credit_limit";
+
+/// Account repository backed by a SQLite database.
+///
+/// Accounts are read from the database on every lookup. As the trait hands out references, the
+/// results of the latest lookup are cached by the repository until the next one.
+pub struct AccountRepositorySqlite {
+    connection: Rc<Connection>,
+    found: Option<Account>,
+    found_all: Vec<Account>,
+}
+
+impl AccountRepositorySqlite {
+    pub fn new(connection: Rc<Connection>) -> AccountRepositorySqlite {
+        AccountRepositorySqlite {
+            connection,
+            found: None,
+            found_all: Vec::new(),
+        }
+    }
+
+    fn query_one(&self, (client_id, currency): AccountKey) -> rusqlite::Result<Option<Account>> {
+        let sql = format!(
+            "SELECT {} FROM accounts WHERE client_id = ?1 AND currency = ?2",
+            COLUMNS
+        );
+        self.connection
+            .query_row(&sql, params![client_id, currency.code()], |row| {
+                row_to_record(row, 7)
+            })
+            .optional()
+            .map(|record| record.map(|r| account_from_record(&r).expect("Invalid account row")))
+    }
+
+    fn query_all(&self) -> rusqlite::Result<Vec<Account>> {
+        let sql = format!(
+            "SELECT {} FROM accounts ORDER BY client_id, currency",
+            COLUMNS
+        );
+        let mut statement = self.connection.prepare(&sql)?;
+        let records = statement.query_map([], |row| row_to_record(row, 7))?;
+        records
+            .map(|record| record.map(|r| account_from_record(&r).expect("Invalid account row")))
+            .collect()
+    }
+}
+
+impl AccountRepositoryTrait for AccountRepositorySqlite {
+    fn update(&mut self, _key: AccountKey, account: Account) {
+        let sql = format!(
+            "INSERT OR REPLACE INTO accounts ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            COLUMNS
+        );
+        // Failing to persist is a fundamental system failure
+        self.connection
+            .execute(&sql, params_from_iter(account_to_record(&account).iter()))
+            .expect("Failed to write to the accounts table");
+    }
+
+    fn find(&mut self, key: AccountKey) -> Option<&Account> {
+        self.found = self
+            .query_one(key)
+            .expect("Failed to read from the accounts table");
+        self.found.as_ref()
+    }
+
+    fn find_or_create(&mut self, key: AccountKey) -> Option<&Account> {
+        if self.find(key).is_none() {
+            let (client_id, currency) = key;
+            self.update(key, Account::build_default_account(client_id, currency));
+        }
+        self.find(key)
+    }
+
+    fn find_all(&mut self) -> Vec<&Account> {
+        self.found_all = self
+            .query_all()
+            .expect("Failed to read from the accounts table");
+        self.found_all.iter().collect()
+    }
+
+    fn begin(&mut self) {
+        sqlite::begin(&self.connection, "accounts");
+    }
+
+    fn commit(&mut self) {
+        sqlite::commit(&self.connection, "accounts");
+    }
+
+    fn rollback(&mut self) {
+        sqlite::rollback(&self.connection, "accounts");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::currency::Currency;
+    use crate::repositories::log::test_dir;
+    use crate::repositories::sqlite::open_database;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn it_can_insert_and_find_all_sorted() {
+        let path = test_dir("account-sqlite-find").join("payments.db");
+        let mut ar = AccountRepositorySqlite::new(open_database(&path).unwrap());
+
+        let a = Account::new(42, amount("1.23"), amount("0.0"), amount("1.23"), false);
+        let b = Account::new(420, amount("10.23"), amount("0.0"), amount("10.23"), true)
+            .with_credit_limit(amount("50"));
+        let c = Account::new(
+            1,
+            Amount::from_fixed(-11100),
+            amount("1.22"),
+            amount("1.33"),
+            true,
+        );
+
+        ar.update(a.key(), a.clone());
+        ar.update(b.key(), b.clone());
+        ar.update(c.key(), c.clone());
+
+        assert_eq!(ar.find((42, Currency::DEFAULT)).unwrap(), &a);
+        assert_eq!(ar.find((68, Currency::DEFAULT)), None);
+        assert_eq!(ar.find_all(), vec![&c, &a, &b]);
+    }
+
+    #[test]
+    fn it_reloads_accounts_after_reopening() {
+        let path = test_dir("account-sqlite-reload").join("payments.db");
+
+        let mut ar = AccountRepositorySqlite::new(open_database(&path).unwrap());
+        let initial = Account::new(42, amount("42.42"), amount("0.0"), amount("42.42"), false);
+        let update = Account::new(42, amount("20.23"), amount("3.0"), amount("23.23"), false);
+        ar.update((42, Currency::DEFAULT), initial);
+        ar.update((42, Currency::DEFAULT), update.clone());
+        assert_eq!(
+            ar.find_or_create((7, Currency::DEFAULT)).unwrap(),
+            &Account::build_default_account(7, Currency::DEFAULT)
+        );
+        drop(ar);
+
+        let mut ar = AccountRepositorySqlite::new(open_database(&path).unwrap());
+        assert_eq!(ar.find((42, Currency::DEFAULT)).unwrap(), &update);
+        assert_eq!(
+            ar.find((7, Currency::DEFAULT)).unwrap(),
+            &Account::build_default_account(7, Currency::DEFAULT)
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use std::io;
+use std::path::Path;
+
+use super::EventRepositoryTrait;
+use crate::core::event::RecordedEvent;
+use crate::repositories::log::RecordLog;
+use crate::repositories::record::{event_from_record, event_to_record, LAYOUT_VERSION};
+
+/// Event repository persisted to an append-only log file in a state directory.
+///
+/// All events are held in memory, and are reloaded from the log when the repository is opened.
+pub struct EventRepositoryFile {
+    log: RecordLog,
+    store: Vec<RecordedEvent>,
+    /// Number of events stored when the current unit of work began. Events after it are only
+    /// appended to the log when the unit of work is committed.
+    begun_at: Option<usize>,
+}
+
+impl EventRepositoryFile {
+    pub const FILENAME: &'static str = "events.log";
+
+    pub fn open(state_dir: &Path) -> io::Result<EventRepositoryFile> {
+        let (log, records) =
+            RecordLog::open_versioned(&state_dir.join(Self::FILENAME), LAYOUT_VERSION)?;
+
+        let store = records
+            .iter()
+            .map(event_from_record)
+            .collect::<io::Result<Vec<RecordedEvent>>>()?;
+
+        Ok(EventRepositoryFile {
+            log,
+            store,
+            begun_at: None,
+        })
+    }
+
+    fn append_to_log(log: &mut RecordLog, event: &RecordedEvent) {
+        // Failing to persist is a fundamental system failure
+        log.append(&event_to_record(event))
+            .expect("Failed to write to the event log");
+    }
+
+    fn sync(&mut self) {
+        self.log.sync().expect("Failed to sync the event log");
+    }
+}
+
+impl EventRepositoryTrait for EventRepositoryFile {
+    fn append(&mut self, event: RecordedEvent) {
+        if self.begun_at.is_none() {
+            Self::append_to_log(&mut self.log, &event);
+            self.sync();
+        }
+        self.store.push(event);
+    }
+
+    fn find_all(&mut self) -> Vec<&RecordedEvent> {
+        self.store.iter().collect()
+    }
+
+    fn find_by_transaction(&mut self, tx_id: u32) -> Vec<RecordedEvent> {
+        self.store
+            .iter()
+            .filter(|recorded| recorded.event.tx_id() == Some(tx_id))
+            .copied()
+            .collect()
+    }
+
+    fn last(&mut self) -> Option<RecordedEvent> {
+        self.store.last().copied()
+    }
+
+    fn count(&mut self) -> usize {
+        self.store.len()
+    }
+
+    fn begin(&mut self) {
+        assert!(self.begun_at.is_none(), "Unit of work already in progress");
+        self.begun_at = Some(self.store.len());
+    }
+
+    fn commit(&mut self) {
+        if let Some(len) = self.begun_at.take() {
+            for event in &self.store[len..] {
+                Self::append_to_log(&mut self.log, event);
+            }
+            self.sync();
+        }
+    }
+
+    fn rollback(&mut self) {
+        if let Some(len) = self.begun_at.take() {
+            self.store.truncate(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::currency::Currency;
+    use crate::repositories::event::{Event, Position};
+    use crate::repositories::log::test_dir;
+
+    fn recorded(event: Event, line: u64) -> RecordedEvent {
+        RecordedEvent {
+            event,
+            position: Position {
+                line,
+                timestamp: 1_700_000_000 + line,
+            },
+        }
+    }
+
+    #[test]
+    fn it_reloads_committed_events_after_reopening() {
+        let dir = test_dir("event-file-reload");
+        let deposit = recorded(
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: 1,
+                amount: Amount::from_fixed(10000),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            2,
+        );
+        let held = recorded(
+            Event::FundsHeld {
+                client_id: 1,
+                tx_id: 1,
+            },
+            3,
+        );
+
+        let mut er = EventRepositoryFile::open(&dir).unwrap();
+        er.append(deposit);
+        er.begin();
+        er.append(held);
+        er.commit();
+        er.begin();
+        er.append(recorded(
+            Event::AccountLocked {
+                client_id: 1,
+                currency: Currency::DEFAULT,
+            },
+            4,
+        ));
+        er.rollback();
+        drop(er);
+
+        let mut er = EventRepositoryFile::open(&dir).unwrap();
+        assert_eq!(er.find_all(), vec![&deposit, &held]);
+    }
+}
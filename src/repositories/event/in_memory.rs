@@ -0,0 +1,128 @@
+use super::EventRepositoryTrait;
+use crate::core::event::RecordedEvent;
+
+pub struct EventRepositoryInMemory {
+    pub store: Vec<RecordedEvent>,
+    /// Number of events stored when the current unit of work began
+    begun_at: Option<usize>,
+}
+
+impl Default for EventRepositoryInMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventRepositoryInMemory {
+    pub fn new() -> EventRepositoryInMemory {
+        EventRepositoryInMemory {
+            store: Vec::new(),
+            begun_at: None,
+        }
+    }
+}
+
+impl EventRepositoryTrait for EventRepositoryInMemory {
+    fn append(&mut self, event: RecordedEvent) {
+        self.store.push(event);
+    }
+
+    fn find_all(&mut self) -> Vec<&RecordedEvent> {
+        self.store.iter().collect()
+    }
+
+    fn find_by_transaction(&mut self, tx_id: u32) -> Vec<RecordedEvent> {
+        self.store
+            .iter()
+            .filter(|recorded| recorded.event.tx_id() == Some(tx_id))
+            .copied()
+            .collect()
+    }
+
+    fn last(&mut self) -> Option<RecordedEvent> {
+        self.store.last().copied()
+    }
+
+    fn count(&mut self) -> usize {
+        self.store.len()
+    }
+
+    fn begin(&mut self) {
+        assert!(self.begun_at.is_none(), "Unit of work already in progress");
+        self.begun_at = Some(self.store.len());
+    }
+
+    fn commit(&mut self) {
+        self.begun_at = None;
+    }
+
+    fn rollback(&mut self) {
+        if let Some(len) = self.begun_at.take() {
+            self.store.truncate(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::currency::Currency;
+    use crate::core::event::{Event, Position};
+
+    fn locked(client_id: u16) -> RecordedEvent {
+        RecordedEvent {
+            event: Event::AccountLocked {
+                client_id,
+                currency: Currency::DEFAULT,
+            },
+            position: Position::default(),
+        }
+    }
+
+    #[test]
+    fn it_keeps_events_in_order() {
+        let mut er = EventRepositoryInMemory::new();
+
+        er.append(locked(2));
+        er.append(locked(1));
+
+        assert_eq!(er.find_all(), vec![&locked(2), &locked(1),]);
+        assert_eq!(er.last(), Some(locked(1)));
+    }
+
+    #[test]
+    fn it_finds_the_events_of_a_transaction() {
+        let mut er = EventRepositoryInMemory::new();
+        let held = |tx_id| RecordedEvent {
+            event: Event::FundsHeld {
+                client_id: 1,
+                tx_id,
+            },
+            position: Position::default(),
+        };
+        assert_eq!(er.last(), None);
+
+        er.append(held(1));
+        er.append(locked(1));
+        er.append(held(2));
+        er.append(held(1));
+
+        assert_eq!(er.find_by_transaction(1), vec![held(1), held(1)]);
+        assert!(er.find_by_transaction(3).is_empty());
+    }
+
+    #[test]
+    fn it_discards_events_on_rollback() {
+        let mut er = EventRepositoryInMemory::new();
+        er.append(locked(1));
+
+        er.begin();
+        er.append(locked(2));
+        er.rollback();
+        er.begin();
+        er.append(locked(3));
+        er.commit();
+
+        assert_eq!(er.find_all(), vec![&locked(1), &locked(3),]);
+    }
+}
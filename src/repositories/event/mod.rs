@@ -0,0 +1,25 @@
+pub mod file;
+pub mod in_memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+pub use crate::core::event::{Event, Position, RecordedEvent};
+
+/// Append-only store of the events recorded by the payment service, in the order they happened
+pub trait EventRepositoryTrait {
+    fn append(&mut self, event: RecordedEvent);
+    fn find_all(&mut self) -> Vec<&RecordedEvent>;
+    /// Events recorded for a transaction, in the order they happened
+    fn find_by_transaction(&mut self, tx_id: u32) -> Vec<RecordedEvent>;
+    /// The latest event stored
+    fn last(&mut self) -> Option<RecordedEvent>;
+    /// Number of events stored
+    fn count(&mut self) -> usize;
+
+    /// Start a unit of work. Changes made until `commit()` or `rollback()` is called are applied
+    /// or discarded together (see `repositories::unit_of_work::UnitOfWork`)
+    fn begin(&mut self);
+    fn commit(&mut self);
+    /// Discard the changes made since `begin()`
+    fn rollback(&mut self);
+}
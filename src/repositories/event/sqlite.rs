@@ -0,0 +1,157 @@
+use rusqlite::{params_from_iter, Connection, Params};
+use std::rc::Rc;
+
+use super::EventRepositoryTrait;
+use crate::core::event::RecordedEvent;
+use crate::repositories::record::{event_from_record, event_to_record};
+use crate::repositories::sqlite::{self, row_to_record};
+
+const COLUMNS: &str =
+    "kind, client_id, tx_id, amount, line, timestamp, currency, original_amount, \
+     original_currency, rate";
+
+/// Event repository backed by a SQLite database.
+///
+/// Events are numbered in the order they are appended. As the trait hands out references, the
+/// events read by the latest `find_all()` are cached by the repository until the next one.
+pub struct EventRepositorySqlite {
+    connection: Rc<Connection>,
+    found_all: Vec<RecordedEvent>,
+}
+
+impl EventRepositorySqlite {
+    pub fn new(connection: Rc<Connection>) -> EventRepositorySqlite {
+        EventRepositorySqlite {
+            connection,
+            found_all: Vec::new(),
+        }
+    }
+
+    /// Read the events selected by the clauses following `FROM events`
+    fn query(&self, clauses: &str, params: impl Params) -> rusqlite::Result<Vec<RecordedEvent>> {
+        let sql = format!("SELECT {} FROM events {}", COLUMNS, clauses);
+        let mut statement = self.connection.prepare(&sql)?;
+        let records = statement.query_map(params, |row| row_to_record(row, 10))?;
+        records
+            .map(|record| record.map(|r| event_from_record(&r).expect("Invalid event row")))
+            .collect()
+    }
+}
+
+impl EventRepositoryTrait for EventRepositorySqlite {
+    fn append(&mut self, event: RecordedEvent) {
+        // Columns that don't apply to the event are stored as NULL rather than empty strings
+        let record = event_to_record(&event);
+        let values = record
+            .iter()
+            .map(|field| (!field.is_empty()).then_some(field));
+
+        let sql = format!(
+            "INSERT INTO events ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            COLUMNS
+        );
+        // Failing to persist is a fundamental system failure
+        self.connection
+            .execute(&sql, params_from_iter(values))
+            .expect("Failed to write to the events table");
+    }
+
+    fn find_all(&mut self) -> Vec<&RecordedEvent> {
+        self.found_all = self
+            .query("ORDER BY seq", [])
+            .expect("Failed to read from the events table");
+        self.found_all.iter().collect()
+    }
+
+    fn find_by_transaction(&mut self, tx_id: u32) -> Vec<RecordedEvent> {
+        self.query("WHERE tx_id = ?1 ORDER BY seq", [tx_id])
+            .expect("Failed to read from the events table")
+    }
+
+    fn last(&mut self) -> Option<RecordedEvent> {
+        self.query("ORDER BY seq DESC LIMIT 1", [])
+            .expect("Failed to read from the events table")
+            .pop()
+    }
+
+    fn count(&mut self) -> usize {
+        self.connection
+            .query_row("SELECT COUNT(*) FROM events", [], |row| row.get(0))
+            .expect("Failed to read from the events table")
+    }
+
+    fn begin(&mut self) {
+        sqlite::begin(&self.connection, "events");
+    }
+
+    fn commit(&mut self) {
+        sqlite::commit(&self.connection, "events");
+    }
+
+    fn rollback(&mut self) {
+        sqlite::rollback(&self.connection, "events");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::currency::Currency;
+    use crate::repositories::event::{Event, Position};
+    use crate::repositories::log::test_dir;
+
+    fn recorded(event: Event, line: u64) -> RecordedEvent {
+        RecordedEvent {
+            event,
+            position: Position {
+                line,
+                timestamp: 1_700_000_000 + line,
+            },
+        }
+    }
+    use crate::repositories::sqlite::open_database;
+
+    #[test]
+    fn it_reloads_committed_events_after_reopening() {
+        let path = test_dir("event-sqlite-reload").join("payments.db");
+        let deposit = recorded(
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: 1,
+                amount: Amount::from_fixed(10000),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            2,
+        );
+        let locked = recorded(
+            Event::AccountLocked {
+                client_id: 1,
+                currency: Currency::DEFAULT,
+            },
+            3,
+        );
+
+        let mut er = EventRepositorySqlite::new(open_database(&path).unwrap());
+        er.append(deposit);
+        er.begin();
+        er.append(locked);
+        er.commit();
+        er.begin();
+        er.append(recorded(
+            Event::AccountLocked {
+                client_id: 2,
+                currency: Currency::DEFAULT,
+            },
+            4,
+        ));
+        er.rollback();
+        drop(er);
+
+        let mut er = EventRepositorySqlite::new(open_database(&path).unwrap());
+        assert_eq!(er.find_all(), vec![&deposit, &locked]);
+        assert_eq!(er.find_by_transaction(1), vec![deposit]);
+        assert_eq!(er.last(), Some(locked));
+    }
+}
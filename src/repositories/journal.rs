@@ -0,0 +1,613 @@
+use csv::StringRecord;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::core::entities::account::{Account, AccountKey};
+use crate::core::entities::transaction::Transaction;
+use crate::core::event::RecordedEvent;
+use crate::repositories::account::AccountRepositoryTrait;
+use crate::repositories::event::EventRepositoryTrait;
+use crate::repositories::log::RecordLog;
+use crate::repositories::record::{
+    account_from_record, account_to_record, event_from_record, event_to_record,
+    transaction_from_record, transaction_to_record,
+};
+use crate::repositories::transaction::TransactionRepositoryTrait;
+
+const BEGIN: &str = "begin";
+const ACCOUNT: &str = "account";
+const TRANSACTION: &str = "transaction";
+const EVENT: &str = "event";
+const COMMIT: &str = "commit";
+const ABORT: &str = "abort";
+
+/// The account and transaction state written by one payment service operation, along with the
+/// events it recorded. Operations on an account alone, such as setting its credit limit, write no
+/// transaction, operations charging or refunding a fee also write the fee transaction, and
+/// operations on a transfer write the accounts of both its clients.
+#[derive(PartialEq, Clone, Debug)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub accounts: Vec<Account>,
+    pub transactions: Vec<Transaction>,
+    pub events: Vec<RecordedEvent>,
+    /// Number of events in the event store before the operation's events were appended
+    pub events_before: usize,
+}
+
+/// An entry read from the journal that hasn't been committed (yet)
+struct PendingEntry {
+    seq: u64,
+    events_before: usize,
+    accounts: Vec<Account>,
+    transactions: Vec<Transaction>,
+    events: Vec<RecordedEvent>,
+}
+
+impl PendingEntry {
+    fn complete(self) -> Option<JournalEntry> {
+        if self.accounts.is_empty() {
+            return None;
+        }
+        Some(JournalEntry {
+            seq: self.seq,
+            accounts: self.accounts,
+            transactions: self.transactions,
+            events: self.events,
+            events_before: self.events_before,
+        })
+    }
+}
+
+/// Entries read from the journal when it is opened
+#[derive(PartialEq, Debug, Default)]
+pub struct JournalContents {
+    /// Complete entries in the order they were written
+    pub committed: Vec<JournalEntry>,
+    /// Sequence numbers of entries that were begun but never committed
+    pub rolled_back: Vec<u64>,
+}
+
+/// Write-ahead journal of the operations applied by the payment service.
+///
+/// An operation updates accounts and a transaction in separate repository calls, so a process
+/// dying between them would leave the repositories inconsistent with each other. Before touching
+/// the repositories, the state they will hold afterwards and the events recorded by the operation
+/// are appended to the journal as an entry of `begin`, `account` (one per account), `transaction`
+/// (one per transaction), `event` (one per event) and `commit` records.
+///
+/// The repositories are only updated once an entry is committed, so an entry without a `commit`
+/// never reached them and is rolled back by appending an `abort` record when the journal is
+/// opened. The `commit` record is synced to disk, so a committed entry survives the machine going
+/// down as well as the process dying.
+///
+/// Once the repositories have durably stored an entry it is no longer needed, and the journal is
+/// emptied with `checkpoint()`. Operations are applied one at a time, so the journal holds at
+/// most the entry of the operation in progress, which is redone with `redo()` on startup if it
+/// was committed.
+pub struct Journal {
+    log: RecordLog,
+    next_seq: u64,
+}
+
+impl Journal {
+    pub const FILENAME: &'static str = "journal.log";
+
+    /// Open (creating if needed) the journal in `state_dir`, rolling back any incomplete entry
+    pub fn open(state_dir: &Path) -> io::Result<(Journal, JournalContents)> {
+        let (log, records) = RecordLog::open(&state_dir.join(Self::FILENAME))?;
+
+        let mut contents = JournalContents::default();
+        let mut next_seq = 0;
+        let mut pending: Option<PendingEntry> = None;
+        for record in records {
+            let seq = number_field(&record, 1)?;
+            next_seq = next_seq.max(seq + 1);
+
+            match (&record[0], pending.as_mut()) {
+                (BEGIN, None) => {
+                    pending = Some(PendingEntry {
+                        seq,
+                        events_before: number_field(&record, 2)? as usize,
+                        accounts: Vec::new(),
+                        transactions: Vec::new(),
+                        events: Vec::new(),
+                    })
+                }
+                (ACCOUNT, Some(entry)) if entry.seq == seq => {
+                    entry.accounts.push(account_from_record(&image(&record))?)
+                }
+                (TRANSACTION, Some(entry)) if entry.seq == seq => {
+                    let transaction = transaction_from_record(&image(&record))?;
+                    entry.transactions.push(transaction)
+                }
+                (EVENT, Some(entry)) if entry.seq == seq => {
+                    entry.events.push(event_from_record(&image(&record))?)
+                }
+                (COMMIT, Some(entry)) if entry.seq == seq => {
+                    match pending.take().and_then(PendingEntry::complete) {
+                        Some(entry) => contents.committed.push(entry),
+                        None => return Err(invalid_data(&record, "incomplete journal entry")),
+                    }
+                }
+                (ABORT, Some(entry)) if entry.seq == seq => pending = None,
+                _ => return Err(invalid_data(&record, "unexpected journal record")),
+            }
+        }
+
+        let mut journal = Journal { log, next_seq };
+        if let Some(entry) = pending {
+            journal.log.append(&marker(ABORT, entry.seq))?;
+            contents.rolled_back.push(entry.seq);
+        }
+
+        Ok((journal, contents))
+    }
+
+    /// Append a committed entry holding the state of the accounts and transactions after an
+    /// operation, and the events it recorded when `events_before` events were already stored.
+    /// The repositories must only be updated once this has returned.
+    pub fn append(
+        &mut self,
+        accounts: &[Account],
+        transactions: &[Transaction],
+        events: &[RecordedEvent],
+        events_before: usize,
+    ) -> io::Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let mut begin = marker(BEGIN, seq);
+        begin.push_field(&events_before.to_string());
+        self.log.append(&begin)?;
+        for account in accounts {
+            self.log
+                .append(&with_prefix(ACCOUNT, seq, &account_to_record(account)))?;
+        }
+        for transaction in transactions {
+            self.log.append(&with_prefix(
+                TRANSACTION,
+                seq,
+                &transaction_to_record(transaction),
+            ))?;
+        }
+        for event in events {
+            self.log
+                .append(&with_prefix(EVENT, seq, &event_to_record(event)))?;
+        }
+        self.log.append(&marker(COMMIT, seq))?;
+        self.log.sync()
+    }
+
+    /// Drop every entry, once the repositories have durably stored the state they record
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.log.truncate()
+    }
+}
+
+/// Write the state and events held by a committed entry to the repositories, returning whether
+/// any of them was missing it. Redoing an entry that was already applied leaves them unchanged.
+///
+/// The event log may hold any prefix of the entry's events, as they are appended one at a time,
+/// so only the events after those already logged are appended. An event log holding fewer events
+/// than were logged before the entry, or more than after it, can't be reconciled with it.
+pub fn redo(
+    entry: &JournalEntry,
+    tx_store: &mut dyn TransactionRepositoryTrait,
+    ac_store: &mut dyn AccountRepositoryTrait,
+    ev_store: &mut dyn EventRepositoryTrait,
+) -> io::Result<bool> {
+    let logged = ev_store
+        .count()
+        .checked_sub(entry.events_before)
+        .filter(|&logged| logged <= entry.events.len())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "event log has {} events, journal entry {} expects {} to {}",
+                    ev_store.count(),
+                    entry.seq,
+                    entry.events_before,
+                    entry.events_before + entry.events.len()
+                ),
+            )
+        })?;
+    let mut redone = logged < entry.events.len();
+    for event in &entry.events[logged..] {
+        ev_store.append(*event);
+    }
+    for account in &entry.accounts {
+        if ac_store.find(account.key()) != Some(account) {
+            ac_store.update(account.key(), account.clone());
+            redone = true;
+        }
+    }
+    for transaction in &entry.transactions {
+        if tx_store.find(transaction.tx_id) != Some(transaction) {
+            tx_store.update(transaction.tx_id, transaction.clone());
+            redone = true;
+        }
+    }
+    Ok(redone)
+}
+
+/// A difference between the repositories and the state recorded in the journal
+#[derive(PartialEq, Clone, Debug)]
+pub enum Mismatch {
+    Account {
+        key: AccountKey,
+        journal: Account,
+        store: Option<Account>,
+    },
+    Transaction {
+        tx_id: u32,
+        journal: Transaction,
+        store: Option<Transaction>,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Account {
+                key: (client_id, currency),
+                journal,
+                store,
+            } => {
+                write!(f, "account {}", client_id)?;
+                if !currency.is_default() {
+                    write!(f, " {}", currency)?;
+                }
+                write!(f, ": journal has {:?}, store has {:?}", journal, store)
+            }
+            Mismatch::Transaction {
+                tx_id,
+                journal,
+                store,
+            } => write!(
+                f,
+                "transaction {}: journal has {:?}, store has {:?}",
+                tx_id, journal, store
+            ),
+        }
+    }
+}
+
+/// Compare the repositories with the latest state of every account and transaction in the
+/// journal. The journal only holds the entries since its last checkpoint, so records it knows
+/// nothing about aren't checked.
+pub fn verify(
+    entries: &[JournalEntry],
+    tx_store: &mut dyn TransactionRepositoryTrait,
+    ac_store: &mut dyn AccountRepositoryTrait,
+) -> Vec<Mismatch> {
+    let mut accounts = BTreeMap::new();
+    let mut transactions = BTreeMap::new();
+    for entry in entries {
+        for account in &entry.accounts {
+            accounts.insert(account.key(), account);
+        }
+        for transaction in &entry.transactions {
+            transactions.insert(transaction.tx_id, transaction);
+        }
+    }
+
+    let mut mismatches = Vec::new();
+    for (key, account) in accounts {
+        let store = ac_store.find(key);
+        if store != Some(account) {
+            mismatches.push(Mismatch::Account {
+                key,
+                journal: account.clone(),
+                store: store.cloned(),
+            });
+        }
+    }
+    for (tx_id, transaction) in transactions {
+        let store = tx_store.find(tx_id);
+        if store != Some(transaction) {
+            mismatches.push(Mismatch::Transaction {
+                tx_id,
+                journal: transaction.clone(),
+                store: store.cloned(),
+            });
+        }
+    }
+    mismatches
+}
+
+fn marker(kind: &str, seq: u64) -> StringRecord {
+    StringRecord::from(vec![kind.to_string(), seq.to_string()])
+}
+
+fn with_prefix(kind: &str, seq: u64, image: &StringRecord) -> StringRecord {
+    let mut record = marker(kind, seq);
+    record.extend(image.iter());
+    record
+}
+
+/// The entity fields of an `account` or `transaction` record
+fn image(record: &StringRecord) -> StringRecord {
+    record.iter().skip(2).collect()
+}
+
+fn number_field(record: &StringRecord, index: usize) -> io::Result<u64> {
+    match record.get(index).map(|seq| seq.parse()) {
+        Some(Ok(seq)) => Ok(seq),
+        _ => Err(invalid_data(record, "invalid number in journal record")),
+    }
+}
+
+fn invalid_data(record: &StringRecord, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} in record {:?}", message, record),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::currency::Currency;
+    use crate::core::event::{Event, Position};
+    use crate::repositories::account::in_memory::AccountRepositoryInMemory;
+    use crate::repositories::event::in_memory::EventRepositoryInMemory;
+    use crate::repositories::log::test_dir;
+    use crate::repositories::transaction::in_memory::TransactionRepositoryInMemory;
+    use std::fs;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn account(available: &str) -> Account {
+        Account::new(1, amount(available), Amount::ZERO, amount(available), false)
+    }
+
+    fn transaction(tx_id: u32, value: &str) -> Transaction {
+        Transaction::new(tx_id, 0, 1, amount(value), 0)
+    }
+
+    fn deposit(tx_id: u32, value: &str) -> RecordedEvent {
+        RecordedEvent {
+            event: Event::DepositApplied {
+                client_id: 1,
+                tx_id,
+                amount: amount(value),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            position: Position {
+                line: tx_id as u64 + 1,
+                timestamp: 1_700_000_000,
+            },
+        }
+    }
+
+    #[test]
+    fn it_reloads_committed_entries() {
+        let dir = test_dir("journal-reload");
+
+        let (mut journal, contents) = Journal::open(&dir).unwrap();
+        assert_eq!(contents, JournalContents::default());
+        journal
+            .append(
+                &[account("1.0")],
+                &[transaction(1, "1.0")],
+                &[deposit(1, "1.0")],
+                0,
+            )
+            .unwrap();
+        journal
+            .append(
+                &[account("3.0")],
+                &[transaction(2, "2.0"), transaction(3, "0.1")],
+                &[deposit(2, "2.0")],
+                1,
+            )
+            .unwrap();
+        drop(journal);
+
+        let (mut journal, contents) = Journal::open(&dir).unwrap();
+        assert_eq!(
+            contents.committed,
+            vec![
+                JournalEntry {
+                    seq: 0,
+                    accounts: vec![account("1.0")],
+                    transactions: vec![transaction(1, "1.0")],
+                    events: vec![deposit(1, "1.0")],
+                    events_before: 0,
+                },
+                JournalEntry {
+                    seq: 1,
+                    accounts: vec![account("3.0")],
+                    transactions: vec![transaction(2, "2.0"), transaction(3, "0.1")],
+                    events: vec![deposit(2, "2.0")],
+                    events_before: 1,
+                },
+            ]
+        );
+        assert!(contents.rolled_back.is_empty());
+
+        // Sequence numbers carry on from the reloaded entries, which may not write a transaction
+        journal.append(&[account("6.0")], &[], &[], 2).unwrap();
+        drop(journal);
+        let (_, contents) = Journal::open(&dir).unwrap();
+        assert_eq!(contents.committed[2].seq, 2);
+        assert!(contents.committed[2].transactions.is_empty());
+    }
+
+    #[test]
+    fn it_rolls_back_an_incomplete_entry() {
+        let dir = test_dir("journal-rollback");
+        let (mut journal, _) = Journal::open(&dir).unwrap();
+        journal
+            .append(&[account("1.0")], &[transaction(1, "1.0")], &[], 0)
+            .unwrap();
+        drop(journal);
+
+        // The process died after journaling the account of the next operation
+        let path = dir.join(Journal::FILENAME);
+        let mut log = fs::read_to_string(&path).unwrap();
+        log.push_str("begin,1,0\naccount,1,1,30000,0,30000,false,,0\n");
+        fs::write(&path, log).unwrap();
+
+        let (mut journal, contents) = Journal::open(&dir).unwrap();
+        assert_eq!(contents.committed.len(), 1);
+        assert_eq!(contents.rolled_back, vec![1]);
+
+        journal
+            .append(&[account("2.0")], &[transaction(2, "1.0")], &[], 0)
+            .unwrap();
+        drop(journal);
+
+        // The rollback is recorded, so it isn't reported again
+        let (_, contents) = Journal::open(&dir).unwrap();
+        assert_eq!(contents.committed.len(), 2);
+        assert_eq!(contents.committed[1].seq, 2);
+        assert!(contents.rolled_back.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_records_out_of_sequence() {
+        let dir = test_dir("journal-invalid");
+        fs::write(dir.join(Journal::FILENAME), "begin,0,0\ncommit,0\n").unwrap();
+        assert!(Journal::open(&dir).is_err());
+
+        fs::write(
+            dir.join(Journal::FILENAME),
+            "begin,0,0\naccount,1,1,0,0,0,false\n",
+        )
+        .unwrap();
+        assert!(Journal::open(&dir).is_err());
+    }
+
+    #[test]
+    fn it_redoes_an_entry_missing_from_the_stores() {
+        let mut tr = TransactionRepositoryInMemory::new();
+        let mut ar = AccountRepositoryInMemory::new();
+        let mut er = EventRepositoryInMemory::new();
+        let entry = JournalEntry {
+            seq: 0,
+            accounts: vec![account("1.0")],
+            transactions: vec![transaction(1, "1.0")],
+            events: vec![deposit(1, "1.0")],
+            events_before: 0,
+        };
+
+        // Only the account was updated before the process died
+        ar.update((1, Currency::DEFAULT), account("1.0"));
+        assert!(redo(&entry, &mut tr, &mut ar, &mut er).unwrap());
+        assert_eq!(tr.find(1), Some(&transaction(1, "1.0")));
+        assert_eq!(er.find_all(), vec![&deposit(1, "1.0")]);
+
+        // The events are only recorded once
+        assert!(!redo(&entry, &mut tr, &mut ar, &mut er).unwrap());
+        assert_eq!(er.count(), 1);
+    }
+
+    #[test]
+    fn it_redoes_the_events_missing_from_a_partially_logged_entry() {
+        let mut tr = TransactionRepositoryInMemory::new();
+        let mut ar = AccountRepositoryInMemory::new();
+        let mut er = EventRepositoryInMemory::new();
+        er.append(deposit(1, "1.0"));
+        let entry = JournalEntry {
+            seq: 1,
+            accounts: vec![account("3.0")],
+            transactions: vec![transaction(2, "2.0")],
+            events: vec![deposit(2, "2.0"), deposit(3, "3.0")],
+            events_before: 1,
+        };
+
+        // The process died after logging the first event of the entry
+        er.append(deposit(2, "2.0"));
+        assert!(redo(&entry, &mut tr, &mut ar, &mut er).unwrap());
+        assert_eq!(
+            er.find_all(),
+            vec![&deposit(1, "1.0"), &deposit(2, "2.0"), &deposit(3, "3.0")]
+        );
+
+        // An event log longer than the entry accounts for doesn't match it
+        er.append(deposit(4, "4.0"));
+        assert!(redo(&entry, &mut tr, &mut ar, &mut er).is_err());
+
+        // Nor does one missing events from before it
+        let mut er = EventRepositoryInMemory::new();
+        assert!(redo(&entry, &mut tr, &mut ar, &mut er).is_err());
+    }
+
+    #[test]
+    fn it_reports_stores_that_do_not_match_the_journal() {
+        let mut tr = TransactionRepositoryInMemory::new();
+        let mut ar = AccountRepositoryInMemory::new();
+        let entries = vec![
+            JournalEntry {
+                seq: 0,
+                accounts: vec![account("1.0")],
+                transactions: vec![transaction(1, "1.0")],
+                events: vec![],
+                events_before: 0,
+            },
+            JournalEntry {
+                seq: 1,
+                accounts: vec![account("3.0")],
+                transactions: vec![transaction(2, "2.0")],
+                events: vec![],
+                events_before: 0,
+            },
+        ];
+
+        ar.update((1, Currency::DEFAULT), account("3.0"));
+        tr.update(1, transaction(1, "1.0"));
+        tr.update(2, transaction(2, "2.0"));
+        assert!(verify(&entries, &mut tr, &mut ar).is_empty());
+
+        // Records from before the last checkpoint aren't in the journal, so they aren't checked
+        ar.update((1, Currency::DEFAULT), account("1.0"));
+        tr.update(2, transaction(2, "5.0"));
+        tr.update(3, transaction(3, "3.0"));
+        assert_eq!(
+            verify(&entries, &mut tr, &mut ar),
+            vec![
+                Mismatch::Account {
+                    key: (1, Currency::DEFAULT),
+                    journal: account("3.0"),
+                    store: Some(account("1.0")),
+                },
+                Mismatch::Transaction {
+                    tx_id: 2,
+                    journal: transaction(2, "2.0"),
+                    store: Some(transaction(2, "5.0")),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn it_drops_entries_on_checkpoint() {
+        let dir = test_dir("journal-checkpoint");
+        let (mut journal, _) = Journal::open(&dir).unwrap();
+        journal
+            .append(&[account("1.0")], &[transaction(1, "1.0")], &[], 0)
+            .unwrap();
+        journal.checkpoint().unwrap();
+        assert_eq!(fs::read_to_string(dir.join(Journal::FILENAME)).unwrap(), "");
+
+        journal
+            .append(&[account("3.0")], &[transaction(2, "2.0")], &[], 1)
+            .unwrap();
+        drop(journal);
+        let (_, contents) = Journal::open(&dir).unwrap();
+        assert_eq!(contents.committed.len(), 1);
+        assert_eq!(
+            contents.committed[0].transactions,
+            vec![transaction(2, "2.0")]
+        );
+    }
+}
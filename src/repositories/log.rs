@@ -0,0 +1,201 @@
+use csv::{ReaderBuilder, StringRecord, Writer, WriterBuilder};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Append-only file of CSV records, used as the durable storage of the file backed repositories.
+///
+/// Records are only ever appended, so the latest record for a key supersedes any earlier ones.
+/// The repositories keep an in-memory index of the latest records which is rebuilt from the log
+/// when it is opened.
+pub struct RecordLog {
+    writer: Writer<File>,
+}
+
+impl RecordLog {
+    /// Open (creating if needed) the log at `path`, returning it along with the records it holds.
+    ///
+    /// A record that was only partially written (e.g. the process died mid-write) can only be the
+    /// last one in the log. It is discarded and truncated from the file so that new records are
+    /// appended after the last complete one.
+    pub fn open(path: &Path) -> io::Result<(RecordLog, Vec<StringRecord>)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(&file);
+        let mut records = Vec::new();
+        for result in reader.records() {
+            let record =
+                result.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+            records.push(record);
+        }
+
+        // Every complete record is terminated, so an unterminated last record is partial
+        if file_len > 0 && last_byte(&file, file_len)? != b'\n' {
+            let partial = records.pop();
+            file.set_len(
+                partial
+                    .and_then(|r| r.position().map(|p| p.byte()))
+                    .unwrap_or(0),
+            )?;
+        }
+
+        let writer = WriterBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_writer(file);
+
+        Ok((RecordLog { writer }, records))
+    }
+
+    /// Open the log at `path` like `open()`, for records in the layout of the given `version`.
+    ///
+    /// The first record of the log gives the version of the layout of the records after it. It is
+    /// written when the log is created, and a log in any other version is rejected rather than
+    /// misread.
+    pub fn open_versioned(path: &Path, version: u32) -> io::Result<(RecordLog, Vec<StringRecord>)> {
+        let (mut log, mut records) = RecordLog::open(path)?;
+        let header = StringRecord::from(vec!["version".to_string(), version.to_string()]);
+
+        if records.is_empty() {
+            log.append(&header)?;
+            log.sync()?;
+        } else if records.remove(0) != header {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported record layout in {}", path.display()),
+            ));
+        }
+        Ok((log, records))
+    }
+
+    /// Append a record, flushing it to the operating system before returning
+    pub fn append(&mut self, record: &StringRecord) -> io::Result<()> {
+        self.writer.write_record(record)?;
+        self.writer.flush()
+    }
+
+    /// Sync the records appended so far to disk, so that they survive the machine going down
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+
+    /// Remove every record from the log
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().set_len(0)?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
+fn last_byte(file: &File, file_len: u64) -> io::Result<u8> {
+    let mut file = file;
+    let mut byte = [0u8; 1];
+    file.seek(SeekFrom::Start(file_len - 1))?;
+    file.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Create an empty directory for a test to keep state in
+#[cfg(test)]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("payments-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn record(fields: &[&str]) -> StringRecord {
+        StringRecord::from(fields.to_vec())
+    }
+
+    #[test]
+    fn it_reloads_appended_records() {
+        let path = test_dir("log-reload").join("records.log");
+
+        let (mut log, records) = RecordLog::open(&path).unwrap();
+        assert!(records.is_empty());
+        log.append(&record(&["1", "a"])).unwrap();
+        log.append(&record(&["2", "b"])).unwrap();
+        drop(log);
+
+        let (mut log, records) = RecordLog::open(&path).unwrap();
+        assert_eq!(records, vec![record(&["1", "a"]), record(&["2", "b"])]);
+        log.append(&record(&["1", "c"])).unwrap();
+        drop(log);
+
+        let (_, records) = RecordLog::open(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2], record(&["1", "c"]));
+    }
+
+    #[test]
+    fn it_discards_a_partially_written_record() {
+        let path = test_dir("log-partial").join("records.log");
+        fs::write(&path, "1,a\n2,b\n3,c").unwrap();
+
+        let (mut log, records) = RecordLog::open(&path).unwrap();
+        assert_eq!(records, vec![record(&["1", "a"]), record(&["2", "b"])]);
+
+        log.append(&record(&["4", "d"])).unwrap();
+        drop(log);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1,a\n2,b\n4,d\n");
+    }
+
+    #[test]
+    fn it_checks_the_version_of_a_versioned_log() {
+        let path = test_dir("log-version").join("records.log");
+
+        let (mut log, records) = RecordLog::open_versioned(&path, 2).unwrap();
+        assert!(records.is_empty());
+        log.append(&record(&["1", "a"])).unwrap();
+        drop(log);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "version,2\n1,a\n");
+
+        let (_, records) = RecordLog::open_versioned(&path, 2).unwrap();
+        assert_eq!(records, vec![record(&["1", "a"])]);
+        assert!(RecordLog::open_versioned(&path, 3).is_err());
+
+        // A log written without a version
+        fs::write(&path, "1,a\n").unwrap();
+        assert!(RecordLog::open_versioned(&path, 2).is_err());
+    }
+
+    #[test]
+    fn it_appends_after_truncating() {
+        let path = test_dir("log-truncate").join("records.log");
+
+        let (mut log, _) = RecordLog::open(&path).unwrap();
+        log.append(&record(&["1", "a"])).unwrap();
+        log.sync().unwrap();
+        log.truncate().unwrap();
+        log.append(&record(&["2", "b"])).unwrap();
+        drop(log);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2,b\n");
+    }
+
+    #[test]
+    fn it_discards_a_partially_written_first_record() {
+        let path = test_dir("log-partial-first").join("records.log");
+        fs::write(&path, "1,a").unwrap();
+
+        let (_, records) = RecordLog::open(&path).unwrap();
+        assert!(records.is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+}
@@ -1,3 +1,10 @@
 
 pub mod account;
+pub mod event;
+pub mod journal;
+pub mod log;
+pub mod record;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 pub mod transaction;
+pub mod unit_of_work;
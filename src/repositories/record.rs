@@ -0,0 +1,531 @@
+use csv::StringRecord;
+use std::io;
+use std::str::FromStr;
+
+// Conversion of entities to and from flat records for the persistent repositories. Amounts and
+// rates are stored in their fixed point representation so that records round trip exactly.
+// Every kind of record has a fixed number of fields, and records with any other number are
+// rejected. The layout is versioned as a whole by `LAYOUT_VERSION`.
+
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
+use crate::core::entities::account::Account;
+use crate::core::entities::transaction::{Transaction, TransactionState, TransactionType};
+use crate::core::event::{Event, Position, RecordedEvent};
+use crate::core::exchange::{Conversion, Rate};
+
+/// Version of the layout of the records, which must change whenever the fields of any of them do
+pub const LAYOUT_VERSION: u32 = 1;
+pub const ACCOUNT_FIELDS: usize = 7;
+pub const TRANSACTION_FIELDS: usize = 13;
+pub const EVENT_FIELDS: usize = 10;
+
+pub fn account_to_record(account: &Account) -> StringRecord {
+    StringRecord::from(vec![
+        account.client_id.to_string(),
+        account.available.to_fixed().to_string(),
+        account.held.to_fixed().to_string(),
+        account.total.to_fixed().to_string(),
+        account.locked.to_string(),
+        account.currency.to_string(),
+        account.credit_limit.to_fixed().to_string(),
+    ])
+}
+
+pub fn account_from_record(record: &StringRecord) -> io::Result<Account> {
+    expect_fields(record, ACCOUNT_FIELDS)?;
+    Ok(Account::new(
+        field(record, 0)?,
+        amount_field(record, 1)?,
+        amount_field(record, 2)?,
+        amount_field(record, 3)?,
+        field(record, 4)?,
+    )
+    .with_currency(field(record, 5)?)
+    .with_credit_limit(amount_field(record, 6)?))
+}
+
+pub fn transaction_to_record(transaction: &Transaction) -> StringRecord {
+    let mut record = StringRecord::from(vec![
+        transaction.tx_id.to_string(),
+        transaction.tx_type.to_string(),
+        transaction.client_id.to_string(),
+        transaction.amount.to_fixed().to_string(),
+        transaction.state.to_string(),
+        transaction.currency.to_string(),
+    ]);
+    record.extend(conversion_fields(transaction.conversion.as_ref()));
+    record.push_field(&optional_field_value(transaction.fee_for));
+    record.push_field(&transaction.captured.to_fixed().to_string());
+    record.push_field(&optional_field_value(transaction.destination));
+    record.push_field(&transaction.refunded.to_fixed().to_string());
+    record
+}
+
+pub fn transaction_from_record(record: &StringRecord) -> io::Result<Transaction> {
+    expect_fields(record, TRANSACTION_FIELDS)?;
+    let tx_type: u8 = field(record, 1)?;
+    let state: u8 = field(record, 4)?;
+
+    // Transaction::new() panics on invalid encodings, which shouldn't be possible for bad input
+    if tx_type > TransactionType::Fee as u8 {
+        return Err(invalid_data(record, "invalid transaction type"));
+    }
+    if state > TransactionState::Expired as u8 {
+        return Err(invalid_data(record, "invalid transaction state"));
+    }
+
+    Ok(Transaction::new(
+        field(record, 0)?,
+        tx_type,
+        field(record, 2)?,
+        amount_field(record, 3)?,
+        state,
+    )
+    .with_currency(field(record, 5)?)
+    .with_conversion(conversion_field(record, 6)?)
+    .with_fee_for(optional_field(record, 9)?)
+    .with_captured(amount_field(record, 10)?)
+    .with_destination(optional_field(record, 11)?)
+    .with_refunded(amount_field(record, 12)?))
+}
+
+pub fn event_to_record(recorded: &RecordedEvent) -> StringRecord {
+    let mut record = event_fields(&recorded.event);
+    record.push_field(&recorded.position.line.to_string());
+    record.push_field(&recorded.position.timestamp.to_string());
+    record.push_field(&recorded.event.currency().unwrap_or_default().to_string());
+    record.extend(conversion_fields(recorded.event.conversion().as_ref()));
+    record
+}
+
+fn event_fields(event: &Event) -> StringRecord {
+    StringRecord::from(vec![
+        event.kind().to_string(),
+        event.client_id().to_string(),
+        event
+            .tx_id()
+            .map(|tx_id| tx_id.to_string())
+            .unwrap_or_default(),
+        event
+            .amount()
+            .map(|amount| amount.to_fixed().to_string())
+            .unwrap_or_default(),
+    ])
+}
+
+pub fn event_from_record(record: &StringRecord) -> io::Result<RecordedEvent> {
+    expect_fields(record, EVENT_FIELDS)?;
+    let position = Position {
+        line: field(record, 4)?,
+        timestamp: field(record, 5)?,
+    };
+    let client_id = field(record, 1)?;
+    let currency: Currency = field(record, 6)?;
+    let conversion = conversion_field(record, 7)?;
+    let event = match &record[0] {
+        "DepositApplied" => Event::DepositApplied {
+            client_id,
+            tx_id: field(record, 2)?,
+            amount: amount_field(record, 3)?,
+            currency,
+            conversion,
+        },
+        "WithdrawalApplied" => Event::WithdrawalApplied {
+            client_id,
+            tx_id: field(record, 2)?,
+            amount: amount_field(record, 3)?,
+            currency,
+            conversion,
+        },
+        "FundsHeld" => Event::FundsHeld {
+            client_id,
+            tx_id: field(record, 2)?,
+        },
+        "FundsReleased" => Event::FundsReleased {
+            client_id,
+            tx_id: field(record, 2)?,
+        },
+        "ChargedBack" => Event::ChargedBack {
+            client_id,
+            tx_id: field(record, 2)?,
+        },
+        "AccountLocked" => Event::AccountLocked {
+            client_id,
+            currency,
+        },
+        "FeeCharged" => Event::FeeCharged {
+            client_id,
+            tx_id: field(record, 2)?,
+            amount: amount_field(record, 3)?,
+            currency,
+        },
+        "FeeRefunded" => Event::FeeRefunded {
+            client_id,
+            tx_id: field(record, 2)?,
+        },
+        "CreditLimitSet" => Event::CreditLimitSet {
+            client_id,
+            currency,
+            limit: amount_field(record, 3)?,
+        },
+        "Overdrawn" => Event::Overdrawn {
+            client_id,
+            tx_id: field(record, 2)?,
+            amount: amount_field(record, 3)?,
+        },
+        "FundsAuthorized" => Event::FundsAuthorized {
+            client_id,
+            tx_id: field(record, 2)?,
+            amount: amount_field(record, 3)?,
+            currency,
+            conversion,
+        },
+        "FundsCaptured" => Event::FundsCaptured {
+            client_id,
+            tx_id: field(record, 2)?,
+            amount: amount_field(record, 3)?,
+        },
+        "AuthorizationVoided" => Event::AuthorizationVoided {
+            client_id,
+            tx_id: field(record, 2)?,
+        },
+        "AuthorizationExpired" => Event::AuthorizationExpired {
+            client_id,
+            tx_id: field(record, 2)?,
+        },
+        "TransferSent" => Event::TransferSent {
+            client_id,
+            tx_id: field(record, 2)?,
+            amount: amount_field(record, 3)?,
+            currency,
+            conversion,
+        },
+        "TransferReceived" => Event::TransferReceived {
+            client_id,
+            tx_id: field(record, 2)?,
+        },
+        "DepositRefunded" => Event::DepositRefunded {
+            client_id,
+            tx_id: field(record, 2)?,
+            amount: amount_field(record, 3)?,
+        },
+        _ => return Err(invalid_data(record, "invalid event kind")),
+    };
+
+    // Re-encoding catches fields that don't belong to the event
+    if event_fields(&event).iter().ne(record.iter().take(4))
+        || event.currency().unwrap_or_default() != currency
+        || event.conversion() != conversion
+    {
+        return Err(invalid_data(record, "invalid event fields"));
+    }
+    Ok(RecordedEvent { event, position })
+}
+
+fn field<T: FromStr>(record: &StringRecord, index: usize) -> io::Result<T> {
+    record[index]
+        .parse()
+        .map_err(|_| invalid_data(record, "invalid field value"))
+}
+
+/// Read a field which is empty when there is no value
+fn optional_field<T: FromStr>(record: &StringRecord, index: usize) -> io::Result<Option<T>> {
+    match &record[index] {
+        "" => Ok(None),
+        _ => field(record, index).map(Some),
+    }
+}
+
+fn optional_field_value<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn amount_field(record: &StringRecord, index: usize) -> io::Result<Amount> {
+    field(record, index).map(Amount::from_fixed)
+}
+
+fn expect_fields(record: &StringRecord, fields: usize) -> io::Result<()> {
+    if record.len() != fields {
+        return Err(invalid_data(record, "unexpected number of fields"));
+    }
+    Ok(())
+}
+
+/// The fields of a conversion, which are empty for an unconverted amount
+fn conversion_fields(conversion: Option<&Conversion>) -> [String; 3] {
+    match conversion {
+        Some(conversion) => [
+            conversion.amount.to_fixed().to_string(),
+            conversion.currency.to_string(),
+            conversion.rate.to_fixed().to_string(),
+        ],
+        None => Default::default(),
+    }
+}
+
+/// Read the conversion in the three fields starting at `index`, which are either all empty or
+/// all set
+fn conversion_field(record: &StringRecord, index: usize) -> io::Result<Option<Conversion>> {
+    if record.iter().skip(index).take(3).all(str::is_empty) {
+        return Ok(None);
+    }
+    let rate = field::<i128>(record, index + 2)?;
+    let conversion = Conversion {
+        amount: amount_field(record, index)?,
+        currency: field(record, index + 1)?,
+        rate: Rate::from_fixed(rate),
+    };
+    if rate <= 0 || conversion.currency.is_default() {
+        return Err(invalid_data(record, "invalid conversion"));
+    }
+    Ok(Some(conversion))
+}
+
+fn invalid_data(record: &StringRecord, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} in record {:?}", message, record),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_accounts() {
+        let account = Account::new(
+            42,
+            Amount::from_fixed(-12345),
+            Amount::from_fixed(i128::MAX),
+            Amount::from_fixed(0),
+            true,
+        )
+        .with_currency("EUR".parse().unwrap())
+        .with_credit_limit(Amount::from_fixed(500000));
+
+        let record = account_to_record(&account);
+        assert_eq!(record.len(), ACCOUNT_FIELDS);
+        assert_eq!(account_from_record(&record).unwrap(), account);
+
+        // Records in another layout are rejected rather than padded
+        let record: StringRecord = record.iter().take(6).collect();
+        assert!(account_from_record(&record).is_err());
+    }
+
+    #[test]
+    fn it_round_trips_transactions() {
+        let transaction = Transaction::new(
+            u32::MAX,
+            TransactionType::Withdrawal as u8,
+            7,
+            Amount::from_fixed(1),
+            TransactionState::Disputed as u8,
+        )
+        .with_currency("JPY".parse().unwrap());
+
+        let record = transaction_to_record(&transaction);
+        assert_eq!(transaction_from_record(&record).unwrap(), transaction);
+
+        let transaction = transaction.with_conversion(Some(Conversion {
+            amount: Amount::from_fixed(25000),
+            currency: "EUR".parse().unwrap(),
+            rate: Rate::from_fixed(16_250_000_000),
+        }));
+        let record = transaction_to_record(&transaction);
+        assert_eq!(transaction_from_record(&record).unwrap(), transaction);
+
+        let transaction = transaction
+            .with_fee_for(Some(5))
+            .with_captured(Amount::from_fixed(1))
+            .with_destination(Some(9))
+            .with_refunded(Amount::from_fixed(3));
+        let record = transaction_to_record(&transaction);
+        assert_eq!(record.len(), TRANSACTION_FIELDS);
+        assert_eq!(transaction_from_record(&record).unwrap(), transaction);
+
+        let record: StringRecord = record.iter().take(12).collect();
+        assert!(transaction_from_record(&record).is_err());
+    }
+
+    #[test]
+    fn it_round_trips_events() {
+        let events = [
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: u32::MAX,
+                amount: Amount::from_fixed(12345),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::FundsHeld {
+                client_id: 1,
+                tx_id: 2,
+            },
+            Event::AccountLocked {
+                client_id: 7,
+                currency: Currency::DEFAULT,
+            },
+            Event::WithdrawalApplied {
+                client_id: 1,
+                tx_id: 3,
+                amount: Amount::from_fixed(100),
+                currency: "USD".parse().unwrap(),
+                conversion: None,
+            },
+            Event::DepositApplied {
+                client_id: 1,
+                tx_id: 4,
+                amount: Amount::from_fixed(110000),
+                currency: Currency::DEFAULT,
+                conversion: Some(Conversion {
+                    amount: Amount::from_fixed(1000000),
+                    currency: "JPY".parse().unwrap(),
+                    rate: Rate::from_fixed(1_100_000),
+                }),
+            },
+            Event::FeeCharged {
+                client_id: 1,
+                tx_id: u32::MAX - 4,
+                amount: Amount::from_fixed(2500),
+                currency: Currency::DEFAULT,
+            },
+            Event::FeeRefunded {
+                client_id: 1,
+                tx_id: u32::MAX - 4,
+            },
+            Event::CreditLimitSet {
+                client_id: 2,
+                currency: "EUR".parse().unwrap(),
+                limit: Amount::from_fixed(1000000),
+            },
+            Event::Overdrawn {
+                client_id: 2,
+                tx_id: 5,
+                amount: Amount::from_fixed(2500),
+            },
+            Event::FundsAuthorized {
+                client_id: 3,
+                tx_id: 6,
+                amount: Amount::from_fixed(50000),
+                currency: "USD".parse().unwrap(),
+                conversion: None,
+            },
+            Event::FundsCaptured {
+                client_id: 3,
+                tx_id: 6,
+                amount: Amount::from_fixed(20000),
+            },
+            Event::AuthorizationVoided {
+                client_id: 3,
+                tx_id: 6,
+            },
+            Event::AuthorizationExpired {
+                client_id: 3,
+                tx_id: 7,
+            },
+            Event::TransferSent {
+                client_id: 3,
+                tx_id: 8,
+                amount: Amount::from_fixed(10000),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::TransferReceived {
+                client_id: 4,
+                tx_id: 8,
+            },
+            Event::DepositRefunded {
+                client_id: 1,
+                tx_id: 4,
+                amount: Amount::from_fixed(5000),
+            },
+        ];
+
+        for event in events {
+            let recorded = RecordedEvent {
+                event,
+                position: Position {
+                    line: 2,
+                    timestamp: 1_700_000_000,
+                },
+            };
+            assert_eq!(
+                event_from_record(&event_to_record(&recorded)).unwrap(),
+                recorded
+            );
+        }
+        assert_eq!(
+            event_to_record(&RecordedEvent {
+                event: events[2],
+                position: Position::default(),
+            }),
+            StringRecord::from(vec!["AccountLocked", "7", "", "", "0", "0", "", "", "", ""])
+        );
+        assert!(event_from_record(&StringRecord::from(vec![
+            "AccountLocked",
+            "7",
+            "",
+            "",
+            "0",
+            "0",
+            ""
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn it_rejects_invalid_records() {
+        fn record(fields: &[&str]) -> StringRecord {
+            StringRecord::from(fields.to_vec())
+        }
+
+        assert!(account_from_record(&record(&["1", "2", "3"])).is_err());
+        assert!(account_from_record(&record(&["1", "2", "3", "x", "true", "", "0"])).is_err());
+        assert!(account_from_record(&record(&["1", "2", "3", "5", "true", "XYZ", "0"])).is_err());
+
+        let transaction = ["1", "0", "3", "4", "0", "", "", "", "", "", "0", "", "0"];
+        assert!(transaction_from_record(&record(&transaction)).is_ok());
+        for (index, value) in [
+            (1, "9"),
+            (4, "7"),
+            (7, "USD"),
+            (9, "x"),
+            (10, ""),
+            (11, "x"),
+        ] {
+            let mut fields = transaction;
+            fields[index] = value;
+            assert!(transaction_from_record(&record(&fields)).is_err());
+        }
+        let mut fields = transaction;
+        fields[6..9].copy_from_slice(&["100", "", "5"]);
+        assert!(transaction_from_record(&record(&fields)).is_err());
+
+        let event = |kind, tx_id, amount, currency| {
+            record(&[kind, "1", tx_id, amount, "0", "0", currency, "", "", ""])
+        };
+        assert!(event_from_record(&event("FundsHeld", "2", "", "")).is_ok());
+        assert!(event_from_record(&event("Deposited", "2", "3", "")).is_err());
+        assert!(event_from_record(&event("FundsHeld", "2", "3", "")).is_err());
+        assert!(event_from_record(&event("FundsHeld", "2", "", "USD")).is_err());
+        assert!(event_from_record(&event("FeeCharged", "2", "", "")).is_err());
+        assert!(event_from_record(&event("FeeRefunded", "2", "3", "")).is_err());
+        assert!(event_from_record(&event("DepositApplied", "2", "", "")).is_err());
+        assert!(event_from_record(&event("AccountLocked", "2", "", "")).is_err());
+        assert!(event_from_record(&record(&[
+            "AccountLocked",
+            "7",
+            "",
+            "",
+            "0",
+            "0",
+            "",
+            "100",
+            "USD",
+            "5"
+        ]))
+        .is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use csv::StringRecord;
+use rusqlite::types::ValueRef;
+use rusqlite::{ffi, Connection, Row};
+use std::path::Path;
+use std::rc::Rc;
+
+pub const DATABASE_FILENAME: &str = "payments.db";
+
+/// Version of the schema, kept in the database's `user_version`. It must change whenever the
+/// schema does, and databases of any other version are rejected rather than misread.
+const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE accounts (
+        client_id INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        locked TEXT NOT NULL,
+        credit_limit TEXT NOT NULL,
+        PRIMARY KEY (client_id, currency)
+    );
+    CREATE TABLE transactions (
+        tx_id INTEGER PRIMARY KEY,
+        tx_type INTEGER NOT NULL,
+        client_id INTEGER NOT NULL,
+        amount TEXT NOT NULL,
+        state INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        original_amount TEXT NOT NULL,
+        original_currency TEXT NOT NULL,
+        rate TEXT NOT NULL,
+        fee_for TEXT NOT NULL,
+        captured TEXT NOT NULL,
+        destination TEXT NOT NULL,
+        refunded TEXT NOT NULL
+    );
+    CREATE TABLE events (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        kind TEXT NOT NULL,
+        client_id INTEGER NOT NULL,
+        tx_id INTEGER,
+        amount TEXT,
+        line INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        currency TEXT,
+        original_amount TEXT,
+        original_currency TEXT,
+        rate TEXT
+    );
+    CREATE INDEX events_by_transaction ON events (tx_id);
+";
+
+/// Open (creating if needed) the SQLite database at `path`, creating its schema if it is new.
+///
+/// The connection is shared by the account and transaction repositories so that both operate on
+/// the same database.
+pub fn open_database(path: &Path) -> rusqlite::Result<Rc<Connection>> {
+    let mut connection = Connection::open(path)?;
+    create_schema(&mut connection)?;
+    Ok(Rc::new(connection))
+}
+
+fn create_schema(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    match version {
+        0 => {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(SCHEMA)?;
+            transaction.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+            transaction.commit()
+        }
+        SCHEMA_VERSION => Ok(()),
+        _ => Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_MISMATCH),
+            Some(format!("unsupported schema version {}", version)),
+        )),
+    }
+}
+
+/// Start, commit or roll back a unit of work on the repositories sharing a connection.
+///
+/// Each repository uses a savepoint named after it, so the savepoints of repositories taking part
+/// in the same unit of work nest, and the changes are only made durable when the outermost one is
+/// released.
+pub fn begin(connection: &Connection, savepoint: &str) {
+    connection
+        .execute_batch(&format!("SAVEPOINT {}", savepoint))
+        .expect("Failed to begin a unit of work");
+}
+
+pub fn commit(connection: &Connection, savepoint: &str) {
+    connection
+        .execute_batch(&format!("RELEASE {}", savepoint))
+        .expect("Failed to commit a unit of work");
+}
+
+pub fn rollback(connection: &Connection, savepoint: &str) {
+    connection
+        .execute_batch(&format!("ROLLBACK TO {0}; RELEASE {0}", savepoint))
+        .expect("Failed to roll back a unit of work");
+}
+
+/// Read the columns of a row as a record, so that rows can be decoded with the same validation as
+/// the other persistent repositories
+pub fn row_to_record(row: &Row, columns: usize) -> rusqlite::Result<StringRecord> {
+    let mut record = StringRecord::new();
+    for index in 0..columns {
+        match row.get_ref(index)? {
+            ValueRef::Integer(value) => record.push_field(&value.to_string()),
+            ValueRef::Text(value) => record.push_field(&String::from_utf8_lossy(value)),
+            ValueRef::Null => record.push_field(""),
+            _ => {
+                return Err(rusqlite::Error::InvalidColumnType(
+                    index,
+                    "".into(),
+                    row.get_ref(index)?.data_type(),
+                ))
+            }
+        }
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::log::test_dir;
+
+    #[test]
+    fn it_creates_the_schema_of_a_new_database_once() {
+        let path = test_dir("sqlite-schema").join("payments.db");
+
+        let connection = open_database(&path).unwrap();
+        let version: u32 = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        drop(connection);
+
+        // Reopening doesn't try to recreate the tables
+        assert!(open_database(&path).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_a_database_of_another_version() {
+        let path = test_dir("sqlite-version").join("payments.db");
+
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        drop(connection);
+
+        assert!(open_database(&path).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use super::TransactionRepositoryTrait;
+use crate::core::entities::transaction::Transaction;
+use crate::repositories::log::RecordLog;
+use crate::repositories::record::{transaction_from_record, transaction_to_record, LAYOUT_VERSION};
+use crate::repositories::unit_of_work::UndoLog;
+
+/// Transaction repository persisted to an append-only log file in a state directory.
+///
+/// The latest version of every transaction is indexed in memory, and the index is rebuilt from
+/// the log when the repository is opened so that state survives process restarts.
+pub struct TransactionRepositoryFile {
+    log: RecordLog,
+    store: BTreeMap<u32, Transaction>,
+    undo: UndoLog<u32, Transaction>,
+    /// Records written during a unit of work, appended to the log when it is committed
+    pending: Vec<Transaction>,
+}
+
+impl TransactionRepositoryFile {
+    pub const FILENAME: &'static str = "transactions.log";
+
+    pub fn open(state_dir: &Path) -> io::Result<TransactionRepositoryFile> {
+        let (log, records) =
+            RecordLog::open_versioned(&state_dir.join(Self::FILENAME), LAYOUT_VERSION)?;
+
+        let mut store = BTreeMap::new();
+        for record in records {
+            let transaction = transaction_from_record(&record)?;
+            store.insert(transaction.tx_id, transaction);
+        }
+
+        Ok(TransactionRepositoryFile {
+            log,
+            store,
+            undo: UndoLog::default(),
+            pending: Vec::new(),
+        })
+    }
+
+    fn append(&mut self, transaction: &Transaction) {
+        // Failing to persist is a fundamental system failure
+        self.log
+            .append(&transaction_to_record(transaction))
+            .expect("Failed to write to the transaction log");
+    }
+
+    /// Committed transactions must be on disk before their journal entry is dropped
+    fn sync(&mut self) {
+        self.log.sync().expect("Failed to sync the transaction log");
+    }
+}
+
+impl TransactionRepositoryTrait for TransactionRepositoryFile {
+    fn insert(&mut self, transaction: Transaction) -> bool {
+        if self.store.contains_key(&transaction.tx_id) {
+            return false;
+        }
+        self.update(transaction.tx_id, transaction);
+        true
+    }
+
+    fn update(&mut self, tx_id: u32, transaction: Transaction) {
+        if self.undo.is_active() {
+            self.pending.push(transaction.clone());
+        } else {
+            self.append(&transaction);
+            self.sync();
+        }
+        let previous = self.store.insert(tx_id, transaction);
+        self.undo.record(tx_id, previous);
+    }
+
+    fn find(&mut self, tx_id: u32) -> Option<&Transaction> {
+        self.store.get(&tx_id)
+    }
+
+    fn find_all(&mut self) -> Vec<&Transaction> {
+        self.store.values().collect()
+    }
+
+    fn begin(&mut self) {
+        self.undo.begin();
+    }
+
+    fn commit(&mut self) {
+        for transaction in std::mem::take(&mut self.pending) {
+            self.append(&transaction);
+        }
+        self.sync();
+        self.undo.commit();
+    }
+
+    fn rollback(&mut self) {
+        self.pending.clear();
+        self.undo.rollback(&mut self.store);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::repositories::log::test_dir;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn it_reloads_transactions_after_reopening() {
+        let dir = test_dir("transaction-file-reload");
+
+        let a = Transaction::new(1600002, 1, 42, amount("1.23"), 0);
+        let b = Transaction::new(16, 0, 21, amount("2.23"), 0);
+        let b_disputed = Transaction::new(16, 0, 21, amount("2.23"), 1);
+
+        let mut tr = TransactionRepositoryFile::open(&dir).unwrap();
+        assert!(tr.insert(a.clone()));
+        assert!(tr.insert(b.clone()));
+        tr.update(b_disputed.tx_id, b_disputed.clone());
+        drop(tr);
+
+        let mut tr = TransactionRepositoryFile::open(&dir).unwrap();
+        assert_eq!(tr.find(a.tx_id).unwrap(), &a);
+        assert_eq!(tr.find(b.tx_id).unwrap(), &b_disputed);
+        assert_eq!(tr.find(68), None);
+        assert_eq!(tr.find_all(), vec![&b_disputed, &a]);
+    }
+
+    #[test]
+    fn it_does_not_overwrite_on_insert_after_reopening() {
+        let dir = test_dir("transaction-file-insert");
+
+        let a = Transaction::new(16, 0, 42, amount("1.23"), 0);
+        let mut tr = TransactionRepositoryFile::open(&dir).unwrap();
+        assert!(tr.insert(a.clone()));
+        drop(tr);
+
+        let mut tr = TransactionRepositoryFile::open(&dir).unwrap();
+        assert!(!tr.insert(Transaction::new(16, 0, 21, amount("9.0"), 0)));
+        assert_eq!(tr.find(16).unwrap(), &a);
+    }
+}
@@ -1,16 +1,26 @@
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 
 use super::TransactionRepositoryTrait;
 use crate::core::entities::transaction::Transaction;
+use crate::repositories::unit_of_work::UndoLog;
 
 pub struct TransactionRepositoryInMemory {
     pub store: Box<BTreeMap<u32, Transaction>>,
+    undo: UndoLog<u32, Transaction>,
+}
+
+impl Default for TransactionRepositoryInMemory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TransactionRepositoryInMemory {
     pub fn new() -> TransactionRepositoryInMemory {
         TransactionRepositoryInMemory {
             store: Box::new(BTreeMap::new()),
+            undo: UndoLog::default(),
         }
     }
 
@@ -22,8 +32,20 @@ impl TransactionRepositoryInMemory {
 }
 
 impl TransactionRepositoryTrait for TransactionRepositoryInMemory {
+    fn insert(&mut self, transaction: Transaction) -> bool {
+        match self.store.entry(transaction.tx_id) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                self.undo.record(transaction.tx_id, None);
+                entry.insert(transaction);
+                true
+            }
+        }
+    }
+
     fn update(&mut self, tx_id: u32, transaction: Transaction) {
-        self.store.insert(tx_id, transaction);
+        let previous = self.store.insert(tx_id, transaction);
+        self.undo.record(tx_id, previous);
     }
 
     fn find(&mut self, tx_id: u32) -> Option<&Transaction> {
@@ -37,18 +59,35 @@ impl TransactionRepositoryTrait for TransactionRepositoryInMemory {
         }
         elements
     }
+
+    fn begin(&mut self) {
+        self.undo.begin();
+    }
+
+    fn commit(&mut self) {
+        self.undo.commit();
+    }
+
+    fn rollback(&mut self) {
+        self.undo.rollback(&mut self.store);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::amount::Amount;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
 
     #[test]
     fn it_can_insert_and_find() {
         let mut tr = TransactionRepositoryInMemory::new();
 
-        let a = Transaction::new(1600002, 1, 42, 1.23, 0);
-        let b = Transaction::new(1600004, 1, 21, 2.23, 1);
+        let a = Transaction::new(1600002, 1, 42, amount("1.23"), 0);
+        let b = Transaction::new(1600004, 1, 21, amount("2.23"), 1);
 
         tr.update(a.tx_id, a.clone());
         tr.update(b.tx_id, b.clone());
@@ -74,9 +113,9 @@ mod tests {
     fn it_can_insert_and_find_all_sorted() {
         let mut tr = TransactionRepositoryInMemory::new();
 
-        let a = Transaction::new(1600042, 1, 42, 1.23, 0);
-        let b = Transaction::new(1600056, 1, 21, 2.23, 1);
-        let c = Transaction::new(16, 0, 1, 700.11, 2);
+        let a = Transaction::new(1600042, 1, 42, amount("1.23"), 0);
+        let b = Transaction::new(1600056, 1, 21, amount("2.23"), 1);
+        let c = Transaction::new(16, 0, 1, amount("700.11"), 2);
 
         tr.update(a.tx_id, a.clone());
         tr.update(b.tx_id, b.clone());
@@ -90,4 +129,18 @@ mod tests {
         assert_eq!(res[1], &a);
         assert_eq!(res[2], &b);
     }
+
+    #[test]
+    fn it_does_not_overwrite_on_insert() {
+        let mut tr = TransactionRepositoryInMemory::new();
+
+        let a = Transaction::new(16, 0, 42, amount("1.23"), 1);
+        let b = Transaction::new(16, 0, 21, amount("2.23"), 0);
+
+        assert!(tr.insert(a.clone()));
+        assert!(!tr.insert(b));
+
+        assert_eq!(tr.find(16).unwrap(), &a);
+        assert_eq!(tr.find_all().len(), 1);
+    }
 }
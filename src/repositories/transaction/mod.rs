@@ -1,5 +1,8 @@
 
+pub mod file;
 pub mod in_memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 // Use the Transaction structure and related from core in repository layer as it is identical
 pub use crate::core::entities::transaction::{
@@ -14,6 +17,9 @@ impl Transaction {
         match type_enum {
             TransactionType::Deposit => 0,
             TransactionType::Withdrawal => 1,
+            TransactionType::Authorization => 2,
+            TransactionType::Transfer => 3,
+            TransactionType::Fee => 4,
         }
     }
 
@@ -21,6 +27,9 @@ impl Transaction {
         match value {
             0 => TransactionType::Deposit,
             1 => TransactionType::Withdrawal,
+            2 => TransactionType::Authorization,
+            3 => TransactionType::Transfer,
+            4 => TransactionType::Fee,
             _other => panic!("Unexpected transaction type encoding")
         }
     }
@@ -30,6 +39,10 @@ impl Transaction {
             TransactionState::Normal => 0,
             TransactionState::Disputed => 1,
             TransactionState::Reversed => 2,
+            TransactionState::Authorized => 3,
+            TransactionState::Captured => 4,
+            TransactionState::Voided => 5,
+            TransactionState::Expired => 6,
         }
     }
 
@@ -38,14 +51,28 @@ impl Transaction {
             0 => TransactionState::Normal,
             1 => TransactionState::Disputed,
             2 => TransactionState::Reversed,
-            3_u8..=u8::MAX => panic!("Unexpected transaction state encoding")
+            3 => TransactionState::Authorized,
+            4 => TransactionState::Captured,
+            5 => TransactionState::Voided,
+            6 => TransactionState::Expired,
+            7_u8..=u8::MAX => panic!("Unexpected transaction state encoding")
         }
     }
 }
 
 
 pub trait TransactionRepositoryTrait {
+    /// Store a new transaction, returning false (and leaving the store unchanged) if a transaction
+    /// with the same id already exists
+    fn insert(&mut self, transaction: Transaction) -> bool;
     fn update(&mut self, tx_id: u32, transaction: Transaction);
     fn find(&mut self, tx_id: u32) -> Option<&Transaction>;
     fn find_all(&mut self) -> Vec<&Transaction>;
+
+    /// Start a unit of work. Changes made until `commit()` or `rollback()` is called are applied
+    /// or discarded together (see `repositories::unit_of_work::UnitOfWork`)
+    fn begin(&mut self);
+    fn commit(&mut self);
+    /// Discard the changes made since `begin()`
+    fn rollback(&mut self);
 }
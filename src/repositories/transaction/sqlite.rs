@@ -0,0 +1,195 @@
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
+use std::rc::Rc;
+
+use super::TransactionRepositoryTrait;
+use crate::core::entities::transaction::Transaction;
+use crate::repositories::record::{transaction_from_record, transaction_to_record};
+use crate::repositories::sqlite::{self, row_to_record};
+
+const COLUMNS: &str =
+    "tx_id, tx_type, client_id, amount, state, currency, original_amount, original_currency, \
+     rate, fee_for, captured, destination, refunded";
+
+/// Transaction repository backed by a SQLite database.
+///
+/// Transactions are read from the database on every lookup. As the trait hands out references,
+/// the results of the latest lookup are cached by the repository until the next one.
+pub struct TransactionRepositorySqlite {
+    connection: Rc<Connection>,
+    found: Option<Transaction>,
+    found_all: Vec<Transaction>,
+}
+
+impl TransactionRepositorySqlite {
+    pub fn new(connection: Rc<Connection>) -> TransactionRepositorySqlite {
+        TransactionRepositorySqlite {
+            connection,
+            found: None,
+            found_all: Vec::new(),
+        }
+    }
+
+    fn write(&self, verb: &str, transaction: &Transaction) -> usize {
+        let sql = format!(
+            "{} INTO transactions ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            verb, COLUMNS
+        );
+        // Failing to persist is a fundamental system failure
+        self.connection
+            .execute(
+                &sql,
+                params_from_iter(transaction_to_record(transaction).iter()),
+            )
+            .expect("Failed to write to the transactions table")
+    }
+
+    fn query_one(&self, tx_id: u32) -> rusqlite::Result<Option<Transaction>> {
+        let sql = format!("SELECT {} FROM transactions WHERE tx_id = ?1", COLUMNS);
+        self.connection
+            .query_row(&sql, [tx_id], |row| row_to_record(row, 13))
+            .optional()
+            .map(|record| {
+                record.map(|r| transaction_from_record(&r).expect("Invalid transaction row"))
+            })
+    }
+
+    fn query_all(&self) -> rusqlite::Result<Vec<Transaction>> {
+        let sql = format!("SELECT {} FROM transactions ORDER BY tx_id", COLUMNS);
+        let mut statement = self.connection.prepare(&sql)?;
+        let records = statement.query_map([], |row| row_to_record(row, 13))?;
+        records
+            .map(|record| {
+                record.map(|r| transaction_from_record(&r).expect("Invalid transaction row"))
+            })
+            .collect()
+    }
+}
+
+impl TransactionRepositoryTrait for TransactionRepositorySqlite {
+    fn insert(&mut self, transaction: Transaction) -> bool {
+        self.write("INSERT OR IGNORE", &transaction) == 1
+    }
+
+    fn update(&mut self, _tx_id: u32, transaction: Transaction) {
+        self.write("INSERT OR REPLACE", &transaction);
+    }
+
+    fn find(&mut self, tx_id: u32) -> Option<&Transaction> {
+        self.found = self
+            .query_one(tx_id)
+            .expect("Failed to read from the transactions table");
+        self.found.as_ref()
+    }
+
+    fn find_all(&mut self) -> Vec<&Transaction> {
+        self.found_all = self
+            .query_all()
+            .expect("Failed to read from the transactions table");
+        self.found_all.iter().collect()
+    }
+
+    fn begin(&mut self) {
+        sqlite::begin(&self.connection, "transactions");
+    }
+
+    fn commit(&mut self) {
+        sqlite::commit(&self.connection, "transactions");
+    }
+
+    fn rollback(&mut self) {
+        sqlite::rollback(&self.connection, "transactions");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::currency::Currency;
+    use crate::core::exchange::Conversion;
+    use crate::repositories::account::sqlite::AccountRepositorySqlite;
+    use crate::repositories::account::{Account, AccountRepositoryTrait};
+    use crate::repositories::log::test_dir;
+    use crate::repositories::sqlite::open_database;
+    use crate::repositories::unit_of_work::UnitOfWork;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn it_can_insert_and_find_all_sorted() {
+        let path = test_dir("transaction-sqlite-find").join("payments.db");
+        let mut tr = TransactionRepositorySqlite::new(open_database(&path).unwrap());
+
+        let a = Transaction::new(1600042, 2, 42, amount("1.23"), 3).with_captured(amount("0.23"));
+        let b = Transaction::new(u32::MAX, 3, 21, amount("2.23"), 1).with_destination(Some(7));
+        let c = Transaction::new(16, 0, 1, amount("700.11"), 2)
+            .with_conversion(Some(Conversion {
+                amount: amount("104500"),
+                currency: "JPY".parse().unwrap(),
+                rate: "0.0067".parse().unwrap(),
+            }))
+            .with_refunded(amount("100.11"));
+
+        assert!(tr.insert(a.clone()));
+        assert!(tr.insert(b.clone()));
+        assert!(tr.insert(c.clone()));
+
+        assert_eq!(tr.find(16).unwrap(), &c);
+        assert_eq!(tr.find(68), None);
+        assert_eq!(tr.find_all(), vec![&c, &a, &b]);
+    }
+
+    #[test]
+    fn it_does_not_overwrite_on_insert() {
+        let path = test_dir("transaction-sqlite-insert").join("payments.db");
+        let mut tr = TransactionRepositorySqlite::new(open_database(&path).unwrap());
+
+        let a = Transaction::new(16, 0, 42, amount("1.23"), 0);
+        let a_disputed = Transaction::new(16, 0, 42, amount("1.23"), 1);
+        assert!(tr.insert(a.clone()));
+        assert!(!tr.insert(Transaction::new(16, 0, 21, amount("9.0"), 0)));
+        assert_eq!(tr.find(16).unwrap(), &a);
+
+        tr.update(16, a_disputed.clone());
+        drop(tr);
+
+        let mut tr = TransactionRepositorySqlite::new(open_database(&path).unwrap());
+        assert_eq!(tr.find(16).unwrap(), &a_disputed);
+    }
+
+    #[test]
+    fn it_commits_or_rolls_back_with_the_account_repository() {
+        let path = test_dir("transaction-sqlite-unit-of-work").join("payments.db");
+        let connection = open_database(&path).unwrap();
+        let mut tr = TransactionRepositorySqlite::new(connection.clone());
+        let mut ar = AccountRepositorySqlite::new(connection);
+
+        let account = Account::new(42, amount("1.0"), amount("0.0"), amount("1.0"), false);
+        let a = Transaction::new(16, 0, 42, amount("1.0"), 0);
+        let b = Transaction::new(17, 0, 42, amount("2.0"), 0);
+
+        let mut uow = UnitOfWork::begin(&mut tr, &mut ar);
+        uow.accounts()
+            .update((42, Currency::DEFAULT), account.clone());
+        uow.transactions().update(16, a.clone());
+        uow.commit();
+
+        let mut uow = UnitOfWork::begin(&mut tr, &mut ar);
+        uow.accounts().update(
+            (42, Currency::DEFAULT),
+            Account::build_default_account(42, Currency::DEFAULT),
+        );
+        uow.transactions().update(17, b);
+        uow.rollback();
+        drop(tr);
+        drop(ar);
+
+        let connection = open_database(&path).unwrap();
+        let mut tr = TransactionRepositorySqlite::new(connection.clone());
+        let mut ar = AccountRepositorySqlite::new(connection);
+        assert_eq!(tr.find_all(), vec![&a]);
+        assert_eq!(ar.find_all(), vec![&account]);
+    }
+}
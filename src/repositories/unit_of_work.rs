@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+
+use crate::repositories::account::AccountRepositoryTrait;
+use crate::repositories::event::EventRepositoryTrait;
+use crate::repositories::transaction::TransactionRepositoryTrait;
+
+/// A set of changes to the account and transaction repositories that is applied atomically.
+///
+/// Changes made through the unit of work are committed together with `commit()`. If the unit of
+/// work is dropped without being committed (including while unwinding from a panic part way
+/// through the changes) they are rolled back, so that no partial effects are left behind.
+pub struct UnitOfWork<'a> {
+    tx_store: &'a mut dyn TransactionRepositoryTrait,
+    ac_store: &'a mut dyn AccountRepositoryTrait,
+    ev_store: Option<&'a mut dyn EventRepositoryTrait>,
+    finished: bool,
+}
+
+impl<'a> UnitOfWork<'a> {
+    pub fn begin(
+        tx_store: &'a mut dyn TransactionRepositoryTrait,
+        ac_store: &'a mut dyn AccountRepositoryTrait,
+    ) -> UnitOfWork<'a> {
+        // Repositories sharing a database nest their transactions, so they are begun in one order
+        // and finished in the reverse
+        ac_store.begin();
+        tx_store.begin();
+        UnitOfWork {
+            tx_store,
+            ac_store,
+            ev_store: None,
+            finished: false,
+        }
+    }
+
+    /// Include an event store in the unit of work, so that events are recorded together with the
+    /// changes they describe
+    pub fn with_events(mut self, ev_store: &'a mut dyn EventRepositoryTrait) -> UnitOfWork<'a> {
+        ev_store.begin();
+        self.ev_store = Some(ev_store);
+        self
+    }
+
+    pub fn accounts(&mut self) -> &mut dyn AccountRepositoryTrait {
+        self.ac_store
+    }
+
+    pub fn transactions(&mut self) -> &mut dyn TransactionRepositoryTrait {
+        self.tx_store
+    }
+
+    pub fn events(&mut self) -> &mut dyn EventRepositoryTrait {
+        self.ev_store
+            .as_deref_mut()
+            .expect("Unit of work has no event store")
+    }
+
+    pub fn commit(mut self) {
+        if let Some(ev_store) = &mut self.ev_store {
+            ev_store.commit();
+        }
+        self.tx_store.commit();
+        self.ac_store.commit();
+        self.finished = true;
+    }
+
+    pub fn rollback(mut self) {
+        self.abort();
+    }
+
+    fn abort(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            if let Some(ev_store) = &mut self.ev_store {
+                ev_store.rollback();
+            }
+            self.tx_store.rollback();
+            self.ac_store.rollback();
+        }
+    }
+}
+
+impl Drop for UnitOfWork<'_> {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+/// Previous values of the entries changed in a map during a unit of work, used by the map backed
+/// repositories to roll back their changes.
+pub struct UndoLog<K, V> {
+    changes: Option<Vec<(K, Option<V>)>>,
+}
+
+impl<K, V> Default for UndoLog<K, V> {
+    fn default() -> Self {
+        UndoLog { changes: None }
+    }
+}
+
+impl<K: Ord, V> UndoLog<K, V> {
+    pub fn is_active(&self) -> bool {
+        self.changes.is_some()
+    }
+
+    pub fn begin(&mut self) {
+        assert!(!self.is_active(), "Unit of work already in progress");
+        self.changes = Some(Vec::new());
+    }
+
+    /// Record the value held by `key` before it is changed. Changes outside a unit of work are
+    /// not recorded.
+    pub fn record(&mut self, key: K, previous: Option<V>) {
+        if let Some(changes) = &mut self.changes {
+            changes.push((key, previous));
+        }
+    }
+
+    pub fn commit(&mut self) {
+        self.changes = None;
+    }
+
+    /// Restore the values recorded since the unit of work began
+    pub fn rollback(&mut self, store: &mut BTreeMap<K, V>) {
+        for (key, previous) in self.changes.take().unwrap_or_default().into_iter().rev() {
+            match previous {
+                Some(value) => store.insert(key, value),
+                None => store.remove(&key),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::currency::Currency;
+    use crate::core::entities::account::Account;
+    use crate::core::entities::transaction::Transaction;
+    use crate::repositories::account::in_memory::AccountRepositoryInMemory;
+    use crate::repositories::transaction::in_memory::TransactionRepositoryInMemory;
+    use std::panic::{self, AssertUnwindSafe};
+
+    fn account(available: i128) -> Account {
+        let available = Amount::from_fixed(available);
+        Account::new(1, available, Amount::ZERO, available, false)
+    }
+
+    fn transaction(tx_id: u32) -> Transaction {
+        Transaction::new(tx_id, 0, 1, Amount::from_fixed(10000), 0)
+    }
+
+    /// Transaction repository that fails on every write
+    struct FailingTransactionRepository(TransactionRepositoryInMemory);
+
+    impl TransactionRepositoryTrait for FailingTransactionRepository {
+        fn insert(&mut self, _transaction: Transaction) -> bool {
+            panic!("Failed to write to the transaction store")
+        }
+        fn update(&mut self, _tx_id: u32, _transaction: Transaction) {
+            panic!("Failed to write to the transaction store")
+        }
+        fn find(&mut self, tx_id: u32) -> Option<&Transaction> {
+            self.0.find(tx_id)
+        }
+        fn find_all(&mut self) -> Vec<&Transaction> {
+            self.0.find_all()
+        }
+        fn begin(&mut self) {
+            self.0.begin()
+        }
+        fn commit(&mut self) {
+            self.0.commit()
+        }
+        fn rollback(&mut self) {
+            self.0.rollback()
+        }
+    }
+
+    #[test]
+    fn it_commits_changes_to_both_repositories() {
+        let mut tr = TransactionRepositoryInMemory::new();
+        let mut ar = AccountRepositoryInMemory::new();
+
+        let mut uow = UnitOfWork::begin(&mut tr, &mut ar);
+        uow.accounts()
+            .update((1, Currency::DEFAULT), account(10000));
+        uow.transactions().update(1, transaction(1));
+        uow.commit();
+
+        assert_eq!(ar.find((1, Currency::DEFAULT)), Some(&account(10000)));
+        assert_eq!(tr.find(1), Some(&transaction(1)));
+    }
+
+    #[test]
+    fn it_rolls_back_changes_to_both_repositories() {
+        let mut tr = TransactionRepositoryInMemory::new();
+        let mut ar = AccountRepositoryInMemory::new();
+        ar.update((1, Currency::DEFAULT), account(10000));
+
+        let mut uow = UnitOfWork::begin(&mut tr, &mut ar);
+        uow.accounts()
+            .update((1, Currency::DEFAULT), account(20000));
+        uow.accounts()
+            .update((1, Currency::DEFAULT), account(30000));
+        uow.accounts().find_or_create((2, Currency::DEFAULT));
+        uow.transactions().update(1, transaction(1));
+        uow.rollback();
+
+        assert_eq!(ar.find_all(), vec![&account(10000)]);
+        assert!(tr.find_all().is_empty());
+    }
+
+    #[test]
+    fn it_rolls_back_when_dropped_without_committing() {
+        let mut tr = TransactionRepositoryInMemory::new();
+        let mut ar = AccountRepositoryInMemory::new();
+
+        {
+            let mut uow = UnitOfWork::begin(&mut tr, &mut ar);
+            uow.accounts()
+                .update((1, Currency::DEFAULT), account(10000));
+        }
+
+        assert_eq!(ar.find((1, Currency::DEFAULT)), None);
+
+        // The repositories can start another unit of work
+        let uow = UnitOfWork::begin(&mut tr, &mut ar);
+        uow.commit();
+    }
+
+    #[test]
+    fn it_leaves_no_partial_effects_on_a_failure_between_writes() {
+        let mut tr = FailingTransactionRepository(TransactionRepositoryInMemory::new());
+        let mut ar = AccountRepositoryInMemory::new();
+        ar.update((1, Currency::DEFAULT), account(10000));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut uow = UnitOfWork::begin(&mut tr, &mut ar);
+            uow.accounts()
+                .update((1, Currency::DEFAULT), account(20000));
+            uow.transactions().update(1, transaction(1));
+            uow.commit();
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(ar.find((1, Currency::DEFAULT)), Some(&account(10000)));
+    }
+}
@@ -0,0 +1,185 @@
+use csv::{ReaderBuilder, Trim};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
+use crate::core::exchange::{Conversion, Rate, RoundingMode};
+
+/// Conversion of amounts in other currencies into a base currency, at the rates in effect at the
+/// time of the conversion.
+///
+/// Rates are read from a CSV file with a `currency, rate, effective` row for each change of rate,
+/// where `rate` is the number of units of the base currency per unit of `currency` and `effective`
+/// is the Unix timestamp the rate applies from.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Exchange {
+    base: Currency,
+    rounding: RoundingMode,
+    /// The rates of each currency, ordered by the time they take effect
+    rates: BTreeMap<Currency, Vec<(u64, Rate)>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RateLine {
+    currency: Currency,
+    rate: Rate,
+    effective: u64,
+}
+
+impl Exchange {
+    pub fn new(base: Currency, rounding: RoundingMode) -> Exchange {
+        Exchange {
+            base,
+            rounding,
+            rates: BTreeMap::new(),
+        }
+    }
+
+    /// The currency amounts are converted into
+    pub fn base(&self) -> Currency {
+        self.base
+    }
+
+    /// Set the rate of a currency from a point in time, replacing any rate set for the same time
+    pub fn insert(&mut self, currency: Currency, effective: u64, rate: Rate) {
+        let rates = self.rates.entry(currency).or_default();
+        match rates.binary_search_by_key(&effective, |&(effective, _)| effective) {
+            Ok(index) => rates[index] = (effective, rate),
+            Err(index) => rates.insert(index, (effective, rate)),
+        }
+    }
+
+    /// Read the rates of a rates file
+    pub fn read_rates<R: io::Read>(&mut self, input: R) -> io::Result<()> {
+        let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+        for result in reader.deserialize() {
+            let line: RateLine = result.map_err(|error| invalid_data(&error.to_string()))?;
+            if line.currency.is_default() || line.currency == self.base {
+                return Err(invalid_data(&format!(
+                    "rate for the base currency '{}'",
+                    line.currency
+                )));
+            }
+            self.insert(line.currency, line.effective, line.rate);
+        }
+        Ok(())
+    }
+
+    /// The rate of a currency in effect at a time, if one has been set by then
+    pub fn rate_at(&self, currency: Currency, timestamp: u64) -> Option<Rate> {
+        let rates = self.rates.get(&currency)?;
+        let count = rates.partition_point(|&(effective, _)| effective <= timestamp);
+        count.checked_sub(1).map(|index| rates[index].1)
+    }
+
+    /// Convert an amount into the base currency at the rate in effect at a time, returning the
+    /// converted amount and how it was converted. Amounts in the base currency, or without a
+    /// currency, are already in the base currency. Returns `None` if there is no rate in effect
+    /// or the converted amount is out of range.
+    pub fn convert(
+        &self,
+        amount: Amount,
+        currency: Currency,
+        timestamp: u64,
+    ) -> Option<(Amount, Option<Conversion>)> {
+        if currency.is_default() || currency == self.base {
+            return Some((amount, None));
+        }
+        let rate = self.rate_at(currency, timestamp)?;
+        let converted = rate.convert(amount, self.base, self.rounding)?;
+        Some((
+            converted,
+            Some(Conversion {
+                amount,
+                currency,
+                rate,
+            }),
+        ))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid rates file: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn currency(code: &str) -> Currency {
+        code.parse().unwrap()
+    }
+
+    fn exchange() -> Exchange {
+        let mut exchange = Exchange::new(currency("USD"), RoundingMode::HalfUp);
+        exchange
+            .read_rates(
+                "currency, rate, effective\n\
+                 EUR, 1.1, 100\n\
+                 EUR, 1.08, 200\n\
+                 JPY, 0.0067, 0\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+        exchange
+    }
+
+    #[test]
+    fn it_uses_the_rate_in_effect_at_the_time() {
+        let exchange = exchange();
+        let eur = currency("EUR");
+        assert_eq!(exchange.rate_at(eur, 99), None);
+        assert_eq!(exchange.rate_at(eur, 100), Some("1.1".parse().unwrap()));
+        assert_eq!(exchange.rate_at(eur, 199), Some("1.1".parse().unwrap()));
+        assert_eq!(exchange.rate_at(eur, 200), Some("1.08".parse().unwrap()));
+        assert_eq!(exchange.rate_at(currency("GBP"), 200), None);
+    }
+
+    #[test]
+    fn it_converts_into_the_base_currency() {
+        let exchange = exchange();
+        assert_eq!(
+            exchange.convert(amount("10.25"), currency("EUR"), 150),
+            Some((
+                amount("11.28"),
+                Some(Conversion {
+                    amount: amount("10.25"),
+                    currency: currency("EUR"),
+                    rate: "1.1".parse().unwrap(),
+                })
+            ))
+        );
+        assert_eq!(
+            exchange.convert(amount("10.25"), currency("USD"), 0),
+            Some((amount("10.25"), None))
+        );
+        assert_eq!(
+            exchange.convert(amount("10.25"), Currency::DEFAULT, 0),
+            Some((amount("10.25"), None))
+        );
+        assert_eq!(exchange.convert(amount("1"), currency("EUR"), 50), None);
+    }
+
+    #[test]
+    fn it_rejects_invalid_rates() {
+        let mut exchange = Exchange::new(currency("USD"), RoundingMode::HalfUp);
+        for rates in [
+            "currency, rate, effective\nEUR, 0, 100\n",
+            "currency, rate, effective\nEUR, 1.1\n",
+            "currency, rate, effective\nXYZ, 1.1, 100\n",
+            "currency, rate, effective\nUSD, 1, 100\n",
+            "currency, rate, effective\n, 1, 100\n",
+        ] {
+            assert!(exchange.read_rates(rates.as_bytes()).is_err());
+        }
+    }
+}
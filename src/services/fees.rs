@@ -0,0 +1,234 @@
+use csv::{ReaderBuilder, Trim};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
+use crate::core::entities::transaction::TransactionType;
+use crate::core::exchange::RoundingMode;
+use crate::core::FIXED_DECIMAL_PLACES;
+
+/// Scale of a percentage of an amount, both in fixed point, before dividing by 100
+const PERCENT_SCALING: u32 = FIXED_DECIMAL_PLACES + 2;
+
+/// The fee charged for a deposit or withdrawal in a range of amounts
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct FeeTier {
+    /// Smallest amount the tier applies to
+    pub from: Amount,
+    /// Fixed part of the fee
+    pub flat: Amount,
+    /// Percentage of the amount added to the fixed part
+    pub percent: Amount,
+    /// Smallest fee charged, however small the amount
+    pub min: Option<Amount>,
+    /// Largest fee charged, however large the amount
+    pub max: Option<Amount>,
+}
+
+/// Fees charged for deposits and withdrawals.
+///
+/// The schedule is read from a CSV file with a `type, from, flat, percent, min, max` row for each
+/// tier of a transaction type. The tier with the largest `from` that isn't above the amount of a
+/// transaction sets its fee: `flat` plus `percent` of the amount, kept between `min` and `max`,
+/// and rounded half up to the minor unit of the account's currency. Only `type` is required, and
+/// transactions below the first tier of their type, or of a type without tiers, are free.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct FeeSchedule {
+    /// The tiers of each transaction type, ordered by the amount they apply from
+    tiers: BTreeMap<u8, Vec<FeeTier>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeeLine {
+    #[serde(rename = "type")]
+    tx_type: FeeType,
+    from: Option<Amount>,
+    flat: Option<Amount>,
+    percent: Option<Amount>,
+    min: Option<Amount>,
+    max: Option<Amount>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+enum FeeType {
+    #[serde(rename = "deposit")]
+    Deposit,
+    #[serde(rename = "withdrawal")]
+    Withdrawal,
+}
+
+impl FeeSchedule {
+    /// Add a tier to the fees of a transaction type
+    pub fn insert(&mut self, tx_type: TransactionType, tier: FeeTier) -> Result<(), &'static str> {
+        if tier.min.zip(tier.max).is_some_and(|(min, max)| min > max) {
+            return Err("minimum fee is larger than the maximum");
+        }
+        let tiers = self.tiers.entry(tx_type as u8).or_default();
+        match tiers.binary_search_by_key(&tier.from, |tier| tier.from) {
+            Ok(_) => Err("more than one tier from the same amount"),
+            Err(index) => {
+                tiers.insert(index, tier);
+                Ok(())
+            }
+        }
+    }
+
+    /// Read a fee schedule file
+    pub fn read<R: io::Read>(input: R) -> io::Result<FeeSchedule> {
+        let mut schedule = FeeSchedule::default();
+        let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+        for result in reader.deserialize() {
+            let line: FeeLine = result.map_err(|error| invalid_data(&error.to_string()))?;
+            let tx_type = match line.tx_type {
+                FeeType::Deposit => TransactionType::Deposit,
+                FeeType::Withdrawal => TransactionType::Withdrawal,
+            };
+            let tier = FeeTier {
+                from: line.from.unwrap_or_default(),
+                flat: line.flat.unwrap_or_default(),
+                percent: line.percent.unwrap_or_default(),
+                min: line.min,
+                max: line.max,
+            };
+            schedule.insert(tx_type, tier).map_err(invalid_data)?;
+        }
+        Ok(schedule)
+    }
+
+    /// The fee for a transaction of a type and amount, made to an account in a currency
+    pub fn fee(&self, tx_type: TransactionType, amount: Amount, currency: Currency) -> Amount {
+        let tiers = match self.tiers.get(&(tx_type as u8)) {
+            Some(tiers) => tiers,
+            None => return Amount::ZERO,
+        };
+        let count = tiers.partition_point(|tier| tier.from <= amount);
+        let tier = match count.checked_sub(1) {
+            Some(index) => &tiers[index],
+            None => return Amount::ZERO,
+        };
+
+        // Work at the scale of the percentage so that the fee is only rounded once, saturating as
+        // a fee that large can't be paid anyway
+        let scaling = 10i128.pow(PERCENT_SCALING);
+        let scaled = |amount: Amount| amount.to_fixed().saturating_mul(scaling);
+        let mut fee = scaled(tier.flat)
+            .saturating_add(amount.to_fixed().saturating_mul(tier.percent.to_fixed()));
+        if let Some(min) = tier.min {
+            fee = fee.max(scaled(min));
+        }
+        if let Some(max) = tier.max {
+            fee = fee.min(scaled(max));
+        }
+
+        let dropped = FIXED_DECIMAL_PLACES - currency.minor_units();
+        let minor_units = RoundingMode::HalfUp.divide(fee, 10i128.pow(PERCENT_SCALING + dropped));
+        Amount::from_fixed(minor_units * 10i128.pow(dropped))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid fee schedule: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn schedule() -> FeeSchedule {
+        FeeSchedule::read(
+            "type, from, flat, percent, min, max\n\
+             deposit, , 0.10, , , \n\
+             withdrawal, , 0.50, 1.5, 1.00, 25\n\
+             withdrawal, 1000, , 1, , 20\n"
+                .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_charges_the_fee_of_the_tier() {
+        let schedule = schedule();
+        let fee = |tx_type, value| schedule.fee(tx_type, amount(value), Currency::DEFAULT);
+
+        assert_eq!(fee(TransactionType::Deposit, "100"), amount("0.10"));
+        // 0.50 + 1.5% is below the minimum
+        assert_eq!(fee(TransactionType::Withdrawal, "10"), amount("1.00"));
+        assert_eq!(fee(TransactionType::Withdrawal, "100"), amount("2.00"));
+        assert_eq!(
+            fee(TransactionType::Withdrawal, "999.99"),
+            amount("15.4999")
+        );
+        // the next tier starts at 1000, with a lower cap
+        assert_eq!(fee(TransactionType::Withdrawal, "1000"), amount("10.00"));
+        assert_eq!(fee(TransactionType::Withdrawal, "5000"), amount("20.00"));
+    }
+
+    #[test]
+    fn it_rounds_fees_to_the_minor_unit() {
+        let schedule = schedule();
+        let usd = "USD".parse().unwrap();
+        let jpy = "JPY".parse().unwrap();
+
+        assert_eq!(
+            schedule.fee(TransactionType::Withdrawal, amount("999.99"), usd),
+            amount("15.50")
+        );
+        assert_eq!(
+            schedule.fee(TransactionType::Deposit, amount("100"), jpy),
+            Amount::ZERO
+        );
+        assert_eq!(
+            schedule.fee(
+                TransactionType::Withdrawal,
+                Amount::from_fixed(i128::MAX),
+                usd
+            ),
+            amount("20")
+        );
+    }
+
+    #[test]
+    fn it_charges_nothing_without_a_tier() {
+        let mut schedule = FeeSchedule::default();
+        assert_eq!(
+            schedule.fee(TransactionType::Deposit, amount("100"), Currency::DEFAULT),
+            Amount::ZERO
+        );
+
+        let tier = FeeTier {
+            from: amount("10"),
+            flat: amount("1"),
+            ..FeeTier::default()
+        };
+        schedule.insert(TransactionType::Deposit, tier).unwrap();
+        assert_eq!(
+            schedule.fee(TransactionType::Deposit, amount("9.99"), Currency::DEFAULT),
+            Amount::ZERO
+        );
+        assert_eq!(
+            schedule.fee(TransactionType::Deposit, amount("10"), Currency::DEFAULT),
+            amount("1")
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_schedules() {
+        for fees in [
+            "type, from, flat, percent, min, max\ntransfer, , 1, , , \n",
+            "type, from, flat, percent, min, max\ndeposit, , 1, , 5, 2\n",
+            "type, from, flat, percent, min, max\ndeposit, , 1, , , \ndeposit, 0, 2, , , \n",
+            "type, from, flat, percent, min, max\ndeposit, , -1, , , \n",
+        ] {
+            assert!(FeeSchedule::read(fees.as_bytes()).is_err(), "{}", fees);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
+use crate::core::entities::account::AccountKey;
+use crate::core::entities::transaction::TransactionState;
+use crate::core::event::Event;
+use crate::services::payment::PaymentServiceTrait;
+
+/// Which of the invariants may be relaxed
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct InvariantPolicy {
+    /// Whether balances may be negative beyond the account's credit limit, as they are when a
+    /// deposit is disputed after its funds were withdrawn
+    pub negative_balances_allowed: bool,
+}
+
+/// An invariant that doesn't hold for an account
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Violation {
+    /// The total isn't the sum of the available and held balances
+    TotalMismatch {
+        client_id: u16,
+        currency: Currency,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+    },
+    /// The held balance isn't the sum of the client's disputed transactions, including transfers
+    /// they received, less what was refunded of them, and the uncaptured part of their open
+    /// authorizations in the currency
+    HeldMismatch {
+        client_id: u16,
+        currency: Currency,
+        held: Amount,
+        expected: Amount,
+    },
+    /// The account is locked but none of the client's transactions in the currency was charged
+    /// back
+    LockedWithoutChargeback { client_id: u16, currency: Currency },
+    /// A balance is below zero by more than the credit limit allows, which the held balance
+    /// never may be. The available and total balances of an account a chargeback overdrew, which
+    /// is flagged with an `Overdrawn` event, may be.
+    NegativeBalance {
+        client_id: u16,
+        currency: Currency,
+        balance: &'static str,
+        amount: Amount,
+    },
+}
+
+impl Violation {
+    /// Stable, machine-readable name of the violation variant
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Violation::TotalMismatch { .. } => "TotalMismatch",
+            Violation::HeldMismatch { .. } => "HeldMismatch",
+            Violation::LockedWithoutChargeback { .. } => "LockedWithoutChargeback",
+            Violation::NegativeBalance { .. } => "NegativeBalance",
+        }
+    }
+
+    /// The account the violation was found in
+    pub fn account_key(&self) -> AccountKey {
+        match *self {
+            Violation::TotalMismatch {
+                client_id,
+                currency,
+                ..
+            }
+            | Violation::HeldMismatch {
+                client_id,
+                currency,
+                ..
+            }
+            | Violation::LockedWithoutChargeback {
+                client_id,
+                currency,
+            }
+            | Violation::NegativeBalance {
+                client_id,
+                currency,
+                ..
+            } => (client_id, currency),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (client_id, currency) = self.account_key();
+        write!(f, "{}: client {}", self.kind(), client_id)?;
+        // Accounts in the default currency are named as they were before currencies
+        if !currency.is_default() {
+            write!(f, " {}", currency)?;
+        }
+        match self {
+            Violation::TotalMismatch {
+                available,
+                held,
+                total,
+                ..
+            } => write!(
+                f,
+                " has total {} but available {} and held {}",
+                total, available, held
+            ),
+            Violation::HeldMismatch { held, expected, .. } => write!(
+                f,
+                " has held {} but disputed transactions and authorizations of {}",
+                held, expected
+            ),
+            Violation::LockedWithoutChargeback { .. } => {
+                write!(f, " is locked but has no charged back transaction")
+            }
+            Violation::NegativeBalance {
+                balance, amount, ..
+            } => write!(f, " has {} {}", balance, amount),
+        }
+    }
+}
+
+/// Check the invariants of every account held by a payment service against its transactions in
+/// the account's currency, returning the violations in client and currency order
+pub fn check(
+    payment_service: &mut dyn PaymentServiceTrait,
+    policy: &InvariantPolicy,
+) -> Vec<Violation> {
+    let mut expected_held: BTreeMap<AccountKey, Amount> = BTreeMap::new();
+    let mut charged_back = BTreeSet::new();
+    let mut currencies = BTreeMap::new();
+    for transaction in payment_service.get_transactions() {
+        let key = (transaction.client_id, transaction.currency);
+        currencies.insert(transaction.tx_id, transaction.currency);
+        let held = match transaction.state {
+            // only what is left of a partially refunded deposit is held by its dispute
+            state if state == TransactionState::Disputed as u8 => transaction.unrefunded(),
+            state if state == TransactionState::Authorized as u8 => transaction.uncaptured(),
+            _ => Amount::ZERO,
+        };
+        // a disputed transfer holds its amount in the accounts of both its clients, and a reversed
+        // one locks them both
+        let destination = transaction
+            .destination
+            .map(|client_id| (client_id, transaction.currency));
+        if held != Amount::ZERO {
+            for key in std::iter::once(key).chain(destination) {
+                let sum = expected_held.entry(key).or_default();
+                *sum = *sum + held;
+            }
+        }
+        if transaction.state == TransactionState::Reversed as u8 {
+            charged_back.extend(std::iter::once(key).chain(destination));
+        }
+    }
+
+    let overdrawn: BTreeSet<AccountKey> = payment_service
+        .get_events()
+        .into_iter()
+        .filter_map(|recorded| match recorded.event {
+            Event::Overdrawn {
+                client_id, tx_id, ..
+            } => currencies
+                .get(&tx_id)
+                .map(|&currency| (client_id, currency)),
+            _ => None,
+        })
+        .collect();
+
+    let mut violations = Vec::new();
+    for account in payment_service.get_accounts() {
+        let (client_id, currency) = account.key();
+
+        if account.total != account.available + account.held {
+            violations.push(Violation::TotalMismatch {
+                client_id,
+                currency,
+                available: account.available,
+                held: account.held,
+                total: account.total,
+            });
+        }
+
+        let expected = expected_held
+            .get(&account.key())
+            .copied()
+            .unwrap_or_default();
+        if account.held != expected {
+            violations.push(Violation::HeldMismatch {
+                client_id,
+                currency,
+                held: account.held,
+                expected,
+            });
+        }
+
+        if account.locked && !charged_back.contains(&account.key()) {
+            violations.push(Violation::LockedWithoutChargeback {
+                client_id,
+                currency,
+            });
+        }
+
+        if !policy.negative_balances_allowed {
+            let overdrawn = overdrawn.contains(&account.key());
+            let balances = [
+                ("available", account.available, account.credit_limit),
+                ("held", account.held, Amount::ZERO),
+                ("total", account.total, account.credit_limit),
+            ];
+            for (balance, amount, credit_limit) in balances {
+                // a chargeback may overdraw the account, which is flagged when it is applied
+                let allowed = overdrawn && balance != "held";
+                if !allowed && (amount + credit_limit).is_negative() {
+                    violations.push(Violation::NegativeBalance {
+                        client_id,
+                        currency,
+                        balance,
+                        amount,
+                    });
+                }
+            }
+        }
+    }
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::currency::Currency;
+    use crate::core::entities::account::Account;
+    use crate::core::entities::transaction::Transaction;
+    use crate::repositories::account::in_memory::AccountRepositoryInMemory;
+    use crate::repositories::account::AccountRepositoryTrait;
+    use crate::repositories::transaction::in_memory::TransactionRepositoryInMemory;
+    use crate::repositories::transaction::TransactionRepositoryTrait;
+    use crate::services::payment::PaymentService;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn build_payments_service(
+        accounts: &[Account],
+        transactions: &[Transaction],
+    ) -> PaymentService {
+        let mut tr = TransactionRepositoryInMemory::new();
+        let mut ar = AccountRepositoryInMemory::new();
+        for account in accounts {
+            ar.update(account.key(), account.clone());
+        }
+        for transaction in transactions {
+            tr.update(transaction.tx_id, transaction.clone());
+        }
+        PaymentService::new(Box::new(tr), Box::new(ar))
+    }
+
+    #[test]
+    fn it_finds_no_violations_in_state_built_by_the_service() {
+        let mut ps = build_payments_service(&[], &[]);
+        ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).unwrap();
+        ps.withdrawal(1, 2, amount("4.0"), Currency::DEFAULT)
+            .unwrap();
+        ps.dispute(1, 2).unwrap();
+        ps.deposit(2, 3, amount("5.0"), Currency::DEFAULT).unwrap();
+        ps.dispute(2, 3).unwrap();
+        ps.chargeback(2, 3).unwrap();
+        ps.authorize(1, 4, amount("3.0"), Currency::DEFAULT)
+            .unwrap();
+        ps.capture(1, 4, Some(amount("1.0"))).unwrap();
+        ps.transfer(1, 5, amount("2.0"), Currency::DEFAULT, 3)
+            .unwrap();
+        ps.dispute(1, 5).unwrap();
+        ps.deposit(3, 6, amount("4.0"), Currency::DEFAULT).unwrap();
+        ps.refund(3, 6, amount("1.5")).unwrap();
+        ps.dispute(3, 6).unwrap();
+
+        assert!(check(&mut ps, &InvariantPolicy::default()).is_empty());
+    }
+
+    #[test]
+    fn it_reports_every_violated_invariant() {
+        let usd: Currency = "USD".parse().unwrap();
+        let mut ps = build_payments_service(
+            &[
+                Account::new(1, amount("1.0"), amount("2.0"), amount("4.0"), false),
+                Account::new(2, Amount::ZERO, Amount::ZERO, Amount::ZERO, true).with_currency(usd),
+            ],
+            &[
+                Transaction::new(1, 0, 1, amount("1.0"), 1),
+                // Transactions only count towards the account in their currency
+                Transaction::new(2, 0, 1, amount("5.0"), 1).with_currency(usd),
+                Transaction::new(3, 0, 2, amount("1.0"), 2),
+            ],
+        );
+
+        let violations = check(&mut ps, &InvariantPolicy::default());
+        assert_eq!(
+            violations,
+            vec![
+                Violation::TotalMismatch {
+                    client_id: 1,
+                    currency: Currency::DEFAULT,
+                    available: amount("1.0"),
+                    held: amount("2.0"),
+                    total: amount("4.0"),
+                },
+                Violation::HeldMismatch {
+                    client_id: 1,
+                    currency: Currency::DEFAULT,
+                    held: amount("2.0"),
+                    expected: amount("1.0"),
+                },
+                Violation::LockedWithoutChargeback {
+                    client_id: 2,
+                    currency: usd,
+                },
+            ]
+        );
+        assert_eq!(
+            violations[2].to_string(),
+            "LockedWithoutChargeback: client 2 USD is locked but has no charged back transaction"
+        );
+    }
+
+    #[test]
+    fn it_reports_negative_balances_unless_allowed() {
+        let mut ps = build_payments_service(&[], &[]);
+        ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).unwrap();
+        ps.withdrawal(1, 2, amount("8.0"), Currency::DEFAULT)
+            .unwrap();
+        ps.dispute(1, 1).unwrap();
+
+        let violations = check(&mut ps, &InvariantPolicy::default());
+        assert_eq!(
+            violations,
+            vec![Violation::NegativeBalance {
+                client_id: 1,
+                currency: Currency::DEFAULT,
+                balance: "available",
+                amount: -amount("8.0"),
+            }]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            "NegativeBalance: client 1 has available -8.0000"
+        );
+
+        let policy = InvariantPolicy {
+            negative_balances_allowed: true,
+        };
+        assert!(check(&mut ps, &policy).is_empty());
+    }
+
+    #[test]
+    fn it_allows_negative_balances_left_by_a_chargeback() {
+        let mut ps = build_payments_service(&[], &[]);
+        ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).unwrap();
+        ps.withdrawal(1, 2, amount("8.0"), Currency::DEFAULT)
+            .unwrap();
+        ps.dispute(1, 1).unwrap();
+        ps.chargeback(1, 1).unwrap();
+
+        assert!(ps.get_accounts()[0].total.is_negative());
+        assert!(check(&mut ps, &InvariantPolicy::default()).is_empty());
+
+        // only the account the chargeback overdrew
+        ps.deposit(2, 3, amount("10.0"), Currency::DEFAULT).unwrap();
+        ps.withdrawal(2, 4, amount("8.0"), Currency::DEFAULT)
+            .unwrap();
+        ps.dispute(2, 3).unwrap();
+        assert_eq!(
+            check(&mut ps, &InvariantPolicy::default()),
+            vec![Violation::NegativeBalance {
+                client_id: 2,
+                currency: Currency::DEFAULT,
+                balance: "available",
+                amount: -amount("8.0"),
+            }]
+        );
+    }
+
+    #[test]
+    fn it_allows_negative_balances_within_the_credit_limit() {
+        let mut ps = build_payments_service(&[], &[]);
+        ps.set_credit_limit(1, Currency::DEFAULT, amount("5.0"))
+            .unwrap();
+        ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).unwrap();
+        ps.withdrawal(1, 2, amount("15.0"), Currency::DEFAULT)
+            .unwrap();
+        assert!(check(&mut ps, &InvariantPolicy::default()).is_empty());
+
+        // the dispute holds funds the client no longer has
+        ps.dispute(1, 1).unwrap();
+        assert_eq!(
+            check(&mut ps, &InvariantPolicy::default()),
+            vec![Violation::NegativeBalance {
+                client_id: 1,
+                currency: Currency::DEFAULT,
+                balance: "available",
+                amount: -amount("15.0"),
+            }]
+        );
+    }
+}
@@ -1,2 +1,7 @@
 
-pub mod payment;
\ No newline at end of file
+pub mod exchange;
+pub mod fees;
+pub mod invariants;
+pub mod payment;
+pub mod profiles;
+pub mod snapshot;
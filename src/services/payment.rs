@@ -1,35 +1,270 @@
-use anyhow::Result;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
 
 use crate::repositories::account::AccountRepositoryTrait;
+use crate::repositories::event::{in_memory::EventRepositoryInMemory, EventRepositoryTrait};
+use crate::repositories::journal::Journal;
 use crate::repositories::transaction::{
     Transaction, TransactionRepositoryTrait, TransactionState, TransactionType,
 };
+use crate::repositories::unit_of_work::UnitOfWork;
 
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
 use crate::core::entities::account::Account;
+use crate::core::event::{self, Event, PointInTime, Position, RecordedEvent};
+use crate::core::exchange::Conversion;
+use crate::core::ledger::LedgerAccount;
+use crate::services::exchange::Exchange;
+use crate::services::fees::FeeSchedule;
 
+/// Reasons a transaction can be rejected by the payment service.
+///
+/// Each variant carries the client and/or transaction ids involved so callers can report on the
+/// rejected operation without having to parse the error message.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum PaymentServiceError {
+    AccountLocked { client_id: u16 },
+    AccountDoesNotExist { client_id: u16 },
+    InsufficientFunds { client_id: u16, tx_id: u32 },
+    TransactionDoesNotExist { tx_id: u32 },
+    InvalidTransactionState { tx_id: u32 },
+    ClientMismatch { client_id: u16, tx_id: u32 },
+    DuplicateTransaction { tx_id: u32 },
+    TransactionNotDisputable { tx_id: u32 },
+    ConversionFailed { tx_id: u32 },
+    ExceedsAuthorization { tx_id: u32 },
+    InvalidDestination { client_id: u16, tx_id: u32 },
+    TransactionNotRefundable { tx_id: u32 },
+    ExceedsRefundable { tx_id: u32 },
+    BalanceOverflow { client_id: u16 },
+}
+
+impl PaymentServiceError {
+    /// Stable, machine-readable name of the error variant
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PaymentServiceError::AccountLocked { .. } => "AccountLocked",
+            PaymentServiceError::AccountDoesNotExist { .. } => "AccountDoesNotExist",
+            PaymentServiceError::InsufficientFunds { .. } => "InsufficientFunds",
+            PaymentServiceError::TransactionDoesNotExist { .. } => "TransactionDoesNotExist",
+            PaymentServiceError::InvalidTransactionState { .. } => "InvalidTransactionState",
+            PaymentServiceError::ClientMismatch { .. } => "ClientMismatch",
+            PaymentServiceError::DuplicateTransaction { .. } => "DuplicateTransaction",
+            PaymentServiceError::TransactionNotDisputable { .. } => "TransactionNotDisputable",
+            PaymentServiceError::ConversionFailed { .. } => "ConversionFailed",
+            PaymentServiceError::ExceedsAuthorization { .. } => "ExceedsAuthorization",
+            PaymentServiceError::InvalidDestination { .. } => "InvalidDestination",
+            PaymentServiceError::TransactionNotRefundable { .. } => "TransactionNotRefundable",
+            PaymentServiceError::ExceedsRefundable { .. } => "ExceedsRefundable",
+            PaymentServiceError::BalanceOverflow { .. } => "BalanceOverflow",
+        }
+    }
+
+    pub fn client_id(&self) -> Option<u16> {
+        match self {
+            PaymentServiceError::AccountLocked { client_id }
+            | PaymentServiceError::AccountDoesNotExist { client_id }
+            | PaymentServiceError::InsufficientFunds { client_id, .. }
+            | PaymentServiceError::ClientMismatch { client_id, .. }
+            | PaymentServiceError::InvalidDestination { client_id, .. }
+            | PaymentServiceError::BalanceOverflow { client_id } => Some(*client_id),
+            PaymentServiceError::TransactionDoesNotExist { .. }
+            | PaymentServiceError::InvalidTransactionState { .. }
+            | PaymentServiceError::DuplicateTransaction { .. }
+            | PaymentServiceError::TransactionNotDisputable { .. }
+            | PaymentServiceError::ConversionFailed { .. }
+            | PaymentServiceError::ExceedsAuthorization { .. }
+            | PaymentServiceError::TransactionNotRefundable { .. }
+            | PaymentServiceError::ExceedsRefundable { .. } => None,
+        }
+    }
+
+    pub fn tx_id(&self) -> Option<u32> {
+        match self {
+            PaymentServiceError::InsufficientFunds { tx_id, .. }
+            | PaymentServiceError::TransactionDoesNotExist { tx_id }
+            | PaymentServiceError::InvalidTransactionState { tx_id }
+            | PaymentServiceError::ClientMismatch { tx_id, .. }
+            | PaymentServiceError::DuplicateTransaction { tx_id }
+            | PaymentServiceError::TransactionNotDisputable { tx_id }
+            | PaymentServiceError::ConversionFailed { tx_id }
+            | PaymentServiceError::ExceedsAuthorization { tx_id }
+            | PaymentServiceError::InvalidDestination { tx_id, .. }
+            | PaymentServiceError::TransactionNotRefundable { tx_id }
+            | PaymentServiceError::ExceedsRefundable { tx_id } => Some(*tx_id),
+            PaymentServiceError::AccountLocked { .. }
+            | PaymentServiceError::AccountDoesNotExist { .. }
+            | PaymentServiceError::BalanceOverflow { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for PaymentServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PaymentServiceError::{}", self.kind())?;
+        match (self.client_id(), self.tx_id()) {
+            (Some(client_id), Some(tx_id)) => write!(f, " (client: {}, tx: {})", client_id, tx_id),
+            (Some(client_id), None) => write!(f, " (client: {})", client_id),
+            (None, Some(tx_id)) => write!(f, " (tx: {})", tx_id),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl Error for PaymentServiceError {}
+
+pub type Result<T> = std::result::Result<T, PaymentServiceError>;
+
+/// Operations of the payment service. Deposits and withdrawals are made to the client's account in
+/// their currency, or in the base currency if the service converts them, operations on an existing
+/// transaction act on the account in its currency.
+///
+/// Authorizations hold funds of the client's account until they are captured, in one or more
+/// parts, voided or expire. Transfers move funds between the accounts of two clients, and their
+/// disputes, resolves and chargebacks act on both. Deposits can be refunded in one or more parts
+/// without a dispute, which then only holds what is left of the deposit.
 pub trait PaymentServiceTrait {
-    fn deposit(&mut self, client_id: u16, tx_id: u32, amount: f64) -> Result<()>;
-    fn withdrawal(&mut self, client_id: u16, tx_id: u32, amount: f64) -> Result<()>;
+    fn deposit(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<()>;
+    fn withdrawal(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<()>;
+    /// Move funds from the client's account to that of the destination client, creating it if
+    /// needed
+    fn transfer(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+        destination: u16,
+    ) -> Result<()>;
     fn dispute(&mut self, client_id: u16, tx_id: u32) -> Result<()>;
     fn resolve(&mut self, client_id: u16, tx_id: u32) -> Result<()>;
     fn chargeback(&mut self, client_id: u16, tx_id: u32) -> Result<()>;
-    fn get_account<'a>(&'a mut self, _client_id: u16) -> Option<&'a Account> {
+    fn authorize(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<()>;
+    /// Pay out part of the funds held by an authorization, or all it still holds without an
+    /// amount
+    fn capture(&mut self, client_id: u16, tx_id: u32, amount: Option<Amount>) -> Result<()>;
+    fn void(&mut self, client_id: u16, tx_id: u32) -> Result<()>;
+    /// Return part of a deposit to the payer, without locking the account
+    fn refund(&mut self, client_id: u16, tx_id: u32, amount: Amount) -> Result<()>;
+    /// Release the funds of every authorization that went stale by the current position
+    fn expire_authorizations(&mut self) {}
+    /// Set how far below zero withdrawals may take the available balance of the client's account
+    /// in a currency, creating the account if needed
+    fn set_credit_limit(&mut self, client_id: u16, currency: Currency, limit: Amount)
+        -> Result<()>;
+    fn get_account(&mut self, _client_id: u16, _currency: Currency) -> Option<&Account> {
+        None
+    }
+    fn get_accounts(&mut self) -> Vec<&Account> {
+        Vec::new()
+    }
+    fn get_transaction(&mut self, _tx_id: u32) -> Option<&Transaction> {
         None
     }
-    fn get_accounts<'a>(&'a mut self) -> Vec<&'a Account> {
+    fn get_transactions(&mut self) -> Vec<&Transaction> {
+        Vec::new()
+    }
+    fn get_events(&mut self) -> Vec<&RecordedEvent> {
         Vec::new()
     }
-    fn get_transaction<'a>(&'a mut self, _tx_id: u32) -> Option<&'a Transaction> {
+    /// The latest event recorded by the service
+    fn get_last_event(&mut self) -> Option<RecordedEvent> {
+        None
+    }
+    /// Currency the amounts of other currencies are converted into, if the service converts them
+    fn get_base_currency(&self) -> Option<Currency> {
+        None
+    }
+    /// Set the position recorded with the events of the operations that follow
+    fn set_position(&mut self, _position: Position) {}
+    /// State of an account at a point in the history of the service, if it existed by then
+    fn get_account_at(
+        &mut self,
+        _client_id: u16,
+        _currency: Currency,
+        _point: PointInTime,
+    ) -> Option<Account> {
         None
     }
-    fn get_transactions<'a>(&'a mut self) -> Vec<&'a Transaction> {
+    /// State of every account at a point in the history of the service
+    fn get_accounts_at(&mut self, _point: PointInTime) -> Vec<Account> {
+        Vec::new()
+    }
+    /// Balance of every ledger account the service has posted to. The balances in each currency
+    /// sum to zero.
+    fn get_trial_balance(&mut self) -> Vec<(LedgerAccount, Amount)> {
         Vec::new()
     }
 }
 
+/// Business rules of the payment service that can be configured per deployment
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct PaymentServicePolicy {
+    /// Whether withdrawals can be disputed, otherwise only deposits can be
+    pub withdrawals_disputable: bool,
+    /// Number of input lines after the one an authorization was made on that it stays open for
+    pub authorization_expiry_lines: Option<u64>,
+    /// Number of seconds after an authorization was made that it stays open for
+    pub authorization_expiry_seconds: Option<u64>,
+}
+
+impl PaymentServicePolicy {
+    /// Whether an authorization made at a position is stale at another
+    fn authorization_expired(&self, authorized: Position, now: Position) -> bool {
+        let lines = now.line.saturating_sub(authorized.line);
+        let seconds = now.timestamp.saturating_sub(authorized.timestamp);
+        self.authorization_expiry_lines.is_some_and(|n| lines > n)
+            || self
+                .authorization_expiry_seconds
+                .is_some_and(|n| seconds > n)
+    }
+
+    fn authorizations_expire(&self) -> bool {
+        self.authorization_expiry_lines.is_some() || self.authorization_expiry_seconds.is_some()
+    }
+}
+
+impl Default for PaymentServicePolicy {
+    fn default() -> PaymentServicePolicy {
+        PaymentServicePolicy {
+            withdrawals_disputable: true,
+            authorization_expiry_lines: None,
+            authorization_expiry_seconds: None,
+        }
+    }
+}
+
 pub struct PaymentService {
     tx_store: Box<dyn TransactionRepositoryTrait>,
     ac_store: Box<dyn AccountRepositoryTrait>,
+    ev_store: Box<dyn EventRepositoryTrait>,
+    policy: PaymentServicePolicy,
+    journal: Option<Journal>,
+    exchange: Option<Exchange>,
+    fees: FeeSchedule,
+    position: Position,
+    /// Position of every open authorization, once the policy needed it
+    authorizations: Option<BTreeMap<u32, Position>>,
 }
 
 impl PaymentService {
@@ -37,216 +272,804 @@ impl PaymentService {
         tx_store: Box<dyn TransactionRepositoryTrait>,
         ac_store: Box<dyn AccountRepositoryTrait>,
     ) -> PaymentService {
-        PaymentService { tx_store, ac_store }
+        Self::with_policy(tx_store, ac_store, PaymentServicePolicy::default())
     }
-}
 
-impl PaymentServiceTrait for PaymentService {
-    fn deposit(&mut self, client_id: u16, tx_id: u32, amount: f64) -> Result<()> {
-        // get account, creating it if needed
-        let acc = self.ac_store.find_or_create(client_id).unwrap();
+    /// Build a service recording its events in memory
+    pub fn with_policy(
+        tx_store: Box<dyn TransactionRepositoryTrait>,
+        ac_store: Box<dyn AccountRepositoryTrait>,
+        policy: PaymentServicePolicy,
+    ) -> PaymentService {
+        Self::with_event_store(
+            tx_store,
+            ac_store,
+            Box::new(EventRepositoryInMemory::new()),
+            policy,
+        )
+    }
 
-        // bail out if account is locked
-        if acc.locked {
-            return Err(anyhow!("PaymentServiceError::AccountLocked"));
+    pub fn with_event_store(
+        tx_store: Box<dyn TransactionRepositoryTrait>,
+        ac_store: Box<dyn AccountRepositoryTrait>,
+        ev_store: Box<dyn EventRepositoryTrait>,
+        policy: PaymentServicePolicy,
+    ) -> PaymentService {
+        PaymentService {
+            tx_store,
+            ac_store,
+            ev_store,
+            policy,
+            journal: None,
+            exchange: None,
+            fees: FeeSchedule::default(),
+            position: Position::default(),
+            authorizations: None,
         }
+    }
 
-        // store the transaction
-        self.tx_store.update(
-            tx_id,
-            Transaction::new(
-                tx_id,
-                Transaction::transaction_type_encode(TransactionType::Deposit),
-                client_id,
-                amount,
-                Transaction::transaction_state_encode(TransactionState::Normal),
-            ),
-        );
+    /// Build a service that journals every operation before applying it to the repositories, so
+    /// that the repositories can be recovered if the process dies part way through an operation
+    pub fn with_journal(
+        tx_store: Box<dyn TransactionRepositoryTrait>,
+        ac_store: Box<dyn AccountRepositoryTrait>,
+        ev_store: Box<dyn EventRepositoryTrait>,
+        policy: PaymentServicePolicy,
+        journal: Journal,
+    ) -> PaymentService {
+        PaymentService {
+            journal: Some(journal),
+            ..Self::with_event_store(tx_store, ac_store, ev_store, policy)
+        }
+    }
 
-        // update account
-        let acc = Account {
-            client_id,
-            available: acc.available + Account::to_fixed(amount),
-            held: acc.held,
-            total: acc.total + Account::to_fixed(amount),
-            locked: false,
+    /// Convert deposits and withdrawals in other currencies into the base currency of the exchange,
+    /// at the rate in effect at the time of the current position
+    pub fn with_exchange(self, exchange: Exchange) -> PaymentService {
+        PaymentService {
+            exchange: Some(exchange),
+            ..self
+        }
+    }
+
+    /// Charge the fees of the schedule for deposits and withdrawals, which are refunded if the
+    /// transaction is charged back
+    pub fn with_fees(self, fees: FeeSchedule) -> PaymentService {
+        PaymentService { fees, ..self }
+    }
+
+    /// The amount and currency a deposit or withdrawal is made in, and how the amount was
+    /// converted if it was
+    fn convert(
+        &self,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<(Amount, Currency, Option<Conversion>)> {
+        let exchange = match &self.exchange {
+            Some(exchange) => exchange,
+            None => return Ok((amount, currency, None)),
         };
-        let _ = self.ac_store.update(client_id, acc);
+        match exchange.convert(amount, currency, self.position.timestamp) {
+            Some((amount, conversion)) => Ok((amount, exchange.base(), conversion)),
+            None => Err(PaymentServiceError::ConversionFailed { tx_id }),
+        }
+    }
 
+    /// Apply the events of an operation to the accounts and transactions they concern, and store
+    /// the resulting state along with the events as a single unit of work, so that a failure part
+    /// way through leaves no repository changed. Operations on the account alone concern no
+    /// transaction, operations charging or refunding a fee also concern the fee transaction, and
+    /// operations on a transfer concern the accounts of both its clients. Nothing is stored if a
+    /// balance would overflow.
+    ///
+    /// Transactions created by the events are inserted rather than written over an existing
+    /// transaction with the same id.
+    fn apply(
+        &mut self,
+        mut accounts: Vec<Account>,
+        mut transactions: Vec<Transaction>,
+        events: &[Event],
+    ) -> Result<()> {
+        let existing = transactions.len();
+        apply_events(&mut accounts, &mut transactions, events)?;
+        for tx in &transactions[existing..] {
+            // checked before anything is journaled, so that an entry is never redone over an
+            // existing transaction
+            if self.tx_store.find(tx.tx_id).is_some() {
+                return Err(PaymentServiceError::DuplicateTransaction { tx_id: tx.tx_id });
+            }
+        }
+        for tx in &transactions {
+            self.track_authorization(tx);
+        }
+        let events: Vec<RecordedEvent> = events
+            .iter()
+            .map(|&event| RecordedEvent {
+                event,
+                position: self.position,
+            })
+            .collect();
+
+        if let Some(journal) = &mut self.journal {
+            // Failing to persist is a fundamental system failure
+            journal
+                .append(&accounts, &transactions, &events, self.ev_store.count())
+                .expect("Failed to write to the journal");
+        }
+
+        let mut uow = UnitOfWork::begin(self.tx_store.as_mut(), self.ac_store.as_mut())
+            .with_events(self.ev_store.as_mut());
+        for acc in accounts {
+            uow.accounts().update(acc.key(), acc);
+        }
+        let created = transactions.split_off(existing);
+        for tx in transactions {
+            uow.transactions().update(tx.tx_id, tx);
+        }
+        for tx in created {
+            let tx_id = tx.tx_id;
+            if !uow.transactions().insert(tx) {
+                panic!("Transaction {} was created twice", tx_id);
+            }
+        }
+        for event in events {
+            uow.events().append(event);
+        }
+        uow.commit();
+        if let Some(journal) = &mut self.journal {
+            journal
+                .checkpoint()
+                .expect("Failed to checkpoint the journal");
+        }
         Ok(())
     }
 
-    fn withdrawal(&mut self, client_id: u16, tx_id: u32, amount: f64) -> Result<()> {
-        // get account
-        let acc = match self.ac_store.find(client_id) {
-            Some(a) => a,
-            None => return Err(anyhow!("PaymentServiceError::AccountDoesNotExist")),
+    /// Open authorizations and the position they were made at. Authorizations made before the
+    /// service started are read from the event log, and those without a recorded event (e.g.
+    /// restored from a snapshot) are taken to be made at the current position.
+    fn authorizations(&mut self) -> &mut BTreeMap<u32, Position> {
+        if self.authorizations.is_none() {
+            let open: Vec<u32> = self
+                .tx_store
+                .find_all()
+                .into_iter()
+                .filter(|tx| tx.state == TransactionState::Authorized as u8)
+                .map(|tx| tx.tx_id)
+                .collect();
+            let mut authorized = BTreeMap::new();
+            for tx_id in open {
+                let position = self
+                    .ev_store
+                    .find_by_transaction(tx_id)
+                    .into_iter()
+                    .find(|recorded| matches!(recorded.event, Event::FundsAuthorized { .. }))
+                    .map_or(self.position, |recorded| recorded.position);
+                authorized.insert(tx_id, position);
+            }
+            self.authorizations = Some(authorized);
+        }
+        self.authorizations.get_or_insert_with(BTreeMap::new)
+    }
+
+    /// Keep the open authorizations up to date with a transaction changed by an operation, if the
+    /// policy lets authorizations expire
+    fn track_authorization(&mut self, tx: &Transaction) {
+        if tx.tx_type != TransactionType::Authorization as u8
+            || !self.policy.authorizations_expire()
+        {
+            return;
+        }
+        let position = self.position;
+        let authorizations = self.authorizations();
+        if tx.state == TransactionState::Authorized as u8 {
+            authorizations.entry(tx.tx_id).or_insert(position);
+        } else {
+            authorizations.remove(&tx.tx_id);
+        }
+    }
+
+    /// Check the account and transaction an operation on an existing transaction concerns,
+    /// returning copies of them. The account is the client's account in the currency of the
+    /// transaction.
+    fn find_for_dispute(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        state: TransactionState,
+    ) -> Result<(Account, Transaction)> {
+        // handle non-existant transaction
+        let tx = match self.tx_store.find(tx_id) {
+            Some(a) => a.clone(),
+            None => return Err(PaymentServiceError::TransactionDoesNotExist { tx_id }),
         };
+        // bail out if the transaction belongs to another client
+        if tx.client_id != client_id {
+            return Err(PaymentServiceError::ClientMismatch { client_id, tx_id });
+        }
 
+        // get the account in the currency of the transaction
+        let acc = match self.ac_store.find((client_id, tx.currency)) {
+            Some(a) => a.clone(),
+            None => return Err(PaymentServiceError::AccountDoesNotExist { client_id }),
+        };
         // bail out if account is locked
         if acc.locked {
-            return Err(anyhow!("PaymentServiceError::AccountLocked"));
+            return Err(PaymentServiceError::AccountLocked { client_id });
         }
+        // handle incorrect transaction state
+        if tx.state != state as u8 {
+            return Err(PaymentServiceError::InvalidTransactionState { tx_id });
+        }
+        Ok((acc, tx))
+    }
 
-        // bail out if insufficient funds
-        if acc.available() < Account::to_fixed(amount) {
-            return Err(anyhow!("PaymentServiceError::InsufficientFunds"));
+    /// The fee transaction charged for a transaction, if it was charged one
+    fn fee_for(&mut self, tx: &Transaction) -> Option<Transaction> {
+        self.tx_store
+            .find(Transaction::fee_tx_id(tx.tx_id))
+            .filter(|fee| fee.fee_for == Some(tx.tx_id))
+            .cloned()
+    }
+
+    /// The accounts an operation on an existing transaction changes: the client's, as found by
+    /// `find_for_dispute()`, and for a transfer also the destination's. Like the transfer itself,
+    /// operations on it need both accounts to exist and be unlocked.
+    fn with_destination(&mut self, acc: Account, tx: &Transaction) -> Result<Vec<Account>> {
+        let mut accounts = vec![acc];
+        if let Some(client_id) = tx.destination {
+            let destination = match self.ac_store.find((client_id, tx.currency)) {
+                Some(a) => a.clone(),
+                None => return Err(PaymentServiceError::AccountDoesNotExist { client_id }),
+            };
+            if destination.locked {
+                return Err(PaymentServiceError::AccountLocked { client_id });
+            }
+            accounts.push(destination);
         }
+        Ok(accounts)
+    }
+}
 
-        self.tx_store.update(
-            tx_id,
-            Transaction::new(
-                tx_id,
-                Transaction::transaction_type_encode(TransactionType::Withdrawal),
-                client_id,
-                amount,
-                Transaction::transaction_state_encode(TransactionState::Normal),
+/// Apply events to the accounts they concern, which must be among `accounts`, and to the
+/// transactions they concern, adding those they create to `transactions`
+fn apply_events(
+    accounts: &mut [Account],
+    transactions: &mut Vec<Transaction>,
+    events: &[Event],
+) -> Result<()> {
+    for event in events {
+        let index = event
+            .tx_id()
+            .and_then(|tx_id| transactions.iter().position(|tx| tx.tx_id == tx_id));
+        let mut tx = index.map(|index| transactions[index].clone());
+        let key = event.account_key(tx.as_ref());
+        let acc = match accounts.iter_mut().find(|acc| acc.key() == key) {
+            Some(acc) => acc,
+            None => panic!(
+                "{} event for an account the operation didn't change",
+                event.kind()
             ),
-        );
+        };
+        if event.apply(acc, &mut tx).is_err() {
+            return Err(PaymentServiceError::BalanceOverflow {
+                client_id: acc.client_id,
+            });
+        }
+        match (index, tx) {
+            (Some(index), Some(tx)) => transactions[index] = tx,
+            (None, Some(tx)) => transactions.push(tx),
+            _ => {}
+        }
+    }
+    Ok(())
+}
 
-        // update account
-        let acc = Account {
-            client_id,
-            available: acc.available - Account::to_fixed(amount),
-            held: acc.held,
-            total: acc.total - Account::to_fixed(amount),
-            locked: false,
+/// An event for each client whose account a transaction moved funds in: its own, and the
+/// destination's for a transfer
+fn for_each_client(tx: &Transaction, event: impl Fn(u16) -> Event) -> Vec<Event> {
+    std::iter::once(tx.client_id)
+        .chain(tx.destination)
+        .map(event)
+        .collect()
+}
+
+impl PaymentServiceTrait for PaymentService {
+    fn deposit(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<()> {
+        // bail out on a redelivered transaction
+        if self.tx_store.find(tx_id).is_some() {
+            return Err(PaymentServiceError::DuplicateTransaction { tx_id });
+        }
+
+        let (amount, currency, conversion) = self.convert(tx_id, amount, currency)?;
+
+        // get account, a new one is only stored along with the deposit
+        let acc = match self.ac_store.find((client_id, currency)) {
+            Some(a) => a.clone(),
+            None => Account::build_default_account(client_id, currency),
         };
-        let _ = self.ac_store.update(client_id, acc);
+
+        // bail out if account is locked
+        if acc.locked {
+            return Err(PaymentServiceError::AccountLocked { client_id });
+        }
+
+        // bail out if the deposit doesn't cover its fee
+        let fee = self.fees.fee(TransactionType::Deposit, amount, currency);
+        if amount < fee {
+            return Err(PaymentServiceError::InsufficientFunds { client_id, tx_id });
+        }
+
+        let mut events = vec![Event::DepositApplied {
+            client_id,
+            tx_id,
+            amount,
+            currency,
+            conversion,
+        }];
+        if fee > Amount::ZERO {
+            events.push(Event::FeeCharged {
+                client_id,
+                tx_id: Transaction::fee_tx_id(tx_id),
+                amount: fee,
+                currency,
+            });
+        }
+        self.apply(vec![acc], vec![], &events)?;
 
         Ok(())
     }
 
-    fn dispute(&mut self, client_id: u16, tx_id: u32) -> Result<()> {
+    fn withdrawal(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<()> {
+        // bail out on a redelivered transaction
+        if self.tx_store.find(tx_id).is_some() {
+            return Err(PaymentServiceError::DuplicateTransaction { tx_id });
+        }
+
+        let (amount, currency, conversion) = self.convert(tx_id, amount, currency)?;
+
         // get account
-        let acc = match self.ac_store.find(client_id) {
-            Some(a) => a,
-            None => return Err(anyhow!("PaymentServiceError::AccountDoesNotExist")),
+        let acc = match self.ac_store.find((client_id, currency)) {
+            Some(a) => a.clone(),
+            None => return Err(PaymentServiceError::AccountDoesNotExist { client_id }),
         };
+
         // bail out if account is locked
         if acc.locked {
-            return Err(anyhow!("PaymentServiceError::AccountLocked"));
+            return Err(PaymentServiceError::AccountLocked { client_id });
         }
-        // handle non-existant transaction
-        let tx = match self.tx_store.find(tx_id) {
-            Some(a) => a,
-            None => return Err(anyhow!("PaymentServiceError::TransactionDoesNotExist")),
-        };
-        // handle incorrect transaction state
-        if tx.state != TransactionState::Normal as u8 {
-            return Err(anyhow!("PaymentServiceError::InvalidTransactionState"));
+
+        // bail out if the withdrawal and its fee would take the account beyond its credit limit
+        let fee = self.fees.fee(TransactionType::Withdrawal, amount, currency);
+        if acc.headroom() < amount + fee {
+            return Err(PaymentServiceError::InsufficientFunds { client_id, tx_id });
         }
 
-        let acc = Account {
-            client_id: acc.client_id,
-            available: acc.available - tx.amount,
-            held: acc.held + tx.amount,
-            total: acc.total,
-            locked: acc.locked,
+        let mut events = vec![Event::WithdrawalApplied {
+            client_id,
+            tx_id,
+            amount,
+            currency,
+            conversion,
+        }];
+        if fee > Amount::ZERO {
+            events.push(Event::FeeCharged {
+                client_id,
+                tx_id: Transaction::fee_tx_id(tx_id),
+                amount: fee,
+                currency,
+            });
+        }
+        self.apply(vec![acc], vec![], &events)?;
+
+        Ok(())
+    }
+
+    fn transfer(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+        destination: u16,
+    ) -> Result<()> {
+        // bail out on a redelivered transaction
+        if self.tx_store.find(tx_id).is_some() {
+            return Err(PaymentServiceError::DuplicateTransaction { tx_id });
+        }
+
+        // bail out on a transfer to the client's own account
+        if destination == client_id {
+            return Err(PaymentServiceError::InvalidDestination { client_id, tx_id });
+        }
+
+        let (amount, currency, conversion) = self.convert(tx_id, amount, currency)?;
+
+        // get the accounts, a new destination account is only stored along with the transfer
+        let acc = match self.ac_store.find((client_id, currency)) {
+            Some(a) => a.clone(),
+            None => return Err(PaymentServiceError::AccountDoesNotExist { client_id }),
         };
-        let tx = Transaction {
-            state: TransactionState::Disputed as u8,
-            ..*tx
+        let dest = match self.ac_store.find((destination, currency)) {
+            Some(a) => a.clone(),
+            None => Account::build_default_account(destination, currency),
         };
 
-        self.ac_store.update(acc.client_id, acc);
-        self.tx_store.update(tx.tx_id, tx);
+        // bail out if either account is locked
+        for account in [&acc, &dest] {
+            if account.locked {
+                return Err(PaymentServiceError::AccountLocked {
+                    client_id: account.client_id,
+                });
+            }
+        }
+
+        // bail out if the transfer would take the account beyond its credit limit
+        if acc.headroom() < amount {
+            return Err(PaymentServiceError::InsufficientFunds { client_id, tx_id });
+        }
+
+        self.apply(
+            vec![acc, dest],
+            vec![],
+            &[
+                Event::TransferSent {
+                    client_id,
+                    tx_id,
+                    amount,
+                    currency,
+                    conversion,
+                },
+                Event::TransferReceived {
+                    client_id: destination,
+                    tx_id,
+                },
+            ],
+        )?;
 
         Ok(())
     }
 
-    fn resolve(&mut self, client_id: u16, tx_id: u32) -> Result<()> {
-        // get account
-        let acc = match self.ac_store.find(client_id) {
-            Some(a) => a,
-            None => return Err(anyhow!("PaymentServiceError::AccountDoesNotExist")),
+    fn dispute(&mut self, client_id: u16, tx_id: u32) -> Result<()> {
+        let (acc, tx) = self.find_for_dispute(client_id, tx_id, TransactionState::Normal)?;
+
+        // bail out if policy doesn't allow the transaction type to be disputed, fees are only
+        // reversed along with the transaction they were charged for
+        let disputable = match Transaction::transaction_type_decode(tx.tx_type) {
+            TransactionType::Withdrawal => self.policy.withdrawals_disputable,
+            TransactionType::Fee => false,
+            _ => true,
         };
-        // bail out if account is locked
-        if acc.locked {
-            return Err(anyhow!("PaymentServiceError::AccountLocked"));
+        if !disputable {
+            return Err(PaymentServiceError::TransactionNotDisputable { tx_id });
         }
-        // handle non-existant transaction
-        let tx = match self.tx_store.find(tx_id) {
-            Some(a) => a,
-            None => return Err(anyhow!("PaymentServiceError::TransactionDoesNotExist")),
-        };
-        // handle incorrect transaction state
-        if tx.state != TransactionState::Disputed as u8 {
-            return Err(anyhow!("PaymentServiceError::InvalidTransactionState"));
+
+        // bail out if a deposit was refunded in full, leaving nothing to hold
+        if tx.unrefunded() == Amount::ZERO {
+            return Err(PaymentServiceError::TransactionNotDisputable { tx_id });
         }
 
-        let acc = Account {
-            client_id: acc.client_id,
-            available: acc.available + tx.amount,
-            held: acc.held - tx.amount,
-            total: acc.total,
-            locked: acc.locked,
-        };
-        let tx = Transaction {
-            state: TransactionState::Normal as u8,
-            ..*tx
-        };
+        let events = for_each_client(&tx, |client_id| Event::FundsHeld { client_id, tx_id });
+        let accounts = self.with_destination(acc, &tx)?;
+        self.apply(accounts, vec![tx], &events)?;
+
+        Ok(())
+    }
+
+    fn resolve(&mut self, client_id: u16, tx_id: u32) -> Result<()> {
+        let (acc, tx) = self.find_for_dispute(client_id, tx_id, TransactionState::Disputed)?;
 
-        self.ac_store.update(acc.client_id, acc);
-        self.tx_store.update(tx.tx_id, tx);
+        let events = for_each_client(&tx, |client_id| Event::FundsReleased { client_id, tx_id });
+        let accounts = self.with_destination(acc, &tx)?;
+        self.apply(accounts, vec![tx], &events)?;
 
         Ok(())
     }
 
     fn chargeback(&mut self, client_id: u16, tx_id: u32) -> Result<()> {
+        let (acc, tx) = self.find_for_dispute(client_id, tx_id, TransactionState::Disputed)?;
+        let currency = tx.currency;
+
+        let accounts = self.with_destination(acc, &tx)?;
+
+        // the fee transaction charged for the transaction is reversed along with it, and a
+        // transfer is reversed for both its clients
+        let mut events = for_each_client(&tx, |client_id| Event::ChargedBack { client_id, tx_id });
+        let mut transactions = vec![tx.clone()];
+        if let Some(fee) = self.fee_for(&tx) {
+            events.push(Event::FeeRefunded {
+                client_id,
+                tx_id: fee.tx_id,
+            });
+            transactions.push(fee);
+        }
+
+        // the chargeback stands even if it takes an account below zero, which is flagged
+        let mut after = accounts.clone();
+        apply_events(&mut after, &mut transactions.clone(), &events)?;
+        for acc in after.iter().filter(|acc| acc.total.is_negative()) {
+            events.push(Event::Overdrawn {
+                client_id: acc.client_id,
+                tx_id,
+                amount: -acc.total,
+            });
+        }
+
+        // both clients of a reversed transfer are locked, as the recipient may be overdrawn
+        events.extend(for_each_client(&tx, |client_id| Event::AccountLocked {
+            client_id,
+            currency,
+        }));
+        self.apply(accounts, transactions, &events)?;
+
+        Ok(())
+    }
+
+    fn authorize(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<()> {
+        // bail out on a redelivered transaction
+        if self.tx_store.find(tx_id).is_some() {
+            return Err(PaymentServiceError::DuplicateTransaction { tx_id });
+        }
+
+        let (amount, currency, conversion) = self.convert(tx_id, amount, currency)?;
+
         // get account
-        let acc = match self.ac_store.find(client_id) {
-            Some(a) => a,
-            None => return Err(anyhow!("PaymentServiceError::AccountDoesNotExist")),
+        let acc = match self.ac_store.find((client_id, currency)) {
+            Some(a) => a.clone(),
+            None => return Err(PaymentServiceError::AccountDoesNotExist { client_id }),
         };
+
         // bail out if account is locked
         if acc.locked {
-            return Err(anyhow!("PaymentServiceError::AccountLocked"));
+            return Err(PaymentServiceError::AccountLocked { client_id });
         }
-        // handle non-existant transaction
-        let tx = match self.tx_store.find(tx_id) {
-            Some(a) => a,
-            None => return Err(anyhow!("PaymentServiceError::TransactionDoesNotExist")),
-        };
-        // handle incorrect transaction state
-        if tx.state != TransactionState::Disputed as u8 {
-            return Err(anyhow!("PaymentServiceError::InvalidTransactionState"));
+
+        // bail out if holding the funds would take the account beyond its credit limit
+        if acc.headroom() < amount {
+            return Err(PaymentServiceError::InsufficientFunds { client_id, tx_id });
         }
 
-        let acc = Account {
-            client_id: acc.client_id,
-            available: acc.available,
-            held: acc.held - tx.amount,
-            total: acc.total - tx.amount,
-            locked: true,
-        };
-        let tx = Transaction {
-            state: TransactionState::Reversed as u8,
-            ..*tx
+        self.apply(
+            vec![acc],
+            vec![],
+            &[Event::FundsAuthorized {
+                client_id,
+                tx_id,
+                amount,
+                currency,
+                conversion,
+            }],
+        )?;
+
+        Ok(())
+    }
+
+    fn capture(&mut self, client_id: u16, tx_id: u32, amount: Option<Amount>) -> Result<()> {
+        let (acc, tx) = self.find_for_dispute(client_id, tx_id, TransactionState::Authorized)?;
+
+        // bail out if more is captured than the authorization still holds
+        let amount = amount.unwrap_or_else(|| tx.uncaptured());
+        if amount > tx.uncaptured() {
+            return Err(PaymentServiceError::ExceedsAuthorization { tx_id });
+        }
+
+        self.apply(
+            vec![acc],
+            vec![tx],
+            &[Event::FundsCaptured {
+                client_id,
+                tx_id,
+                amount,
+            }],
+        )?;
+
+        Ok(())
+    }
+
+    fn void(&mut self, client_id: u16, tx_id: u32) -> Result<()> {
+        let (acc, tx) = self.find_for_dispute(client_id, tx_id, TransactionState::Authorized)?;
+
+        self.apply(
+            vec![acc],
+            vec![tx],
+            &[Event::AuthorizationVoided { client_id, tx_id }],
+        )?;
+
+        Ok(())
+    }
+
+    fn refund(&mut self, client_id: u16, tx_id: u32, amount: Amount) -> Result<()> {
+        let (acc, tx) = self.find_for_dispute(client_id, tx_id, TransactionState::Normal)?;
+
+        // bail out if the transaction isn't a deposit
+        if !matches!(
+            Transaction::transaction_type_decode(tx.tx_type),
+            TransactionType::Deposit
+        ) {
+            return Err(PaymentServiceError::TransactionNotRefundable { tx_id });
+        }
+
+        // bail out if more is refunded than is left of the deposit
+        if amount > tx.unrefunded() {
+            return Err(PaymentServiceError::ExceedsRefundable { tx_id });
+        }
+
+        // bail out if the refund would take the account beyond its credit limit
+        if acc.headroom() < amount {
+            return Err(PaymentServiceError::InsufficientFunds { client_id, tx_id });
+        }
+
+        self.apply(
+            vec![acc],
+            vec![tx],
+            &[Event::DepositRefunded {
+                client_id,
+                tx_id,
+                amount,
+            }],
+        )?;
+
+        Ok(())
+    }
+
+    fn expire_authorizations(&mut self) {
+        if !self.policy.authorizations_expire() {
+            return;
+        }
+        let (policy, position) = (self.policy.clone(), self.position);
+        let expired: Vec<u32> = self
+            .authorizations()
+            .iter()
+            .filter(|(_, &authorized)| policy.authorization_expired(authorized, position))
+            .map(|(&tx_id, _)| tx_id)
+            .collect();
+
+        // authorizations expire even on locked accounts, releasing the funds they held
+        for tx_id in expired {
+            let tx = match self.tx_store.find(tx_id) {
+                Some(tx) if tx.state == TransactionState::Authorized as u8 => tx.clone(),
+                _ => continue,
+            };
+            let acc = match self.ac_store.find((tx.client_id, tx.currency)) {
+                Some(a) => a.clone(),
+                None => continue,
+            };
+            let client_id = tx.client_id;
+            // the funds go back to the available balance they were held from, within the total
+            // the account already holds, and the event creates no transaction, so this can't fail
+            self.apply(
+                vec![acc],
+                vec![tx],
+                &[Event::AuthorizationExpired { client_id, tx_id }],
+            )
+            .expect("Expiring an authorization failed");
+        }
+    }
+
+    fn set_credit_limit(
+        &mut self,
+        client_id: u16,
+        currency: Currency,
+        limit: Amount,
+    ) -> Result<()> {
+        // get account, a new one is only stored along with its limit
+        let acc = match self.ac_store.find((client_id, currency)) {
+            Some(a) if a.credit_limit == limit => return Ok(()),
+            Some(a) => a.clone(),
+            None => Account::build_default_account(client_id, currency),
         };
 
-        self.ac_store.update(acc.client_id, acc);
-        self.tx_store.update(tx.tx_id, tx);
+        self.apply(
+            vec![acc],
+            vec![],
+            &[Event::CreditLimitSet {
+                client_id,
+                currency,
+                limit,
+            }],
+        )?;
 
         Ok(())
     }
 
-    fn get_account<'a>(&'a mut self, client_id: u16) -> Option<&'a Account> {
-        self.ac_store.find(client_id)
+    fn get_account(&mut self, client_id: u16, currency: Currency) -> Option<&Account> {
+        self.ac_store.find((client_id, currency))
     }
 
-    fn get_accounts<'a>(&'a mut self) -> Vec<&'a Account> {
+    fn get_accounts(&mut self) -> Vec<&Account> {
         self.ac_store.find_all()
     }
 
-    fn get_transaction<'a>(&'a mut self, tx_id: u32) -> Option<&'a Transaction> {
+    fn get_transaction(&mut self, tx_id: u32) -> Option<&Transaction> {
         self.tx_store.find(tx_id)
     }
 
-    fn get_transactions<'a>(&'a mut self) -> Vec<&'a Transaction> {
+    fn get_transactions(&mut self) -> Vec<&Transaction> {
         self.tx_store.find_all()
     }
+
+    fn get_events(&mut self) -> Vec<&RecordedEvent> {
+        self.ev_store.find_all()
+    }
+
+    fn get_last_event(&mut self) -> Option<RecordedEvent> {
+        self.ev_store.last()
+    }
+
+    fn get_base_currency(&self) -> Option<Currency> {
+        self.exchange.as_ref().map(Exchange::base)
+    }
+
+    fn set_position(&mut self, position: Position) {
+        self.position = position;
+    }
+
+    fn get_account_at(
+        &mut self,
+        client_id: u16,
+        currency: Currency,
+        point: PointInTime,
+    ) -> Option<Account> {
+        event::replay_until(self.ev_store.find_all(), point)
+            .accounts
+            .remove(&(client_id, currency))
+    }
+
+    fn get_accounts_at(&mut self, point: PointInTime) -> Vec<Account> {
+        event::replay_until(self.ev_store.find_all(), point)
+            .accounts
+            .into_values()
+            .collect()
+    }
+
+    fn get_trial_balance(&mut self) -> Vec<(LedgerAccount, Amount)> {
+        let events = self.ev_store.find_all();
+        event::replay(events.into_iter().map(|recorded| &recorded.event))
+            .ledger
+            .trial_balance()
+    }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_display_includes_kind_and_ids() {
+        let error = PaymentServiceError::InsufficientFunds {
+            client_id: 2,
+            tx_id: 5,
+        };
+        assert_eq!(error.kind(), "InsufficientFunds");
+        assert_eq!(
+            error.to_string(),
+            "PaymentServiceError::InsufficientFunds (client: 2, tx: 5)"
+        );
+
+        let error = PaymentServiceError::TransactionDoesNotExist { tx_id: 7 };
+        assert_eq!(error.client_id(), None);
+        assert_eq!(error.tx_id(), Some(7));
+        assert_eq!(
+            error.to_string(),
+            "PaymentServiceError::TransactionDoesNotExist (tx: 7)"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use csv::{ReaderBuilder, Trim};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::core::amount::Amount;
+use crate::core::currency::Currency;
+use crate::core::entities::account::AccountKey;
+
+/// Settings of client accounts that are applied before any input is processed.
+///
+/// Profiles are read from a CSV file with a `client, limit, currency` row per account, where
+/// `limit` is how far below zero withdrawals may take the available balance of the client's
+/// account in `currency`. Input without a currency column, or with an empty one, is in the
+/// default currency.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct ClientProfiles {
+    credit_limits: BTreeMap<AccountKey, Amount>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileLine {
+    client: u16,
+    limit: Amount,
+    #[serde(default)]
+    currency: Currency,
+}
+
+impl ClientProfiles {
+    /// Set the credit limit of a client's account in a currency
+    pub fn insert(&mut self, key: AccountKey, limit: Amount) -> Result<(), &'static str> {
+        let (_, currency) = key;
+        if !currency.accepts(limit) {
+            return Err("limit has more decimal places than its currency");
+        }
+        match self.credit_limits.insert(key, limit) {
+            Some(_) => Err("more than one profile for the same account"),
+            None => Ok(()),
+        }
+    }
+
+    /// Read a client profile file
+    pub fn read<R: io::Read>(input: R) -> io::Result<ClientProfiles> {
+        let mut profiles = ClientProfiles::default();
+        let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+        for result in reader.deserialize() {
+            let line: ProfileLine = result.map_err(|error| invalid_data(&error.to_string()))?;
+            profiles
+                .insert((line.client, line.currency), line.limit)
+                .map_err(invalid_data)?;
+        }
+        Ok(profiles)
+    }
+
+    /// The credit limit of every account with a profile, ordered by client and then currency
+    pub fn credit_limits(&self) -> impl Iterator<Item = (AccountKey, Amount)> + '_ {
+        self.credit_limits.iter().map(|(&key, &limit)| (key, limit))
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid client profiles: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn it_reads_credit_limits() {
+        let usd: Currency = "USD".parse().unwrap();
+        let profiles = ClientProfiles::read(
+            "client, limit, currency\n\
+             2, 50.25, USD\n\
+             2, 100, \n\
+             1, 0, \n"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            profiles.credit_limits().collect::<Vec<_>>(),
+            vec![
+                ((1, Currency::DEFAULT), Amount::ZERO),
+                ((2, Currency::DEFAULT), amount("100")),
+                ((2, usd), amount("50.25")),
+            ]
+        );
+
+        // the currency column is optional
+        let profiles = ClientProfiles::read("client, limit\n3, 10\n".as_bytes()).unwrap();
+        assert_eq!(
+            profiles.credit_limits().collect::<Vec<_>>(),
+            vec![((3, Currency::DEFAULT), amount("10"))]
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_profiles() {
+        for profiles in [
+            "client, limit, currency\n1, -5, \n",
+            "client, limit, currency\n1, , \n",
+            "client, limit, currency\n1, 5, \n1, 10, \n",
+            "client, limit, currency\n1, 0.001, USD\n",
+            "client, limit, currency\n1, 5, XYZ\n",
+        ] {
+            assert!(
+                ClientProfiles::read(profiles.as_bytes()).is_err(),
+                "{}",
+                profiles
+            );
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use crc::{Crc, CRC_32_ISO_HDLC};
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use std::io;
+
+use crate::core::entities::account::Account;
+use crate::core::entities::transaction::Transaction;
+use crate::core::event::RecordedEvent;
+use crate::repositories::account::AccountRepositoryTrait;
+use crate::repositories::event::EventRepositoryTrait;
+use crate::repositories::record::{
+    account_from_record, account_to_record, event_from_record, event_to_record,
+    transaction_from_record, transaction_to_record, ACCOUNT_FIELDS, EVENT_FIELDS,
+    TRANSACTION_FIELDS,
+};
+use crate::repositories::transaction::TransactionRepositoryTrait;
+use crate::repositories::unit_of_work::UnitOfWork;
+use crate::services::payment::PaymentServiceTrait;
+
+const CHECKSUM: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+const HEADER: &str = "payments-snapshot";
+const ACCOUNT: &str = "account";
+const TRANSACTION: &str = "transaction";
+const EVENT: &str = "event";
+const CHECKSUM_PREFIX: &[u8] = b"checksum,";
+
+/// Complete state of a payment service: its accounts, its transactions and the history of events
+/// that led to them.
+///
+/// Snapshots are written as CSV records: a `payments-snapshot,<version>` header, an `account`,
+/// `transaction` or `event` record per entity (fields as in the persistent repositories) and a
+/// final `checksum,<crc32>` record covering every byte before it. Readers reject snapshots of any
+/// other version, and records with a different number of fields, so any change to the records
+/// must come with a new version.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Snapshot {
+    pub accounts: Vec<Account>,
+    pub transactions: Vec<Transaction>,
+    pub events: Vec<RecordedEvent>,
+}
+
+impl Snapshot {
+    pub const VERSION: u32 = 1;
+
+    /// Copy the state of a payment service
+    pub fn capture(payment_service: &mut dyn PaymentServiceTrait) -> Snapshot {
+        Snapshot {
+            accounts: payment_service
+                .get_accounts()
+                .into_iter()
+                .cloned()
+                .collect(),
+            transactions: payment_service
+                .get_transactions()
+                .into_iter()
+                .cloned()
+                .collect(),
+            events: payment_service.get_events().into_iter().copied().collect(),
+        }
+    }
+
+    /// Write the snapshot's state to the repositories of a fresh service, as a single unit of work
+    pub fn restore(
+        &self,
+        tx_store: &mut dyn TransactionRepositoryTrait,
+        ac_store: &mut dyn AccountRepositoryTrait,
+        ev_store: &mut dyn EventRepositoryTrait,
+    ) {
+        let mut uow = UnitOfWork::begin(tx_store, ac_store).with_events(ev_store);
+        for account in &self.accounts {
+            uow.accounts().update(account.key(), account.clone());
+        }
+        for transaction in &self.transactions {
+            uow.transactions()
+                .update(transaction.tx_id, transaction.clone());
+        }
+        for &event in &self.events {
+            uow.events().append(event);
+        }
+        uow.commit();
+    }
+
+    pub fn write<W: io::Write>(&self, mut output: W) -> io::Result<()> {
+        let mut writer = WriterBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_writer(Vec::new());
+
+        writer.write_record([HEADER, &Self::VERSION.to_string()])?;
+        for account in &self.accounts {
+            writer.write_record(&with_kind(ACCOUNT, &account_to_record(account)))?;
+        }
+        for transaction in &self.transactions {
+            writer.write_record(&with_kind(TRANSACTION, &transaction_to_record(transaction)))?;
+        }
+        for event in &self.events {
+            writer.write_record(&with_kind(EVENT, &event_to_record(event)))?;
+        }
+        let body = writer.into_inner().map_err(|error| error.into_error())?;
+
+        output.write_all(&body)?;
+        output.write_all(CHECKSUM_PREFIX)?;
+        writeln!(output, "{:08x}", CHECKSUM.checksum(&body))?;
+        output.flush()
+    }
+
+    pub fn read<R: io::Read>(mut input: R) -> io::Result<Snapshot> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        let body = verify_checksum(&bytes)?;
+
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(body);
+        let mut records = reader.records();
+
+        match records.next().transpose()? {
+            Some(header) if header.len() == 2 && &header[0] == HEADER => {
+                if header[1].parse() != Ok(Self::VERSION) {
+                    return Err(invalid_data(&format!(
+                        "unsupported snapshot version {}",
+                        &header[1]
+                    )));
+                }
+            }
+            _ => return Err(invalid_data("not a snapshot")),
+        }
+
+        let mut snapshot = Snapshot::default();
+        for record in records {
+            let record = record?;
+            let fields: StringRecord = record.iter().skip(1).collect();
+            match (record.get(0), fields.len()) {
+                (Some(ACCOUNT), ACCOUNT_FIELDS) => {
+                    snapshot.accounts.push(account_from_record(&fields)?)
+                }
+                (Some(TRANSACTION), TRANSACTION_FIELDS) => snapshot
+                    .transactions
+                    .push(transaction_from_record(&fields)?),
+                (Some(EVENT), EVENT_FIELDS) => snapshot.events.push(event_from_record(&fields)?),
+                _ => return Err(invalid_data(&format!("unexpected record {:?}", record))),
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Check the trailing checksum record, returning the bytes it covers
+fn verify_checksum(bytes: &[u8]) -> io::Result<&[u8]> {
+    // The checksum record is the last line, which is terminated
+    let content = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    let start = content
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |index| index + 1);
+    let (body, trailer) = bytes.split_at(start);
+
+    let expected = trailer
+        .strip_prefix(CHECKSUM_PREFIX)
+        .and_then(|checksum| std::str::from_utf8(checksum).ok())
+        .and_then(|checksum| u32::from_str_radix(checksum.trim_end(), 16).ok());
+    match expected {
+        Some(expected) if expected == CHECKSUM.checksum(body) => Ok(body),
+        Some(_) => Err(invalid_data("snapshot checksum doesn't match")),
+        None => Err(invalid_data("snapshot has no checksum")),
+    }
+}
+
+fn with_kind(kind: &str, record: &StringRecord) -> StringRecord {
+    let mut prefixed = StringRecord::from(vec![kind]);
+    prefixed.extend(record.iter());
+    prefixed
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::currency::Currency;
+    use crate::core::event::{Event, Position};
+    use crate::core::exchange::Conversion;
+    use crate::repositories::account::in_memory::AccountRepositoryInMemory;
+    use crate::repositories::event::in_memory::EventRepositoryInMemory;
+    use crate::repositories::transaction::in_memory::TransactionRepositoryInMemory;
+    use crate::services::payment::{PaymentService, PaymentServicePolicy};
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn build_payments_service() -> PaymentService {
+        PaymentService::new(
+            Box::new(TransactionRepositoryInMemory::new()),
+            Box::new(AccountRepositoryInMemory::new()),
+        )
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            accounts: vec![
+                Account::new(1, amount("1.5"), amount("2.0"), amount("3.5"), false),
+                Account::new(2, amount("0.0"), amount("0.0"), amount("0.0"), true),
+                Account::new(2, amount("1.0"), amount("0.0"), amount("1.0"), false)
+                    .with_currency("USD".parse().unwrap())
+                    .with_credit_limit(amount("100")),
+            ],
+            transactions: vec![
+                Transaction::new(1, 0, 1, amount("2.0"), 1),
+                Transaction::new(2, 0, 2, amount("1.0"), 2),
+                Transaction::new(4, 0, 2, amount("1.0"), 0).with_currency("USD".parse().unwrap()),
+                Transaction::new(3, 1, 1, amount("0.5"), 0),
+                Transaction::new(u32::MAX - 3, 4, 1, amount("0.25"), 0).with_fee_for(Some(3)),
+                Transaction::new(5, 0, 1, amount("5.5"), 0).with_conversion(Some(Conversion {
+                    amount: amount("5"),
+                    currency: "EUR".parse().unwrap(),
+                    rate: "1.1".parse().unwrap(),
+                })),
+            ],
+            events: vec![RecordedEvent {
+                event: Event::FundsHeld {
+                    client_id: 1,
+                    tx_id: 1,
+                },
+                position: Position {
+                    line: 3,
+                    timestamp: 1_700_000_000,
+                },
+            }],
+        }
+    }
+
+    fn with_checksum(body: &[u8]) -> Vec<u8> {
+        let mut bytes = body.to_vec();
+        bytes.extend(format!("checksum,{:08x}\n", CHECKSUM.checksum(body)).bytes());
+        bytes
+    }
+
+    fn written(snapshot: &Snapshot) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn it_writes_a_stable_format() {
+        let bytes = written(&Snapshot {
+            accounts: vec![Account::new(
+                1,
+                amount("1.5"),
+                Amount::ZERO,
+                amount("1.5"),
+                false,
+            )],
+            transactions: vec![Transaction::new(7, 0, 1, amount("1.5"), 0)],
+            events: vec![RecordedEvent {
+                event: Event::DepositApplied {
+                    client_id: 1,
+                    tx_id: 7,
+                    amount: amount("1.5"),
+                    currency: Currency::DEFAULT,
+                    conversion: None,
+                },
+                position: Position {
+                    line: 2,
+                    timestamp: 1_700_000_000,
+                },
+            }],
+        });
+
+        let text = String::from_utf8(bytes).unwrap();
+        let (body, checksum) = text.split_at(text.find("checksum,").unwrap());
+        assert_eq!(
+            body,
+            "payments-snapshot,1\n\
+             account,1,15000,0,15000,false,,0\n\
+             transaction,7,0,1,15000,0,,,,,,0,,0\n\
+             event,DepositApplied,1,7,15000,2,1700000000,,,,\n"
+        );
+        assert_eq!(
+            checksum,
+            format!("checksum,{:08x}\n", CHECKSUM.checksum(body.as_bytes()))
+        );
+    }
+
+    #[test]
+    fn it_round_trips_through_a_fresh_service() {
+        let mut ps = build_payments_service();
+        ps.deposit(1, 1, amount("5.0"), Currency::DEFAULT).unwrap();
+        ps.deposit(2, 2, amount("3.0"), Currency::DEFAULT).unwrap();
+        ps.withdrawal(1, 3, amount("1.5"), Currency::DEFAULT)
+            .unwrap();
+        ps.dispute(2, 2).unwrap();
+
+        let snapshot = Snapshot::read(written(&Snapshot::capture(&mut ps)).as_slice()).unwrap();
+
+        let mut tr = TransactionRepositoryInMemory::new();
+        let mut ar = AccountRepositoryInMemory::new();
+        let mut er = EventRepositoryInMemory::new();
+        snapshot.restore(&mut tr, &mut ar, &mut er);
+        let mut restored = PaymentService::with_event_store(
+            Box::new(tr),
+            Box::new(ar),
+            Box::new(er),
+            PaymentServicePolicy::default(),
+        );
+
+        assert_eq!(Snapshot::capture(&mut restored), Snapshot::capture(&mut ps));
+        // The history is restored along with the state it led to
+        assert_eq!(restored.get_trial_balance(), ps.get_trial_balance());
+        // The restored transaction states carry on from where they were
+        assert!(restored.resolve(2, 2).is_ok());
+    }
+
+    #[test]
+    fn it_round_trips_an_empty_snapshot() {
+        let empty = Snapshot::default();
+        assert_eq!(Snapshot::read(written(&empty).as_slice()).unwrap(), empty);
+    }
+
+    #[test]
+    fn it_rejects_a_corrupted_snapshot() {
+        let mut bytes = written(&snapshot());
+        let position = bytes.iter().position(|&byte| byte == b'5').unwrap();
+        bytes[position] = b'6';
+
+        assert!(Snapshot::read(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn it_rejects_a_truncated_snapshot() {
+        let bytes = written(&snapshot());
+
+        for len in [0, 10, bytes.len() - 20, bytes.len() - 3] {
+            assert!(Snapshot::read(&bytes[..len]).is_err(), "length {}", len);
+        }
+        assert_eq!(Snapshot::read(bytes.as_slice()).unwrap(), snapshot());
+    }
+
+    #[test]
+    fn it_rejects_records_with_another_number_of_fields() {
+        for body in [
+            &b"payments-snapshot,1\naccount,1,15000,0,15000,false\n"[..],
+            b"payments-snapshot,1\ntransaction,7,0,1,15000,0,,,,,0,0,\n",
+            b"payments-snapshot,1\nevent,FundsHeld,1,1,\n",
+        ] {
+            let error = Snapshot::read(with_checksum(body).as_slice()).unwrap_err();
+            assert!(
+                error.to_string().starts_with("unexpected record"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_another_version() {
+        for version in ["0", "2", "9"] {
+            let bytes = with_checksum(format!("payments-snapshot,{}\n", version).as_bytes());
+
+            let error = Snapshot::read(bytes.as_slice()).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("unsupported snapshot version {}", version)
+            );
+        }
+    }
+}
@@ -0,0 +1,1113 @@
+use payments::core::amount::{Amount, AmountFormat};
+use payments::core::currency::Currency;
+use payments::core::entities::account::Account;
+use payments::core::entities::transaction::{Transaction, TransactionState, TransactionType};
+use payments::core::event::{Event, PointInTime, Position, RecordedEvent};
+use payments::core::exchange::RoundingMode;
+use payments::repositories::account::file::AccountRepositoryFile;
+use payments::repositories::account::in_memory::AccountRepositoryInMemory;
+use payments::repositories::account::AccountRepositoryTrait;
+use payments::repositories::event::file::EventRepositoryFile;
+use payments::repositories::event::EventRepositoryTrait;
+use payments::repositories::journal::Journal;
+use payments::repositories::transaction::file::TransactionRepositoryFile;
+use payments::repositories::transaction::in_memory::TransactionRepositoryInMemory;
+use payments::repositories::transaction::TransactionRepositoryTrait;
+use payments::services::exchange::Exchange;
+use payments::services::payment::{PaymentService, PaymentServicePolicy, PaymentServiceTrait};
+use payments::{process, query, run, trial_balance, verify, Backend, Command, Config, ErrorPolicy};
+
+fn amount(value: &str) -> Amount {
+    value.parse().unwrap()
+}
+
+fn build_payments_service() -> PaymentService {
+    let transaction_repository = Box::new(TransactionRepositoryInMemory::new());
+    let account_repository = Box::new(AccountRepositoryInMemory::new());
+    PaymentService::new(transaction_repository, account_repository)
+}
+
+fn process_str(input: &str, config: &Config) -> (anyhow::Result<()>, String) {
+    let mut ps = build_payments_service();
+    let mut output = Vec::new();
+    let result = process(input.as_bytes(), &mut output, config, &mut ps);
+    (result, String::from_utf8(output).unwrap())
+}
+
+#[test]
+fn process_reads_from_an_in_memory_buffer() {
+    let input = "\
+type,       client, tx, amount
+deposit,         1,  1,    1.0
+deposit,         2,  2,    2.0
+deposit,         1,  3,    2.0
+withdrawal,      1,  4,    1.5
+withdrawal,      2,  5,    3.0
+";
+    let (result, output) = process_str(input, &Config::default());
+
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "\
+client, available, held, total, locked
+1, 1.5000, 0.0000, 1.5000, false
+2, 2.0000, 0.0000, 2.0000, false
+"
+    );
+}
+
+#[test]
+fn process_leaves_state_in_the_payment_service() {
+    let input = "\
+type, client, tx, amount
+deposit, 7, 1, 3.25
+";
+    let mut ps = build_payments_service();
+    let mut output = Vec::new();
+
+    process(input.as_bytes(), &mut output, &Config::default(), &mut ps).unwrap();
+
+    assert_eq!(ps.get_accounts().len(), 1);
+    assert_eq!(
+        ps.get_account(7, Currency::DEFAULT)
+            .unwrap()
+            .available
+            .to_string(),
+        "3.2500"
+    );
+}
+
+#[test]
+fn process_formats_amounts_from_config() {
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 1.5
+";
+    let config = Config {
+        amount_format: AmountFormat {
+            fraction_digits: 4,
+            trim_trailing_zeros: true,
+        },
+        ..Config::default()
+    };
+    let (result, output) = process_str(input, &config);
+
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "client, available, held, total, locked\n1, 1.5, 0, 1.5, false\n"
+    );
+}
+
+#[test]
+fn process_writes_the_outcome_of_every_line() {
+    let path =
+        std::env::temp_dir().join(format!("payments-lib-outcomes-{}.csv", std::process::id()));
+    let config = Config {
+        outcomes: Some(path.to_str().unwrap().to_string()),
+        ..Config::default()
+    };
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 15.0
+deposit, 2, 3,
+xxxx, 1, 4, 1.0
+dispute, 1, 1,
+";
+    let (result, _) = process_str(input, &config);
+    assert!(result.is_ok());
+
+    // A line that didn't parse has no transaction fields
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "\
+line,tx,client,type,outcome,error
+2,1,1,deposit,Applied,
+3,2,1,withdrawal,Rejected,InsufficientFunds
+4,3,2,deposit,Rejected,ValidationError
+5,,,,Rejected,ParseError
+6,1,1,dispute,Applied,
+"
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn lenient_policy_skips_bad_lines() {
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2,
+xxxx, 1, 3, 1.0
+withdrawal, 1, 4, 5.0
+deposit, 1, 5, 0.5
+";
+    let (result, output) = process_str(input, &Config::default());
+
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "client, available, held, total, locked\n1, 1.5000, 0.0000, 1.5000, false\n"
+    );
+}
+
+#[test]
+fn strict_policy_aborts_on_bad_lines() {
+    let config = Config {
+        error_policy: ErrorPolicy::Strict,
+        ..Config::default()
+    };
+
+    // Validation error
+    let (result, _) = process_str("type, client, tx, amount\ndeposit, 1, 1,\n", &config);
+    assert!(result.is_err());
+
+    // Parse error
+    let (result, _) = process_str("type, client, tx, amount\nxxxx, 1, 1, 1.0\n", &config);
+    assert!(result.is_err());
+
+    // Service error
+    let (result, output) =
+        process_str("type, client, tx, amount\nwithdrawal, 1, 1, 1.0\n", &config);
+    let error = result.unwrap_err().to_string();
+    assert!(error.contains("line 2"));
+    assert!(error.contains("AccountDoesNotExist"));
+    assert_eq!(output, ""); // No summary on abort
+}
+
+#[test]
+fn strict_policy_stops_before_the_lines_after_a_bad_one() {
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 15.0
+deposit, 1, 3, 5.0
+";
+    let args = ["payments", "input.csv", "--error-policy", "strict"];
+    let config = Config::new(args.iter().map(|arg| arg.to_string())).unwrap();
+    let mut ps = build_payments_service();
+    let result = process(input.as_bytes(), std::io::sink(), &config, &mut ps);
+
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Error processing input line 3: PaymentServiceError::InsufficientFunds (client: 1, tx: 2)"
+    );
+    assert_eq!(ps.get_transaction(3), None);
+
+    // Without a policy the bad line is skipped rather than aborting the run
+    let config = Config::new(["payments", "input.csv"].iter().map(|arg| arg.to_string())).unwrap();
+    let (result, output) = process_str(input, &config);
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "client, available, held, total, locked\n1, 15.0000, 0.0000, 15.0000, false\n"
+    );
+}
+
+#[test]
+fn quarantine_policy_writes_rejected_rows_with_the_reason() {
+    let path = std::env::temp_dir().join(format!(
+        "payments-lib-quarantine-{}.csv",
+        std::process::id()
+    ));
+    let config = Config {
+        error_policy: ErrorPolicy::Quarantine(path.to_str().unwrap().to_string()),
+        ..Config::default()
+    };
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 15.0
+deposit, 2, 3,
+xxxx, 1, 4, 1.0
+deposit, 1, 5, 1.0
+";
+    let (result, output) = process_str(input, &config);
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "client, available, held, total, locked\n1, 11.0000, 0.0000, 11.0000, false\n"
+    );
+
+    // Rows are written as they were read, after the line number, kind of error and its detail
+    let quarantined = std::fs::read_to_string(&path).unwrap();
+    let rows: Vec<&str> = quarantined.lines().collect();
+    assert_eq!(
+        rows[..3],
+        [
+            "line,error,detail,type,client,tx,amount",
+            "3,InsufficientFunds,\"PaymentServiceError::InsufficientFunds (client: 1, tx: 2)\",withdrawal,1,2,15.0",
+            "4,ValidationError,TransactionLine type 'Deposit' must have an amounts field,deposit,2,3,",
+        ]
+    );
+    assert!(rows[3].starts_with("5,ParseError,"));
+    assert!(rows[3].ends_with(",xxxx,1,4,1.0"));
+    assert_eq!(rows.len(), 4);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn amounts_with_excess_precision_are_rejected() {
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 1.00001
+deposit, 1, 2, 1.0001
+";
+    let (result, output) = process_str(input, &Config::default());
+
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "client, available, held, total, locked\n1, 1.0001, 0.0000, 1.0001, false\n"
+    );
+}
+
+#[test]
+fn amounts_beyond_the_maximum_are_rejected() {
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 17014118346046923173168730371588410
+deposit, 1, 2, 17014118346046923173168730371588410
+deposit, 1, 3, 99999999999999999999.9999
+";
+    let (result, output) = process_str(input, &Config::default());
+
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "client, available, held, total, locked\n\
+         1, 99999999999999999999.9999, 0.0000, 99999999999999999999.9999, false\n"
+    );
+}
+
+#[test]
+fn process_reports_a_row_per_client_and_currency() {
+    let input = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 2.5,
+deposit, 1, 2, 10.25, USD
+deposit, 2, 3, 500, JPY
+deposit, 1, 4, 1.005, USD
+withdrawal, 1, 5, 0.25, USD
+dispute, 1, 2, , USD
+dispute, 1, 2, ,
+deposit, 2, 6, 1, XYZ
+";
+    let mut ps = build_payments_service();
+    let mut output = Vec::new();
+    process(input.as_bytes(), &mut output, &Config::default(), &mut ps).unwrap();
+
+    // Amounts beyond the minor unit of the currency, disputes naming a currency and unknown
+    // currencies are skipped. Amounts are shown to the minor unit of their currency.
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+client, currency, available, held, total, locked
+1, , 2.5000, 0.0000, 2.5000, false
+1, USD, -0.25, 10.25, 10.00, false
+2, JPY, 500, 0, 500, false
+"
+    );
+
+    let mut output = Vec::new();
+    trial_balance(&mut ps, &mut output, &Default::default()).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+account, balance
+client 1 available, 2.5000
+client 1 available USD, -0.25
+client 2 available JPY, 500
+client 1 held USD, 10.25
+external funding, -2.5000
+external funding JPY, -500
+external funding USD, -10.00
+total, 0.0000
+total JPY, 0
+total USD, 0.00
+"
+    );
+}
+
+#[test]
+fn conversions_use_the_rate_in_effect_at_the_time_of_the_line() {
+    let input = "\
+type, client, tx, amount, currency, timestamp
+deposit, 1, 1, 10.00, EUR, 150
+deposit, 1, 2, 10.00, EUR,
+deposit, 1, 3, 10.00, EUR, 250
+deposit, 1, 4, 10.00, EUR, 199
+";
+    let process_with_rates = || {
+        let mut exchange = Exchange::new("USD".parse().unwrap(), RoundingMode::HalfEven);
+        exchange
+            .read_rates("currency, rate, effective\nEUR, 1.1, 100\nEUR, 1.25, 200\n".as_bytes())
+            .unwrap();
+        let mut ps = build_payments_service().with_exchange(exchange);
+        let mut output = Vec::new();
+        process(input.as_bytes(), &mut output, &Config::default(), &mut ps).unwrap();
+        (String::from_utf8(output).unwrap(), ps)
+    };
+
+    // The second line happens at the time of the first, and the last line goes back in time, so
+    // it is rejected
+    let (output, mut ps) = process_with_rates();
+    assert_eq!(
+        output,
+        "\
+client, currency, available, held, total, locked
+1, USD, 34.50, 0.00, 34.50, false
+"
+    );
+    assert_eq!(ps.get_transaction(2).unwrap().amount, amount("11.00"));
+    assert_eq!(ps.get_transaction(3).unwrap().amount, amount("12.50"));
+
+    // Processing the same input again gives the same result, whenever it is run
+    assert_eq!(process_with_rates().0, output);
+}
+
+#[test]
+fn lines_without_a_currency_have_the_precision_of_the_base_currency() {
+    let input = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 1.2345,
+deposit, 1, 2, 1.23,
+withdrawal, 1, 3, 0.001,
+withdrawal, 1, 4, 0.5, USD
+";
+    let exchange = Exchange::new("USD".parse().unwrap(), RoundingMode::HalfEven);
+    let mut ps = build_payments_service().with_exchange(exchange);
+    let mut output = Vec::new();
+    process(input.as_bytes(), &mut output, &Config::default(), &mut ps).unwrap();
+
+    // They are booked in the base currency, so amounts with sub-cent precision are rejected
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+client, currency, available, held, total, locked
+1, USD, 0.73, 0.00, 0.73, false
+"
+    );
+    assert_eq!(ps.get_transaction(1), None);
+    assert_eq!(ps.get_transaction(3), None);
+}
+
+#[test]
+fn state_persists_between_runs_with_file_repositories() {
+    let dir = std::env::temp_dir().join(format!("payments-lib-state-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let build = || {
+        PaymentService::new(
+            Box::new(TransactionRepositoryFile::open(&dir).unwrap()),
+            Box::new(AccountRepositoryFile::open(&dir).unwrap()),
+        )
+    };
+
+    let day1 = "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+";
+    let mut output = Vec::new();
+    process(
+        day1.as_bytes(),
+        &mut output,
+        &Config::default(),
+        &mut build(),
+    )
+    .unwrap();
+
+    // Yesterday's balances and transactions are picked up by a fresh service
+    let day2 = "\
+type, client, tx, amount
+withdrawal, 1, 3, 4.0
+dispute, 2, 2,
+deposit, 1, 1, 10.0
+";
+    let mut output = Vec::new();
+    process(
+        day2.as_bytes(),
+        &mut output,
+        &Config::default(),
+        &mut build(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+client, available, held, total, locked
+1, 6.0000, 0.0000, 6.0000, false
+2, 0.0000, 5.0000, 5.0000, false
+"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recover_redoes_an_operation_interrupted_by_a_crash() {
+    let dir = std::env::temp_dir().join(format!("payments-lib-recover-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let config = || Config {
+        command: Command::Recover,
+        state_dir: Some(dir.to_str().unwrap().to_string()),
+        backend: Backend::File,
+        ..Config::default()
+    };
+
+    // The process died after journaling a deposit and updating the account, but before the
+    // transaction was stored
+    let account = Account::new(1, amount("10.0"), Amount::ZERO, amount("10.0"), false);
+    let transaction = Transaction::new(1, 0, 1, amount("10.0"), 0);
+    let event = RecordedEvent {
+        event: Event::DepositApplied {
+            client_id: 1,
+            tx_id: 1,
+            amount: amount("10.0"),
+            currency: Currency::DEFAULT,
+            conversion: None,
+        },
+        position: Position {
+            line: 2,
+            timestamp: 1_700_000_000,
+        },
+    };
+    let (mut journal, _) = Journal::open(&dir).unwrap();
+    journal
+        .append(
+            std::slice::from_ref(&account),
+            std::slice::from_ref(&transaction),
+            &[event],
+            0,
+        )
+        .unwrap();
+    AccountRepositoryFile::open(&dir)
+        .unwrap()
+        .update((1, Currency::DEFAULT), account);
+    drop(journal);
+
+    assert!(run(config()).is_ok());
+    assert_eq!(
+        TransactionRepositoryFile::open(&dir).unwrap().find(1),
+        Some(&transaction)
+    );
+    assert_eq!(
+        EventRepositoryFile::open(&dir).unwrap().find_all(),
+        vec![&event]
+    );
+
+    // The redone entry is checkpointed, so there is nothing left to recover
+    assert_eq!(
+        std::fs::read_to_string(dir.join(Journal::FILENAME)).unwrap(),
+        ""
+    );
+    assert!(run(config()).is_ok());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recover_logs_the_rest_of_the_events_of_a_partially_committed_operation() {
+    let dir = std::env::temp_dir().join(format!(
+        "payments-lib-recover-events-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let config = Config {
+        command: Command::Recover,
+        state_dir: Some(dir.to_str().unwrap().to_string()),
+        backend: Backend::File,
+        ..Config::default()
+    };
+
+    // The process died while committing a transfer, after logging the first of its events
+    let accounts = [
+        Account::new(1, amount("6.0"), Amount::ZERO, amount("6.0"), false),
+        Account::new(2, amount("4.0"), Amount::ZERO, amount("4.0"), false),
+    ];
+    let transaction = Transaction::new(1, TransactionType::Transfer as u8, 1, amount("4.0"), 0)
+        .with_destination(Some(2));
+    let position = Position {
+        line: 3,
+        timestamp: 1_700_000_000,
+    };
+    let events = [
+        RecordedEvent {
+            event: Event::TransferSent {
+                client_id: 1,
+                tx_id: 1,
+                amount: amount("4.0"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            position,
+        },
+        RecordedEvent {
+            event: Event::TransferReceived {
+                client_id: 2,
+                tx_id: 1,
+            },
+            position,
+        },
+    ];
+    let (mut journal, _) = Journal::open(&dir).unwrap();
+    journal
+        .append(&accounts, std::slice::from_ref(&transaction), &events, 0)
+        .unwrap();
+    EventRepositoryFile::open(&dir).unwrap().append(events[0]);
+    drop(journal);
+
+    assert!(run(config).is_ok());
+    assert_eq!(
+        EventRepositoryFile::open(&dir).unwrap().find_all(),
+        vec![&events[0], &events[1]]
+    );
+    assert_eq!(
+        TransactionRepositoryFile::open(&dir).unwrap().find(1),
+        Some(&transaction)
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn replay_rebuilds_the_state_from_the_event_log() {
+    let dir = std::env::temp_dir().join(format!("payments-lib-replay-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let input = dir.join("input.csv");
+    std::fs::write(
+        &input,
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 4.0
+deposit, 2, 3, 5.0
+dispute, 2, 3,
+chargeback, 2, 3,
+",
+    )
+    .unwrap();
+    let config = |command| Config {
+        command,
+        filename: input.to_str().unwrap().to_string(),
+        state_dir: Some(dir.to_str().unwrap().to_string()),
+        backend: Backend::File,
+        ..Config::default()
+    };
+
+    run(config(Command::Process)).unwrap();
+    assert_eq!(EventRepositoryFile::open(&dir).unwrap().count(), 6);
+    assert!(run(config(Command::Replay)).is_ok());
+
+    // A change that bypassed the event log is reported
+    AccountRepositoryFile::open(&dir).unwrap().update(
+        (1, Currency::DEFAULT),
+        Account::new(1, amount("1.0"), Amount::ZERO, amount("1.0"), false),
+    );
+    assert!(run(config(Command::Replay)).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn query_reports_balances_after_an_input_line() {
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 3.0
+withdrawal, 1, 3, 4.0
+dispute, 1, 1,
+";
+    let mut ps = build_payments_service();
+    process(
+        input.as_bytes(),
+        std::io::sink(),
+        &Config::default(),
+        &mut ps,
+    )
+    .unwrap();
+
+    let query_str = |ps: &mut PaymentService, point, client_id| {
+        let mut output = Vec::new();
+        query(ps, point, client_id, &mut output, &Default::default()).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    assert_eq!(
+        query_str(&mut ps, PointInTime::AfterLine(4), Some(1)),
+        "client, available, held, total, locked\n1, 6.0000, 0.0000, 6.0000, false\n"
+    );
+    assert_eq!(
+        query_str(&mut ps, PointInTime::AfterLine(2), None),
+        "client, available, held, total, locked\n1, 10.0000, 0.0000, 10.0000, false\n"
+    );
+    // The client had no account yet
+    assert_eq!(
+        query_str(&mut ps, PointInTime::AfterLine(2), Some(2)),
+        "client, available, held, total, locked\n"
+    );
+    // Input without timestamps happens at time 0
+    assert_eq!(
+        query_str(&mut ps, PointInTime::At(0), Some(1)),
+        "client, available, held, total, locked\n1, -4.0000, 10.0000, 6.0000, false\n"
+    );
+}
+
+#[test]
+fn query_reports_balances_at_a_time_of_the_input() {
+    let input = "\
+type, client, tx, amount, timestamp
+deposit, 1, 1, 10.0, 1700000000
+withdrawal, 1, 2, 4.0,
+deposit, 1, 3, 1.0, 1700000060
+dispute, 1, 1, , 1700000120
+";
+    let query_at = |timestamp| {
+        let mut ps = build_payments_service();
+        process(
+            input.as_bytes(),
+            std::io::sink(),
+            &Config::default(),
+            &mut ps,
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        let point = PointInTime::At(timestamp);
+        query(&mut ps, point, Some(1), &mut output, &Default::default()).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    assert_eq!(
+        query_at(1_699_999_999),
+        "client, available, held, total, locked\n"
+    );
+    // The withdrawal happens at the time of the line before it
+    assert_eq!(
+        query_at(1_700_000_059),
+        "client, available, held, total, locked\n1, 6.0000, 0.0000, 6.0000, false\n"
+    );
+    assert_eq!(
+        query_at(1_700_000_119),
+        "client, available, held, total, locked\n1, 7.0000, 0.0000, 7.0000, false\n"
+    );
+}
+
+#[test]
+fn trial_balance_lists_ledger_accounts() {
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 4.0
+dispute, 1, 2,
+chargeback, 1, 2,
+";
+    let mut ps = build_payments_service();
+    process(
+        input.as_bytes(),
+        std::io::sink(),
+        &Config::default(),
+        &mut ps,
+    )
+    .unwrap();
+
+    let mut output = Vec::new();
+    trial_balance(&mut ps, &mut output, &Default::default()).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+account, balance
+client 1 available, 10.0000
+client 1 held, 0.0000
+external funding, -6.0000
+chargeback loss, -4.0000
+total, 0.0000
+"
+    );
+}
+
+#[test]
+fn verify_reports_invariant_violations_in_a_state_directory() {
+    let dir = std::env::temp_dir().join(format!("payments-lib-verify-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let input = dir.join("input.csv");
+    std::fs::write(
+        &input,
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+dispute, 2, 2,
+",
+    )
+    .unwrap();
+    let config = |command, filename: &str| Config {
+        command,
+        filename: filename.to_string(),
+        state_dir: Some(dir.to_str().unwrap().to_string()),
+        backend: Backend::File,
+        ..Config::default()
+    };
+
+    run(config(Command::Process, input.to_str().unwrap())).unwrap();
+    assert!(run(config(Command::Verify, "-")).is_ok());
+
+    // A held balance without a disputed transaction
+    AccountRepositoryFile::open(&dir).unwrap().update(
+        (1, Currency::DEFAULT),
+        Account::new(1, amount("9.0"), amount("1.0"), amount("10.0"), false),
+    );
+    let mut ps = PaymentService::new(
+        Box::new(TransactionRepositoryFile::open(&dir).unwrap()),
+        Box::new(AccountRepositoryFile::open(&dir).unwrap()),
+    );
+    let mut output = Vec::new();
+    assert!(verify(&mut ps, &Default::default(), &mut output).is_err());
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+Checked 2 accounts and 2 transactions
+Violation: HeldMismatch: client 1 has held 1.0000 but disputed transactions and authorizations of 0.0000
+"
+    );
+    assert!(run(config(Command::Verify, "-")).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn verify_allows_an_account_overdrawn_by_a_chargeback() {
+    let dir = std::env::temp_dir().join(format!(
+        "payments-lib-verify-overdrawn-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let input = dir.join("input.csv");
+    std::fs::write(
+        &input,
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 8.0
+dispute, 1, 1,
+chargeback, 1, 1,
+",
+    )
+    .unwrap();
+
+    assert!(run(Config {
+        command: Command::Verify,
+        filename: input.to_str().unwrap().to_string(),
+        ..Config::default()
+    })
+    .is_ok());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn process_reports_credit_limits_and_headroom() {
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+limit, 1, 2, 50.0
+withdrawal, 1, 3, 40.0
+withdrawal, 1, 4, 30.0
+deposit, 2, 5, 5.0
+";
+    let (result, output) = process_str(input, &Config::default());
+
+    // The limit line's tx id doesn't take that of a transaction
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "\
+client, available, held, total, locked, limit, headroom
+1, -30.0000, 0.0000, -30.0000, false, 50.0000, 20.0000
+2, 5.0000, 0.0000, 5.0000, false, 0.0000, 5.0000
+"
+    );
+}
+
+#[test]
+fn process_holds_authorized_funds_until_they_are_captured_voided_or_expire() {
+    let input = "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+authorize, 1, 2, 6.0
+capture, 1, 2, 2.0
+authorize, 1, 3, 3.0
+void, 1, 3,
+deposit, 2, 4, 1.0
+capture, 1, 2,
+";
+    let (result, output) = process_str(input, &Config::default());
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "\
+client, available, held, total, locked
+1, 4.0000, 0.0000, 4.0000, false
+2, 1.0000, 0.0000, 1.0000, false
+"
+    );
+
+    // The first authorization expires before its last capture
+    let policy = PaymentServicePolicy {
+        authorization_expiry_lines: Some(3),
+        ..PaymentServicePolicy::default()
+    };
+    let mut ps = PaymentService::with_policy(
+        Box::new(TransactionRepositoryInMemory::new()),
+        Box::new(AccountRepositoryInMemory::new()),
+        policy,
+    );
+    let mut output = Vec::new();
+    let config = Config {
+        error_policy: ErrorPolicy::Strict,
+        ..Config::default()
+    };
+    let result = process(input.as_bytes(), &mut output, &config, &mut ps);
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Error processing input line 8: PaymentServiceError::InvalidTransactionState (tx: 2)"
+    );
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("8.0"), Amount::ZERO, amount("8.0"), false)
+    );
+}
+
+#[test]
+fn authorizations_expire_by_the_time_of_the_input_across_runs() {
+    let dir = std::env::temp_dir().join(format!("payments-lib-expiry-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let run_input = |name: &str, input: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, input).unwrap();
+        let args = [
+            "payments",
+            path.to_str().unwrap(),
+            "--state-dir",
+            dir.to_str().unwrap(),
+            "--backend",
+            "file",
+            "--auth-expiry-lines",
+            "3",
+            "--auth-expiry-seconds",
+            "60",
+        ];
+        run(Config::new(args.iter().map(|arg| arg.to_string())).unwrap()).unwrap();
+        TransactionRepositoryFile::open(&dir)
+            .unwrap()
+            .find(2)
+            .unwrap()
+            .state
+    };
+
+    let state = run_input(
+        "day1.csv",
+        "\
+type, client, tx, amount, timestamp
+deposit, 1, 1, 10.0, 1000
+authorize, 1, 2, 6.0, 1000
+",
+    );
+    assert_eq!(state, TransactionState::Authorized as u8);
+
+    // The authorization keeps the line it was made on, so it isn't expired by the lines of the
+    // next input until it is more than three past it
+    let state = run_input(
+        "day2.csv",
+        "\
+type, client, tx, amount, timestamp
+deposit, 1, 3, 1.0, 1010
+deposit, 1, 4, 1.0, 1020
+deposit, 1, 5, 1.0, 1060
+",
+    );
+    assert_eq!(state, TransactionState::Authorized as u8);
+
+    // A minute after it was made by the time of the input, however long the runs took
+    let state = run_input(
+        "day3.csv",
+        "\
+type, client, tx, amount, timestamp
+deposit, 1, 6, 1.0, 1061
+",
+    );
+    assert_eq!(state, TransactionState::Expired as u8);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn process_rejects_authorization_lines_with_misplaced_fields() {
+    let input = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 10.0,
+authorize, 1, 2, ,
+authorize, 1, 3, 5.0, USD
+capture, 1, 3, 1.0, USD
+void, 1, 3, 1.0,
+";
+    let (result, output) = process_str(input, &Config::default());
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "\
+client, available, held, total, locked
+1, 10.0000, 0.0000, 10.0000, false
+"
+    );
+}
+
+#[test]
+fn process_moves_funds_between_clients_with_transfers() {
+    let input = "\
+type, client, tx, amount, currency, destination
+deposit, 1, 1, 10.0, ,
+transfer, 1, 2, 4.0, , 2
+transfer, 2, 3, 1.0, , 3
+transfer, 1, 4, 1.0, , 1
+transfer, 1, 5, 1.0, ,
+deposit, 1, 6, 1.0, , 2
+transfer, 3, 7, 9.0, , 1
+";
+    let (result, output) = process_str(input, &Config::default());
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "\
+client, available, held, total, locked
+1, 6.0000, 0.0000, 6.0000, false
+2, 3.0000, 0.0000, 3.0000, false
+3, 1.0000, 0.0000, 1.0000, false
+"
+    );
+
+    // Disputing a transfer holds the funds on both sides
+    let input = "\
+type, client, tx, amount, destination
+deposit, 1, 1, 10.0,
+transfer, 1, 2, 4.0, 2
+dispute, 1, 2, ,
+";
+    let (result, output) = process_str(input, &Config::default());
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "\
+client, available, held, total, locked
+1, 6.0000, 4.0000, 10.0000, false
+2, 0.0000, 4.0000, 4.0000, false
+"
+    );
+}
+
+#[test]
+fn process_refunds_deposits_without_locking_the_account() {
+    let input = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 10.0,
+refund, 1, 1, 3.0,
+refund, 1, 1, 8.0,
+refund, 1, 1, ,
+refund, 1, 1, 1.0, USD
+dispute, 1, 1, ,
+";
+    let (result, output) = process_str(input, &Config::default());
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "\
+client, available, held, total, locked
+1, 0.0000, 7.0000, 7.0000, false
+"
+    );
+
+    // Refunds and captures can't have more decimal places than the currency of their
+    // transaction
+    let input = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 100, JPY
+refund, 1, 1, 0.5,
+refund, 1, 1, 1,
+authorize, 1, 2, 10, JPY
+capture, 1, 2, 2.5,
+capture, 1, 2, 2,
+";
+    let (result, output) = process_str(input, &Config::default());
+    assert!(result.is_ok());
+    assert_eq!(
+        output,
+        "\
+client, currency, available, held, total, locked
+1, JPY, 89, 8, 97, false
+"
+    );
+}
+
+#[test]
+fn profiles_set_credit_limits_once_across_runs() {
+    let dir = std::env::temp_dir().join(format!("payments-lib-profiles-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let input = dir.join("input.csv");
+    std::fs::write(
+        &input,
+        "type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 15.0\n",
+    )
+    .unwrap();
+    let profiles = dir.join("profiles.csv");
+    std::fs::write(&profiles, "client, limit, currency\n1, 20, \n").unwrap();
+    let config = || Config {
+        filename: input.to_str().unwrap().to_string(),
+        state_dir: Some(dir.to_str().unwrap().to_string()),
+        backend: Backend::File,
+        profiles: Some(profiles.to_str().unwrap().to_string()),
+        ..Config::default()
+    };
+
+    // The second run rejects the withdrawal as a duplicate, but the limit is already set
+    run(config()).unwrap();
+    run(config()).unwrap();
+
+    assert_eq!(
+        AccountRepositoryFile::open(&dir)
+            .unwrap()
+            .find((1, Currency::DEFAULT)),
+        Some(
+            &Account::new(1, -amount("5.0"), Amount::ZERO, -amount("5.0"), false)
+                .with_credit_limit(amount("20"))
+        )
+    );
+    let mut events = EventRepositoryFile::open(&dir).unwrap();
+    let limits_set = events
+        .find_all()
+        .iter()
+        .filter(|recorded| recorded.event.kind() == "CreditLimitSet")
+        .count();
+    assert_eq!(limits_set, 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
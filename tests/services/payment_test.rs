@@ -1,9 +1,26 @@
 use payments::repositories::account::in_memory::AccountRepositoryInMemory;
 use payments::repositories::transaction::in_memory::TransactionRepositoryInMemory;
-use payments::services::payment::{PaymentService, PaymentServiceTrait};
-
+use payments::repositories::transaction::TransactionRepositoryTrait;
+use payments::services::exchange::Exchange;
+use payments::services::fees::FeeSchedule;
+use payments::services::payment::{
+    PaymentService, PaymentServiceError, PaymentServicePolicy, PaymentServiceTrait,
+};
+
+use payments::core::amount::Amount;
+use payments::core::currency::Currency;
 use payments::core::entities::account::Account;
 use payments::core::entities::transaction::{Transaction, TransactionState, TransactionType};
+use payments::core::event::{self, Event, PointInTime, Position};
+use payments::core::exchange::{Conversion, RoundingMode};
+use payments::core::ledger::LedgerAccount;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn amount(value: &str) -> Amount {
+    value.parse().unwrap()
+}
 
 fn build_payments_service() -> PaymentService {
     let transaction_repository = Box::new(TransactionRepositoryInMemory::new());
@@ -16,18 +33,24 @@ fn build_payments_service_with_locked_account(client_id: u16, tx_id: u32) -> Pay
     let mut account_repository = Box::new(AccountRepositoryInMemory::new());
 
     // Create a locked account
-    let expected_ac = Account::new(client_id, 42.42, 0.0, 42.42, true);
+    let expected_ac = Account::new(
+        client_id,
+        amount("42.42"),
+        amount("0.0"),
+        amount("42.42"),
+        true,
+    );
     account_repository
         .as_mut()
         .store
-        .insert(client_id, expected_ac.clone());
+        .insert(expected_ac.key(), expected_ac.clone());
 
     // Create a transaction
     let tx1 = Transaction::new(
         tx_id,
         TransactionType::Deposit as u8,
         client_id,
-        42.42,
+        amount("42.42"),
         TransactionState::Normal as u8,
     );
     transaction_repository
@@ -43,11 +66,17 @@ fn build_payments_service_with_empty_account(client_id: u16) -> PaymentService {
     let mut account_repository = Box::new(AccountRepositoryInMemory::new());
 
     // Create a locked account
-    let expected_ac = Account::new(client_id, 0.0, 0.0, 0.0, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("0.0"),
+        amount("0.0"),
+        amount("0.0"),
+        false,
+    );
     account_repository
         .as_mut()
         .store
-        .insert(client_id, expected_ac.clone());
+        .insert(expected_ac.key(), expected_ac.clone());
 
     PaymentService::new(transaction_repository, account_repository)
 }
@@ -63,18 +92,24 @@ fn build_payments_service_with_default_account(client_id: u16) -> PaymentService
     let mut account_repository = Box::new(AccountRepositoryInMemory::new());
 
     // Create a locked account
-    let expected_ac = Account::new(client_id, 50.0, 10.0, 60.0, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("50.0"),
+        amount("10.0"),
+        amount("60.0"),
+        false,
+    );
     account_repository
         .as_mut()
         .store
-        .insert(client_id, expected_ac.clone());
+        .insert(expected_ac.key(), expected_ac.clone());
 
     // Create corresponding transactions
     let tx1 = Transaction::new(
         1,
         TransactionType::Deposit as u8,
         client_id,
-        50.00,
+        amount("50.00"),
         TransactionState::Normal as u8,
     );
     transaction_repository
@@ -85,7 +120,7 @@ fn build_payments_service_with_default_account(client_id: u16) -> PaymentService
         2,
         TransactionType::Deposit as u8,
         client_id,
-        10.00,
+        amount("10.00"),
         TransactionState::Disputed as u8,
     );
     transaction_repository
@@ -102,22 +137,36 @@ fn transactions_on_locked_accounts_fail() {
     let existing_tx_id = 1;
     let mut ps = build_payments_service_with_locked_account(client_id, existing_tx_id);
 
-    let initial_ac = ps.get_account(client_id).unwrap().clone();
+    let initial_ac = ps
+        .get_account(client_id, Currency::DEFAULT)
+        .unwrap()
+        .clone();
     let initial_tx = ps.get_transaction(existing_tx_id).unwrap().clone();
 
+    let locked = Err(PaymentServiceError::AccountLocked { client_id });
+
     let tx_id = 2;
-    assert!(ps.deposit(client_id, tx_id, 0.01).is_err());
+    assert_eq!(
+        ps.deposit(client_id, tx_id, amount("0.01"), Currency::DEFAULT),
+        locked
+    );
 
     let tx_id = 3;
-    assert!(ps.withdrawal(client_id, tx_id, 0.01).is_err());
+    assert_eq!(
+        ps.withdrawal(client_id, tx_id, amount("0.01"), Currency::DEFAULT),
+        locked
+    );
 
-    assert!(ps.dispute(client_id, existing_tx_id).is_err());
+    assert_eq!(ps.dispute(client_id, existing_tx_id), locked);
 
-    assert!(ps.resolve(client_id, existing_tx_id).is_err());
+    assert_eq!(ps.resolve(client_id, existing_tx_id), locked);
 
-    assert!(ps.chargeback(client_id, existing_tx_id).is_err());
+    assert_eq!(ps.chargeback(client_id, existing_tx_id), locked);
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &initial_ac); // No change
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &initial_ac
+    ); // No change
     assert_eq!(ps.get_transaction(existing_tx_id).unwrap(), &initial_tx); // No change to transaction
 }
 
@@ -127,17 +176,24 @@ fn transactions_on_non_existant_account_fails() {
     let client_id = 42;
     let mut ps = build_payments_service(); // No accounts
 
+    let missing = Err(PaymentServiceError::AccountDoesNotExist { client_id });
+
     let tx_id = 1;
-    assert!(ps.withdrawal(client_id, tx_id, 0.01).is_err());
+    assert_eq!(
+        ps.withdrawal(client_id, tx_id, amount("0.01"), Currency::DEFAULT),
+        missing
+    );
 
+    // Operations on an existing transaction look for it before the account
     let tx_id = 1;
-    assert!(ps.dispute(client_id, tx_id).is_err());
+    let unknown = Err(PaymentServiceError::TransactionDoesNotExist { tx_id });
+    assert_eq!(ps.dispute(client_id, tx_id), unknown);
 
-    assert!(ps.resolve(client_id, tx_id).is_err());
+    assert_eq!(ps.resolve(client_id, tx_id), unknown);
 
-    assert!(ps.chargeback(client_id, tx_id).is_err());
+    assert_eq!(ps.chargeback(client_id, tx_id), unknown);
 
-    assert_eq!(ps.get_account(client_id), None); // No account
+    assert_eq!(ps.get_account(client_id, Currency::DEFAULT), None); // No account
     assert_eq!(ps.get_transaction(tx_id), None); // No stored transaction
 }
 
@@ -146,22 +202,35 @@ fn deposit_creates_in_a_new_account() {
     let mut ps = build_payments_service();
 
     let client_id = 42;
-    let expected_ac = Account::new(client_id, 42.42, 0.0, 42.42, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("42.42"),
+        amount("0.0"),
+        amount("42.42"),
+        false,
+    );
     let tx_id = 1;
     let expected_tr = Transaction::new(
         tx_id,
         TransactionType::Deposit as u8,
         client_id,
-        42.42,
+        amount("42.42"),
         TransactionState::Normal as u8,
     );
 
-    let acc = ps.get_account(client_id);
+    let acc = ps.get_account(client_id, Currency::DEFAULT);
     assert_eq!(acc, None);
 
-    assert_eq!((), ps.deposit(client_id, tx_id, 42.42).unwrap());
+    assert_eq!(
+        (),
+        ps.deposit(client_id, tx_id, amount("42.42"), Currency::DEFAULT)
+            .unwrap()
+    );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
     assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
     assert_eq!(ps.get_accounts().len(), 1);
     assert_eq!(ps.get_transactions().len(), 1);
@@ -175,24 +244,37 @@ fn deposit_updates_total_and_available() {
     let client_id = 42;
     let mut ps = build_payments_service_with_empty_account(client_id);
 
-    let expected_ac = Account::new(client_id, 0.0001, 0.0, 0.0001, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("0.0001"),
+        amount("0.0"),
+        amount("0.0001"),
+        false,
+    );
     let tx_id = 2;
     let expected_tr = Transaction::new(
         tx_id,
         TransactionType::Deposit as u8,
         client_id,
-        0.0001,
+        amount("0.0001"),
         TransactionState::Normal as u8,
     );
 
-    assert_eq!((), ps.deposit(client_id, tx_id, 0.0001).unwrap()); // Smallest deposit
+    assert_eq!(
+        (),
+        ps.deposit(client_id, tx_id, amount("0.0001"), Currency::DEFAULT)
+            .unwrap()
+    ); // Smallest deposit
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
     assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
 
     let expected_ac = Account {
-        available: Account::to_fixed(0.0002),
-        total: Account::to_fixed(0.0002),
+        available: amount("0.0002"),
+        total: amount("0.0002"),
         ..expected_ac
     };
     let tx_id = 3;
@@ -200,13 +282,20 @@ fn deposit_updates_total_and_available() {
         tx_id,
         TransactionType::Deposit as u8,
         client_id,
-        0.0001,
+        amount("0.0001"),
         TransactionState::Normal as u8,
     );
 
-    assert_eq!((), ps.deposit(client_id, tx_id, 0.0001).unwrap()); // Smallest deposit
+    assert_eq!(
+        (),
+        ps.deposit(client_id, tx_id, amount("0.0001"), Currency::DEFAULT)
+            .unwrap()
+    ); // Smallest deposit
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
     assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
 
     assert_eq!(ps.get_accounts().len(), 1);
@@ -221,25 +310,47 @@ fn withdrawal_updates_total_and_available() {
     let client_id = 42;
     let mut ps = build_payments_service_with_default_account(client_id);
 
-    let expected_ac = Account::new(client_id, 50.0, 10.0, 60.0, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("50.0"),
+        amount("10.0"),
+        amount("60.0"),
+        false,
+    );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
 
     // Succeeds if less than available amount
 
-    let expected_ac = Account::new(client_id, 49.9999, 10.0, 59.9999, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("49.9999"),
+        amount("10.0"),
+        amount("59.9999"),
+        false,
+    );
     let tx_id = 201;
     let expected_tr = Transaction::new(
         tx_id,
         TransactionType::Withdrawal as u8,
         client_id,
-        0.0001,
+        amount("0.0001"),
         TransactionState::Normal as u8,
     );
 
-    assert_eq!((), ps.withdrawal(client_id, tx_id, 0.0001).unwrap());
+    assert_eq!(
+        (),
+        ps.withdrawal(client_id, tx_id, amount("0.0001"), Currency::DEFAULT)
+            .unwrap()
+    );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
     assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
 
     assert_eq!(ps.get_accounts().len(), 1);
@@ -247,20 +358,33 @@ fn withdrawal_updates_total_and_available() {
 
     // Succeeds if exactly available amount
 
-    let expected_ac = Account::new(client_id, 0.0, 10.0, 10.0, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("0.0"),
+        amount("10.0"),
+        amount("10.0"),
+        false,
+    );
 
     let tx_id = 202;
     let expected_tr = Transaction::new(
         tx_id,
         TransactionType::Withdrawal as u8,
         client_id,
-        49.9999,
+        amount("49.9999"),
         TransactionState::Normal as u8,
     );
 
-    assert_eq!((), ps.withdrawal(client_id, tx_id, 49.9999).unwrap());
+    assert_eq!(
+        (),
+        ps.withdrawal(client_id, tx_id, amount("49.9999"), Currency::DEFAULT)
+            .unwrap()
+    );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
     assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
 
     assert_eq!(ps.get_accounts().len(), 1);
@@ -275,15 +399,30 @@ fn withdrawal_fails_if_insufficient_funds() {
     let client_id = 42;
     let mut ps = build_payments_service_with_default_account(client_id);
 
-    let expected_ac = Account::new(client_id, 50.0, 10.0, 60.0, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("50.0"),
+        amount("10.0"),
+        amount("60.0"),
+        false,
+    );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
 
     // Fail on insufficient funds
     let tx_id = 200;
-    assert!(ps.withdrawal(client_id, tx_id, 100.0).is_err());
+    assert_eq!(
+        ps.withdrawal(client_id, tx_id, amount("100.0"), Currency::DEFAULT),
+        Err(PaymentServiceError::InsufficientFunds { client_id, tx_id })
+    );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac); // unchanged
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    ); // unchanged
     assert_eq!(ps.get_transaction(tx_id), None); // No stored transaction
 
     assert_eq!(ps.get_accounts().len(), 1);
@@ -300,20 +439,31 @@ fn dispute_adjusts_account_amounts_and_transaction_state() {
     let client_id = 42;
 
     let tx_id = 1;
-    assert!(!ps.deposit(client_id, tx_id, 42.42).is_err());
+    assert!(ps
+        .deposit(client_id, tx_id, amount("42.42"), Currency::DEFAULT)
+        .is_ok());
 
-    assert!(!ps.dispute(client_id, tx_id).is_err());
+    assert!(ps.dispute(client_id, tx_id).is_ok());
 
-    let expected_ac = Account::new(client_id, 0.00, 42.42, 42.42, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("0.00"),
+        amount("42.42"),
+        amount("42.42"),
+        false,
+    );
     let expected_tr = Transaction::new(
         1,
         TransactionType::Deposit as u8,
         client_id,
-        42.42,
+        amount("42.42"),
         TransactionState::Disputed as u8,
     );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
     assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
 }
 
@@ -325,18 +475,24 @@ fn dispute_handles_incorrect_transaction_state() {
     let mut account_repository = Box::new(AccountRepositoryInMemory::new());
 
     // Create a locked account
-    let ac = Account::new(client_id, 50.0, 10.0, 60.0, false);
+    let ac = Account::new(
+        client_id,
+        amount("50.0"),
+        amount("10.0"),
+        amount("60.0"),
+        false,
+    );
     account_repository
         .as_mut()
         .store
-        .insert(client_id, ac.clone());
+        .insert(ac.key(), ac.clone());
 
     // Create a disputed transaction
     let tx1 = Transaction::new(
         1,
         TransactionType::Deposit as u8,
         client_id,
-        10.0,
+        amount("10.0"),
         TransactionState::Disputed as u8,
     );
     transaction_repository
@@ -349,7 +505,7 @@ fn dispute_handles_incorrect_transaction_state() {
         2,
         TransactionType::Deposit as u8,
         client_id,
-        10.0,
+        amount("10.0"),
         TransactionState::Reversed as u8,
     );
     transaction_repository
@@ -359,10 +515,16 @@ fn dispute_handles_incorrect_transaction_state() {
 
     let mut ps = PaymentService::new(transaction_repository, account_repository);
 
-    assert!(ps.dispute(client_id, tx1.tx_id).is_err());
-    assert!(ps.dispute(client_id, tx2.tx_id).is_err());
+    assert_eq!(
+        ps.dispute(client_id, tx1.tx_id),
+        Err(PaymentServiceError::InvalidTransactionState { tx_id: tx1.tx_id })
+    );
+    assert_eq!(
+        ps.dispute(client_id, tx2.tx_id),
+        Err(PaymentServiceError::InvalidTransactionState { tx_id: tx2.tx_id })
+    );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &ac); // No change
+    assert_eq!(ps.get_account(client_id, Currency::DEFAULT).unwrap(), &ac); // No change
     assert_eq!(ps.get_transaction(tx1.tx_id).unwrap(), &tx1); // No change
     assert_eq!(ps.get_transaction(tx2.tx_id).unwrap(), &tx2); // No change
 }
@@ -374,7 +536,10 @@ fn dispute_on_non_existant_transaction_fails() {
 
     let tx_id = 201;
 
-    assert!(ps.dispute(client_id, tx_id).is_err());
+    assert_eq!(
+        ps.dispute(client_id, tx_id),
+        Err(PaymentServiceError::TransactionDoesNotExist { tx_id })
+    );
 }
 
 #[test]
@@ -384,21 +549,32 @@ fn resolve_adjusts_account_amounts_and_transaction_state() {
     let client_id = 42;
 
     let tx_id = 1;
-    assert!(!ps.deposit(client_id, tx_id, 42.42).is_err());
-    assert!(!ps.dispute(client_id, tx_id).is_err());
+    assert!(ps
+        .deposit(client_id, tx_id, amount("42.42"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.dispute(client_id, tx_id).is_ok());
 
-    assert!(!ps.resolve(client_id, tx_id).is_err());
+    assert!(ps.resolve(client_id, tx_id).is_ok());
 
-    let expected_ac = Account::new(client_id, 42.42, 0.00, 42.42, false);
+    let expected_ac = Account::new(
+        client_id,
+        amount("42.42"),
+        amount("0.00"),
+        amount("42.42"),
+        false,
+    );
     let expected_tr = Transaction::new(
         1,
         TransactionType::Deposit as u8,
         client_id,
-        42.42,
+        amount("42.42"),
         TransactionState::Normal as u8,
     );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
     assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
 }
 
@@ -408,7 +584,12 @@ fn resolve_handles_incorrect_transaction_state() {
     let mut ps = build_payments_service_with_default_account(client_id);
 
     let normal_tx_id = 1;
-    assert!(ps.resolve(client_id, normal_tx_id).is_err());
+    assert_eq!(
+        ps.resolve(client_id, normal_tx_id),
+        Err(PaymentServiceError::InvalidTransactionState {
+            tx_id: normal_tx_id
+        })
+    );
 }
 
 #[test]
@@ -417,7 +598,10 @@ fn resolve_on_non_existant_transaction_fails() {
     let mut ps = build_payments_service_with_default_account(client_id);
 
     let tx_id = 888;
-    assert!(ps.resolve(client_id, tx_id).is_err());
+    assert_eq!(
+        ps.resolve(client_id, tx_id),
+        Err(PaymentServiceError::TransactionDoesNotExist { tx_id })
+    );
 }
 
 #[test]
@@ -427,21 +611,32 @@ fn chargeback_adjusts_account_amounts_and_transaction_state() {
     let client_id = 42;
 
     let tx_id = 1;
-    assert!(!ps.deposit(client_id, tx_id, 42.42).is_err());
-    assert!(!ps.dispute(client_id, tx_id).is_err());
+    assert!(ps
+        .deposit(client_id, tx_id, amount("42.42"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.dispute(client_id, tx_id).is_ok());
 
-    assert!(!ps.chargeback(client_id, tx_id).is_err());
+    assert!(ps.chargeback(client_id, tx_id).is_ok());
 
-    let expected_ac = Account::new(client_id, 0.00, 0.00, 0.00, true);
+    let expected_ac = Account::new(
+        client_id,
+        amount("0.00"),
+        amount("0.00"),
+        amount("0.00"),
+        true,
+    );
     let expected_tr = Transaction::new(
         1,
         TransactionType::Deposit as u8,
         client_id,
-        42.42,
+        amount("42.42"),
         TransactionState::Reversed as u8,
     );
 
-    assert_eq!(ps.get_account(client_id).unwrap(), &expected_ac);
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
     assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
 }
 
@@ -451,7 +646,12 @@ fn chargeback_handles_incorrect_transaction_state() {
     let mut ps = build_payments_service_with_default_account(client_id);
 
     let normal_tx_id = 1;
-    assert!(ps.chargeback(client_id, normal_tx_id).is_err());
+    assert_eq!(
+        ps.chargeback(client_id, normal_tx_id),
+        Err(PaymentServiceError::InvalidTransactionState {
+            tx_id: normal_tx_id
+        })
+    );
 }
 
 #[test]
@@ -460,5 +660,1727 @@ fn chargeback_on_non_existant_transaction_fails() {
     let mut ps = build_payments_service_with_default_account(client_id);
 
     let tx_id = 888;
-    assert!(ps.chargeback(client_id, tx_id).is_err());
+    assert_eq!(
+        ps.chargeback(client_id, tx_id),
+        Err(PaymentServiceError::TransactionDoesNotExist { tx_id })
+    );
+}
+
+#[test]
+fn dispute_resolve_and_chargeback_fail_for_another_clients_transaction() {
+    let owner_id = 1;
+    let other_id = 2;
+    let mut ps = build_payments_service();
+
+    assert!(ps
+        .deposit(owner_id, 1, amount("10.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps
+        .deposit(other_id, 2, amount("5.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps
+        .deposit(owner_id, 3, amount("7.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.dispute(owner_id, 3).is_ok());
+
+    let owner_ac = ps.get_account(owner_id, Currency::DEFAULT).unwrap().clone();
+    let other_ac = ps.get_account(other_id, Currency::DEFAULT).unwrap().clone();
+    let normal_tx = ps.get_transaction(1).unwrap().clone();
+    let disputed_tx = ps.get_transaction(3).unwrap().clone();
+
+    assert_eq!(
+        ps.dispute(other_id, 1),
+        Err(PaymentServiceError::ClientMismatch {
+            client_id: other_id,
+            tx_id: 1
+        })
+    );
+    assert_eq!(
+        ps.resolve(other_id, 3),
+        Err(PaymentServiceError::ClientMismatch {
+            client_id: other_id,
+            tx_id: 3
+        })
+    );
+    assert_eq!(
+        ps.chargeback(other_id, 3),
+        Err(PaymentServiceError::ClientMismatch {
+            client_id: other_id,
+            tx_id: 3
+        })
+    );
+
+    // Neither account nor the transactions are changed
+    assert_eq!(
+        ps.get_account(owner_id, Currency::DEFAULT).unwrap(),
+        &owner_ac
+    );
+    assert_eq!(
+        ps.get_account(other_id, Currency::DEFAULT).unwrap(),
+        &other_ac
+    );
+    assert_eq!(ps.get_transaction(1).unwrap(), &normal_tx);
+    assert_eq!(ps.get_transaction(3).unwrap(), &disputed_tx);
+}
+
+#[test]
+fn disputes_check_the_transaction_before_the_account() {
+    let mut ps = build_payments_service();
+    let usd: Currency = "USD".parse().unwrap();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(2, 2, amount("10.00"), usd).is_ok());
+
+    // Ownership is considered before the disputing client's account
+    assert_eq!(
+        ps.dispute(2, 1),
+        Err(PaymentServiceError::ClientMismatch {
+            client_id: 2,
+            tx_id: 1
+        })
+    );
+    assert_eq!(ps.get_account(2, Currency::DEFAULT), None);
+
+    // The account is looked up in the currency of the transaction, whatever other accounts the
+    // client has
+    assert!(ps.dispute(2, 2).is_ok());
+    assert_eq!(
+        ps.get_account(2, usd).unwrap(),
+        &Account::new(2, Amount::ZERO, amount("10.00"), amount("10.00"), false).with_currency(usd)
+    );
+}
+
+#[test]
+fn redelivered_deposit_is_rejected_without_changes() {
+    let client_id = 42;
+    let tx_id = 1;
+    let mut ps = build_payments_service();
+
+    assert!(ps
+        .deposit(client_id, tx_id, amount("10.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.dispute(client_id, tx_id).is_ok());
+
+    let initial_ac = ps
+        .get_account(client_id, Currency::DEFAULT)
+        .unwrap()
+        .clone();
+    let initial_tx = ps.get_transaction(tx_id).unwrap().clone();
+
+    assert_eq!(
+        ps.deposit(client_id, tx_id, amount("10.0"), Currency::DEFAULT),
+        Err(PaymentServiceError::DuplicateTransaction { tx_id })
+    );
+
+    // No double credit, and the disputed state is kept
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &initial_ac
+    );
+    assert_eq!(ps.get_transaction(tx_id).unwrap(), &initial_tx);
+    assert_eq!(ps.get_transactions().len(), 1);
+}
+
+#[test]
+fn redelivered_withdrawal_is_rejected_without_changes() {
+    let client_id = 42;
+    let mut ps = build_payments_service_with_default_account(client_id);
+
+    let tx_id = 201;
+    assert!(ps
+        .withdrawal(client_id, tx_id, amount("5.0"), Currency::DEFAULT)
+        .is_ok());
+
+    let initial_ac = ps
+        .get_account(client_id, Currency::DEFAULT)
+        .unwrap()
+        .clone();
+    let initial_tx = ps.get_transaction(tx_id).unwrap().clone();
+
+    assert_eq!(
+        ps.withdrawal(client_id, tx_id, amount("5.0"), Currency::DEFAULT),
+        Err(PaymentServiceError::DuplicateTransaction { tx_id })
+    );
+
+    // Existing deposit ids can't be reused as withdrawals either
+    assert_eq!(
+        ps.withdrawal(client_id, 1, amount("5.0"), Currency::DEFAULT),
+        Err(PaymentServiceError::DuplicateTransaction { tx_id: 1 })
+    );
+
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &initial_ac
+    );
+    assert_eq!(ps.get_transaction(tx_id).unwrap(), &initial_tx);
+    assert_eq!(ps.get_transactions().len(), 3);
+}
+
+#[test]
+fn redelivered_transaction_for_another_client_does_not_create_an_account() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+
+    assert_eq!(
+        ps.deposit(2, 1, amount("10.0"), Currency::DEFAULT),
+        Err(PaymentServiceError::DuplicateTransaction { tx_id: 1 })
+    );
+
+    assert_eq!(ps.get_account(2, Currency::DEFAULT), None);
+    assert_eq!(ps.get_transaction(1).unwrap().client_id, 1);
+}
+
+fn build_payments_service_with_withdrawal(
+    client_id: u16,
+    tx_id: u32,
+    policy: PaymentServicePolicy,
+) -> PaymentService {
+    // This test setup has:
+    // - 1 account - client_id specified by argument - (30.00 available, 0.00 held)
+    // - 2 transactions:
+    //      tx_id 1 - deposit (50.00) - normal state
+    //      tx_id specified by argument - withdrawal (20.00) - normal state
+    let transaction_repository = Box::new(TransactionRepositoryInMemory::new());
+    let account_repository = Box::new(AccountRepositoryInMemory::new());
+    let mut ps = PaymentService::with_policy(transaction_repository, account_repository, policy);
+
+    assert!(ps
+        .deposit(client_id, 1, amount("50.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps
+        .withdrawal(client_id, tx_id, amount("20.0"), Currency::DEFAULT)
+        .is_ok());
+
+    ps
+}
+
+#[test]
+fn withdrawal_dispute_holds_the_withdrawn_amount() {
+    let client_id = 42;
+    let tx_id = 2;
+    let mut ps =
+        build_payments_service_with_withdrawal(client_id, tx_id, PaymentServicePolicy::default());
+
+    assert!(ps.dispute(client_id, tx_id).is_ok());
+
+    // Available funds are unchanged, the disputed withdrawal is held pending the outcome
+    let expected_ac = Account::new(
+        client_id,
+        amount("30.0"),
+        amount("20.0"),
+        amount("50.0"),
+        false,
+    );
+    let expected_tr = Transaction::new(
+        tx_id,
+        TransactionType::Withdrawal as u8,
+        client_id,
+        amount("20.0"),
+        TransactionState::Disputed as u8,
+    );
+
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
+    assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
+}
+
+#[test]
+fn withdrawal_resolve_releases_the_held_amount() {
+    let client_id = 42;
+    let tx_id = 2;
+    let mut ps =
+        build_payments_service_with_withdrawal(client_id, tx_id, PaymentServicePolicy::default());
+
+    assert!(ps.dispute(client_id, tx_id).is_ok());
+    assert!(ps.resolve(client_id, tx_id).is_ok());
+
+    // The withdrawal stands, so the account is as it was before the dispute
+    let expected_ac = Account::new(
+        client_id,
+        amount("30.0"),
+        amount("0.0"),
+        amount("30.0"),
+        false,
+    );
+    let expected_tr = Transaction::new(
+        tx_id,
+        TransactionType::Withdrawal as u8,
+        client_id,
+        amount("20.0"),
+        TransactionState::Normal as u8,
+    );
+
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
+    assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
+}
+
+#[test]
+fn withdrawal_chargeback_credits_funds_back() {
+    let client_id = 42;
+    let tx_id = 2;
+    let mut ps =
+        build_payments_service_with_withdrawal(client_id, tx_id, PaymentServicePolicy::default());
+
+    assert!(ps.dispute(client_id, tx_id).is_ok());
+    assert!(ps.chargeback(client_id, tx_id).is_ok());
+
+    // The withdrawal is reversed, so the client has the full deposit available again
+    let expected_ac = Account::new(
+        client_id,
+        amount("50.0"),
+        amount("0.0"),
+        amount("50.0"),
+        true,
+    );
+    let expected_tr = Transaction::new(
+        tx_id,
+        TransactionType::Withdrawal as u8,
+        client_id,
+        amount("20.0"),
+        TransactionState::Reversed as u8,
+    );
+
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
+    assert_eq!(ps.get_transaction(tx_id).unwrap(), &expected_tr);
+}
+
+#[test]
+fn withdrawal_dispute_fails_when_policy_disallows_it() {
+    let client_id = 42;
+    let tx_id = 2;
+    let policy = PaymentServicePolicy {
+        withdrawals_disputable: false,
+        ..PaymentServicePolicy::default()
+    };
+    let mut ps = build_payments_service_with_withdrawal(client_id, tx_id, policy);
+
+    let initial_ac = ps
+        .get_account(client_id, Currency::DEFAULT)
+        .unwrap()
+        .clone();
+    let initial_tx = ps.get_transaction(tx_id).unwrap().clone();
+
+    assert_eq!(
+        ps.dispute(client_id, tx_id),
+        Err(PaymentServiceError::TransactionNotDisputable { tx_id })
+    );
+    // Without a dispute there is nothing to resolve or charge back
+    assert_eq!(
+        ps.resolve(client_id, tx_id),
+        Err(PaymentServiceError::InvalidTransactionState { tx_id })
+    );
+    assert_eq!(
+        ps.chargeback(client_id, tx_id),
+        Err(PaymentServiceError::InvalidTransactionState { tx_id })
+    );
+
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &initial_ac
+    );
+    assert_eq!(ps.get_transaction(tx_id).unwrap(), &initial_tx);
+}
+
+#[test]
+fn deposit_dispute_resolve_and_chargeback_are_allowed_when_withdrawals_are_not_disputable() {
+    let client_id = 42;
+    let policy = PaymentServicePolicy {
+        withdrawals_disputable: false,
+        ..PaymentServicePolicy::default()
+    };
+    let mut ps = build_payments_service_with_withdrawal(client_id, 2, policy);
+
+    assert!(ps
+        .deposit(client_id, 3, amount("10.0"), Currency::DEFAULT)
+        .is_ok());
+
+    assert!(ps.dispute(client_id, 1).is_ok());
+    assert!(ps.resolve(client_id, 1).is_ok());
+    assert!(ps.dispute(client_id, 3).is_ok());
+    assert!(ps.chargeback(client_id, 3).is_ok());
+
+    let expected_ac = Account::new(
+        client_id,
+        amount("30.0"),
+        amount("0.0"),
+        amount("30.0"),
+        true,
+    );
+    assert_eq!(
+        ps.get_account(client_id, Currency::DEFAULT).unwrap(),
+        &expected_ac
+    );
+}
+
+#[test]
+fn failed_operations_record_no_events() {
+    let mut ps = build_payments_service();
+
+    assert!(ps
+        .withdrawal(1, 1, amount("1.0"), Currency::DEFAULT)
+        .is_err());
+    assert!(ps.deposit(1, 2, amount("1.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .withdrawal(1, 3, amount("5.0"), Currency::DEFAULT)
+        .is_err());
+    assert!(ps.resolve(1, 2).is_err());
+
+    let events: Vec<Event> = ps
+        .get_events()
+        .iter()
+        .map(|recorded| recorded.event)
+        .collect();
+    assert_eq!(
+        events,
+        vec![Event::DepositApplied {
+            client_id: 1,
+            tx_id: 2,
+            amount: amount("1.0"),
+            currency: Currency::DEFAULT,
+            conversion: None,
+        }]
+    );
+}
+
+#[test]
+fn replaying_the_events_rebuilds_the_state() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(2, 2, amount("5.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .withdrawal(1, 3, amount("4.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.dispute(1, 3).is_ok());
+    assert!(ps.resolve(1, 3).is_ok());
+    assert!(ps.dispute(2, 2).is_ok());
+    assert!(ps.chargeback(2, 2).is_ok());
+    assert!(ps.dispute(1, 1).is_ok());
+
+    assert_eq!(ps.get_events().len(), 9);
+    let events = ps.get_events();
+    let state = event::replay(events.into_iter().map(|recorded| &recorded.event));
+    assert_eq!(
+        state.accounts.values().collect::<Vec<_>>(),
+        ps.get_accounts()
+    );
+    assert_eq!(
+        state.transactions.values().collect::<Vec<_>>(),
+        ps.get_transactions()
+    );
+}
+
+#[test]
+fn accounts_can_be_queried_at_a_point_in_time() {
+    let mut ps = build_payments_service();
+    let at = |line, timestamp| Position { line, timestamp };
+
+    ps.set_position(at(2, 100));
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    ps.set_position(at(3, 100));
+    assert!(ps
+        .withdrawal(1, 2, amount("4.0"), Currency::DEFAULT)
+        .is_ok());
+    ps.set_position(at(4, 160));
+    assert!(ps.dispute(1, 1).is_ok());
+    ps.set_position(at(5, 200));
+    assert!(ps.chargeback(1, 1).is_ok());
+
+    let account = |available, held, total, locked| {
+        Account::new(1, amount(available), amount(held), amount(total), locked)
+    };
+    assert_eq!(
+        ps.get_account_at(1, Currency::DEFAULT, PointInTime::AfterLine(1)),
+        None
+    );
+    assert_eq!(
+        ps.get_account_at(1, Currency::DEFAULT, PointInTime::AfterLine(2)),
+        Some(account("10.0", "0.0", "10.0", false))
+    );
+    assert_eq!(
+        ps.get_account_at(1, Currency::DEFAULT, PointInTime::At(100)),
+        Some(account("6.0", "0.0", "6.0", false))
+    );
+    assert_eq!(
+        ps.get_account_at(1, Currency::DEFAULT, PointInTime::AfterLine(4)),
+        Some(Account::new(
+            1,
+            Amount::ZERO - amount("4.0"),
+            amount("10.0"),
+            amount("6.0"),
+            false
+        ))
+    );
+    assert_eq!(
+        ps.get_accounts_at(PointInTime::At(200)),
+        vec![ps.get_account(1, Currency::DEFAULT).unwrap().clone()]
+    );
+}
+
+#[test]
+fn trial_balance_sums_to_zero() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(2, 2, amount("5.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .withdrawal(1, 3, amount("4.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.dispute(1, 3).is_ok());
+    assert!(ps.chargeback(1, 3).is_ok());
+    assert!(ps.dispute(2, 2).is_ok());
+    assert!(ps.chargeback(2, 2).is_ok());
+    assert!(ps.deposit(3, 4, amount("1.5"), Currency::DEFAULT).is_ok());
+    assert!(ps.dispute(3, 4).is_ok());
+
+    let trial_balance = ps.get_trial_balance();
+    let sum = trial_balance
+        .iter()
+        .fold(Amount::ZERO, |sum, (_, balance)| sum + *balance);
+    assert_eq!(sum, Amount::ZERO);
+
+    // Client balances are those of their ledger accounts
+    for account in ps.get_accounts() {
+        let balance = |ledger_account| {
+            trial_balance
+                .iter()
+                .find(|(account, _)| *account == ledger_account)
+                .map_or(Amount::ZERO, |(_, balance)| *balance)
+        };
+        assert_eq!(
+            account.available,
+            balance(LedgerAccount::ClientAvailable(
+                account.client_id,
+                account.currency
+            ))
+        );
+        assert_eq!(
+            account.held,
+            balance(LedgerAccount::ClientHeld(
+                account.client_id,
+                account.currency
+            ))
+        );
+        assert_eq!(account.total, account.available + account.held);
+    }
+}
+
+#[test]
+fn balances_are_kept_per_currency() {
+    let usd: Currency = "USD".parse().unwrap();
+    let eur: Currency = "EUR".parse().unwrap();
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), usd).is_ok());
+    assert!(ps.deposit(1, 2, amount("5.0"), eur).is_ok());
+
+    // Funds in one currency can't be withdrawn in another
+    assert_eq!(
+        ps.withdrawal(1, 3, amount("6.0"), eur),
+        Err(PaymentServiceError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 3
+        })
+    );
+    assert_eq!(
+        ps.withdrawal(1, 4, amount("1.0"), Currency::DEFAULT),
+        Err(PaymentServiceError::AccountDoesNotExist { client_id: 1 })
+    );
+    assert!(ps.withdrawal(1, 5, amount("6.0"), usd).is_ok());
+
+    assert_eq!(
+        ps.get_accounts(),
+        vec![
+            &Account::new(1, amount("5.0"), Amount::ZERO, amount("5.0"), false).with_currency(eur),
+            &Account::new(1, amount("4.0"), Amount::ZERO, amount("4.0"), false).with_currency(usd),
+        ]
+    );
+    assert_eq!(ps.get_transaction(5).unwrap().currency, usd);
+}
+
+#[test]
+fn disputes_act_in_the_currency_of_the_transaction() {
+    let usd: Currency = "USD".parse().unwrap();
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(1, 2, amount("3.0"), usd).is_ok());
+
+    assert!(ps.dispute(1, 2).is_ok());
+    assert_eq!(
+        ps.get_account(1, usd).unwrap(),
+        &Account::new(1, Amount::ZERO, amount("3.0"), amount("3.0"), false).with_currency(usd)
+    );
+
+    // Only the account in the currency of the charged back transaction is locked
+    assert!(ps.chargeback(1, 2).is_ok());
+    assert_eq!(
+        ps.get_account(1, usd).unwrap(),
+        &Account::new(1, Amount::ZERO, Amount::ZERO, Amount::ZERO, true).with_currency(usd)
+    );
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("10.0"), Amount::ZERO, amount("10.0"), false)
+    );
+    assert_eq!(
+        ps.deposit(1, 3, amount("1.0"), usd),
+        Err(PaymentServiceError::AccountLocked { client_id: 1 })
+    );
+    assert!(ps.deposit(1, 4, amount("1.0"), Currency::DEFAULT).is_ok());
+
+    // The ledger balances in each currency
+    let trial_balance = ps.get_trial_balance();
+    for currency in [Currency::DEFAULT, usd] {
+        let sum = trial_balance
+            .iter()
+            .filter(|(account, _)| account.currency() == currency)
+            .fold(Amount::ZERO, |sum, (_, balance)| sum + *balance);
+        assert_eq!(sum, Amount::ZERO);
+    }
+}
+
+fn build_payments_service_with_exchange() -> PaymentService {
+    let mut exchange = Exchange::new("USD".parse().unwrap(), RoundingMode::HalfEven);
+    exchange
+        .read_rates(
+            "currency, rate, effective\n\
+             EUR, 1.1, 100\n\
+             EUR, 1.25, 200\n"
+                .as_bytes(),
+        )
+        .unwrap();
+    build_payments_service().with_exchange(exchange)
+}
+
+#[test]
+fn deposits_are_converted_at_the_rate_in_effect() {
+    let usd: Currency = "USD".parse().unwrap();
+    let eur: Currency = "EUR".parse().unwrap();
+    let mut ps = build_payments_service_with_exchange();
+
+    ps.set_position(Position {
+        line: 2,
+        timestamp: 150,
+    });
+    assert!(ps.deposit(1, 1, amount("10.35"), eur).is_ok());
+    assert!(ps.deposit(1, 2, amount("1.0"), usd).is_ok());
+    ps.set_position(Position {
+        line: 3,
+        timestamp: 200,
+    });
+    assert!(ps.withdrawal(1, 3, amount("2.0"), eur).is_ok());
+
+    // 11.385 is rounded half to even, and every amount is kept in the base currency
+    assert_eq!(
+        ps.get_accounts(),
+        vec![
+            &Account::new(1, amount("9.88"), Amount::ZERO, amount("9.88"), false)
+                .with_currency(usd)
+        ]
+    );
+    let tx = ps.get_transaction(1).unwrap();
+    assert_eq!((tx.amount, tx.currency), (amount("11.38"), usd));
+    assert_eq!(
+        tx.conversion,
+        Some(Conversion {
+            amount: amount("10.35"),
+            currency: eur,
+            rate: "1.1".parse().unwrap(),
+        })
+    );
+    assert_eq!(ps.get_transaction(2).unwrap().conversion, None);
+    assert_eq!(
+        ps.get_transaction(3).unwrap().conversion.unwrap().rate,
+        "1.25".parse().unwrap()
+    );
+}
+
+#[test]
+fn disputes_reverse_the_converted_amount() {
+    let usd: Currency = "USD".parse().unwrap();
+    let eur: Currency = "EUR".parse().unwrap();
+    let mut ps = build_payments_service_with_exchange();
+
+    ps.set_position(Position {
+        line: 2,
+        timestamp: 150,
+    });
+    assert!(ps.deposit(1, 1, amount("10.0"), eur).is_ok());
+
+    // The rate has changed since, but the amount held is the one credited
+    ps.set_position(Position {
+        line: 3,
+        timestamp: 250,
+    });
+    assert!(ps.dispute(1, 1).is_ok());
+    assert_eq!(
+        ps.get_account(1, usd).unwrap(),
+        &Account::new(1, Amount::ZERO, amount("11.0"), amount("11.0"), false).with_currency(usd)
+    );
+    assert!(ps.chargeback(1, 1).is_ok());
+    assert_eq!(
+        ps.get_account(1, usd).unwrap(),
+        &Account::new(1, Amount::ZERO, Amount::ZERO, Amount::ZERO, true).with_currency(usd)
+    );
+
+    // Replaying the events rebuilds the converted transaction
+    let events: Vec<Event> = ps
+        .get_events()
+        .iter()
+        .map(|recorded| recorded.event)
+        .collect();
+    let state = event::replay(&events);
+    assert_eq!(state.transactions.get(&1), ps.get_transaction(1));
+}
+
+#[test]
+fn conversion_fails_without_a_rate_in_effect() {
+    let eur: Currency = "EUR".parse().unwrap();
+    let gbp: Currency = "GBP".parse().unwrap();
+    let mut ps = build_payments_service_with_exchange();
+
+    ps.set_position(Position {
+        line: 2,
+        timestamp: 50,
+    });
+    assert_eq!(
+        ps.deposit(1, 1, amount("10.0"), eur),
+        Err(PaymentServiceError::ConversionFailed { tx_id: 1 })
+    );
+    ps.set_position(Position {
+        line: 3,
+        timestamp: 150,
+    });
+    assert_eq!(
+        ps.deposit(1, 2, amount("10.0"), gbp),
+        Err(PaymentServiceError::ConversionFailed { tx_id: 2 })
+    );
+    assert!(ps.get_accounts().is_empty());
+    assert!(ps.deposit(1, 2, amount("10.0"), eur).is_ok());
+}
+
+fn build_payments_service_with_fees() -> PaymentService {
+    let fees = FeeSchedule::read(
+        "type, from, flat, percent, min, max\n\
+         deposit, 10, 0.10, , , \n\
+         withdrawal, , 0.50, 1, , 2\n"
+            .as_bytes(),
+    )
+    .unwrap();
+    build_payments_service().with_fees(fees)
+}
+
+#[test]
+fn fees_are_charged_to_the_client_and_kept_as_house_revenue() {
+    let mut ps = build_payments_service_with_fees();
+
+    // Deposits below the first tier are free
+    assert!(ps.deposit(1, 1, amount("5.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(1, 2, amount("100.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .withdrawal(1, 3, amount("50.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps
+        .withdrawal(1, 4, amount("500.0"), Currency::DEFAULT)
+        .is_err());
+
+    // 105 deposited, 50 withdrawn, 0.10 + 1.00 in fees
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("53.9"), Amount::ZERO, amount("53.9"), false)
+    );
+
+    // Each fee is a transaction of its own, linked to the transaction it was charged for
+    assert_eq!(ps.get_transaction(Transaction::fee_tx_id(1)), None);
+    assert_eq!(
+        ps.get_transaction(Transaction::fee_tx_id(2)).unwrap(),
+        &Transaction::new(
+            u32::MAX - 2,
+            TransactionType::Fee as u8,
+            1,
+            amount("0.10"),
+            0
+        )
+        .with_fee_for(Some(2))
+    );
+    assert_eq!(
+        ps.get_transaction(Transaction::fee_tx_id(3)).unwrap(),
+        &Transaction::new(
+            u32::MAX - 3,
+            TransactionType::Fee as u8,
+            1,
+            amount("1.00"),
+            0
+        )
+        .with_fee_for(Some(3))
+    );
+    assert_eq!(ps.get_transaction(2).unwrap().amount, amount("100.0"));
+
+    // Fees can't be disputed on their own, nor their ids reused
+    assert_eq!(
+        ps.dispute(1, u32::MAX - 2),
+        Err(PaymentServiceError::TransactionNotDisputable {
+            tx_id: u32::MAX - 2
+        })
+    );
+    assert_eq!(
+        ps.deposit(1, u32::MAX - 2, amount("1.0"), Currency::DEFAULT),
+        Err(PaymentServiceError::DuplicateTransaction {
+            tx_id: u32::MAX - 2
+        })
+    );
+
+    let trial_balance = ps.get_trial_balance();
+    assert!(trial_balance.contains(&(
+        LedgerAccount::HouseRevenue(Currency::DEFAULT),
+        amount("1.10")
+    )));
+    let sum = trial_balance
+        .iter()
+        .fold(Amount::ZERO, |sum, (_, balance)| sum + *balance);
+    assert_eq!(sum, Amount::ZERO);
+}
+
+#[test]
+fn withdrawals_must_cover_their_fee() {
+    let mut ps = build_payments_service_with_fees();
+
+    assert!(ps.deposit(1, 1, amount("5.0"), Currency::DEFAULT).is_ok());
+    // 4.6 + 0.546 in fees is more than is available
+    assert_eq!(
+        ps.withdrawal(1, 2, amount("4.6"), Currency::DEFAULT),
+        Err(PaymentServiceError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 2
+        })
+    );
+    assert!(ps
+        .withdrawal(1, 3, amount("4.45"), Currency::DEFAULT)
+        .is_ok());
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap().available,
+        amount("0.0055")
+    );
+}
+
+#[test]
+fn fees_are_refunded_on_chargeback() {
+    let mut ps = build_payments_service_with_fees();
+
+    assert!(ps.deposit(1, 1, amount("100.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(1, 2, amount("20.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.dispute(1, 2).is_ok());
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("99.8"), amount("20.0"), amount("119.8"), false)
+    );
+
+    // The deposit goes back to the payer and its fee to the client
+    assert!(ps.chargeback(1, 2).is_ok());
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("99.9"), Amount::ZERO, amount("99.9"), true)
+    );
+    assert_eq!(
+        ps.get_transaction(Transaction::fee_tx_id(2)).unwrap().state,
+        TransactionState::Reversed as u8
+    );
+    assert_eq!(
+        ps.get_transaction(Transaction::fee_tx_id(1)).unwrap().state,
+        TransactionState::Normal as u8
+    );
+    assert!(ps.get_trial_balance().contains(&(
+        LedgerAccount::HouseRevenue(Currency::DEFAULT),
+        amount("0.10")
+    )));
+
+    // Replaying the events charges and refunds the same fees
+    let events: Vec<Event> = ps
+        .get_events()
+        .iter()
+        .map(|recorded| recorded.event)
+        .collect();
+    let state = event::replay(&events);
+    assert_eq!(
+        state.accounts.values().collect::<Vec<_>>(),
+        ps.get_accounts()
+    );
+    assert_eq!(
+        state.transactions.values().collect::<Vec<_>>(),
+        ps.get_transactions()
+    );
+}
+
+#[test]
+fn withdrawals_may_use_the_credit_limit() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .set_credit_limit(1, Currency::DEFAULT, amount("25.0"))
+        .is_ok());
+    assert!(ps
+        .withdrawal(1, 2, amount("30.0"), Currency::DEFAULT)
+        .is_ok());
+    assert_eq!(
+        ps.withdrawal(1, 3, amount("5.01"), Currency::DEFAULT),
+        Err(PaymentServiceError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 3
+        })
+    );
+    assert!(ps
+        .withdrawal(1, 4, amount("5.0"), Currency::DEFAULT)
+        .is_ok());
+
+    let account = ps.get_account(1, Currency::DEFAULT).unwrap();
+    assert_eq!(
+        account,
+        &Account::new(1, -amount("25.0"), Amount::ZERO, -amount("25.0"), false)
+            .with_credit_limit(amount("25.0"))
+    );
+    assert_eq!(account.headroom(), Amount::ZERO);
+
+    // Limits only apply to the account in their currency
+    let usd = "USD".parse().unwrap();
+    assert!(ps.deposit(1, 5, amount("1.0"), usd).is_ok());
+    assert!(ps.withdrawal(1, 6, amount("2.0"), usd).is_err());
+}
+
+#[test]
+fn credit_limits_create_the_account_and_are_only_recorded_when_changed() {
+    let mut ps = build_payments_service();
+
+    assert!(ps
+        .set_credit_limit(2, Currency::DEFAULT, amount("100"))
+        .is_ok());
+    assert!(ps
+        .set_credit_limit(2, Currency::DEFAULT, amount("100"))
+        .is_ok());
+    assert_eq!(
+        ps.get_account(2, Currency::DEFAULT).unwrap(),
+        &Account::new(2, Amount::ZERO, Amount::ZERO, Amount::ZERO, false)
+            .with_credit_limit(amount("100"))
+    );
+    assert!(ps.get_transactions().is_empty());
+
+    assert!(ps
+        .set_credit_limit(2, Currency::DEFAULT, Amount::ZERO)
+        .is_ok());
+    let events: Vec<Event> = ps
+        .get_events()
+        .iter()
+        .map(|recorded| recorded.event)
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            Event::CreditLimitSet {
+                client_id: 2,
+                currency: Currency::DEFAULT,
+                limit: amount("100"),
+            },
+            Event::CreditLimitSet {
+                client_id: 2,
+                currency: Currency::DEFAULT,
+                limit: Amount::ZERO,
+            },
+        ]
+    );
+    let state = event::replay(&events);
+    assert_eq!(
+        state.accounts.values().collect::<Vec<_>>(),
+        ps.get_accounts()
+    );
+}
+
+#[test]
+fn chargebacks_below_zero_are_applied_and_flagged() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(1, 2, amount("5.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .withdrawal(1, 3, amount("12.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.dispute(1, 1).is_ok());
+    assert!(ps.chargeback(1, 1).is_ok());
+
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, -amount("7.0"), Amount::ZERO, -amount("7.0"), true)
+    );
+    let events: Vec<Event> = ps
+        .get_events()
+        .iter()
+        .map(|recorded| recorded.event)
+        .skip(4)
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            Event::ChargedBack {
+                client_id: 1,
+                tx_id: 1
+            },
+            Event::Overdrawn {
+                client_id: 1,
+                tx_id: 1,
+                amount: amount("7.0"),
+            },
+            Event::AccountLocked {
+                client_id: 1,
+                currency: Currency::DEFAULT,
+            },
+        ]
+    );
+
+    // A chargeback that leaves the account at or above zero isn't flagged
+    assert!(ps.deposit(2, 4, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.dispute(2, 4).is_ok());
+    assert!(ps.chargeback(2, 4).is_ok());
+    assert!(!ps
+        .get_events()
+        .iter()
+        .any(|recorded| recorded.event.kind() == "Overdrawn" && recorded.event.client_id() == 2));
+}
+
+#[test]
+fn authorizations_hold_funds_until_captured_or_voided() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.authorize(1, 2, amount("6.0"), Currency::DEFAULT).is_ok());
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("4.0"), amount("6.0"), amount("10.0"), false)
+    );
+    // An authorization is not a dispute
+    assert_eq!(
+        ps.dispute(1, 2),
+        Err(PaymentServiceError::InvalidTransactionState { tx_id: 2 })
+    );
+
+    // Captures pay out part of the held funds, up to what the authorization still holds
+    assert!(ps.capture(1, 2, Some(amount("2.0"))).is_ok());
+    assert_eq!(
+        ps.capture(1, 2, Some(amount("4.5"))),
+        Err(PaymentServiceError::ExceedsAuthorization { tx_id: 2 })
+    );
+    assert_eq!(
+        ps.get_transaction(2).unwrap().state,
+        TransactionState::Authorized as u8
+    );
+    assert!(ps.capture(1, 2, None).is_ok());
+    assert_eq!(
+        ps.get_transaction(2).unwrap(),
+        &Transaction::new(
+            2,
+            TransactionType::Authorization as u8,
+            1,
+            amount("6.0"),
+            TransactionState::Captured as u8,
+        )
+        .with_captured(amount("6.0"))
+    );
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("4.0"), Amount::ZERO, amount("4.0"), false)
+    );
+    assert_eq!(
+        ps.void(1, 2),
+        Err(PaymentServiceError::InvalidTransactionState { tx_id: 2 })
+    );
+
+    // A void releases the funds
+    assert!(ps.authorize(1, 3, amount("3.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.void(1, 3).is_ok());
+    assert_eq!(
+        ps.get_transaction(3).unwrap().state,
+        TransactionState::Voided as u8
+    );
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("4.0"), Amount::ZERO, amount("4.0"), false)
+    );
+
+    let events: Vec<Event> = ps
+        .get_events()
+        .iter()
+        .map(|recorded| recorded.event)
+        .collect();
+    let state = event::replay(&events);
+    assert_eq!(
+        state.accounts.values().collect::<Vec<_>>(),
+        ps.get_accounts()
+    );
+    assert_eq!(
+        state.transactions.values().collect::<Vec<_>>(),
+        ps.get_transactions()
+    );
+    assert_eq!(
+        ps.get_trial_balance(),
+        vec![
+            (
+                LedgerAccount::ClientAvailable(1, Currency::DEFAULT),
+                amount("4.0")
+            ),
+            (
+                LedgerAccount::ClientHeld(1, Currency::DEFAULT),
+                Amount::ZERO
+            ),
+            (
+                LedgerAccount::ExternalFunding(Currency::DEFAULT),
+                -amount("4.0")
+            ),
+        ]
+    );
+}
+
+#[test]
+fn authorizations_are_rejected_without_the_funds() {
+    let mut ps = build_payments_service();
+
+    assert_eq!(
+        ps.authorize(1, 1, amount("1.0"), Currency::DEFAULT),
+        Err(PaymentServiceError::AccountDoesNotExist { client_id: 1 })
+    );
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert_eq!(
+        ps.authorize(1, 1, amount("1.0"), Currency::DEFAULT),
+        Err(PaymentServiceError::DuplicateTransaction { tx_id: 1 })
+    );
+    assert_eq!(
+        ps.authorize(1, 2, amount("10.5"), Currency::DEFAULT),
+        Err(PaymentServiceError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 2
+        })
+    );
+    assert_eq!(
+        ps.capture(2, 1, None),
+        Err(PaymentServiceError::ClientMismatch {
+            client_id: 2,
+            tx_id: 1
+        })
+    );
+    // Deposits can't be captured
+    assert_eq!(
+        ps.capture(1, 1, None),
+        Err(PaymentServiceError::InvalidTransactionState { tx_id: 1 })
+    );
+}
+
+#[test]
+fn stale_authorizations_expire() {
+    let policy = PaymentServicePolicy {
+        authorization_expiry_lines: Some(2),
+        authorization_expiry_seconds: Some(60),
+        ..PaymentServicePolicy::default()
+    };
+    let mut ps = PaymentService::with_policy(
+        Box::new(TransactionRepositoryInMemory::new()),
+        Box::new(AccountRepositoryInMemory::new()),
+        policy,
+    );
+    let at = |ps: &mut PaymentService, line, timestamp| {
+        ps.set_position(Position { line, timestamp });
+        ps.expire_authorizations();
+    };
+
+    at(&mut ps, 1, 1000);
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    at(&mut ps, 2, 1000);
+    assert!(ps.authorize(1, 2, amount("6.0"), Currency::DEFAULT).is_ok());
+    at(&mut ps, 3, 1030);
+    assert!(ps.authorize(1, 3, amount("3.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.capture(1, 3, Some(amount("1.0"))).is_ok());
+
+    // Open for two more lines
+    at(&mut ps, 4, 1030);
+    assert_eq!(
+        ps.get_transaction(2).unwrap().state,
+        TransactionState::Authorized as u8
+    );
+    at(&mut ps, 5, 1030);
+    assert_eq!(
+        ps.get_transaction(2).unwrap().state,
+        TransactionState::Expired as u8
+    );
+    assert_eq!(
+        ps.capture(1, 2, None),
+        Err(PaymentServiceError::InvalidTransactionState { tx_id: 2 })
+    );
+
+    // Or for a minute, even if the account was locked in the meantime
+    assert!(ps.deposit(1, 4, amount("1.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.dispute(1, 4).is_ok());
+    assert!(ps.chargeback(1, 4).is_ok());
+    at(&mut ps, 5, 1091);
+    assert_eq!(
+        ps.get_transaction(3).unwrap().state,
+        TransactionState::Expired as u8
+    );
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("9.0"), Amount::ZERO, amount("9.0"), true)
+    );
+
+    let expired: Vec<_> = ps
+        .get_events()
+        .into_iter()
+        .filter(|recorded| recorded.event.kind() == "AuthorizationExpired")
+        .map(|recorded| (recorded.event.tx_id(), recorded.position.line))
+        .collect();
+    assert_eq!(expired, vec![(Some(2), 5), (Some(3), 5)]);
+}
+
+#[test]
+fn transfers_move_funds_between_clients() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .transfer(1, 2, amount("4.0"), Currency::DEFAULT, 2)
+        .is_ok());
+    assert_eq!(
+        ps.get_accounts(),
+        vec![
+            &Account::new(1, amount("6.0"), Amount::ZERO, amount("6.0"), false),
+            &Account::new(2, amount("4.0"), Amount::ZERO, amount("4.0"), false),
+        ]
+    );
+    assert_eq!(
+        ps.get_transaction(2).unwrap(),
+        &Transaction::new(
+            2,
+            TransactionType::Transfer as u8,
+            1,
+            amount("4.0"),
+            TransactionState::Normal as u8,
+        )
+        .with_destination(Some(2))
+    );
+
+    assert_eq!(
+        ps.transfer(1, 3, amount("6.5"), Currency::DEFAULT, 2),
+        Err(PaymentServiceError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 3
+        })
+    );
+    assert_eq!(
+        ps.transfer(1, 3, amount("1.0"), Currency::DEFAULT, 1),
+        Err(PaymentServiceError::InvalidDestination {
+            client_id: 1,
+            tx_id: 3
+        })
+    );
+    assert_eq!(
+        ps.transfer(3, 3, amount("1.0"), Currency::DEFAULT, 1),
+        Err(PaymentServiceError::AccountDoesNotExist { client_id: 3 })
+    );
+    assert_eq!(
+        ps.transfer(1, 2, amount("1.0"), Currency::DEFAULT, 3),
+        Err(PaymentServiceError::DuplicateTransaction { tx_id: 2 })
+    );
+
+    let events: Vec<Event> = ps
+        .get_events()
+        .iter()
+        .map(|recorded| recorded.event)
+        .collect();
+    assert_eq!(
+        events[1..],
+        [
+            Event::TransferSent {
+                client_id: 1,
+                tx_id: 2,
+                amount: amount("4.0"),
+                currency: Currency::DEFAULT,
+                conversion: None,
+            },
+            Event::TransferReceived {
+                client_id: 2,
+                tx_id: 2
+            },
+        ]
+    );
+    let state = event::replay(&events);
+    assert_eq!(
+        state.accounts.values().collect::<Vec<_>>(),
+        ps.get_accounts()
+    );
+    assert_eq!(
+        state.transactions.values().collect::<Vec<_>>(),
+        ps.get_transactions()
+    );
+}
+
+#[test]
+fn transfers_are_rejected_whole_if_either_account_is_locked() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(2, 2, amount("5.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(2, 3, amount("1.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.dispute(2, 3).is_ok());
+    assert!(ps.chargeback(2, 3).is_ok());
+    let accounts: Vec<Account> = ps.get_accounts().into_iter().cloned().collect();
+    let events = ps.get_events().len();
+
+    assert_eq!(
+        ps.transfer(1, 4, amount("1.0"), Currency::DEFAULT, 2),
+        Err(PaymentServiceError::AccountLocked { client_id: 2 })
+    );
+    assert_eq!(
+        ps.transfer(2, 4, amount("1.0"), Currency::DEFAULT, 1),
+        Err(PaymentServiceError::AccountLocked { client_id: 2 })
+    );
+
+    assert_eq!(ps.get_accounts(), accounts.iter().collect::<Vec<_>>());
+    assert_eq!(ps.get_transaction(4), None);
+    assert_eq!(ps.get_events().len(), events);
+}
+
+#[test]
+fn transfer_disputes_act_on_both_clients() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .transfer(1, 2, amount("4.0"), Currency::DEFAULT, 2)
+        .is_ok());
+
+    // Only the sender can dispute the transfer
+    assert_eq!(
+        ps.dispute(2, 2),
+        Err(PaymentServiceError::ClientMismatch {
+            client_id: 2,
+            tx_id: 2
+        })
+    );
+
+    // The sender's claim is held, along with the funds the recipient got
+    assert!(ps.dispute(1, 2).is_ok());
+    assert_eq!(
+        ps.get_accounts(),
+        vec![
+            &Account::new(1, amount("6.0"), amount("4.0"), amount("10.0"), false),
+            &Account::new(2, Amount::ZERO, amount("4.0"), amount("4.0"), false),
+        ]
+    );
+    assert!(ps.resolve(1, 2).is_ok());
+    assert_eq!(
+        ps.get_accounts(),
+        vec![
+            &Account::new(1, amount("6.0"), Amount::ZERO, amount("6.0"), false),
+            &Account::new(2, amount("4.0"), Amount::ZERO, amount("4.0"), false),
+        ]
+    );
+
+    // A chargeback reverses both legs, even once the recipient spent some of the funds, and locks
+    // both accounts
+    assert!(ps.dispute(1, 2).is_ok());
+    assert!(ps
+        .withdrawal(2, 3, amount("3.0"), Currency::DEFAULT)
+        .is_err());
+    assert!(ps.resolve(1, 2).is_ok());
+    assert!(ps
+        .withdrawal(2, 3, amount("3.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.dispute(1, 2).is_ok());
+    assert!(ps.chargeback(1, 2).is_ok());
+    assert_eq!(
+        ps.get_accounts(),
+        vec![
+            &Account::new(1, amount("10.0"), Amount::ZERO, amount("10.0"), true),
+            &Account::new(2, -amount("3.0"), Amount::ZERO, -amount("3.0"), true),
+        ]
+    );
+    assert_eq!(
+        ps.get_transaction(2).unwrap().state,
+        TransactionState::Reversed as u8
+    );
+
+    let events: Vec<Event> = ps
+        .get_events()
+        .iter()
+        .map(|recorded| recorded.event)
+        .collect();
+    assert_eq!(
+        events[events.len() - 5..],
+        [
+            Event::ChargedBack {
+                client_id: 1,
+                tx_id: 2
+            },
+            Event::ChargedBack {
+                client_id: 2,
+                tx_id: 2
+            },
+            Event::Overdrawn {
+                client_id: 2,
+                tx_id: 2,
+                amount: amount("3.0"),
+            },
+            Event::AccountLocked {
+                client_id: 1,
+                currency: Currency::DEFAULT,
+            },
+            Event::AccountLocked {
+                client_id: 2,
+                currency: Currency::DEFAULT,
+            },
+        ]
+    );
+    let state = event::replay(&events);
+    assert_eq!(
+        state.accounts.values().collect::<Vec<_>>(),
+        ps.get_accounts()
+    );
+
+    // Nothing is left in transfer clearing once the dispute is settled
+    let trial_balance = ps.get_trial_balance();
+    assert!(trial_balance.contains(&(
+        LedgerAccount::TransferClearing(Currency::DEFAULT),
+        Amount::ZERO
+    )));
+    let sum = trial_balance
+        .iter()
+        .fold(Amount::ZERO, |sum, (_, balance)| sum + *balance);
+    assert_eq!(sum, Amount::ZERO);
+}
+
+#[test]
+fn transfer_disputes_need_both_accounts_unlocked() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .transfer(1, 2, amount("4.0"), Currency::DEFAULT, 2)
+        .is_ok());
+    assert!(ps
+        .transfer(1, 3, amount("1.0"), Currency::DEFAULT, 2)
+        .is_ok());
+    assert!(ps.dispute(1, 3).is_ok());
+
+    // The recipient is locked by a chargeback of their own
+    assert!(ps.deposit(2, 4, amount("1.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.dispute(2, 4).is_ok());
+    assert!(ps.chargeback(2, 4).is_ok());
+    let accounts: Vec<Account> = ps.get_accounts().into_iter().cloned().collect();
+    let events = ps.get_events().len();
+
+    assert_eq!(
+        ps.dispute(1, 2),
+        Err(PaymentServiceError::AccountLocked { client_id: 2 })
+    );
+    assert_eq!(
+        ps.resolve(1, 3),
+        Err(PaymentServiceError::AccountLocked { client_id: 2 })
+    );
+    assert_eq!(
+        ps.chargeback(1, 3),
+        Err(PaymentServiceError::AccountLocked { client_id: 2 })
+    );
+    assert_eq!(ps.get_accounts(), accounts.iter().collect::<Vec<_>>());
+    assert_eq!(ps.get_events().len(), events);
+}
+
+#[test]
+fn transfer_disputes_are_rejected_if_the_destination_account_is_missing() {
+    let mut tx_store = Box::new(TransactionRepositoryInMemory::new());
+    let mut ac_store = Box::new(AccountRepositoryInMemory::new());
+    let sender = Account::new(1, amount("6.0"), Amount::ZERO, amount("6.0"), false);
+    ac_store.store.insert(sender.key(), sender);
+    let transfer = Transaction::new(
+        2,
+        TransactionType::Transfer as u8,
+        1,
+        amount("4.0"),
+        TransactionState::Normal as u8,
+    )
+    .with_destination(Some(2));
+    tx_store.update(2, transfer);
+    let mut ps = PaymentService::new(tx_store, ac_store);
+
+    assert_eq!(
+        ps.dispute(1, 2),
+        Err(PaymentServiceError::AccountDoesNotExist { client_id: 2 })
+    );
+}
+
+#[test]
+fn deposits_can_be_refunded_in_parts() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.refund(1, 1, amount("4.0")).is_ok());
+    assert!(ps.refund(1, 1, amount("1.5")).is_ok());
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("4.5"), Amount::ZERO, amount("4.5"), false)
+    );
+    assert_eq!(
+        ps.get_transaction(1).unwrap(),
+        &Transaction::new(
+            1,
+            TransactionType::Deposit as u8,
+            1,
+            amount("10.0"),
+            TransactionState::Normal as u8,
+        )
+        .with_refunded(amount("5.5"))
+    );
+
+    // Only what is left of the deposit can be refunded
+    assert_eq!(
+        ps.refund(1, 1, amount("4.6")),
+        Err(PaymentServiceError::ExceedsRefundable { tx_id: 1 })
+    );
+    assert!(ps.refund(1, 1, amount("4.5")).is_ok());
+    assert_eq!(
+        ps.refund(1, 1, amount("0.0001")),
+        Err(PaymentServiceError::ExceedsRefundable { tx_id: 1 })
+    );
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, Amount::ZERO, Amount::ZERO, Amount::ZERO, false)
+    );
+
+    let events: Vec<Event> = ps
+        .get_events()
+        .iter()
+        .map(|recorded| recorded.event)
+        .collect();
+    assert_eq!(
+        events[1],
+        Event::DepositRefunded {
+            client_id: 1,
+            tx_id: 1,
+            amount: amount("4.0"),
+        }
+    );
+    let state = event::replay(&events);
+    assert_eq!(
+        state.transactions.values().collect::<Vec<_>>(),
+        ps.get_transactions()
+    );
+}
+
+#[test]
+fn refunds_are_rejected_for_anything_but_a_settled_deposit() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .withdrawal(1, 2, amount("1.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.deposit(2, 3, amount("1.0"), Currency::DEFAULT).is_ok());
+
+    assert_eq!(
+        ps.refund(1, 2, amount("1.0")),
+        Err(PaymentServiceError::TransactionNotRefundable { tx_id: 2 })
+    );
+    assert_eq!(
+        ps.refund(1, 3, amount("1.0")),
+        Err(PaymentServiceError::ClientMismatch {
+            client_id: 1,
+            tx_id: 3
+        })
+    );
+    assert_eq!(
+        ps.refund(1, 4, amount("1.0")),
+        Err(PaymentServiceError::TransactionDoesNotExist { tx_id: 4 })
+    );
+    assert!(ps.dispute(1, 1).is_ok());
+    assert_eq!(
+        ps.refund(1, 1, amount("1.0")),
+        Err(PaymentServiceError::InvalidTransactionState { tx_id: 1 })
+    );
+    assert!(ps.resolve(1, 1).is_ok());
+
+    // The refund can't take more than the account has
+    assert!(ps
+        .withdrawal(1, 5, amount("8.0"), Currency::DEFAULT)
+        .is_ok());
+    assert_eq!(
+        ps.refund(1, 1, amount("2.0")),
+        Err(PaymentServiceError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 1
+        })
+    );
+    assert!(ps.refund(1, 1, amount("1.0")).is_ok());
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, Amount::ZERO, Amount::ZERO, Amount::ZERO, false)
+    );
+}
+
+#[test]
+fn disputes_on_a_refunded_deposit_hold_what_is_left_of_it() {
+    let mut ps = build_payments_service();
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(1, 2, amount("5.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.refund(1, 1, amount("6.0")).is_ok());
+
+    assert!(ps.dispute(1, 1).is_ok());
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("5.0"), amount("4.0"), amount("9.0"), false)
+    );
+    assert!(ps.resolve(1, 1).is_ok());
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("9.0"), Amount::ZERO, amount("9.0"), false)
+    );
+
+    // Refunding never locks the account, but a chargeback still does
+    assert!(ps.dispute(1, 1).is_ok());
+    assert!(ps.chargeback(1, 1).is_ok());
+    assert_eq!(
+        ps.get_account(1, Currency::DEFAULT).unwrap(),
+        &Account::new(1, amount("5.0"), Amount::ZERO, amount("5.0"), true)
+    );
+    assert_eq!(
+        ps.get_trial_balance(),
+        vec![
+            (
+                LedgerAccount::ClientAvailable(1, Currency::DEFAULT),
+                amount("5.0")
+            ),
+            (
+                LedgerAccount::ClientHeld(1, Currency::DEFAULT),
+                Amount::ZERO
+            ),
+            (
+                LedgerAccount::ExternalFunding(Currency::DEFAULT),
+                -amount("5.0")
+            ),
+        ]
+    );
+
+    // A deposit refunded in full leaves nothing to dispute
+    assert!(ps.deposit(2, 3, amount("5.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.refund(2, 3, amount("5.0")).is_ok());
+    assert_eq!(
+        ps.dispute(2, 3),
+        Err(PaymentServiceError::TransactionNotDisputable { tx_id: 3 })
+    );
+    assert_eq!(
+        ps.get_transaction(3).unwrap().state,
+        TransactionState::Normal as u8
+    );
+}
+
+#[test]
+fn operations_that_would_overflow_a_balance_are_rejected() {
+    let mut account_repository = Box::new(AccountRepositoryInMemory::new());
+    let full = Account::new(
+        1,
+        Amount::from_fixed(i128::MAX),
+        Amount::ZERO,
+        Amount::from_fixed(i128::MAX),
+        false,
+    );
+    account_repository
+        .as_mut()
+        .store
+        .insert(full.key(), full.clone());
+    let mut ps = PaymentService::new(
+        Box::new(TransactionRepositoryInMemory::new()),
+        account_repository,
+    );
+
+    assert_eq!(
+        ps.deposit(1, 1, Amount::MAX, Currency::DEFAULT),
+        Err(PaymentServiceError::BalanceOverflow { client_id: 1 })
+    );
+    assert_eq!(ps.get_account(1, Currency::DEFAULT).unwrap(), &full);
+    assert_eq!(ps.get_transaction(1), None);
+    assert!(ps.get_events().is_empty());
+
+    assert!(ps.withdrawal(1, 2, Amount::MAX, Currency::DEFAULT).is_ok());
+    assert!(ps.deposit(1, 3, Amount::MAX, Currency::DEFAULT).is_ok());
+}
+
+/// Transaction repository that records which transactions were inserted and which updated
+struct RecordingTransactionRepository {
+    inner: TransactionRepositoryInMemory,
+    writes: Rc<RefCell<Vec<(&'static str, u32)>>>,
+}
+
+impl TransactionRepositoryTrait for RecordingTransactionRepository {
+    fn insert(&mut self, transaction: Transaction) -> bool {
+        self.writes.borrow_mut().push(("insert", transaction.tx_id));
+        self.inner.insert(transaction)
+    }
+    fn update(&mut self, tx_id: u32, transaction: Transaction) {
+        self.writes.borrow_mut().push(("update", tx_id));
+        self.inner.update(tx_id, transaction)
+    }
+    fn find(&mut self, tx_id: u32) -> Option<&Transaction> {
+        self.inner.find(tx_id)
+    }
+    fn find_all(&mut self) -> Vec<&Transaction> {
+        self.inner.find_all()
+    }
+    fn begin(&mut self) {
+        self.inner.begin()
+    }
+    fn commit(&mut self) {
+        self.inner.commit()
+    }
+    fn rollback(&mut self) {
+        self.inner.rollback()
+    }
+}
+
+#[test]
+fn new_transactions_are_inserted_and_only_state_changes_update() {
+    let writes = Rc::new(RefCell::new(Vec::new()));
+    let mut ps = PaymentService::new(
+        Box::new(RecordingTransactionRepository {
+            inner: TransactionRepositoryInMemory::new(),
+            writes: writes.clone(),
+        }),
+        Box::new(AccountRepositoryInMemory::new()),
+    );
+
+    assert!(ps.deposit(1, 1, amount("10.0"), Currency::DEFAULT).is_ok());
+    assert!(ps
+        .withdrawal(1, 2, amount("1.0"), Currency::DEFAULT)
+        .is_ok());
+    assert!(ps.dispute(1, 1).is_ok());
+    assert!(ps.resolve(1, 1).is_ok());
+    assert!(ps
+        .transfer(1, 3, amount("1.0"), Currency::DEFAULT, 2)
+        .is_ok());
+    assert!(ps.authorize(1, 4, amount("1.0"), Currency::DEFAULT).is_ok());
+    assert!(ps.capture(1, 4, None).is_ok());
+
+    assert_eq!(
+        *writes.borrow(),
+        vec![
+            ("insert", 1),
+            ("insert", 2),
+            ("update", 1),
+            ("update", 1),
+            ("insert", 3),
+            ("insert", 4),
+            ("update", 4),
+        ]
+    );
 }